@@ -0,0 +1,44 @@
+extern crate byteorder;
+#[macro_use]
+extern crate criterion;
+extern crate net;
+
+use byteorder::{ByteOrder, NetworkEndian};
+use criterion::{black_box, Criterion, Throughput};
+use net::ip_checksum;
+
+/// The previous implementation, which sums 16-bit words, as a baseline.
+fn reference(data: &[u8]) -> u16 {
+    let mut accum: u32 = 0;
+    let mut i = 0;
+    while i < data.len() {
+        let word;
+        if i + 2 <= data.len() {
+            word = NetworkEndian::read_u16(&data[i..i + 2]) as u32
+        } else {
+            word = (data[i] as u32) << 8
+        }
+        accum += word;
+        i += 2;
+    }
+    let sum = (accum >> 16) + (accum & 0xffff);
+    ((sum >> 16) as u16) + (sum as u16)
+}
+
+fn checksum(c: &mut Criterion) {
+    let data = [0xa5; 1501];
+    let mut group = c.benchmark_group("checksum");
+    group.throughput(Throughput::Bytes(1500));
+    group.bench_function("1500_bytes", |b| b.iter(|| ip_checksum::data(black_box(&data[..1500]))));
+    group.bench_function("1500_bytes_unaligned",
+                         |b| b.iter(|| ip_checksum::data(black_box(&data[1..]))));
+    group.bench_function("1500_bytes_reference",
+                         |b| b.iter(|| reference(black_box(&data[..1500]))));
+    group.throughput(Throughput::Bytes(61));
+    group.bench_function("odd_length", |b| b.iter(|| ip_checksum::data(black_box(&data[..61]))));
+    group.bench_function("odd_length_reference", |b| b.iter(|| reference(black_box(&data[..61]))));
+    group.finish();
+}
+
+criterion_group!(benches, checksum);
+criterion_main!(benches);
@@ -0,0 +1,75 @@
+//! Parsing and serialization of whole frames and a TCP handshake.
+//!
+//! The captured frames of `testing::frames` need the `testing` feature:
+//! `cargo bench --features testing`.
+
+#[macro_use]
+extern crate criterion;
+#[macro_use]
+extern crate net;
+
+use criterion::{black_box, Criterion, Throughput};
+use net::{arp, dhcp, parse, HeapTxPacket};
+use net::ethernet::EthernetAddress;
+use net::ipv4::{ConnectionId, Endpoint, Ipv4Address};
+use net::tcp::{TcpConnection, TcpPacket, TcpState};
+use net::testing::frames;
+
+fn parse_frames(c: &mut Criterion) {
+    let mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let ip = Ipv4Address::new(192, 168, 1, 100);
+    // padded to the minimum frame length, like received frames
+    let mut arp_request = HeapTxPacket::write_out(arp::new_request_packet(mac, ip, ip))
+        .unwrap()
+        .to_vec();
+    arp_request.resize(60, 0);
+    let inputs = [("arp_request", arp_request),
+                  ("dhcp_offer", hex!(frames::DHCP_OFFER)),
+                  ("dns_reply", hex!(frames::DNS_REPLY)),
+                  ("tcp_syn", hex!(frames::TCP_SYN))];
+
+    let mut group = c.benchmark_group("parse");
+    for &(name, ref frame) in &inputs {
+        assert!(parse(frame).is_ok(), "{} doesn't parse", name);
+        group.throughput(Throughput::Bytes(frame.len() as u64));
+        group.bench_function(name, |b| b.iter(|| parse(black_box(&frame[..])).is_ok()));
+    }
+    group.finish();
+}
+
+fn write_dhcp_discover(c: &mut Criterion) {
+    let mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    c.bench_function("write_out/dhcp_discover", |b| {
+        b.iter(|| HeapTxPacket::write_out(dhcp::new_discover_msg(black_box(mac))).unwrap())
+    });
+}
+
+/// Passes the queued segments of `from` to `to`.
+fn deliver(from: &mut TcpConnection, to: &mut TcpConnection) {
+    while let Some(segment) = from.next_segment() {
+        let segment = TcpPacket {
+            header: segment.header,
+            payload: &segment.payload[..],
+        };
+        to.handle_packet(&segment, |_, _| None);
+    }
+}
+
+fn tcp_handshake(c: &mut Criterion) {
+    let client = Endpoint::new(Ipv4Address::new(192, 168, 1, 100), 0xc000);
+    let server = Endpoint::new(Ipv4Address::new(192, 168, 1, 1), 80);
+    c.bench_function("tcp_handshake", |b| {
+        b.iter(|| {
+            let mut active = TcpConnection::new(ConnectionId::new(client, server));
+            let mut passive = TcpConnection::new(ConnectionId::new(server, client));
+            active.connect();
+            deliver(&mut active, &mut passive);
+            deliver(&mut passive, &mut active);
+            deliver(&mut active, &mut passive);
+            assert_eq!(passive.state(), TcpState::Established);
+        })
+    });
+}
+
+criterion_group!(benches, parse_frames, write_dhcp_discover, tcp_handshake);
+criterion_main!(benches);
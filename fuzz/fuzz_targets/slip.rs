@@ -0,0 +1,16 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate net;
+
+use net::slip::{self, SlipDecoder};
+
+fuzz_target!(|data: &[u8]| {
+    let mut buf = [0; 128];
+    let mut decoder = SlipDecoder::new(&mut buf);
+    for &byte in data {
+        if decoder.push(byte).is_some() {
+            let _ = slip::parse_ipv4(decoder.frame());
+        }
+    }
+});
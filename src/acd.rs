@@ -0,0 +1,259 @@
+//! IPv4 address conflict detection (RFC 5227) for addresses from DHCP or static
+//! configuration.
+//!
+//! `Acd` probes an address with ARP before it is used and reports
+//! `AcdEvent::Available` when no other host answered; then the address should be
+//! configured with `Interface::set_ip`, which sends the announcements. Afterwards,
+//! conflicts that the interface detects (`StackEvent::ArpConflict`) are passed to
+//! `defend`, which answers them with rate-limited defensive ARPs. Conflicts are
+//! reported as `AcdEvent::Conflict`, so that a DHCP client can decline the lease and
+//! acquire another address.
+//!
+//! `Interface::probe_address` runs all of this and reports the outcome as stack
+//! events. `autoip::AutoIp` runs it for link-local addresses.
+
+use arp::{self, ArpOperation, ArpPacket};
+use ethernet::{EthernetAddress, EthernetPacket};
+use ipv4::Ipv4Address;
+
+/// The maximum random delay before the first probe in milliseconds.
+pub const PROBE_WAIT: u64 = 1000;
+pub const PROBE_NUM: u8 = 3;
+pub const PROBE_MIN: u64 = 1000;
+pub const PROBE_MAX: u64 = 2000;
+/// The time after the last probe until the address is claimed.
+pub const ANNOUNCE_WAIT: u64 = 2000;
+/// A bound address is defended at most once per interval.
+pub const DEFEND_INTERVAL: u64 = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcdState {
+    Stopped,
+    /// The next probe is sent at the deadline.
+    Probing { sent: u8, deadline: u64 },
+    /// The address is in use. `last_defense` is the time of the last defensive ARP.
+    Bound { last_defense: Option<u64> },
+}
+
+/// How conflicts with a bound address are resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefensePolicy {
+    /// Defend once, and give up the address on another conflict within
+    /// `DEFEND_INTERVAL`. Suitable for addresses from DHCP.
+    Retreat,
+    /// Keep the address and defend it at most once per `DEFEND_INTERVAL`, e.g. for
+    /// a statically configured address.
+    Defend,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcdEvent {
+    /// No other host answered the probes, so the address can be used.
+    Available(Ipv4Address),
+    /// The host `mac` uses the address or probes for it. If `given_up` is true, the
+    /// address must not be used (anymore) and the instance is stopped.
+    Conflict {
+        ip: Ipv4Address,
+        mac: EthernetAddress,
+        given_up: bool,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Acd {
+    mac: EthernetAddress,
+    ip: Ipv4Address,
+    random: u32,
+    policy: DefensePolicy,
+    state: AcdState,
+    event: Option<AcdEvent>,
+}
+
+impl Acd {
+    /// Creates a stopped instance. `seed` randomizes the probe delays, so that hosts
+    /// that start at the same time don't probe in lockstep.
+    pub fn new(mac: EthernetAddress, seed: u32) -> Acd {
+        Acd {
+            mac,
+            ip: Ipv4Address::new(0, 0, 0, 0),
+            random: seed | 1,
+            policy: DefensePolicy::Retreat,
+            state: AcdState::Stopped,
+            event: None,
+        }
+    }
+
+    fn random_delay(&mut self, min: u64, max: u64) -> u64 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 17;
+        self.random ^= self.random << 5;
+        min + u64::from(self.random) % (max - min + 1)
+    }
+
+    pub fn set_policy(&mut self, policy: DefensePolicy) {
+        self.policy = policy;
+    }
+
+    pub fn state(&self) -> AcdState {
+        self.state
+    }
+
+    /// The probed or bound address.
+    pub fn ip(&self) -> Ipv4Address {
+        self.ip
+    }
+
+    /// Starts probing `ip`, e.g. after a DHCP ACK or for a static address.
+    pub fn start(&mut self, ip: Ipv4Address, now: u64) {
+        let delay = self.random_delay(0, PROBE_WAIT);
+        self.start_at(ip, now + delay);
+    }
+
+    /// Like `start`, but sends the first probe at `deadline` instead of after a
+    /// random delay.
+    pub fn start_at(&mut self, ip: Ipv4Address, deadline: u64) {
+        self.ip = ip;
+        self.event = None;
+        self.state = AcdState::Probing { sent: 0, deadline };
+    }
+
+    /// Stops monitoring, e.g. when the address is released.
+    pub fn stop(&mut self) {
+        self.state = AcdState::Stopped;
+    }
+
+    /// Returns the last event since the previous call.
+    pub fn poll_event(&mut self) -> Option<AcdEvent> {
+        self.event.take()
+    }
+
+    /// Returns the next ARP probe to send. `now` is a monotonic timestamp in
+    /// milliseconds.
+    pub fn poll(&mut self, now: u64) -> Option<EthernetPacket<ArpPacket>> {
+        match self.state {
+            AcdState::Probing { sent, deadline } if now >= deadline => {
+                if sent == PROBE_NUM {
+                    self.state = AcdState::Bound { last_defense: None };
+                    self.event = Some(AcdEvent::Available(self.ip));
+                    return None;
+                }
+                let delay = if sent + 1 == PROBE_NUM {
+                    ANNOUNCE_WAIT
+                } else {
+                    self.random_delay(PROBE_MIN, PROBE_MAX)
+                };
+                self.state = AcdState::Probing {
+                    sent: sent + 1,
+                    deadline: now + delay,
+                };
+                Some(new_probe_packet(self.mac, self.ip))
+            }
+            _ => None,
+        }
+    }
+
+    /// Checks a received ARP packet for a conflict with the probed address.
+    pub fn handle_arp(&mut self, arp: &ArpPacket) {
+        let probing = match self.state {
+            AcdState::Probing { .. } => true,
+            _ => false,
+        };
+        if !probing || arp.src_mac == self.mac {
+            return;
+        }
+        let probe_conflict = arp.operation == ArpOperation::Request &&
+                             arp.src_ip == Ipv4Address::new(0, 0, 0, 0) &&
+                             arp.dst_ip == self.ip;
+        if arp.src_ip == self.ip || probe_conflict {
+            self.conflict(arp.src_mac, true);
+        }
+    }
+
+    /// Handles an ARP packet of the host `mac` from the bound address, see
+    /// `StackEvent::ArpConflict`.
+    ///
+    /// Returns a gratuitous ARP if the address should be defended.
+    pub fn defend(&mut self,
+                  mac: EthernetAddress,
+                  now: u64)
+                  -> Option<EthernetPacket<ArpPacket>> {
+        let last_defense = match self.state {
+            AcdState::Bound { last_defense } if mac != self.mac => last_defense,
+            _ => return None,
+        };
+        let recently_defended = match last_defense {
+            Some(time) => now < time + DEFEND_INTERVAL,
+            None => false,
+        };
+        if !recently_defended {
+            self.state = AcdState::Bound { last_defense: Some(now) };
+            self.conflict(mac, false);
+            Some(arp::new_gratuitous_packet(self.mac, self.ip))
+        } else {
+            let given_up = self.policy == DefensePolicy::Retreat;
+            self.conflict(mac, given_up);
+            None
+        }
+    }
+
+    fn conflict(&mut self, mac: EthernetAddress, given_up: bool) {
+        if given_up {
+            self.state = AcdState::Stopped;
+        }
+        self.event = Some(AcdEvent::Conflict {
+                              ip: self.ip,
+                              mac,
+                              given_up,
+                          });
+    }
+}
+
+/// Creates an ARP probe, which asks whether `ip` is in use without claiming it.
+pub fn new_probe_packet(mac: EthernetAddress, ip: Ipv4Address) -> EthernetPacket<ArpPacket> {
+    let probe = ArpPacket {
+        operation: ArpOperation::Request,
+        src_mac: mac,
+        dst_mac: EthernetAddress::new([0; 6]),
+        src_ip: Ipv4Address::new(0, 0, 0, 0),
+        dst_ip: ip,
+    };
+    EthernetPacket::new_arp(mac, EthernetAddress::broadcast(), probe)
+}
+
+#[test]
+fn probe_and_defend() {
+    let mac = EthernetAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    let other = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let ip = Ipv4Address::new(192, 168, 1, 2);
+    let mut acd = Acd::new(mac, 7);
+
+    acd.start(ip, 0);
+    let probes = (0..1000).filter_map(|t| acd.poll(t * 10)).count();
+    assert_eq!(probes, usize::from(PROBE_NUM));
+    assert_eq!(acd.poll_event(), Some(AcdEvent::Available(ip)));
+
+    // defended once per interval, then given up
+    let defense = acd.defend(other, 20_000).unwrap();
+    assert_eq!((defense.payload.src_mac, defense.payload.src_ip), (mac, ip));
+    assert_eq!(acd.poll_event(),
+               Some(AcdEvent::Conflict {
+                        ip,
+                        mac: other,
+                        given_up: false,
+                    }));
+    acd.set_policy(DefensePolicy::Defend);
+    assert!(acd.defend(other, 25_000).is_none());
+    assert_eq!(acd.state(), AcdState::Bound { last_defense: Some(20_000) });
+    acd.set_policy(DefensePolicy::Retreat);
+    assert!(acd.defend(other, 26_000).is_none());
+    assert_eq!(acd.state(), AcdState::Stopped);
+
+    // another host probing for the address while we probe
+    acd.start(ip, 30_000);
+    let probe = new_probe_packet(other, ip).payload;
+    acd.handle_arp(&probe);
+    match acd.poll_event() {
+        Some(AcdEvent::Conflict { given_up: true, .. }) => {}
+        other => panic!("unexpected event {:?}", other),
+    }
+}
@@ -2,6 +2,8 @@ use ethernet::{EthernetAddress, EthernetPacket};
 use ipv4::Ipv4Address;
 use byteorder::{ByteOrder, NetworkEndian};
 use {WriteOut, TxPacket};
+#[cfg(feature = "heapless")]
+use heapless::ArrayLength;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct ArpPacket {
@@ -46,10 +48,83 @@ pub fn new_request_packet(src_mac: EthernetAddress,
     EthernetPacket::new_arp(src_mac, EthernetAddress::broadcast(), arp)
 }
 
+/// Creates a gratuitous ARP request, which announces the mapping of `ip` to `mac`
+/// to all hosts on the link.
+pub fn new_gratuitous_packet(mac: EthernetAddress, ip: Ipv4Address) -> EthernetPacket<ArpPacket> {
+    new_request_packet(mac, ip, ip)
+}
+
+/// Creates a RARP request (RFC 903), which asks a RARP server for the IPv4 address
+/// of `mac`.
+pub fn new_rarp_request_packet(mac: EthernetAddress) -> EthernetPacket<ArpPacket> {
+    let rarp = ArpPacket {
+        operation: ArpOperation::RarpRequest,
+        src_mac: mac,
+        dst_mac: mac,
+        src_ip: Ipv4Address::new(0, 0, 0, 0),
+        dst_ip: Ipv4Address::new(0, 0, 0, 0),
+    };
+    EthernetPacket::new_rarp(mac, EthernetAddress::broadcast(), rarp)
+}
+
+/// A fixed-size ARP cache for builds without `alloc`.
+///
+/// Holds up to `N` entries with the time they were learned, e.g. `ArpCache<U8>`
+/// with `heapless::consts::U8`. When the cache is full, the oldest entry is replaced.
+#[cfg(feature = "heapless")]
+#[derive(Debug, Default)]
+pub struct ArpCache<N: ArrayLength<ArpCacheEntry>> {
+    entries: ::heapless::Vec<ArpCacheEntry, N>,
+}
+
+/// An address, the address it resolves to, and the time it was learned.
+#[cfg(feature = "heapless")]
+pub type ArpCacheEntry = (Ipv4Address, EthernetAddress, u64);
+
+#[cfg(feature = "heapless")]
+impl<N: ArrayLength<ArpCacheEntry>> ArpCache<N> {
+    pub fn new() -> Self {
+        ArpCache { entries: ::heapless::Vec::new() }
+    }
+
+    pub fn lookup(&self, ip: Ipv4Address) -> Option<EthernetAddress> {
+        self.entries.iter().find(|e| e.0 == ip).map(|e| e.1)
+    }
+
+    pub fn insert(&mut self, ip: Ipv4Address, mac: EthernetAddress, now: u64) {
+        if let Some(entry) = self.entries.iter_mut().find(|e| e.0 == ip) {
+            *entry = (ip, mac, now);
+            return;
+        }
+        if let Err(entry) = self.entries.push((ip, mac, now)) {
+            if let Some(oldest) = self.entries.iter_mut().min_by_key(|e| e.2) {
+                *oldest = entry;
+            }
+        }
+    }
+
+    /// Drops the entries that were learned `timeout` or more milliseconds ago.
+    pub fn expire(&mut self, now: u64, timeout: u64) {
+        let mut i = 0;
+        while i < self.entries.len() {
+            if now.saturating_sub(self.entries[i].2) < timeout {
+                i += 1;
+            } else {
+                self.entries.swap_remove(i);
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ArpOperation {
     Request,
     Response,
+    /// Only valid in RARP frames (`EtherType::Rarp`).
+    RarpRequest,
+    /// Only valid in RARP frames (`EtherType::Rarp`). The answered IPv4 address is
+    /// `dst_ip`.
+    RarpResponse,
 }
 
 impl WriteOut for ArpPacket {
@@ -58,8 +133,8 @@ impl WriteOut for ArpPacket {
     }
 
     fn write_out<T: TxPacket>(&self, packet: &mut T) -> Result<(), ()> {
-        packet.push_u16(1)?; // hardware type == ethernet (1)
-        packet.push_u16(0x0800)?; // protocol type == ipv4 (0x0800)
+        packet.push_u16(HARDWARE_TYPE_ETHERNET)?;
+        packet.push_u16(PROTOCOL_TYPE_IPV4)?;
         packet.push_byte(6)?; // hardware address size
         packet.push_byte(4)?; // protocol address size
 
@@ -67,6 +142,8 @@ impl WriteOut for ArpPacket {
             .push_u16(match self.operation {
                           ArpOperation::Request => 1,
                           ArpOperation::Response => 2,
+                          ArpOperation::RarpRequest => 3,
+                          ArpOperation::RarpResponse => 4,
                       })?;
 
         packet.push_bytes(&self.src_mac.as_bytes())?;
@@ -79,14 +156,71 @@ impl WriteOut for ArpPacket {
 }
 
 
-use parse::{Parse, ParseError};
+use parse::{Layer, Parse, ParseError};
+
+/// The hardware type of Ethernet.
+pub const HARDWARE_TYPE_ETHERNET: u16 = 1;
 
+/// The protocol type of IPv4, which is its EtherType.
+pub const PROTOCOL_TYPE_IPV4: u16 = 0x0800;
+
+/// The fixed fields of any ARP packet, for hardware and protocol types other than
+/// Ethernet and IPv4. The addresses follow in `payload`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArpHeader<'a> {
+    pub hardware_type: u16,
+    pub protocol_type: u16,
+    pub hardware_len: u8,
+    pub protocol_len: u8,
+    pub operation: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Parse<'a> for ArpHeader<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
+        if data.len() < 8 {
+            return Err(ParseError::truncated(Layer::Arp, data.len()));
+        }
+        Ok(ArpHeader {
+               hardware_type: NetworkEndian::read_u16(&data[0..2]),
+               protocol_type: NetworkEndian::read_u16(&data[2..4]),
+               hardware_len: data[4],
+               protocol_len: data[5],
+               operation: NetworkEndian::read_u16(&data[6..8]),
+               payload: &data[8..],
+           })
+    }
+}
+
+/// Only accepts Ethernet and IPv4 addresses, see `ArpHeader` for others.
 impl<'a> Parse<'a> for ArpPacket {
     fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
-        let operation = match NetworkEndian::read_u16(&data[6..8]) {
+        let header = ArpHeader::parse(data)?;
+        if header.hardware_type != HARDWARE_TYPE_ETHERNET {
+            return Err(ParseError::malformed(Layer::Arp, 0, "ARP hardware type is not Ethernet"));
+        }
+        if header.protocol_type != PROTOCOL_TYPE_IPV4 {
+            return Err(ParseError::malformed(Layer::Arp, 2, "ARP protocol type is not IPv4"));
+        }
+        if header.hardware_len != 6 {
+            return Err(ParseError::malformed(Layer::Arp, 4, "invalid ARP hardware length"));
+        }
+        if header.protocol_len != 4 {
+            return Err(ParseError::malformed(Layer::Arp, 5, "invalid ARP protocol length"));
+        }
+        if data.len() < 28 {
+            return Err(ParseError::truncated(Layer::Arp, data.len()));
+        }
+        let operation = match header.operation {
             1 => ArpOperation::Request,
             2 => ArpOperation::Response,
-            _ => return Err(ParseError::Malformed("invalid value in ARP operation field")),
+            3 => ArpOperation::RarpRequest,
+            4 => ArpOperation::RarpResponse,
+            _ => {
+                return Err(ParseError::malformed(Layer::Arp,
+                                                 6,
+                                                 "invalid value in ARP operation field"))
+            }
         };
         Ok(ArpPacket {
                operation: operation,
@@ -97,3 +231,94 @@ impl<'a> Parse<'a> for ArpPacket {
            })
     }
 }
+
+#[test]
+fn rarp() {
+    use ethernet::{EthernetKind, EtherType};
+    use parse::parse;
+    use HeapTxPacket;
+
+    let mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let request = new_rarp_request_packet(mac);
+    let mut packet = HeapTxPacket::new(60);
+    request.write_out(&mut packet).unwrap();
+    packet.push_bytes(&[0; 18]).unwrap();
+    assert_eq!(&packet[12..14], &[0x80, 0x35]);
+    assert_eq!(&packet[20..22], &[0, 3]);
+
+    // the server answers with our address in the target protocol address
+    let server_mac = EthernetAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    let response = ArpPacket {
+        operation: ArpOperation::RarpResponse,
+        src_mac: server_mac,
+        dst_mac: mac,
+        src_ip: Ipv4Address::new(192, 168, 1, 1),
+        dst_ip: Ipv4Address::new(192, 168, 1, 2),
+    };
+    let mut packet = HeapTxPacket::new(60);
+    EthernetPacket::new_rarp(server_mac, mac, response).write_out(&mut packet).unwrap();
+    packet.push_bytes(&[0; 18]).unwrap();
+    let frame = parse(&packet).unwrap();
+    assert_eq!(frame.header.ether_type, EtherType::Rarp);
+    match frame.payload {
+        EthernetKind::Rarp(rarp) => assert_eq!(rarp, response),
+        other => panic!("unexpected packet {:?}", other),
+    }
+}
+
+#[test]
+fn other_address_types() {
+    use HeapTxPacket;
+
+    let mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let ip = Ipv4Address::new(192, 168, 1, 2);
+    let request = new_request_packet(mac, ip, ip).payload;
+    let mut packet = HeapTxPacket::new(request.len());
+    request.write_out(&mut packet).unwrap();
+    assert_eq!(ArpPacket::parse(&packet), Ok(request));
+
+    // an IEEE 802 hardware type
+    let mut data = packet.to_vec();
+    data[1] = 6;
+    assert_eq!(ArpPacket::parse(&data).unwrap_err().offset(), 0);
+    let header = ArpHeader::parse(&data).unwrap();
+    assert_eq!((header.hardware_type, header.protocol_type), (6, PROTOCOL_TYPE_IPV4));
+    assert_eq!(header.payload.len(), 20);
+
+    let mut data = packet.to_vec();
+    data[3] = 0xdd; // IPv6
+    assert_eq!(ArpPacket::parse(&data).unwrap_err().offset(), 2);
+
+    let mut data = packet.to_vec();
+    data[4] = 8;
+    assert_eq!(ArpPacket::parse(&data).unwrap_err().offset(), 4);
+
+    let mut data = packet.to_vec();
+    data[5] = 16;
+    assert_eq!(ArpPacket::parse(&data).unwrap_err().offset(), 5);
+}
+
+#[cfg(feature = "heapless")]
+#[test]
+fn fixed_size_cache() {
+    use heapless::Vec;
+    use heapless::consts::{U2, U42};
+
+    let mut cache = ArpCache::<U2>::new();
+    let mac = |last| EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, last]);
+    let ip = |last| Ipv4Address::new(192, 168, 1, last);
+    cache.insert(ip(1), mac(1), 0);
+    cache.insert(ip(2), mac(2), 10);
+    cache.insert(ip(3), mac(3), 20); // replaces the oldest entry
+    assert_eq!(cache.lookup(ip(1)), None);
+    assert_eq!(cache.lookup(ip(3)), Some(mac(3)));
+    cache.expire(1010, 1000);
+    assert_eq!(cache.lookup(ip(2)), None);
+    assert_eq!(cache.lookup(ip(3)), Some(mac(3)));
+
+    // serialize without alloc
+    let mut packet: Vec<u8, U42> = Vec::new();
+    new_request_packet(mac(1), ip(1), ip(2)).write_out(&mut packet).unwrap();
+    assert_eq!(&packet[12..14], &[0x08, 0x06]);
+    assert!(new_request_packet(mac(1), ip(1), ip(2)).write_out(&mut packet).is_err());
+}
@@ -0,0 +1,177 @@
+//! IPv4 link-local address assignment (RFC 3927), as a fallback when no DHCP server
+//! answers.
+//!
+//! `AutoIp` picks an address in 169.254/16, probes and defends it with an
+//! `acd::Acd`, and picks a new one on conflicts. Once it is bound, the address should
+//! be configured with `Interface::set_ip`, which sends the announcements.
+
+use acd::{Acd, AcdEvent, AcdState};
+use arp::ArpPacket;
+use ethernet::{EthernetAddress, EthernetPacket};
+use ipv4::Ipv4Address;
+
+/// After this many conflicts, new addresses are only tried once per
+/// `RATE_LIMIT_INTERVAL`.
+pub const MAX_CONFLICTS: u8 = 10;
+pub const RATE_LIMIT_INTERVAL: u64 = 60_000;
+
+#[derive(Debug, Clone)]
+pub struct AutoIp {
+    acd: Acd,
+    random: u32,
+    candidate: Ipv4Address,
+    conflicts: u8,
+}
+
+impl AutoIp {
+    /// Creates a stopped instance. The addresses are derived from `seed` and the MAC
+    /// address, so that a device gets the same address after every reboot unless it
+    /// conflicts.
+    pub fn new(mac: EthernetAddress, seed: u32) -> AutoIp {
+        let bytes = mac.as_bytes();
+        let mut random = seed ^ 0x9e37_79b9;
+        for &byte in &bytes {
+            random = (random ^ u32::from(byte)).wrapping_mul(16_777_619);
+        }
+        let mut auto_ip = AutoIp {
+            // a different sequence for the probe delays
+            acd: Acd::new(mac, random.rotate_left(16)),
+            random: random | 1,
+            candidate: Ipv4Address::new(0, 0, 0, 0),
+            conflicts: 0,
+        };
+        auto_ip.candidate = auto_ip.random_address();
+        auto_ip
+    }
+
+    fn next_random(&mut self) -> u32 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 17;
+        self.random ^= self.random << 5;
+        self.random
+    }
+
+    /// Returns an address between 169.254.1.0 and 169.254.254.255.
+    fn random_address(&mut self) -> Ipv4Address {
+        let host = 0x0100 + self.next_random() % (0xfeff - 0x0100 + 1);
+        Ipv4Address::new(169, 254, (host >> 8) as u8, host as u8)
+    }
+
+    pub fn state(&self) -> AcdState {
+        self.acd.state()
+    }
+
+    /// Returns the link-local address once it was probed successfully.
+    pub fn address(&self) -> Option<Ipv4Address> {
+        match self.acd.state() {
+            AcdState::Bound { .. } => Some(self.candidate),
+            _ => None,
+        }
+    }
+
+    /// Starts probing, e.g. when the DHCP client timed out.
+    pub fn start(&mut self, now: u64) {
+        self.acd.start(self.candidate, now);
+    }
+
+    /// Stops probing or gives up the address, e.g. when a DHCP lease was acquired.
+    pub fn stop(&mut self) {
+        self.acd.stop();
+    }
+
+    /// Returns the next ARP probe to send, see `Acd::poll`.
+    pub fn poll(&mut self, now: u64) -> Option<EthernetPacket<ArpPacket>> {
+        let probe = self.acd.poll(now);
+        // `Available` is reported by `address`
+        self.acd.poll_event();
+        probe
+    }
+
+    /// Checks a received ARP packet for conflicts with the candidate or bound
+    /// address.
+    ///
+    /// Returns a gratuitous ARP if the bound address should be defended. If the
+    /// address has to be given up, probing starts over with a new address.
+    pub fn handle_arp(&mut self,
+                      arp: &ArpPacket,
+                      now: u64)
+                      -> Option<EthernetPacket<ArpPacket>> {
+        let defense = match self.acd.state() {
+            AcdState::Stopped => None,
+            AcdState::Probing { .. } => {
+                self.acd.handle_arp(arp);
+                None
+            }
+            AcdState::Bound { .. } if arp.src_ip == self.candidate => {
+                self.acd.defend(arp.src_mac, now)
+            }
+            AcdState::Bound { .. } => None,
+        };
+        if let Some(AcdEvent::Conflict { given_up: true, .. }) = self.acd.poll_event() {
+            self.restart(now);
+        }
+        defense
+    }
+
+    /// Picks a new address after a conflict.
+    fn restart(&mut self, now: u64) {
+        self.conflicts = self.conflicts.saturating_add(1);
+        self.candidate = self.random_address();
+        let delay = if self.conflicts >= MAX_CONFLICTS {
+            RATE_LIMIT_INTERVAL
+        } else {
+            0
+        };
+        self.acd.start_at(self.candidate, now + delay);
+    }
+}
+
+#[test]
+fn probe_and_conflicts() {
+    use acd::{new_probe_packet, PROBE_MAX, PROBE_MIN, PROBE_NUM};
+    use arp::ArpOperation;
+
+    let mac = EthernetAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    let other = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let mut auto_ip = AutoIp::new(mac, 42);
+    let mut now = 0;
+    let mut run = |auto_ip: &mut AutoIp, until: u64| {
+        let mut probes = Vec::new();
+        while now < until {
+            if let Some(packet) = auto_ip.poll(now) {
+                probes.push((now, packet.payload));
+            }
+            now += 10;
+        }
+        probes
+    };
+
+    auto_ip.start(0);
+    let probes = run(&mut auto_ip, 10_000);
+    assert_eq!(probes.len(), usize::from(PROBE_NUM));
+    let address = auto_ip.address().unwrap();
+    assert_eq!(&address.as_bytes()[..2], &[169, 254]);
+    assert!(probes.iter().all(|&(_, p)| p.dst_ip == address && p.src_ip.as_bytes() == [0; 4]));
+    assert!(probes[1].0 - probes[0].0 >= PROBE_MIN && probes[1].0 - probes[0].0 <= PROBE_MAX);
+
+    // a bound address is defended once, and given up on a second conflict
+    let conflict = ArpPacket {
+        operation: ArpOperation::Response,
+        src_mac: other,
+        dst_mac: mac,
+        src_ip: address,
+        dst_ip: address,
+    };
+    let defense = auto_ip.handle_arp(&conflict, 10_000).unwrap();
+    assert_eq!(defense.payload.src_ip, address);
+    assert!(auto_ip.handle_arp(&conflict, 12_000).is_none());
+    assert_eq!(auto_ip.address(), None);
+    assert!(auto_ip.candidate != address);
+
+    // another host probing for the candidate is a conflict as well
+    let candidate = auto_ip.candidate;
+    let probe = new_probe_packet(other, candidate).payload;
+    auto_ip.handle_arp(&probe, 12_000);
+    assert!(auto_ip.candidate != candidate);
+    assert_eq!(auto_ip.conflicts, 2);
+}
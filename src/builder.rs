@@ -0,0 +1,163 @@
+//! Fluent construction of complete frames.
+//!
+//! ```ignore
+//! let frame = PacketBuilder::ethernet(src_mac, dst_mac)
+//!     .ipv4(src_ip, dst_ip)
+//!     .udp(1000, 53)
+//!     .payload(&data[..]);
+//! ```
+//!
+//! The result is the same nested packet type that the layer-specific constructors
+//! produce, e.g. `EthernetPacket<Ipv4Packet<UdpPacket<&[u8]>>>`, so it can be written
+//! out as usual.
+
+use arp::ArpPacket;
+use ethernet::{EthernetAddress, EthernetPacket};
+#[cfg(any(test, feature = "icmp"))]
+use icmp::IcmpPacket;
+use ipv4::{Ipv4Address, Ipv4Packet};
+#[cfg(any(test, feature = "tcp"))]
+use tcp::{TcpHeader, TcpPacket};
+use udp::UdpPacket;
+
+/// The entry point of the builder chain.
+#[derive(Debug, Clone, Copy)]
+pub struct PacketBuilder;
+
+impl PacketBuilder {
+    pub fn ethernet(src_addr: EthernetAddress, dst_addr: EthernetAddress) -> EthernetBuilder {
+        EthernetBuilder { src_addr, dst_addr }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct EthernetBuilder {
+    src_addr: EthernetAddress,
+    dst_addr: EthernetAddress,
+}
+
+impl EthernetBuilder {
+    pub fn ipv4(self, src_addr: Ipv4Address, dst_addr: Ipv4Address) -> Ipv4Builder {
+        Ipv4Builder {
+            ethernet: self,
+            src_addr,
+            dst_addr,
+        }
+    }
+
+    pub fn arp(self, arp: ArpPacket) -> EthernetPacket<ArpPacket> {
+        EthernetPacket::new_arp(self.src_addr, self.dst_addr, arp)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Ipv4Builder {
+    ethernet: EthernetBuilder,
+    src_addr: Ipv4Address,
+    dst_addr: Ipv4Address,
+}
+
+impl Ipv4Builder {
+    pub fn udp(self, src_port: u16, dst_port: u16) -> UdpBuilder {
+        UdpBuilder {
+            ipv4: self,
+            src_port,
+            dst_port,
+            checksum: true,
+        }
+    }
+
+    /// Continues with a TCP segment, see `TcpHeaderBuilder` for building the header.
+    #[cfg(any(test, feature = "tcp"))]
+    pub fn tcp(self, header: TcpHeader) -> TcpBuilder {
+        TcpBuilder { ipv4: self, header }
+    }
+
+    #[cfg(any(test, feature = "icmp"))]
+    pub fn icmp<T>(self, icmp: IcmpPacket<T>) -> EthernetPacket<Ipv4Packet<IcmpPacket<T>>> {
+        self.wrap(Ipv4Packet::new_icmp(self.src_addr, self.dst_addr, icmp))
+    }
+
+    fn wrap<T>(&self, packet: Ipv4Packet<T>) -> EthernetPacket<Ipv4Packet<T>> {
+        EthernetPacket::new_ipv4(self.ethernet.src_addr, self.ethernet.dst_addr, packet)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct UdpBuilder {
+    ipv4: Ipv4Builder,
+    src_port: u16,
+    dst_port: u16,
+    checksum: bool,
+}
+
+impl UdpBuilder {
+    /// Sends the datagram without a checksum.
+    pub fn without_checksum(mut self) -> Self {
+        self.checksum = false;
+        self
+    }
+
+    pub fn payload<T>(self, payload: T) -> EthernetPacket<Ipv4Packet<UdpPacket<T>>> {
+        let mut udp = UdpPacket::new(self.src_port, self.dst_port, payload);
+        udp.header.checksum = self.checksum;
+        self.ipv4.wrap(Ipv4Packet::new_udp(self.ipv4.src_addr, self.ipv4.dst_addr, udp))
+    }
+}
+
+#[cfg(any(test, feature = "tcp"))]
+#[derive(Debug, Clone, Copy)]
+pub struct TcpBuilder {
+    ipv4: Ipv4Builder,
+    header: TcpHeader,
+}
+
+#[cfg(any(test, feature = "tcp"))]
+impl TcpBuilder {
+    pub fn payload<T>(self, payload: T) -> EthernetPacket<Ipv4Packet<TcpPacket<T>>> {
+        let tcp = TcpPacket {
+            header: self.header,
+            payload,
+        };
+        self.ipv4.wrap(Ipv4Packet::new_tcp_owned(self.ipv4.src_addr, self.ipv4.dst_addr, tcp))
+    }
+}
+
+#[test]
+fn same_as_constructors() {
+    use tcp::{TcpFlags, TcpHeaderBuilder};
+    use testing::{assert_serializes_to, frames};
+    use udp::new_udp_packet;
+    use {HeapTxPacket, WriteOut};
+
+    let router_mac = EthernetAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    let client_mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let router = Ipv4Address::new(192, 168, 1, 1);
+    let client = Ipv4Address::new(192, 168, 1, 100);
+
+    let data = [1, 2, 3, 4];
+    let udp = PacketBuilder::ethernet(client_mac, router_mac)
+        .ipv4(client, router)
+        .udp(1000, 53)
+        .payload(&data[..]);
+    let expected = new_udp_packet(client_mac, router_mac, client, router, 1000, 53, &data[..]);
+    let mut packet = HeapTxPacket::new(expected.len());
+    expected.write_out(&mut packet).unwrap();
+    assert_serializes_to(&udp, packet.as_slice());
+
+    // the ACK of `frames::TCP_ACK`, which has an identification of 2 and padding
+    let mut expected = hex!(frames::TCP_ACK);
+    expected[19] = 0;
+    expected[25] = 0x1a;
+    let header = TcpHeaderBuilder::new(0xc000, 80)
+        .sequence_number(0x0100_0001)
+        .ack_number(0x0000_2001)
+        .flags(TcpFlags::ACK)
+        .window_size(0xfaf0)
+        .build();
+    let tcp = PacketBuilder::ethernet(client_mac, router_mac)
+        .ipv4(client, router)
+        .tcp(header)
+        .payload(&[][..]);
+    assert_serializes_to(&tcp, &expected[..54]);
+}
@@ -0,0 +1,432 @@
+use {TxPacket, WriteOut};
+use byteorder::{ByteOrder, NetworkEndian};
+use core::slice;
+
+/// The default UDP port of CoAP servers.
+pub const COAP_PORT: u16 = 5683;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoapType {
+    Confirmable,
+    NonConfirmable,
+    Acknowledgement,
+    Reset,
+}
+
+impl CoapType {
+    fn from_number(number: u8) -> CoapType {
+        match number & 0b11 {
+            0 => CoapType::Confirmable,
+            1 => CoapType::NonConfirmable,
+            2 => CoapType::Acknowledgement,
+            _ => CoapType::Reset,
+        }
+    }
+
+    fn number(&self) -> u8 {
+        match *self {
+            CoapType::Confirmable => 0,
+            CoapType::NonConfirmable => 1,
+            CoapType::Acknowledgement => 2,
+            CoapType::Reset => 3,
+        }
+    }
+}
+
+/// A CoAP request method or response code in `class.detail` form.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoapCode(pub u8);
+
+impl CoapCode {
+    pub const EMPTY: CoapCode = CoapCode(0x00);
+    pub const GET: CoapCode = CoapCode(0x01);
+    pub const POST: CoapCode = CoapCode(0x02);
+    pub const PUT: CoapCode = CoapCode(0x03);
+    pub const DELETE: CoapCode = CoapCode(0x04);
+    pub const CREATED: CoapCode = CoapCode(0x41);
+    pub const DELETED: CoapCode = CoapCode(0x42);
+    pub const VALID: CoapCode = CoapCode(0x43);
+    pub const CHANGED: CoapCode = CoapCode(0x44);
+    pub const CONTENT: CoapCode = CoapCode(0x45);
+    pub const BAD_REQUEST: CoapCode = CoapCode(0x80);
+    pub const NOT_FOUND: CoapCode = CoapCode(0x84);
+    pub const METHOD_NOT_ALLOWED: CoapCode = CoapCode(0x85);
+    pub const INTERNAL_SERVER_ERROR: CoapCode = CoapCode(0xa0);
+
+    pub fn new(class: u8, detail: u8) -> CoapCode {
+        CoapCode(class << 5 | (detail & 0x1f))
+    }
+
+    pub fn class(&self) -> u8 {
+        self.0 >> 5
+    }
+
+    pub fn detail(&self) -> u8 {
+        self.0 & 0x1f
+    }
+
+    pub fn is_request(&self) -> bool {
+        self.class() == 0 && self.detail() != 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoapOption<'a> {
+    pub number: u16,
+    pub value: &'a [u8],
+}
+
+impl<'a> CoapOption<'a> {
+    pub const IF_MATCH: u16 = 1;
+    pub const URI_HOST: u16 = 3;
+    pub const ETAG: u16 = 4;
+    pub const IF_NONE_MATCH: u16 = 5;
+    pub const OBSERVE: u16 = 6;
+    pub const URI_PORT: u16 = 7;
+    pub const LOCATION_PATH: u16 = 8;
+    pub const URI_PATH: u16 = 11;
+    pub const CONTENT_FORMAT: u16 = 12;
+    pub const MAX_AGE: u16 = 14;
+    pub const URI_QUERY: u16 = 15;
+    pub const ACCEPT: u16 = 17;
+    pub const LOCATION_QUERY: u16 = 20;
+    pub const PROXY_URI: u16 = 35;
+    pub const SIZE1: u16 = 60;
+
+    pub fn new(number: u16, value: &'a [u8]) -> Self {
+        CoapOption { number, value }
+    }
+}
+
+/// The options of a CoAP message.
+///
+/// Messages that are built for sending use a list sorted by option number. Parsed
+/// messages keep the encoded options, which are decoded on iteration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoapOptions<'a> {
+    List(&'a [CoapOption<'a>]),
+    Encoded(&'a [u8]),
+}
+
+impl<'a> CoapOptions<'a> {
+    pub fn iter(&self) -> CoapOptionIter<'a> {
+        match *self {
+            CoapOptions::List(list) => CoapOptionIter::List(list.iter()),
+            CoapOptions::Encoded(data) => CoapOptionIter::Encoded { data, number: 0 },
+        }
+    }
+
+    /// Returns the first option with the given number.
+    pub fn get(&self, number: u16) -> Option<&'a [u8]> {
+        self.iter().find(|o| o.number == number).map(|o| o.value)
+    }
+}
+
+pub enum CoapOptionIter<'a> {
+    List(slice::Iter<'a, CoapOption<'a>>),
+    Encoded { data: &'a [u8], number: u16 },
+}
+
+impl<'a> Iterator for CoapOptionIter<'a> {
+    type Item = CoapOption<'a>;
+
+    fn next(&mut self) -> Option<CoapOption<'a>> {
+        match *self {
+            CoapOptionIter::List(ref mut iter) => iter.next().cloned(),
+            CoapOptionIter::Encoded { ref mut data, ref mut number } => {
+                // the encoded options were validated when parsing
+                match decode_option(data) {
+                    Ok(Some((delta, value, consumed))) => {
+                        *number += delta;
+                        *data = &data[consumed..];
+                        Some(CoapOption::new(*number, value))
+                    }
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoapHeader {
+    pub type_: CoapType,
+    pub code: CoapCode,
+    pub message_id: u16,
+}
+
+/// A CoAP message according to [RFC 7252][rfc7252].
+///
+/// [rfc7252]: https://tools.ietf.org/html/rfc7252
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoapMessage<'a> {
+    pub header: CoapHeader,
+    pub token: &'a [u8],
+    pub options: CoapOptions<'a>,
+    pub payload: &'a [u8],
+}
+
+impl<'a> CoapMessage<'a> {
+    /// Fails if the token is longer than 8 bytes.
+    pub fn new(type_: CoapType,
+               code: CoapCode,
+               message_id: u16,
+               token: &'a [u8])
+               -> Result<Self, ()> {
+        if token.len() > 8 {
+            return Err(());
+        }
+        Ok(CoapMessage {
+               header: CoapHeader {
+                   type_,
+                   code,
+                   message_id,
+               },
+               token,
+               options: CoapOptions::List(&[]),
+               payload: &[],
+           })
+    }
+
+    /// Creates a response that is piggybacked on the acknowledgement of this request
+    /// (or sent as non-confirmable if the request was non-confirmable).
+    pub fn response<'b>(&self,
+                        code: CoapCode,
+                        options: &'b [CoapOption<'b>],
+                        payload: &'b [u8])
+                        -> CoapMessage<'b>
+        where 'a: 'b
+    {
+        let type_ = match self.header.type_ {
+            CoapType::Confirmable => CoapType::Acknowledgement,
+            _ => CoapType::NonConfirmable,
+        };
+        CoapMessage {
+            header: CoapHeader {
+                type_,
+                code,
+                message_id: self.header.message_id,
+            },
+            token: self.token,
+            options: CoapOptions::List(options),
+            payload,
+        }
+    }
+}
+
+fn extended_len(value: u16) -> usize {
+    match value {
+        0...12 => 0,
+        13...268 => 1,
+        _ => 2,
+    }
+}
+
+fn nibble(value: u16) -> u8 {
+    match value {
+        0...12 => value as u8,
+        13...268 => 13,
+        _ => 14,
+    }
+}
+
+fn push_extended<P: TxPacket>(packet: &mut P, value: u16) -> Result<(), ()> {
+    match value {
+        0...12 => Ok(()),
+        13...268 => packet.push_byte((value - 13) as u8).map(|_| ()),
+        _ => packet.push_u16(value - 269).map(|_| ()),
+    }
+}
+
+impl<'a> WriteOut for CoapMessage<'a> {
+    fn len(&self) -> usize {
+        let mut len = 4 + self.token.len();
+        let mut number = 0;
+        for option in self.options.iter() {
+            let delta = option.number.wrapping_sub(number);
+            let value_len = option.value.len() as u16;
+            len += 1 + extended_len(delta) + extended_len(value_len) + option.value.len();
+            number = option.number;
+        }
+        if !self.payload.is_empty() {
+            len += 1 + self.payload.len();
+        }
+        len
+    }
+
+    fn write_out<P: TxPacket>(&self, packet: &mut P) -> Result<(), ()> {
+        if self.token.len() > 8 {
+            return Err(());
+        }
+
+        packet.push_byte(1 << 6 | self.header.type_.number() << 4 | self.token.len() as u8)?;
+        packet.push_byte(self.header.code.0)?;
+        packet.push_u16(self.header.message_id)?;
+        packet.push_bytes(self.token)?;
+
+        let mut number = 0;
+        for option in self.options.iter() {
+            if option.number < number || option.value.len() > 0xffff - 269 {
+                return Err(()); // options must be sorted by number
+            }
+            let delta = option.number - number;
+            let value_len = option.value.len() as u16;
+
+            packet.push_byte(nibble(delta) << 4 | nibble(value_len))?;
+            push_extended(packet, delta)?;
+            push_extended(packet, value_len)?;
+            packet.push_bytes(option.value)?;
+            number = option.number;
+        }
+
+        if !self.payload.is_empty() {
+            packet.push_byte(0xff)?; // payload marker
+            packet.push_bytes(self.payload)?;
+        }
+
+        Ok(())
+    }
+}
+
+use parse::{Layer, Parse, ParseError};
+
+/// Decodes the option at the start of `data`. Returns `None` at the payload marker or
+/// the end of the data, otherwise the option delta, the value, and the encoded length.
+fn decode_option(data: &[u8]) -> Result<Option<(u16, &[u8], usize)>, ParseError> {
+    fn extended(data: &[u8], nibble: u8, index: &mut usize) -> Result<u16, ParseError> {
+        match nibble {
+            0...12 => Ok(u16::from(nibble)),
+            13 => {
+                let value = *data.get(*index).ok_or(ParseError::truncated(Layer::Coap, data.len()))?;
+                *index += 1;
+                Ok(u16::from(value) + 13)
+            }
+            14 => {
+                if data.len() < *index + 2 {
+                    return Err(ParseError::truncated(Layer::Coap, data.len()));
+                }
+                let value = NetworkEndian::read_u16(&data[*index..(*index + 2)]);
+                *index += 2;
+                value
+                    .checked_add(269)
+                    .ok_or(ParseError::malformed(Layer::Coap, *index - 2, "CoAP option value too large"))
+            }
+            _ => Err(ParseError::malformed(Layer::Coap, 0, "reserved CoAP option nibble")),
+        }
+    }
+
+    let first = match data.first() {
+        None | Some(&0xff) => return Ok(None),
+        Some(&first) => first,
+    };
+
+    let mut index = 1;
+    let delta = extended(data, first >> 4, &mut index)?;
+    let len = usize::from(extended(data, first & 0xf, &mut index)?);
+    if data.len() < index + len {
+        return Err(ParseError::truncated(Layer::Coap, data.len()));
+    }
+    Ok(Some((delta, &data[index..(index + len)], index + len)))
+}
+
+impl<'a> Parse<'a> for CoapMessage<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
+        if data.len() < 4 {
+            return Err(ParseError::truncated(Layer::Coap, data.len()));
+        }
+        if data[0] >> 6 != 1 {
+            return Err(ParseError::malformed(Layer::Coap, 0, "unsupported CoAP version"));
+        }
+        let token_len = usize::from(data[0] & 0xf);
+        if token_len > 8 {
+            return Err(ParseError::malformed(Layer::Coap, 0, "CoAP token longer than 8 bytes"));
+        }
+        if data.len() < 4 + token_len {
+            return Err(ParseError::truncated(Layer::Coap, data.len()));
+        }
+
+        let options_start = 4 + token_len;
+        let mut index = options_start;
+        let mut number: u16 = 0;
+        while let Some((delta, _, consumed)) = decode_option(&data[index..])
+                  .map_err(|e| e.at(index))? {
+            number = number
+                .checked_add(delta)
+                .ok_or(ParseError::malformed(Layer::Coap, index, "CoAP option number too large"))?;
+            index += consumed;
+        }
+        let options = &data[options_start..index];
+
+        let payload = if index < data.len() {
+            // payload marker
+            if index + 1 == data.len() {
+                return Err(ParseError::malformed(Layer::Coap,
+                                                 index,
+                                                 "CoAP payload marker without payload"));
+            }
+            &data[(index + 1)..]
+        } else {
+            &[]
+        };
+
+        Ok(CoapMessage {
+               header: CoapHeader {
+                   type_: CoapType::from_number(data[0] >> 4),
+                   code: CoapCode(data[1]),
+                   message_id: NetworkEndian::read_u16(&data[2..4]),
+               },
+               token: &data[4..options_start],
+               options: CoapOptions::Encoded(options),
+               payload,
+           })
+    }
+}
+
+#[test]
+fn get_request() {
+    use HeapTxPacket;
+
+    let options = [CoapOption::new(CoapOption::URI_PATH, b"sensors"),
+                   CoapOption::new(CoapOption::URI_PATH, b"temp")];
+    let mut request = CoapMessage::new(CoapType::Confirmable, CoapCode::GET, 0x1234, &[0xab, 0xcd])
+        .unwrap();
+    request.options = CoapOptions::List(&options);
+
+    let mut packet = HeapTxPacket::new(request.len());
+    request.write_out(&mut packet).unwrap();
+
+    let reference_data = &[0x42, 0x01, 0x12, 0x34, 0xab, 0xcd, 0xb7, b's', b'e', b'n', b's',
+                           b'o', b'r', b's', 0x04, b't', b'e', b'm', b'p'];
+    assert_eq!(packet.as_slice(), reference_data);
+
+    let parsed = CoapMessage::parse(packet.as_slice()).unwrap();
+    assert_eq!(parsed.header, request.header);
+    assert_eq!(parsed.token, &[0xab, 0xcd]);
+    assert!(parsed.options.iter().eq(options.iter().cloned()));
+    assert_eq!(parsed.payload, &[]);
+
+    assert!(CoapMessage::new(CoapType::Confirmable, CoapCode::GET, 1, &[0; 9]).is_err());
+}
+
+#[test]
+fn extended_option_and_payload() {
+    use HeapTxPacket;
+
+    let options = [CoapOption::new(CoapOption::URI_PATH, b"t"), CoapOption::new(300, &[1])];
+    let request = CoapMessage::new(CoapType::NonConfirmable, CoapCode::GET, 7, &[]).unwrap();
+    let response = request.response(CoapCode::CONTENT, &options, b"21.5");
+
+    let mut packet = HeapTxPacket::new(response.len());
+    response.write_out(&mut packet).unwrap();
+
+    let reference_data = &[0x50, 0x45, 0x00, 0x07, 0xb1, b't', 0xe1, 0x00, 0x14, 0x01, 0xff,
+                           b'2', b'1', b'.', b'5'];
+    assert_eq!(packet.as_slice(), reference_data);
+
+    let parsed = CoapMessage::parse(packet.as_slice()).unwrap();
+    assert_eq!(parsed.options.get(300), Some(&[1][..]));
+    assert_eq!(parsed.payload, b"21.5");
+
+    assert!(CoapMessage::parse(&[0x50, 0x45, 0x00, 0x07, 0xff]).is_err());
+    assert!(CoapMessage::parse(&[0x50, 0x45, 0x00, 0x07, 0xe1, 0x00]).is_err());
+}
@@ -0,0 +1,82 @@
+//! The CRC-32 of IEEE 802.3, used for the Ethernet frame check sequence.
+//!
+//! By default a 16 entry table is used, which processes four bits per step. The
+//! `crc-bitwise` feature selects a table-free implementation for minimal flash usage.
+
+/// The reversed CRC-32 polynomial.
+#[cfg(any(test, feature = "crc-bitwise"))]
+const POLYNOMIAL: u32 = 0xedb8_8320;
+
+#[cfg(any(test, not(feature = "crc-bitwise")))]
+const NIBBLE_TABLE: [u32; 16] = [0x00000000, 0x1db71064, 0x3b6e20c8, 0x26d930ac, 0x76dc4190,
+                                 0x6b6b51f4, 0x4db26158, 0x5005713c, 0xedb88320, 0xf00f9344,
+                                 0xd6d6a3e8, 0xcb61b38c, 0x9b64c2b0, 0x86d3d2d4, 0xa00ae278,
+                                 0xbdbdf21c];
+
+/// An incrementally computed CRC-32.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crc32(u32);
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32(0xffff_ffff)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        #[cfg(feature = "crc-bitwise")]
+        {
+            self.0 = update_bitwise(self.0, data);
+        }
+        #[cfg(not(feature = "crc-bitwise"))]
+        {
+            self.0 = update_nibble(self.0, data);
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+#[cfg(any(test, not(feature = "crc-bitwise")))]
+fn update_nibble(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= u32::from(byte);
+        crc = (crc >> 4) ^ NIBBLE_TABLE[(crc & 0xf) as usize];
+        crc = (crc >> 4) ^ NIBBLE_TABLE[(crc & 0xf) as usize];
+    }
+    crc
+}
+
+#[cfg(any(test, feature = "crc-bitwise"))]
+fn update_bitwise(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+#[test]
+fn check_value() {
+    assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    assert_eq!(!update_nibble(!0, b"123456789"), 0xcbf43926);
+    assert_eq!(!update_bitwise(!0, b"123456789"), 0xcbf43926);
+
+    let mut crc = Crc32::new();
+    crc.update(b"1234");
+    crc.update(b"56789");
+    assert_eq!(crc.finish(), 0xcbf43926);
+}
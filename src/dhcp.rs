@@ -1,42 +1,218 @@
 use {TxPacket, WriteOut};
 use ethernet::{EthernetAddress, EthernetPacket};
 use ipv4::{Ipv4Address, Ipv4Packet};
+use tlv::TlvFormat;
 use udp::UdpPacket;
 
-pub fn new_discover_msg(mac: EthernetAddress) -> EthernetPacket<Ipv4Packet<UdpPacket<DhcpPacket>>> {
-    let dhcp_discover = DhcpPacket {
-        mac: mac,
-        transaction_id: 0x12345678,
-        operation: DhcpType::Discover,
-    };
+/// Options have a value length, with the pad option 0 and the end option 255.
+const OPTIONS: TlvFormat = TlvFormat {
+    pad: 0,
+    end: 255,
+    len_includes_header: false,
+};
+
+pub fn new_discover_msg(mac: EthernetAddress)
+                        -> EthernetPacket<Ipv4Packet<UdpPacket<DhcpPacket<'static>>>> {
+    let dhcp_discover = DhcpPacket::new(mac, 0x12345678, DhcpType::Discover);
     let udp = UdpPacket::new(68, 67, dhcp_discover);
-    let ip = Ipv4Packet::new_udp(Ipv4Address::new(0, 0, 0, 0),
-                                 Ipv4Address::new(255, 255, 255, 255),
-                                 udp);
-    EthernetPacket::new_ipv4(mac, EthernetAddress::new([0xff; 6]), ip)
+    let ip = Ipv4Packet::new_udp(Ipv4Address::new(0, 0, 0, 0), Ipv4Address::broadcast(), udp);
+    EthernetPacket::new_ipv4(mac, EthernetAddress::broadcast(), ip)
 }
 
 pub fn new_request_msg(mac: EthernetAddress,
                        ip: Ipv4Address,
                        dhcp_server_ip: Ipv4Address)
-                       -> EthernetPacket<Ipv4Packet<UdpPacket<DhcpPacket>>> {
-    let dhcp_request = DhcpPacket {
-        mac: mac,
-        transaction_id: 0x12345678,
-        operation: DhcpType::Request { ip, dhcp_server_ip },
-    };
+                       -> EthernetPacket<Ipv4Packet<UdpPacket<DhcpPacket<'static>>>> {
+    let dhcp_request = DhcpPacket::new(mac,
+                                       0x12345678,
+                                       DhcpType::Request { ip, dhcp_server_ip });
     let udp = UdpPacket::new(68, 67, dhcp_request);
-    let ip = Ipv4Packet::new_udp(Ipv4Address::new(0, 0, 0, 0),
-                                 Ipv4Address::new(255, 255, 255, 255),
-                                 udp);
-    EthernetPacket::new_ipv4(mac, EthernetAddress::new([0xff; 6]), ip)
+    let ip = Ipv4Packet::new_udp(Ipv4Address::new(0, 0, 0, 0), Ipv4Address::broadcast(), udp);
+    EthernetPacket::new_ipv4(mac, EthernetAddress::broadcast(), ip)
+}
+
+/// Tells the server that offered `ip` that the address is already in use, e.g.
+/// after `Interface::probe_address` reported `StackEvent::AddressConflict`. The
+/// client then restarts with a DISCOVER.
+pub fn new_decline_msg(mac: EthernetAddress,
+                       ip: Ipv4Address,
+                       dhcp_server_ip: Ipv4Address)
+                       -> EthernetPacket<Ipv4Packet<UdpPacket<DhcpPacket<'static>>>> {
+    let dhcp_decline = DhcpPacket::new(mac,
+                                       0x12345678,
+                                       DhcpType::Decline { ip, dhcp_server_ip });
+    let udp = UdpPacket::new(68, 67, dhcp_decline);
+    let ip = Ipv4Packet::new_udp(Ipv4Address::new(0, 0, 0, 0), Ipv4Address::broadcast(), udp);
+    EthernetPacket::new_ipv4(mac, EthernetAddress::broadcast(), ip)
+}
+
+/// Asks the DHCP servers for the site configuration of a host with the statically
+/// configured address `ip`, without obtaining a lease.
+///
+/// The server answers with an `Ack` whose `config` contains the options.
+pub fn new_inform_msg(mac: EthernetAddress,
+                      ip: Ipv4Address)
+                      -> EthernetPacket<Ipv4Packet<UdpPacket<DhcpPacket<'static>>>> {
+    let mut dhcp_inform = DhcpPacket::new(mac, 0x12345678, DhcpType::Inform { ip });
+    dhcp_inform.broadcast = false; // we can receive unicast at `ip`
+    let udp = UdpPacket::new(68, 67, dhcp_inform);
+    let ip = Ipv4Packet::new_udp(ip, Ipv4Address::broadcast(), udp);
+    EthernetPacket::new_ipv4(mac, EthernetAddress::broadcast(), ip)
+}
+
+/// The hardware type of Ethernet.
+pub const HARDWARE_ETHERNET: u8 = 1;
+
+/// The hardware type of EUI-64 addresses, e.g. of IEEE 802.15.4 radios.
+pub const HARDWARE_EUI64: u8 = 27;
+
+/// The client hardware address, sent in the `htype`, `hlen`, and `chaddr` fields.
+///
+/// Links whose addresses don't fit, or clients that are identified by the client
+/// identifier option instead (RFC 4361), can send an empty address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HardwareAddress {
+    pub hardware_type: u8,
+    len: u8,
+    bytes: [u8; 16],
+}
+
+impl HardwareAddress {
+    /// Fails if `address` is longer than the 16 bytes of the `chaddr` field.
+    pub fn new(hardware_type: u8, address: &[u8]) -> Result<Self, ()> {
+        if address.len() > 16 {
+            return Err(());
+        }
+        let mut bytes = [0; 16];
+        bytes[..address.len()].copy_from_slice(address);
+        Ok(HardwareAddress {
+               hardware_type,
+               len: address.len() as u8,
+               bytes,
+           })
+    }
+
+    pub fn ethernet(mac: EthernetAddress) -> Self {
+        let mut bytes = [0; 16];
+        bytes[..6].copy_from_slice(&mac.as_bytes());
+        HardwareAddress {
+            hardware_type: HARDWARE_ETHERNET,
+            len: 6,
+            bytes,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..usize::from(self.len)]
+    }
+
+    /// The MAC address, if this is an Ethernet address.
+    pub fn mac(&self) -> Option<EthernetAddress> {
+        if self.hardware_type == HARDWARE_ETHERNET && self.len == 6 {
+            Some(EthernetAddress::from_bytes(self.as_bytes()))
+        } else {
+            None
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct DhcpPacket {
-    pub mac: EthernetAddress,
+pub struct DhcpPacket<'a> {
+    pub hardware_address: HardwareAddress,
+    /// The client identifier option (61), a type byte followed by the identifier.
+    /// Servers use it instead of the hardware address to identify the client.
+    pub client_id: Option<&'a [u8]>,
     pub transaction_id: u32,
     pub operation: DhcpType,
+    /// Asks the server to broadcast its replies. Clear it if the server should reply
+    /// via unicast to the offered address.
+    pub broadcast: bool,
+    /// The `giaddr` field, set by relay agents.
+    pub relay_agent_ip: Ipv4Address,
+    /// The relay agent information option (82).
+    pub relay_agent_info: Option<RelayAgentInfo<'a>>,
+    /// The configuration options sent by the server. Only filled in by parsing.
+    pub config: DhcpConfig,
+    /// The option codes in the parameter request list option (55) of a client.
+    /// Only filled in by parsing, sent packets request a fixed list.
+    pub parameter_request_list: Option<&'a [u8]>,
+    /// The domain search list option (119) in the encoding of RFC 3397, see
+    /// `dns::read_search_list`. Only filled in by parsing.
+    pub domain_search: Option<&'a [u8]>,
+}
+
+/// Site configuration from DHCP options. For options that carry a list of servers,
+/// only the first one is kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DhcpConfig {
+    pub subnet_mask: Option<Ipv4Address>,
+    pub router: Option<Ipv4Address>,
+    pub dns_server: Option<Ipv4Address>,
+    pub ntp_server: Option<Ipv4Address>,
+}
+
+impl<'a> DhcpPacket<'a> {
+    /// Creates a packet of an Ethernet client that requests broadcast replies and
+    /// isn't relayed.
+    pub fn new(mac: EthernetAddress, transaction_id: u32, operation: DhcpType) -> Self {
+        Self::with_hardware_address(HardwareAddress::ethernet(mac), transaction_id, operation)
+    }
+
+    /// Like `new`, for clients on other links than Ethernet.
+    pub fn with_hardware_address(hardware_address: HardwareAddress,
+                                 transaction_id: u32,
+                                 operation: DhcpType)
+                                 -> Self {
+        DhcpPacket {
+            hardware_address,
+            client_id: None,
+            transaction_id,
+            operation,
+            broadcast: true,
+            relay_agent_ip: Ipv4Address::new(0, 0, 0, 0),
+            relay_agent_info: None,
+            config: DhcpConfig::default(),
+            parameter_request_list: None,
+            domain_search: None,
+        }
+    }
+
+    /// The client MAC address, if the client is on Ethernet.
+    pub fn mac(&self) -> Option<EthernetAddress> {
+        self.hardware_address.mac()
+    }
+
+    fn options_len(&self) -> usize {
+        self.client_id.map(|id| OPTIONS.option_len(id.len())).unwrap_or(0) +
+        self.relay_agent_info.map(|info| OPTIONS.option_len(info.len())).unwrap_or(0)
+    }
+}
+
+/// The relay agent information option according to [RFC 3046][rfc3046].
+///
+/// [rfc3046]: https://tools.ietf.org/html/rfc3046
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RelayAgentInfo<'a> {
+    pub circuit_id: Option<&'a [u8]>,
+    pub remote_id: Option<&'a [u8]>,
+}
+
+impl<'a> RelayAgentInfo<'a> {
+    fn len(&self) -> usize {
+        self.circuit_id.map(|id| OPTIONS.option_len(id.len())).unwrap_or(0) +
+        self.remote_id.map(|id| OPTIONS.option_len(id.len())).unwrap_or(0)
+    }
+
+    fn write_out<T: TxPacket>(&self, packet: &mut T) -> Result<(), ()> {
+        let option = OPTIONS.start(packet, 82)?;
+        if let Some(circuit_id) = self.circuit_id {
+            OPTIONS.push(packet, 1, circuit_id)?; // agent circuit id
+        }
+        if let Some(remote_id) = self.remote_id {
+            OPTIONS.push(packet, 2, remote_id)?; // agent remote id
+        }
+        option.finish(packet)
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -51,14 +227,22 @@ pub enum DhcpType {
         dhcp_server_ip: Ipv4Address,
     },
     Ack { ip: Ipv4Address },
+    /// The offered address `ip` is used by another host.
+    Decline {
+        ip: Ipv4Address,
+        dhcp_server_ip: Ipv4Address,
+    },
+    /// Requests configuration for the already configured address `ip`.
+    Inform { ip: Ipv4Address },
 }
 
-impl WriteOut for DhcpPacket {
+impl<'a> WriteOut for DhcpPacket<'a> {
     fn len(&self) -> usize {
-        240 +
+        240 + self.options_len() +
         match self.operation {
             DhcpType::Discover => 10,
-            DhcpType::Request { .. } => 16,
+            DhcpType::Request { .. } | DhcpType::Decline { .. } => 16,
+            DhcpType::Inform { .. } => 12,
             DhcpType::Offer { .. } => unimplemented!(),
             DhcpType::Ack { .. } => unimplemented!(),
         }
@@ -67,29 +251,35 @@ impl WriteOut for DhcpPacket {
     fn write_out<T: TxPacket>(&self, packet: &mut T) -> Result<(), ()> {
         let operation = match self.operation {
             DhcpType::Discover |
-            DhcpType::Request { .. } => 1,
+            DhcpType::Request { .. } |
+            DhcpType::Decline { .. } |
+            DhcpType::Inform { .. } => 1,
             DhcpType::Offer { .. } |
             DhcpType::Ack { .. } => 2,
         };
 
         packet.push_byte(operation)?;
-        packet.push_byte(1)?; // hardware type (1 == ethernet)
-        packet.push_byte(6)?; // hardware address len
+        let hardware_address = self.hardware_address.as_bytes();
+        packet.push_byte(self.hardware_address.hardware_type)?;
+        packet.push_byte(hardware_address.len() as u8)?;
         packet.push_byte(0)?; // hops
 
         packet.push_u32(self.transaction_id)?;
         packet.push_u16(0)?; // seconds since start
-        packet.push_u16(1 << 15)?; // flags (bit 15 == reply as broadcast)
+        packet.push_u16(if self.broadcast { 1 << 15 } else { 0 })?; // flags
 
         let zero_ip = &Ipv4Address::new(0, 0, 0, 0).as_bytes();
 
-        packet.push_bytes(zero_ip)?; // client ip
+        match self.operation {
+            DhcpType::Inform { ip } => packet.push_bytes(&ip.as_bytes())?, // client ip
+            _ => packet.push_bytes(zero_ip)?,
+        };
         packet.push_bytes(zero_ip)?; // own ip
         packet.push_bytes(zero_ip)?; // server ip
-        packet.push_bytes(zero_ip)?; // relay agent ip
+        packet.push_bytes(&self.relay_agent_ip.as_bytes())?; // relay agent ip
 
-        packet.push_bytes(&self.mac.as_bytes())?; // client mac
-        packet.push_bytes(&[0; 10])?; // client mac padding
+        packet.push_bytes(hardware_address)?; // client hardware address
+        packet.push_bytes(&[0; 16][hardware_address.len()..])?; // padding
 
         packet.push_bytes(&[0; 64])?; // server name
         packet.push_bytes(&[0; 128])?; // file name
@@ -98,38 +288,55 @@ impl WriteOut for DhcpPacket {
         // options
         match self.operation {
             DhcpType::Discover => {
-                // DHCP message type
-                packet.push_byte(53)?; // code
-                packet.push_byte(1)?; // len
-                packet.push_byte(1)?; // 1 == DHCP Discover
-
-                // parameter request list
-                packet.push_byte(55)?; // code
-                packet.push_byte(4)?; // len
-                packet.push_byte(1)?; // request subnet mask
-                packet.push_byte(3)?; // router
-                packet.push_byte(15)?; // domain name
-                packet.push_byte(6)?; // domain name server
-
-                packet.push_byte(255)?; // option end
+                OPTIONS.push(packet, 53, &[1])?; // DHCP message type: discover
+                self.write_client_id(packet)?;
+
+                // parameter request list: subnet mask, router, domain name, DNS server
+                OPTIONS.push(packet, 55, &[1, 3, 15, 6])?;
+
+                if let Some(relay_agent_info) = self.relay_agent_info {
+                    relay_agent_info.write_out(packet)?;
+                }
+
+                packet.push_byte(OPTIONS.end)?;
             }
             DhcpType::Request { ip, dhcp_server_ip } => {
-                // DHCP message type
-                packet.push_byte(53)?; // code
-                packet.push_byte(1)?; // len
-                packet.push_byte(3)?; // 3 == DHCP Request
-
-                // requested ip
-                packet.push_byte(50)?; // code
-                packet.push_byte(4)?; // len
-                packet.push_bytes(&ip.as_bytes())?; // requested ip
-
-                // dhcp server ip
-                packet.push_byte(54)?; // code
-                packet.push_byte(4)?; // len
-                packet.push_bytes(&dhcp_server_ip.as_bytes())?; // dhcp server ip
-
-                packet.push_byte(255)?; // option end
+                OPTIONS.push(packet, 53, &[3])?; // DHCP message type: request
+                self.write_client_id(packet)?;
+                OPTIONS.push(packet, 50, &ip.as_bytes())?; // requested ip
+                OPTIONS.push(packet, 54, &dhcp_server_ip.as_bytes())?; // dhcp server ip
+
+                if let Some(relay_agent_info) = self.relay_agent_info {
+                    relay_agent_info.write_out(packet)?;
+                }
+
+                packet.push_byte(OPTIONS.end)?;
+            }
+            DhcpType::Decline { ip, dhcp_server_ip } => {
+                OPTIONS.push(packet, 53, &[4])?; // DHCP message type: decline
+                self.write_client_id(packet)?;
+                OPTIONS.push(packet, 50, &ip.as_bytes())?; // requested ip
+                OPTIONS.push(packet, 54, &dhcp_server_ip.as_bytes())?; // dhcp server ip
+
+                if let Some(relay_agent_info) = self.relay_agent_info {
+                    relay_agent_info.write_out(packet)?;
+                }
+
+                packet.push_byte(OPTIONS.end)?;
+            }
+            DhcpType::Inform { .. } => {
+                OPTIONS.push(packet, 53, &[8])?; // DHCP message type: inform
+                self.write_client_id(packet)?;
+
+                // parameter request list: subnet mask, router, DNS server, NTP server,
+                // domain name, domain search list
+                OPTIONS.push(packet, 55, &[1, 3, 6, 42, 15, 119])?;
+
+                if let Some(relay_agent_info) = self.relay_agent_info {
+                    relay_agent_info.write_out(packet)?;
+                }
+
+                packet.push_byte(OPTIONS.end)?;
             }
             DhcpType::Offer { .. } |
             DhcpType::Ack { .. } => unimplemented!(),
@@ -139,29 +346,90 @@ impl WriteOut for DhcpPacket {
     }
 }
 
-use parse::{Parse, ParseError};
+impl<'a> DhcpPacket<'a> {
+    fn write_client_id<T: TxPacket>(&self, packet: &mut T) -> Result<(), ()> {
+        match self.client_id {
+            Some(client_id) => OPTIONS.push(packet, 61, client_id),
+            None => Ok(()),
+        }
+    }
+}
+
+use parse::{Layer, Parse, ParseError};
 
-impl<'a> Parse<'a> for DhcpPacket {
+impl<'a> Parse<'a> for RelayAgentInfo<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
+        let mut info = RelayAgentInfo::default();
+        // sub-options have no padding or end, but 0 and 255 are unused codes
+        for option in OPTIONS.options(data, Layer::Dhcp, 0) {
+            let option = option?;
+            match option.code {
+                1 => info.circuit_id = Some(option.value),
+                2 => info.remote_id = Some(option.value),
+                _ => {} // ignore unknown sub-options
+            }
+        }
+        Ok(info)
+    }
+}
+
+impl<'a> Parse<'a> for DhcpPacket<'a> {
     fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
         use byteorder::{ByteOrder, NetworkEndian};
 
-        fn parse_message_type_tag(mut data: &[u8]) -> u8 {
-            loop {
-                let code = data[0];
-                let len = data[1];
-                if code == 53 && len == 1 {
-                    return data[2];
-                } else {
-                    data = &data[(2 + usize::from(len))..];
+        if data.len() < 240 {
+            return Err(ParseError::truncated(Layer::Dhcp, data.len()));
+        }
+
+        let mut message_type = None;
+        let mut relay_agent_info = None;
+        let mut config = DhcpConfig::default();
+        let mut requested_ip = None;
+        let mut server_identifier = None;
+        let mut parameter_request_list = None;
+        let mut domain_search = None;
+        let mut client_id = None;
+        let mut options = OPTIONS.options(&data[240..], Layer::Dhcp, 240);
+        while let Some(option) = options.next() {
+            let option = option?;
+            let (code, value, value_offset) = (option.code, option.value, option.value_offset());
+            match code {
+                53 if value.len() == 1 => message_type = Some((value_offset, value[0])),
+                82 => {
+                    let info = RelayAgentInfo::parse(value).map_err(|e| e.at(value_offset))?;
+                    relay_agent_info = Some(info);
+                }
+                50 if value.len() == 4 => requested_ip = Some(Ipv4Address::from_bytes(value)),
+                54 if value.len() == 4 => {
+                    server_identifier = Some(Ipv4Address::from_bytes(value));
                 }
+                55 => parameter_request_list = Some(value),
+                119 => domain_search = Some(value),
+                61 => client_id = Some(value),
+                1 | 3 | 6 | 42 if value.len() >= 4 => {
+                    let address = Some(Ipv4Address::from_bytes(&value[0..4]));
+                    match code {
+                        1 => config.subnet_mask = address,
+                        3 => config.router = address,
+                        6 => config.dns_server = address,
+                        _ => config.ntp_server = address,
+                    }
+                }
+                _ => {}
             }
         }
+        if !options.ended() {
+            return Err(ParseError::truncated(Layer::Dhcp, data.len()));
+        }
 
-        let operation = match parse_message_type_tag(&data[240..]) {
-            1 => {
-                // discover
-                return Err(ParseError::Unimplemented("dhcp discover"));
+        let (message_type_offset, message_type) = match message_type {
+            Some(message_type) => message_type,
+            None => {
+                return Err(ParseError::malformed(Layer::Dhcp, 240, "missing dhcp message type"))
             }
+        };
+        let operation = match message_type {
+            1 => DhcpType::Discover,
             2 => {
                 // offer
                 let ip = Ipv4Address::from_bytes(&data[16..20]);
@@ -169,21 +437,50 @@ impl<'a> Parse<'a> for DhcpPacket {
                 DhcpType::Offer { ip, dhcp_server_ip }
             }
             3 => {
-                // request
-                return Err(ParseError::Unimplemented("dhcp request"));
+                // request, renewing clients send their address in `ciaddr` instead
+                let ip = requested_ip.unwrap_or_else(|| Ipv4Address::from_bytes(&data[12..16]));
+                let dhcp_server_ip = server_identifier.unwrap_or(Ipv4Address::new(0, 0, 0, 0));
+                DhcpType::Request { ip, dhcp_server_ip }
+            }
+            4 => {
+                // decline
+                let ip = requested_ip.unwrap_or(Ipv4Address::new(0, 0, 0, 0));
+                let dhcp_server_ip = server_identifier.unwrap_or(Ipv4Address::new(0, 0, 0, 0));
+                DhcpType::Decline { ip, dhcp_server_ip }
             }
             5 => {
                 // ack
                 let ip = Ipv4Address::from_bytes(&data[16..20]);
                 DhcpType::Ack { ip }
             }
-            _ => return Err(ParseError::Unimplemented("unknown dhcp message type")),
+            8 => {
+                // inform
+                let ip = Ipv4Address::from_bytes(&data[12..16]);
+                DhcpType::Inform { ip }
+            }
+            _ => {
+                return Err(ParseError::unimplemented(Layer::Dhcp,
+                                                     message_type_offset,
+                                                     "unknown dhcp message type"))
+            }
         };
 
+        // lengths above 16 are rejected by `new`
+        let hardware_address_len = usize::min(usize::from(data[2]), 17);
+        let hardware_address = HardwareAddress::new(data[1], &data[28..28 + hardware_address_len])
+            .map_err(|()| ParseError::malformed(Layer::Dhcp, 2, "hardware address too long"))?;
+
         Ok(DhcpPacket {
-               mac: EthernetAddress::from_bytes(&data[28..34]),
+               hardware_address,
+               client_id,
                transaction_id: NetworkEndian::read_u32(&data[4..8]),
                operation: operation,
+               broadcast: NetworkEndian::read_u16(&data[10..12]) & (1 << 15) != 0,
+               relay_agent_ip: Ipv4Address::from_bytes(&data[24..28]),
+               relay_agent_info,
+               config,
+               parameter_request_list,
+               domain_search,
            })
     }
 }
@@ -192,11 +489,9 @@ impl<'a> Parse<'a> for DhcpPacket {
 fn test_discover() {
     use HeapTxPacket;
 
-    let discover = DhcpPacket {
-        mac: EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]),
-        transaction_id: 0xcafebabe,
-        operation: DhcpType::Discover,
-    };
+    let discover = DhcpPacket::new(EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]),
+                                   0xcafebabe,
+                                   DhcpType::Discover);
 
     let mut packet = HeapTxPacket::new(discover.len());
     discover.write_out(&mut packet).unwrap();
@@ -232,14 +527,12 @@ fn test_discover() {
 fn test_request() {
     use HeapTxPacket;
 
-    let request = DhcpPacket {
-        mac: EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]),
-        transaction_id: 0xcafebabe,
-        operation: DhcpType::Request {
-            ip: Ipv4Address::new(141, 52, 46, 201),
-            dhcp_server_ip: Ipv4Address::new(141, 52, 46, 13),
-        },
-    };
+    let request = DhcpPacket::new(EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]),
+                                  0xcafebabe,
+                                  DhcpType::Request {
+                                      ip: Ipv4Address::new(141, 52, 46, 201),
+                                      dhcp_server_ip: Ipv4Address::new(141, 52, 46, 13),
+                                  });
 
     let mut packet = HeapTxPacket::new(request.len());
     request.write_out(&mut packet).unwrap();
@@ -310,3 +603,140 @@ fn test_discover_packet() {
         assert_eq!(data[i], reference_data[i], "{}", i);
     }
 }
+
+#[test]
+fn relayed_unicast_offer() {
+    let mut offer = [0; 256];
+    offer[0] = 2; // boot reply
+    offer[4..8].copy_from_slice(&[0xca, 0xfe, 0xba, 0xbe]);
+    offer[16..20].copy_from_slice(&[10, 1, 0, 42]); // your ip
+    offer[20..24].copy_from_slice(&[10, 0, 0, 1]); // server ip
+    offer[24..28].copy_from_slice(&[10, 1, 0, 1]); // relay agent ip
+    offer[28..34].copy_from_slice(&[0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    offer[236..240].copy_from_slice(&[0x63, 0x82, 0x53, 0x63]);
+    offer[240..256].copy_from_slice(&[0x00, 0x52, 0x09, 0x01, 0x02, 0x00, 0x07, 0x02, 0x03, b'a',
+                                      b'b', b'c', 0x35, 0x01, 0x02, 0xff]);
+
+    let dhcp = DhcpPacket::parse(&offer).unwrap();
+    assert_eq!(dhcp.operation,
+               DhcpType::Offer {
+                   ip: Ipv4Address::new(10, 1, 0, 42),
+                   dhcp_server_ip: Ipv4Address::new(10, 0, 0, 1),
+               });
+    assert!(!dhcp.broadcast);
+    assert_eq!(dhcp.relay_agent_ip, Ipv4Address::new(10, 1, 0, 1));
+    assert_eq!(dhcp.relay_agent_info,
+               Some(RelayAgentInfo {
+                        circuit_id: Some(&[0x00, 0x07]),
+                        remote_id: Some(b"abc"),
+                    }));
+
+    assert_eq!(DhcpPacket::parse(&offer[..250]).unwrap_err(),
+               ParseError::truncated(Layer::Dhcp, 250));
+}
+
+#[test]
+fn inform() {
+    use HeapTxPacket;
+    use parse::parse;
+    use ethernet::EthernetKind;
+    use ipv4::Ipv4Kind;
+    use udp::UdpKind;
+
+    let mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let ip = Ipv4Address::new(192, 168, 1, 50);
+    let inform = new_inform_msg(mac, ip);
+    let packet = HeapTxPacket::write_out(inform).unwrap();
+    let dhcp = &packet.as_slice()[42..];
+    assert_eq!(&dhcp[12..16], &[192, 168, 1, 50]); // client ip
+    assert_eq!(&dhcp[240..243], &[53, 1, 8]);
+
+    match parse(&packet).unwrap().payload {
+        EthernetKind::Ipv4(ip_packet) => {
+            match ip_packet.payload {
+                Ipv4Kind::Udp(udp) => {
+                    match udp.payload {
+                        UdpKind::Dhcp(dhcp) => assert_eq!(dhcp.operation, DhcpType::Inform { ip }),
+                        other => panic!("unexpected udp payload {:?}", other),
+                    }
+                }
+                other => panic!("unexpected packet {:?}", other),
+            }
+        }
+        other => panic!("unexpected packet {:?}", other),
+    }
+
+    let mut ack = [0; 275];
+    ack[0] = 2; // boot reply
+    ack[236..240].copy_from_slice(&[0x63, 0x82, 0x53, 0x63]);
+    ack[240..260].copy_from_slice(&[53, 1, 5, 3, 4, 192, 168, 1, 1, 6, 8, 192, 168, 1, 2, 8, 8,
+                                    8, 8, 42]);
+    ack[260..275].copy_from_slice(&[4, 192, 168, 1, 3, 119, 5, 3, b'l', b'a', b'n', 0, 0, 0,
+                                    255]);
+    let ack = DhcpPacket::parse(&ack).unwrap();
+    assert_eq!(ack.config,
+               DhcpConfig {
+                   subnet_mask: None,
+                   router: Some(Ipv4Address::new(192, 168, 1, 1)),
+                   dns_server: Some(Ipv4Address::new(192, 168, 1, 2)),
+                   ntp_server: Some(Ipv4Address::new(192, 168, 1, 3)),
+               });
+    assert_eq!(ack.domain_search, Some(&[3, b'l', b'a', b'n', 0][..]));
+}
+
+#[test]
+fn client_messages_round_trip() {
+    use HeapTxPacket;
+
+    let mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let discover = DhcpPacket::new(mac, 0xcafebabe, DhcpType::Discover);
+    let mut packet = HeapTxPacket::new(discover.len());
+    discover.write_out(&mut packet).unwrap();
+    let parsed = DhcpPacket::parse(&packet).unwrap();
+    assert_eq!(parsed.parameter_request_list, Some(&[1, 3, 15, 6][..]));
+    assert_eq!(DhcpPacket { parameter_request_list: None, ..parsed }, discover);
+
+    let operation = DhcpType::Request {
+        ip: Ipv4Address::new(141, 52, 46, 201),
+        dhcp_server_ip: Ipv4Address::new(141, 52, 46, 13),
+    };
+    let request = DhcpPacket::new(mac, 0xcafebabe, operation);
+    let mut packet = HeapTxPacket::new(request.len());
+    request.write_out(&mut packet).unwrap();
+    assert_eq!(DhcpPacket::parse(&packet).unwrap(), request);
+
+    let operation = DhcpType::Decline {
+        ip: Ipv4Address::new(141, 52, 46, 201),
+        dhcp_server_ip: Ipv4Address::new(141, 52, 46, 13),
+    };
+    let decline = DhcpPacket::new(mac, 0xcafebabe, operation);
+    let mut packet = HeapTxPacket::new(decline.len());
+    decline.write_out(&mut packet).unwrap();
+    assert_eq!(&packet.as_slice()[240..243], &[53, 1, 4]);
+    assert_eq!(DhcpPacket::parse(&packet).unwrap(), decline);
+}
+
+#[test]
+fn eui64_client_with_client_id() {
+    use HeapTxPacket;
+
+    let eui64 = [0x02, 0x12, 0x4b, 0x00, 0x01, 0x02, 0x03, 0x04];
+    let hardware_address = HardwareAddress::new(HARDWARE_EUI64, &eui64).unwrap();
+    let mut discover =
+        DhcpPacket::with_hardware_address(hardware_address, 0xcafebabe, DhcpType::Discover);
+    let client_id = [255, 0x12, 0x34, 0x56, 0x78]; // RFC 4361 IAID follows the type
+    discover.client_id = Some(&client_id);
+    let mut packet = HeapTxPacket::new(discover.len());
+    discover.write_out(&mut packet).unwrap();
+    let data = packet.as_slice();
+    assert_eq!(data.len(), discover.len());
+    assert_eq!(&data[1..3], &[HARDWARE_EUI64, 8]);
+    assert_eq!(&data[28..44], &[0x02, 0x12, 0x4b, 0x00, 0x01, 0x02, 0x03, 0x04, 0, 0, 0, 0, 0,
+                                0, 0, 0]);
+
+    let parsed = DhcpPacket::parse(data).unwrap();
+    assert_eq!((parsed.hardware_address, parsed.client_id),
+               (hardware_address, Some(&client_id[..])));
+    assert_eq!(parsed.mac(), None);
+    assert!(HardwareAddress::new(HARDWARE_EUI64, &[0; 17]).is_err());
+}
@@ -0,0 +1,723 @@
+//! DNS queries for A records and a resolver that retries, validates, and caches them.
+//!
+//! Responses that don't fit into a UDP datagram are truncated by the server, and the
+//! resolver repeats the query over TCP, with the length prefix of `encode_tcp_message`.
+
+use alloc::{BTreeMap, String, Vec, VecDeque};
+use byteorder::{ByteOrder, NetworkEndian};
+#[cfg(any(test, feature = "dhcp"))]
+use dhcp::{DhcpConfig, DhcpPacket};
+use ipv4::Ipv4Address;
+use parse::{Layer, Parse, ParseError};
+use retry::{Backoff, Policy};
+
+pub const DNS_PORT: u16 = 53;
+
+/// The timeout of the first attempt in milliseconds. It doubles with every retry.
+pub const INITIAL_QUERY_TIMEOUT: u64 = 1000;
+
+/// The number of attempts, rotating through the servers, before a query fails.
+pub const MAX_QUERY_ATTEMPTS: u8 = 4;
+
+pub const DEFAULT_RETRY_POLICY: Policy = Policy {
+    initial_delay: INITIAL_QUERY_TIMEOUT,
+    backoff: Backoff::Exponential,
+    max_delay: 8 * INITIAL_QUERY_TIMEOUT,
+    max_retries: MAX_QUERY_ATTEMPTS - 1,
+    jitter_percent: 0,
+};
+
+/// The number of cached names. When the cache is full, the entry that expires first
+/// is replaced.
+pub const MAX_CACHE_ENTRIES: usize = 8;
+
+/// The number of queries that can be outstanding at once.
+pub const MAX_QUERIES: usize = 4;
+
+pub const HEADER_LEN: usize = 12;
+pub const TYPE_A: u16 = 1;
+pub const CLASS_IN: u16 = 1;
+pub const FLAG_RESPONSE: u16 = 0x8000;
+pub const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const RCODE_NAME_ERROR: u16 = 3;
+
+/// Encodes a recursive query for the A record of `name`, e.g. `"example.com"`.
+pub fn encode_query(id: u16, name: &str) -> Result<Vec<u8>, ()> {
+    let mut query = Vec::with_capacity(HEADER_LEN + name.len() + 6);
+    let mut header = [0; HEADER_LEN];
+    NetworkEndian::write_u16(&mut header[0..2], id);
+    NetworkEndian::write_u16(&mut header[2..4], FLAG_RECURSION_DESIRED);
+    NetworkEndian::write_u16(&mut header[4..6], 1); // question count
+    query.extend_from_slice(&header);
+    push_name(&mut query, name)?;
+
+    let mut question = [0; 4];
+    NetworkEndian::write_u16(&mut question[0..2], TYPE_A);
+    NetworkEndian::write_u16(&mut question[2..4], CLASS_IN);
+    query.extend_from_slice(&question);
+    Ok(query)
+}
+
+/// Prefixes a DNS message with its 16-bit length, for sending it over TCP.
+pub fn encode_tcp_message(message: &[u8]) -> Result<Vec<u8>, ()> {
+    if message.len() > usize::from(u16::max_value()) {
+        return Err(());
+    }
+    let mut framed = Vec::with_capacity(2 + message.len());
+    let mut len = [0; 2];
+    NetworkEndian::write_u16(&mut len, message.len() as u16);
+    framed.extend_from_slice(&len);
+    framed.extend_from_slice(message);
+    Ok(framed)
+}
+
+/// Splits the data of a TCP connection into the length-prefixed DNS messages.
+#[derive(Debug, Default)]
+pub struct DnsTcpStream {
+    buffer: Vec<u8>,
+    /// The length of the message returned by the last `next_message` call.
+    consumed: usize,
+}
+
+impl DnsTcpStream {
+    pub fn new() -> Self {
+        DnsTcpStream::default()
+    }
+
+    /// Appends received TCP data.
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Returns the next complete message, without the length prefix.
+    pub fn next_message(&mut self) -> Option<&[u8]> {
+        self.buffer.drain(..self.consumed);
+        self.consumed = 0;
+
+        if self.buffer.len() < 2 {
+            return None;
+        }
+        let len = usize::from(NetworkEndian::read_u16(&self.buffer[0..2]));
+        if self.buffer.len() < 2 + len {
+            return None;
+        }
+        self.consumed = 2 + len;
+        Some(&self.buffer[2..2 + len])
+    }
+}
+
+/// Reads a domain search list in the format of DHCP option 119 (RFC 3397), a
+/// sequence of names whose compression pointers are relative to the option data.
+pub fn read_search_list(data: &[u8]) -> Result<Vec<String>, ParseError> {
+    let mut list = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let (name, next) = read_name(data, offset)?;
+        list.push(name);
+        offset = next;
+    }
+    Ok(list)
+}
+
+/// Appends `name` in the uncompressed label format. Fails for empty or too long
+/// labels.
+pub fn push_name(message: &mut Vec<u8>, name: &str) -> Result<(), ()> {
+    for label in without_root(name).split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(());
+        }
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    Ok(())
+}
+
+/// The A records of a DNS response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsResponse {
+    pub id: u16,
+    pub rcode: u16,
+    /// The server truncated the response, so that some records may be missing.
+    pub truncated: bool,
+    /// The name of the first question, in lower case.
+    pub name: String,
+    /// The addresses and their time to live in seconds.
+    pub addresses: Vec<(Ipv4Address, u32)>,
+}
+
+/// Reads the (possibly compressed) name at `offset` and returns it in lower case,
+/// with the offset after it.
+pub fn read_name(data: &[u8], mut offset: usize) -> Result<(String, usize), ParseError> {
+    let mut name = String::new();
+    let mut end = None;
+    // each pointer must point backwards, so this terminates
+    let mut limit = offset;
+    loop {
+        let len = *data.get(offset).ok_or(ParseError::truncated(Layer::Dns, data.len()))?;
+        match len {
+            0 => {
+                return Ok((name, end.unwrap_or(offset + 1)));
+            }
+            0xc0...0xff => {
+                let pointer_bytes = data.get(offset..offset + 2)
+                    .ok_or(ParseError::truncated(Layer::Dns, data.len()))?;
+                let target = usize::from(NetworkEndian::read_u16(pointer_bytes) & 0x3fff);
+                if target >= limit {
+                    return Err(ParseError::malformed(Layer::Dns, offset, "invalid name pointer"));
+                }
+                end = end.or(Some(offset + 2));
+                limit = target;
+                offset = target;
+            }
+            1...63 => {
+                let label = data.get(offset + 1..offset + 1 + usize::from(len))
+                    .ok_or(ParseError::truncated(Layer::Dns, data.len()))?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.extend(label.iter().map(|&b| (b as char).to_ascii_lowercase()));
+                offset += 1 + usize::from(len);
+            }
+            _ => return Err(ParseError::malformed(Layer::Dns, offset, "invalid label length")),
+        }
+    }
+}
+
+/// Checks the header and the questions of a DNS message, a query or a response.
+pub fn check_message(data: &[u8]) -> Result<(), ParseError> {
+    if data.len() < HEADER_LEN {
+        return Err(ParseError::truncated(Layer::Dns, data.len()));
+    }
+    let mut offset = HEADER_LEN;
+    for _ in 0..NetworkEndian::read_u16(&data[4..6]) {
+        offset = read_name(data, offset)?.1 + 4; // type and class
+        if offset > data.len() {
+            return Err(ParseError::truncated(Layer::Dns, data.len()));
+        }
+    }
+    Ok(())
+}
+
+impl<'a> Parse<'a> for DnsResponse {
+    fn parse(data: &'a [u8]) -> Result<DnsResponse, ParseError> {
+        if data.len() < HEADER_LEN {
+            return Err(ParseError::truncated(Layer::Dns, data.len()));
+        }
+        let flags = NetworkEndian::read_u16(&data[2..4]);
+        if flags & FLAG_RESPONSE == 0 {
+            return Err(ParseError::malformed(Layer::Dns, 2, "not a DNS response"));
+        }
+        let questions = NetworkEndian::read_u16(&data[4..6]);
+        let answers = NetworkEndian::read_u16(&data[6..8]);
+        if questions == 0 {
+            return Err(ParseError::unimplemented(Layer::Dns, 4, "response without question"));
+        }
+
+        let (name, mut offset) = read_name(data, HEADER_LEN)?;
+        offset += 4; // type and class
+        for _ in 1..questions {
+            offset = read_name(data, offset)?.1 + 4;
+        }
+
+        let mut addresses = Vec::new();
+        for _ in 0..answers {
+            offset = read_name(data, offset)?.1;
+            let fields = data.get(offset..offset + 10)
+                .ok_or(ParseError::truncated(Layer::Dns, data.len()))?;
+            let type_ = NetworkEndian::read_u16(&fields[0..2]);
+            let class = NetworkEndian::read_u16(&fields[2..4]);
+            let ttl = NetworkEndian::read_u32(&fields[4..8]);
+            let len = usize::from(NetworkEndian::read_u16(&fields[8..10]));
+            offset += 10;
+            let record = data.get(offset..offset + len)
+                .ok_or(ParseError::truncated(Layer::Dns, data.len()))?;
+            if type_ == TYPE_A && class == CLASS_IN && len == 4 {
+                addresses.push((Ipv4Address::from_bytes(record), ttl));
+            }
+            offset += len;
+        }
+
+        Ok(DnsResponse {
+               id: NetworkEndian::read_u16(&data[0..2]),
+               rcode: flags & 0xf,
+               truncated: flags & FLAG_TRUNCATED != 0,
+               name,
+               addresses,
+           })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsError {
+    /// The server reported that the name doesn't exist.
+    NameNotFound,
+    /// The server answered with an error code.
+    ServerFailure(u16),
+    /// The name exists, but has no A record.
+    NoAddress,
+    /// No server answered.
+    TimedOut,
+    /// No servers are configured.
+    NoServers,
+    /// Too many queries are outstanding, or the name is invalid.
+    Rejected,
+}
+
+#[derive(Debug)]
+struct Query {
+    /// The name as passed to `query`, under which the result is reported.
+    name: String,
+    /// The queried name, which is `name` qualified with a search domain.
+    qname: String,
+    /// The index of the next search domain to try if the name doesn't exist.
+    search: usize,
+    id: u16,
+    attempts: u8,
+    server: Ipv4Address,
+    timeout: u64,
+    /// Set when the query was handed out by `next_query`.
+    deadline: Option<u64>,
+    /// A truncated response was received, so the query is sent over TCP.
+    tcp: bool,
+}
+
+/// Resolves host names to IPv4 addresses.
+///
+/// Queries are started with `query` and handed to the application with
+/// `next_query`, which sends them to port 53 of the returned server. Responses are
+/// passed to `handle_response`, and `poll` retries queries that time out. The
+/// results are reported by `poll_result` and cached according to their TTL.
+///
+/// Names without a dot are qualified with the domains of the search list, which
+/// are tried in order until one of the names exists.
+///
+/// When a response is truncated, the query is handed out by `next_tcp_query`
+/// instead. Its message has to be sent over a TCP connection to port 53 of the
+/// server, and the messages that `DnsTcpStream` splits off the received data are
+/// passed to `handle_response` as well.
+#[derive(Debug)]
+pub struct DnsResolver {
+    servers: Vec<Ipv4Address>,
+    search: Vec<String>,
+    queries: Vec<Query>,
+    /// Queries that have to be sent, by id.
+    unsent: VecDeque<u16>,
+    /// Queries that have to be sent over TCP, by id.
+    unsent_tcp: VecDeque<u16>,
+    cache: BTreeMap<String, (Ipv4Address, u64)>,
+    retry: Policy,
+    results: VecDeque<(String, Result<Ipv4Address, DnsError>)>,
+    next_id: u16,
+}
+
+impl DnsResolver {
+    /// Creates a resolver. The query ids are derived from `seed`, which should be
+    /// random, so that off-path attackers can't guess them.
+    pub fn new(seed: u16) -> DnsResolver {
+        DnsResolver {
+            servers: Vec::new(),
+            search: Vec::new(),
+            queries: Vec::new(),
+            unsent: VecDeque::new(),
+            unsent_tcp: VecDeque::new(),
+            cache: BTreeMap::new(),
+            retry: DEFAULT_RETRY_POLICY,
+            results: VecDeque::new(),
+            next_id: seed | 1,
+        }
+    }
+
+    pub fn set_servers(&mut self, servers: &[Ipv4Address]) {
+        self.servers = servers.to_vec();
+    }
+
+    /// Uses the DNS server announced by DHCP.
+    #[cfg(any(test, feature = "dhcp"))]
+    pub fn set_servers_from(&mut self, config: &DhcpConfig) {
+        self.servers = config.dns_server.into_iter().collect();
+    }
+
+    /// Replaces `DEFAULT_RETRY_POLICY`. Outstanding queries follow the new policy from
+    /// their next timeout on.
+    pub fn set_retry_policy(&mut self, policy: Policy) {
+        self.retry = policy;
+    }
+
+    /// Sets the domains that qualify names without a dot, e.g. `"example.com"`.
+    pub fn set_search_list(&mut self, domains: &[&str]) {
+        self.search = domains.iter().map(|domain| normalize(domain)).collect();
+    }
+
+    /// Uses the domain search list option of a DHCP `Ack`, if it is valid.
+    #[cfg(any(test, feature = "dhcp"))]
+    pub fn set_search_list_from(&mut self, packet: &DhcpPacket) {
+        if let Some(Ok(list)) = packet.domain_search.map(read_search_list) {
+            self.search = list;
+        }
+    }
+
+    /// Returns the cached address of `name` if its TTL didn't expire yet.
+    pub fn lookup(&self, name: &str, now: u64) -> Option<Ipv4Address> {
+        let name = normalize(name);
+        match self.cache.get(&name) {
+            Some(&(address, expires)) if now < expires => Some(address),
+            _ => None,
+        }
+    }
+
+    /// Starts resolving `name`, unless a query for it is already outstanding. The
+    /// result is reported by `poll_result`, immediately if the name is cached.
+    pub fn query(&mut self, name: &str, now: u64) -> Result<(), DnsError> {
+        let name = normalize(name);
+        if let Some(address) = self.lookup(&name, now) {
+            self.results.push_back((name, Ok(address)));
+            return Ok(());
+        }
+        if self.queries.iter().any(|q| q.name == name) {
+            return Ok(());
+        }
+        if self.servers.is_empty() {
+            return Err(DnsError::NoServers);
+        }
+        let (qname, search) = match self.search.first() {
+            Some(domain) if !name.contains('.') => (qualify(&name, domain), 1),
+            _ => (name.clone(), self.search.len()),
+        };
+        if self.queries.len() >= MAX_QUERIES || encode_query(0, &qname).is_err() {
+            return Err(DnsError::Rejected);
+        }
+
+        let id = self.new_id();
+        self.queries.push(Query {
+                              name,
+                              qname,
+                              search,
+                              id,
+                              attempts: 1,
+                              server: self.servers[0],
+                              timeout: self.retry.initial_delay,
+                              deadline: None,
+                              tcp: false,
+                          });
+        self.unsent.push_back(id);
+        Ok(())
+    }
+
+    /// A 16-bit xorshift, which never returns 0.
+    fn new_id(&mut self) -> u16 {
+        let mut id = self.next_id;
+        id ^= id << 7;
+        id ^= id >> 9;
+        id ^= id << 8;
+        self.next_id = id;
+        id
+    }
+
+    /// Returns the next query datagram and the server it has to be sent to.
+    pub fn next_query(&mut self, now: u64) -> Option<(Ipv4Address, Vec<u8>)> {
+        while let Some(id) = self.unsent.pop_front() {
+            if let Some(index) = self.queries.iter().position(|q| q.id == id) {
+                self.start_timeout(index, now);
+                let query = &self.queries[index];
+                let packet = encode_query(query.id, &query.qname).unwrap();
+                return Some((query.server, packet));
+            }
+        }
+        None
+    }
+
+    fn start_timeout(&mut self, index: usize, now: u64) {
+        let timeout = self.queries[index].timeout;
+        let timeout = if self.retry.jitter_percent > 0 {
+            // the id generator doubles as the source of randomness
+            let random = u32::from(self.new_id());
+            self.retry.jitter(timeout, random)
+        } else {
+            timeout
+        };
+        self.queries[index].deadline = Some(now + timeout);
+    }
+
+    /// Returns the next length-prefixed query that has to be sent over TCP, and the
+    /// server.
+    pub fn next_tcp_query(&mut self, now: u64) -> Option<(Ipv4Address, Vec<u8>)> {
+        while let Some(id) = self.unsent_tcp.pop_front() {
+            if let Some(index) = self.queries.iter().position(|q| q.id == id) {
+                self.start_timeout(index, now);
+                let query = &self.queries[index];
+                let message = encode_query(query.id, &query.qname).unwrap();
+                return Some((query.server, encode_tcp_message(&message).unwrap()));
+            }
+        }
+        None
+    }
+
+    /// Handles a response that `server` sent from port 53, either a datagram or a
+    /// message received over TCP.
+    ///
+    /// Responses that don't match an outstanding query in id, server, and question
+    /// are ignored. A truncated UDP response makes the query fall back to TCP.
+    pub fn handle_response(&mut self, server: Ipv4Address, data: &[u8], now: u64) {
+        let response = match DnsResponse::parse(data) {
+            Ok(response) => response,
+            Err(_) => return,
+        };
+        let index = match self.queries
+                  .iter()
+                  .position(|q| {
+                                q.id == response.id && q.server == server &&
+                                q.qname == response.name
+                            }) {
+            Some(index) => index,
+            None => return,
+        };
+        if response.truncated && !self.queries[index].tcp {
+            let query = &mut self.queries[index];
+            query.tcp = true;
+            query.deadline = None;
+            self.unsent_tcp.push_back(query.id);
+            return;
+        }
+        let mut query = self.queries.remove(index);
+
+        if response.rcode == RCODE_NAME_ERROR && query.search < self.search.len() {
+            query.qname = qualify(&query.name, &self.search[query.search]);
+            query.search += 1;
+            let server = match self.servers.first() {
+                Some(&server) => server,
+                None => {
+                    self.results.push_back((query.name, Err(DnsError::NoServers)));
+                    return;
+                }
+            };
+            if encode_query(0, &query.qname).is_err() {
+                self.results.push_back((query.name, Err(DnsError::Rejected)));
+                return;
+            }
+            query.id = self.new_id();
+            query.attempts = 1;
+            query.server = server;
+            query.timeout = self.retry.initial_delay;
+            query.deadline = None;
+            query.tcp = false;
+            self.unsent.push_back(query.id);
+            self.queries.push(query);
+            return;
+        }
+
+        let result = match response.rcode {
+            0 => {
+                match response.addresses.iter().min_by_key(|&&(_, ttl)| ttl) {
+                    Some(&(_, ttl)) => {
+                        let address = response.addresses[0].0;
+                        self.insert_cache(query.name.clone(), address, now + u64::from(ttl) * 1000);
+                        Ok(address)
+                    }
+                    None => Err(DnsError::NoAddress),
+                }
+            }
+            RCODE_NAME_ERROR => Err(DnsError::NameNotFound),
+            rcode => Err(DnsError::ServerFailure(rcode)),
+        };
+        self.results.push_back((query.name, result));
+    }
+
+    fn insert_cache(&mut self, name: String, address: Ipv4Address, expires: u64) {
+        if self.cache.len() >= MAX_CACHE_ENTRIES && !self.cache.contains_key(&name) {
+            let first = self.cache
+                .iter()
+                .min_by_key(|&(_, &(_, expires))| expires)
+                .map(|(name, _)| name.clone());
+            if let Some(first) = first {
+                self.cache.remove(&first);
+            }
+        }
+        self.cache.insert(name, (address, expires));
+    }
+
+    /// Retries the queries that timed out with the next server, backing off as
+    /// defined by the retry policy, and fails them after its last retry, or if no
+    /// servers are left.
+    pub fn poll(&mut self, now: u64) {
+        let mut i = 0;
+        while i < self.queries.len() {
+            let expired = self.queries[i].deadline.map_or(false, |deadline| now >= deadline);
+            if !expired {
+                i += 1;
+            } else if self.retry.exhausted(self.queries[i].attempts - 1) {
+                let query = self.queries.remove(i);
+                self.results.push_back((query.name, Err(DnsError::TimedOut)));
+            } else if self.servers.is_empty() {
+                let query = self.queries.remove(i);
+                self.results.push_back((query.name, Err(DnsError::NoServers)));
+            } else {
+                let id = self.new_id();
+                let query = &mut self.queries[i];
+                query.server = self.servers[usize::from(query.attempts) % self.servers.len()];
+                query.attempts += 1;
+                query.timeout = self.retry.next_delay(query.timeout);
+                query.deadline = None;
+                // a new id, so that a late response to the old attempt is ignored
+                query.id = id;
+                if query.tcp {
+                    self.unsent_tcp.push_back(id);
+                } else {
+                    self.unsent.push_back(id);
+                }
+                i += 1;
+            }
+        }
+    }
+
+    /// Returns the result of the next completed query.
+    pub fn poll_result(&mut self) -> Option<(String, Result<Ipv4Address, DnsError>)> {
+        self.results.pop_front()
+    }
+}
+
+/// Strips the dot of a fully qualified name.
+fn without_root(name: &str) -> &str {
+    if name.ends_with('.') {
+        &name[..name.len() - 1]
+    } else {
+        name
+    }
+}
+
+fn normalize(name: &str) -> String {
+    without_root(name).to_ascii_lowercase()
+}
+
+fn qualify(name: &str, domain: &str) -> String {
+    let mut qualified = String::with_capacity(name.len() + 1 + domain.len());
+    qualified.push_str(name);
+    qualified.push('.');
+    qualified.push_str(domain);
+    qualified
+}
+
+#[test]
+fn resolve_and_cache() {
+    use testing::frames;
+
+    let reply = hex!(frames::DNS_REPLY);
+    let server = Ipv4Address::new(192, 168, 1, 1);
+    let backup = Ipv4Address::new(192, 168, 1, 2);
+    let address = Ipv4Address::new(93, 184, 216, 34);
+    let with_id = |id: u16| {
+        let mut data = reply[42..].to_vec();
+        NetworkEndian::write_u16(&mut data[0..2], id);
+        data
+    };
+
+    let mut resolver = DnsResolver::new(0x4242);
+    assert_eq!(resolver.query("example.com", 0), Err(DnsError::NoServers));
+    resolver.set_servers(&[server, backup]);
+    resolver.query("Example.com.", 0).unwrap();
+    let (to, query) = resolver.next_query(0).unwrap();
+    assert_eq!(to, server);
+    assert_eq!(&query[12..], &reply[54..71]); // same question as the reply
+    let id = NetworkEndian::read_u16(&query[0..2]);
+
+    // the second attempt goes to the backup server
+    resolver.poll(INITIAL_QUERY_TIMEOUT);
+    let (to, query) = resolver.next_query(INITIAL_QUERY_TIMEOUT).unwrap();
+    assert_eq!(to, backup);
+    let retry_id = NetworkEndian::read_u16(&query[0..2]);
+    assert!(retry_id != id);
+
+    // the late answer to the first attempt and spoofed answers are ignored
+    resolver.handle_response(server, &with_id(id), 1500);
+    resolver.handle_response(server, &with_id(retry_id), 1500);
+    assert_eq!(resolver.poll_result(), None);
+    resolver.handle_response(backup, &with_id(retry_id), 1500);
+    assert_eq!(resolver.poll_result(), Some((String::from("example.com"), Ok(address))));
+
+    // the TTL of 3600 seconds is respected
+    assert_eq!(resolver.lookup("EXAMPLE.COM", 1500), Some(address));
+    assert_eq!(resolver.lookup("example.com", 1500 + 3_600_000), None);
+    resolver.query("example.com", 2000).unwrap();
+    assert_eq!(resolver.next_query(2000), None);
+    assert_eq!(resolver.poll_result(), Some((String::from("example.com"), Ok(address))));
+
+    // unanswered queries fail after all attempts
+    resolver.query("example.org", 0).unwrap();
+    let mut now = 0;
+    while resolver.poll_result().is_none() {
+        while resolver.next_query(now).is_some() {}
+        now += 1000;
+        resolver.poll(now);
+        assert!(now < 60_000);
+    }
+    assert_eq!(now, 15_000);
+
+    // the servers were removed while a query was outstanding
+    resolver.query("example.net", now).unwrap();
+    resolver.next_query(now).unwrap();
+    resolver.set_servers(&[]);
+    resolver.poll(now + INITIAL_QUERY_TIMEOUT);
+    assert_eq!(resolver.poll_result(),
+               Some((String::from("example.net"), Err(DnsError::NoServers))));
+    resolver.set_servers(&[server, backup]);
+
+    // names without a dot are tried with each search domain
+    let mut list = Vec::new();
+    push_name(&mut list, "lab.example.com").unwrap();
+    list.extend_from_slice(&[4, b't', b'e', b's', b't', 0xc0, 4]);
+    assert_eq!(read_search_list(&list).unwrap(), ["lab.example.com", "test.example.com"]);
+    resolver.set_search_list(&["lab.example.com", "example.com"]);
+    resolver.query("printer", now).unwrap();
+    let (_, mut query) = resolver.next_query(now).unwrap();
+    assert_eq!(&query[13..20], b"printer");
+    assert_eq!(query[21..24], b"lab"[..]);
+    NetworkEndian::write_u16(&mut query[2..4], FLAG_RESPONSE | RCODE_NAME_ERROR);
+    resolver.handle_response(server, &query, now);
+    let (_, query) = resolver.next_query(now).unwrap();
+    assert_eq!(read_name(&query, HEADER_LEN).unwrap().0, "printer.example.com");
+
+    // a qualified name that is too long fails the query
+    let long_label = ["a"; 64].concat();
+    resolver.set_search_list(&["example.com", &long_label]);
+    resolver.query("scanner", now).unwrap();
+    let (_, mut query) = resolver.next_query(now).unwrap();
+    NetworkEndian::write_u16(&mut query[2..4], FLAG_RESPONSE | RCODE_NAME_ERROR);
+    resolver.handle_response(server, &query, now);
+    assert_eq!(resolver.poll_result(),
+               Some((String::from("scanner"), Err(DnsError::Rejected))));
+    assert_eq!(resolver.next_query(now), None);
+}
+
+#[test]
+fn truncated_response() {
+    use testing::frames;
+
+    let server = Ipv4Address::new(192, 168, 1, 1);
+    let mut response = hex!(frames::DNS_REPLY)[42..].to_vec();
+    let mut resolver = DnsResolver::new(0x4242);
+    resolver.set_servers(&[server]);
+    resolver.query("example.com", 0).unwrap();
+    let (_, query) = resolver.next_query(0).unwrap();
+    response[0..2].copy_from_slice(&query[0..2]);
+
+    let flags = NetworkEndian::read_u16(&response[2..4]);
+    let mut truncated = response.clone();
+    NetworkEndian::write_u16(&mut truncated[2..4], flags | FLAG_TRUNCATED);
+    resolver.handle_response(server, &truncated, 100);
+    assert_eq!(resolver.poll_result(), None);
+    assert_eq!(resolver.next_query(100), None);
+    let (to, framed) = resolver.next_tcp_query(100).unwrap();
+    assert_eq!((to, &framed[2..]), (server, &query[..]));
+
+    // the response arrives in two segments
+    let framed = encode_tcp_message(&response).unwrap();
+    let mut stream = DnsTcpStream::new();
+    stream.push(&framed[..10]);
+    assert_eq!(stream.next_message(), None);
+    stream.push(&framed[10..]);
+    resolver.handle_response(server, stream.next_message().unwrap(), 150);
+    assert_eq!(stream.next_message(), None);
+    assert_eq!(resolver.poll_result(),
+               Some((String::from("example.com"), Ok(Ipv4Address::new(93, 184, 216, 34)))));
+}
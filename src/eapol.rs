@@ -0,0 +1,289 @@
+use {TxPacket, WriteOut};
+use byteorder::{ByteOrder, NetworkEndian};
+use ethernet::{EthernetAddress, EthernetPacket};
+
+/// The destination of EAPOL frames sent to the port access entity of the switch.
+pub const PAE_GROUP_ADDRESS: EthernetAddress =
+    EthernetAddress::new([0x01, 0x80, 0xc2, 0x00, 0x00, 0x03]);
+
+/// The protocol version we send (802.1X-2004).
+pub const EAPOL_VERSION: u8 = 2;
+
+pub fn new_start_packet(src_mac: EthernetAddress) -> EthernetPacket<EapolPacket<'static>> {
+    EthernetPacket::new_eapol(src_mac, PAE_GROUP_ADDRESS, EapolPacket::new(EapolBody::Start))
+}
+
+pub fn new_logoff_packet(src_mac: EthernetAddress) -> EthernetPacket<EapolPacket<'static>> {
+    EthernetPacket::new_eapol(src_mac, PAE_GROUP_ADDRESS, EapolPacket::new(EapolBody::Logoff))
+}
+
+pub fn new_identity_response_packet<'a>(src_mac: EthernetAddress,
+                                        identifier: u8,
+                                        identity: &'a [u8])
+                                        -> EthernetPacket<EapolPacket<'a>> {
+    let eap = EapPacket::identity_response(identifier, identity);
+    EthernetPacket::new_eapol(src_mac, PAE_GROUP_ADDRESS, EapolPacket::new(EapolBody::Eap(eap)))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EapolPacket<'a> {
+    pub version: u8,
+    pub body: EapolBody<'a>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EapolBody<'a> {
+    Eap(EapPacket<'a>),
+    Start,
+    Logoff,
+    /// Other packet types (e.g. EAPOL-Key) with their raw body.
+    Unknown(u8, &'a [u8]),
+}
+
+impl<'a> EapolPacket<'a> {
+    pub fn new(body: EapolBody<'a>) -> Self {
+        EapolPacket {
+            version: EAPOL_VERSION,
+            body,
+        }
+    }
+
+    /// Returns the response if this is an EAP Request/Identity.
+    pub fn identity_response<'b>(&self, identity: &'b [u8]) -> Option<EapolPacket<'b>> {
+        match self.body {
+            EapolBody::Eap(EapPacket {
+                               code: EapCode::Request,
+                               identifier,
+                               type_: Some(EapType::Identity),
+                               ..
+                           }) => {
+                let eap = EapPacket::identity_response(identifier, identity);
+                Some(EapolPacket::new(EapolBody::Eap(eap)))
+            }
+            _ => None,
+        }
+    }
+
+    fn packet_type(&self) -> u8 {
+        match self.body {
+            EapolBody::Eap(_) => 0,
+            EapolBody::Start => 1,
+            EapolBody::Logoff => 2,
+            EapolBody::Unknown(packet_type, _) => packet_type,
+        }
+    }
+
+    fn body_len(&self) -> usize {
+        match self.body {
+            EapolBody::Eap(ref eap) => eap.len(),
+            EapolBody::Start | EapolBody::Logoff => 0,
+            EapolBody::Unknown(_, data) => data.len(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EapCode {
+    Request,
+    Response,
+    Success,
+    Failure,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EapType {
+    Identity,
+    Notification,
+    Nak,
+    Md5Challenge,
+    Unknown(u8),
+}
+
+impl EapType {
+    pub fn from_number(number: u8) -> EapType {
+        match number {
+            1 => EapType::Identity,
+            2 => EapType::Notification,
+            3 => EapType::Nak,
+            4 => EapType::Md5Challenge,
+            number => EapType::Unknown(number),
+        }
+    }
+
+    pub fn number(&self) -> u8 {
+        match *self {
+            EapType::Identity => 1,
+            EapType::Notification => 2,
+            EapType::Nak => 3,
+            EapType::Md5Challenge => 4,
+            EapType::Unknown(number) => number,
+        }
+    }
+}
+
+/// An EAP packet according to [RFC 3748][rfc3748]. Only requests and responses have
+/// a type.
+///
+/// [rfc3748]: https://tools.ietf.org/html/rfc3748
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EapPacket<'a> {
+    pub code: EapCode,
+    pub identifier: u8,
+    pub type_: Option<EapType>,
+    pub type_data: &'a [u8],
+}
+
+impl<'a> EapPacket<'a> {
+    pub fn identity_response(identifier: u8, identity: &'a [u8]) -> Self {
+        EapPacket {
+            code: EapCode::Response,
+            identifier,
+            type_: Some(EapType::Identity),
+            type_data: identity,
+        }
+    }
+}
+
+impl<'a> WriteOut for EapPacket<'a> {
+    fn len(&self) -> usize {
+        4 + self.type_.map(|_| 1).unwrap_or(0) + self.type_data.len()
+    }
+
+    fn write_out<P: TxPacket>(&self, packet: &mut P) -> Result<(), ()> {
+        packet.push_byte(match self.code {
+                             EapCode::Request => 1,
+                             EapCode::Response => 2,
+                             EapCode::Success => 3,
+                             EapCode::Failure => 4,
+                         })?;
+        packet.push_byte(self.identifier)?;
+        packet.push_u16(self.len() as u16)?;
+        if let Some(type_) = self.type_ {
+            packet.push_byte(type_.number())?;
+        }
+        packet.push_bytes(self.type_data)?;
+        Ok(())
+    }
+}
+
+impl<'a> WriteOut for EapolPacket<'a> {
+    fn len(&self) -> usize {
+        4 + self.body_len()
+    }
+
+    fn write_out<P: TxPacket>(&self, packet: &mut P) -> Result<(), ()> {
+        packet.push_byte(self.version)?;
+        packet.push_byte(self.packet_type())?;
+        packet.push_u16(self.body_len() as u16)?;
+        match self.body {
+            EapolBody::Eap(ref eap) => eap.write_out(packet),
+            EapolBody::Start | EapolBody::Logoff => Ok(()),
+            EapolBody::Unknown(_, data) => packet.push_bytes(data).map(|_| ()),
+        }
+    }
+}
+
+use parse::{Layer, Parse, ParseError};
+
+impl<'a> Parse<'a> for EapPacket<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
+        if data.len() < 4 {
+            return Err(ParseError::truncated(Layer::Eapol, data.len()));
+        }
+        let code = match data[0] {
+            1 => EapCode::Request,
+            2 => EapCode::Response,
+            3 => EapCode::Success,
+            4 => EapCode::Failure,
+            _ => return Err(ParseError::malformed(Layer::Eapol, 0, "invalid EAP code")),
+        };
+        let len = usize::from(NetworkEndian::read_u16(&data[2..4]));
+        if len < 4 {
+            return Err(ParseError::malformed(Layer::Eapol, 2, "EAP length smaller than header"));
+        }
+        if data.len() < len {
+            return Err(ParseError::truncated(Layer::Eapol, data.len()));
+        }
+
+        let (type_, type_data) = match code {
+            EapCode::Request | EapCode::Response => {
+                if len < 5 {
+                    return Err(ParseError::malformed(Layer::Eapol, 2, "EAP type missing"));
+                }
+                (Some(EapType::from_number(data[4])), &data[5..len])
+            }
+            EapCode::Success | EapCode::Failure => (None, &data[4..len]),
+        };
+
+        Ok(EapPacket {
+               code,
+               identifier: data[1],
+               type_,
+               type_data,
+           })
+    }
+}
+
+impl<'a> Parse<'a> for EapolPacket<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
+        if data.len() < 4 {
+            return Err(ParseError::truncated(Layer::Eapol, data.len()));
+        }
+        let body_len = usize::from(NetworkEndian::read_u16(&data[2..4]));
+        if data.len() < 4 + body_len {
+            return Err(ParseError::truncated(Layer::Eapol, data.len()));
+        }
+        let body = &data[4..(4 + body_len)];
+
+        let body = match data[1] {
+            0 => EapolBody::Eap(EapPacket::parse(body).map_err(|e| e.at(4))?),
+            1 => EapolBody::Start,
+            2 => EapolBody::Logoff,
+            packet_type => EapolBody::Unknown(packet_type, body),
+        };
+
+        Ok(EapolPacket {
+               version: data[0],
+               body,
+           })
+    }
+}
+
+#[test]
+fn start_and_identity_response() {
+    use ethernet::EthernetKind;
+    use parse::parse;
+    use HeapTxPacket;
+
+    let mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+
+    let start = new_start_packet(mac);
+    let mut packet = HeapTxPacket::new(start.len());
+    start.write_out(&mut packet).unwrap();
+    assert_eq!(packet.as_slice(),
+               &[0x01, 0x80, 0xc2, 0x00, 0x00, 0x03, 0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef, 0x88,
+                 0x8e, 0x02, 0x01, 0x00, 0x00]);
+
+    // EAP Request/Identity from the authenticator, padded to 60 bytes
+    let mut request = [0; 60];
+    request[..12].copy_from_slice(&[0x01, 0x80, 0xc2, 0x00, 0x00, 0x03, 0x00, 0x11, 0x22,
+                                    0x33, 0x44, 0x55]);
+    request[12..23].copy_from_slice(&[0x88, 0x8e, 0x01, 0x00, 0x00, 0x05, 0x01, 0x2a, 0x00,
+                                      0x05, 0x01]);
+    let eapol = match parse(&request).unwrap().payload {
+        EthernetKind::Eapol(eapol) => eapol,
+        other => panic!("unexpected packet {:?}", other),
+    };
+    let response = eapol.identity_response(b"device").unwrap();
+
+    let mut packet = HeapTxPacket::new(response.len());
+    response.write_out(&mut packet).unwrap();
+    assert_eq!(packet.as_slice(),
+               &[0x02, 0x00, 0x00, 0x0b, 0x02, 0x2a, 0x00, 0x0b, 0x01, b'd', b'e', b'v', b'i',
+                 b'c', b'e']);
+    assert_eq!(EapolPacket::parse(packet.as_slice()), Ok(response));
+    assert!(response.identity_response(b"device").is_none());
+
+    assert_eq!(EapolPacket::parse(&packet.as_slice()[..14]),
+               Err(ParseError::truncated(Layer::Eapol, 14)));
+}
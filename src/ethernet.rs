@@ -1,7 +1,26 @@
 use {TxPacket, WriteOut};
-use ipv4::Ipv4Packet;
+use ipv4::{Ipv4Address, Ipv4Packet};
+use ipv6::Ipv6Address;
 use arp::ArpPacket;
+use eapol::EapolPacket;
+use ptp::PtpMessage;
 use core::fmt;
+use byteorder::{ByteOrder, LittleEndian};
+
+/// The largest payload of standard Ethernet frames.
+pub const DEFAULT_MTU: usize = 1500;
+
+/// The usual payload limit of jumbo frames.
+pub const JUMBO_MTU: usize = 9000;
+
+/// The header and frame check sequence around the payload of a frame.
+pub const MAX_OVERHEAD: usize = 14 + 4;
+
+/// The prefix of the MAC addresses of IPv4 multicast groups (RFC 1112).
+pub const IPV4_MULTICAST_OUI: [u8; 3] = [0x01, 0x00, 0x5e];
+
+/// The prefix of the MAC addresses of IPv6 multicast groups (RFC 2464).
+pub const IPV6_MULTICAST_PREFIX: [u8; 2] = [0x33, 0x33];
 
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub struct EthernetAddress([u8; 6]);
@@ -22,9 +41,65 @@ impl EthernetAddress {
         Self::new([0xff; 6])
     }
 
+    /// Derives a locally administered unicast address from a device unique ID, e.g.
+    /// the 96-bit serial number of a microcontroller. The same ID always gives the
+    /// same address.
+    pub fn from_unique_id(id: &[u8]) -> Self {
+        // FNV-1a
+        let hash = id.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, &byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+        });
+        let mut addr = [0; 6];
+        for (i, byte) in addr.iter_mut().enumerate() {
+            *byte = (hash >> (8 * i)) as u8;
+        }
+        addr[0] = addr[0] & 0xfc | 0x02;
+        EthernetAddress(addr)
+    }
+
+    /// The multicast address of an IPv4 multicast group, which contains the lower 23
+    /// bits of the group address.
+    pub fn from_ipv4_multicast(group: Ipv4Address) -> Self {
+        let ip = group.as_bytes();
+        let oui = IPV4_MULTICAST_OUI;
+        EthernetAddress([oui[0], oui[1], oui[2], ip[1] & 0x7f, ip[2], ip[3]])
+    }
+
+    /// The multicast address of an IPv6 multicast group, which contains the lower 32
+    /// bits of the group address.
+    pub fn from_ipv6_multicast(group: Ipv6Address) -> Self {
+        let ip = group.as_bytes();
+        let prefix = IPV6_MULTICAST_PREFIX;
+        EthernetAddress([prefix[0], prefix[1], ip[12], ip[13], ip[14], ip[15]])
+    }
+
     pub fn as_bytes(&self) -> [u8; 6] {
         self.0
     }
+
+    pub fn is_broadcast(&self) -> bool {
+        *self == Self::broadcast()
+    }
+
+    /// Returns true for multicast addresses, including the broadcast address.
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 0x01 != 0
+    }
+
+    pub fn is_unicast(&self) -> bool {
+        !self.is_multicast()
+    }
+
+    /// Returns true if the address wasn't assigned by the manufacturer, i.e. the U/L
+    /// bit is set.
+    pub fn is_locally_administered(&self) -> bool {
+        self.0[0] & 0x02 != 0
+    }
+
+    /// The organizationally unique identifier of a universally administered address.
+    pub fn oui(&self) -> [u8; 3] {
+        [self.0[0], self.0[1], self.0[2]]
+    }
 }
 
 impl fmt::Debug for EthernetAddress {
@@ -40,7 +115,7 @@ impl fmt::Debug for EthernetAddress {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct EthernetHeader {
     pub src_addr: EthernetAddress,
     pub dst_addr: EthernetAddress,
@@ -100,63 +175,176 @@ impl EthernetPacket<ArpPacket> {
             payload: arp_data,
         }
     }
+
+    pub fn new_rarp(src_addr: EthernetAddress,
+                    dst_addr: EthernetAddress,
+                    rarp_data: ArpPacket)
+                    -> Self {
+        EthernetPacket {
+            header: EthernetHeader {
+                src_addr: src_addr,
+                dst_addr: dst_addr,
+                ether_type: EtherType::Rarp,
+            },
+            payload: rarp_data,
+        }
+    }
+}
+
+impl<'a> EthernetPacket<EapolPacket<'a>> {
+    pub fn new_eapol(src_addr: EthernetAddress,
+                     dst_addr: EthernetAddress,
+                     eapol_data: EapolPacket<'a>)
+                     -> Self {
+        EthernetPacket {
+            header: EthernetHeader {
+                src_addr: src_addr,
+                dst_addr: dst_addr,
+                ether_type: EtherType::Eapol,
+            },
+            payload: eapol_data,
+        }
+    }
+}
+
+impl EthernetPacket<PtpMessage> {
+    /// Creates a PTP frame, usually to `ptp::PRIMARY_MULTICAST_MAC`.
+    pub fn new_ptp(src_addr: EthernetAddress,
+                   dst_addr: EthernetAddress,
+                   message: PtpMessage)
+                   -> Self {
+        EthernetPacket {
+            header: EthernetHeader {
+                src_addr: src_addr,
+                dst_addr: dst_addr,
+                ether_type: EtherType::Ptp,
+            },
+            payload: message,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum EtherType {
     Ipv4,
     Arp,
+    Rarp,
+    Eapol,
+    Ptp,
     Unknown(u16),
 }
 
 impl EtherType {
+    pub fn from_number(number: u16) -> EtherType {
+        use self::EtherType::*;
+
+        match number {
+            0x0800 => Ipv4,
+            0x0806 => Arp,
+            0x8035 => Rarp,
+            0x888e => Eapol,
+            0x88f7 => Ptp,
+            number => Unknown(number),
+        }
+    }
+
     pub fn number(&self) -> u16 {
         use self::EtherType::*;
 
         match *self {
             Ipv4 => 0x0800,
             Arp => 0x0806,
+            Rarp => 0x8035,
+            Eapol => 0x888e,
+            Ptp => 0x88f7,
             Unknown(number) => number,
         }
     }
 }
 
+/// The minimum frame length, without the frame check sequence.
+pub const MIN_FRAME_LEN: usize = 60;
+
+impl<T: WriteOut> EthernetPacket<T> {
+    fn unpadded_len(&self) -> usize {
+        self.payload.len() + 2 * 6 + 2
+    }
+}
+
 impl<T: WriteOut> WriteOut for EthernetPacket<T> {
+    #[cfg(not(feature = "fcs"))]
     fn len(&self) -> usize {
-        self.payload.len() + 2 * 6 + 2
+        self.unpadded_len()
+    }
+
+    /// With the frame check sequence, frames are padded to the minimum length.
+    #[cfg(feature = "fcs")]
+    fn len(&self) -> usize {
+        usize::max(self.unpadded_len(), MIN_FRAME_LEN) + 4
     }
 
     fn write_out<P: TxPacket>(&self, packet: &mut P) -> Result<(), ()> {
+        let start_index = packet.len();
+
         packet.push_bytes(&self.header.dst_addr.as_bytes())?;
         packet.push_bytes(&self.header.src_addr.as_bytes())?;
         packet.push_u16(self.header.ether_type.number())?;
 
+        let payload_start = packet.len();
         self.payload.write_out(packet)?;
+        let payload_end = packet.len();
+        packet.mark_payload(payload_start..payload_end);
+
+        if cfg!(feature = "fcs") {
+            write_fcs(packet, start_index)?;
+        }
 
         Ok(())
     }
 }
 
-use parse::{Parse, ParseError};
+/// Pads the frame starting at `start_index` and appends its frame check sequence.
+fn write_fcs<P: TxPacket>(packet: &mut P, start_index: usize) -> Result<(), ()> {
+    use crc32::Crc32;
+
+    let len = packet.len() - start_index;
+    if len < MIN_FRAME_LEN {
+        packet.push_bytes(&[0; MIN_FRAME_LEN][len..])?;
+    }
+
+    // read back in small chunks, so that packets in external memory work too
+    let mut crc = Crc32::new();
+    let mut index = start_index;
+    while index < packet.len() {
+        let chunk_len = usize::min(32, packet.len() - index);
+        crc.update(packet.get_bytes(index, chunk_len));
+        index += chunk_len;
+    }
+
+    let mut fcs = [0; 4];
+    LittleEndian::write_u32(&mut fcs, crc.finish()); // sent least significant byte first
+    packet.push_bytes(&fcs)?;
+    Ok(())
+}
+
+use parse::{Layer, Parse, ParseError};
 use ipv4::Ipv4Kind;
 
 impl<'a> Parse<'a> for EthernetPacket<&'a [u8]> {
     fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
         use byteorder::{ByteOrder, NetworkEndian};
 
-        if data.len() < 60 {
-            return Err(ParseError::Truncated(data.len()));
+        // frames without padding, e.g. from a loopback device or a trace, are accepted,
+        // the inner protocols check their own lengths
+        if data.len() < 14 {
+            return Err(ParseError::truncated(Layer::Ethernet, data.len()));
         }
 
         let dst_mac = EthernetAddress::from_bytes(&data[0..6]);
         let src_mac = EthernetAddress::from_bytes(&data[6..12]);
-        let ether_type = match NetworkEndian::read_u16(&data[12..14]) {
-            0x0800 => EtherType::Ipv4,
-            0x0806 => EtherType::Arp,
-            other => EtherType::Unknown(other),
-        };
+        let ether_type = EtherType::from_number(NetworkEndian::read_u16(&data[12..14]));
 
-        Ok(EthernetPacket::new(dst_mac, src_mac, ether_type, &data[14..]))
+        Ok(EthernetPacket::new(src_mac, dst_mac, ether_type, &data[14..]))
     }
 }
 
@@ -164,6 +352,9 @@ impl<'a> Parse<'a> for EthernetPacket<&'a [u8]> {
 pub enum EthernetKind<'a> {
     Ipv4(Ipv4Packet<Ipv4Kind<'a>>),
     Arp(ArpPacket),
+    Rarp(ArpPacket),
+    Eapol(EapolPacket<'a>),
+    Ptp(PtpMessage),
     Unknown(&'a [u8]),
 }
 
@@ -172,22 +363,124 @@ impl<'a> Parse<'a> for EthernetPacket<EthernetKind<'a>> {
         let ethernet = EthernetPacket::parse(data)?;
         match ethernet.header.ether_type {
             EtherType::Ipv4 => {
-                let ipv4 = Ipv4Packet::parse(ethernet.payload)?;
+                let ipv4 = Ipv4Packet::parse(ethernet.payload).map_err(|e| e.at(14))?;
                 Ok(EthernetPacket {
                        header: ethernet.header,
                        payload: EthernetKind::Ipv4(ipv4),
                    })
             }
             EtherType::Arp => {
-                let arp = ArpPacket::parse(ethernet.payload)?;
+                let arp = ArpPacket::parse(ethernet.payload).map_err(|e| e.at(14))?;
                 Ok(EthernetPacket {
                        header: ethernet.header,
                        payload: EthernetKind::Arp(arp),
                    })
             }
+            EtherType::Rarp => {
+                let rarp = ArpPacket::parse(ethernet.payload).map_err(|e| e.at(14))?;
+                Ok(EthernetPacket {
+                       header: ethernet.header,
+                       payload: EthernetKind::Rarp(rarp),
+                   })
+            }
+            EtherType::Eapol => {
+                let eapol = EapolPacket::parse(ethernet.payload).map_err(|e| e.at(14))?;
+                Ok(EthernetPacket {
+                       header: ethernet.header,
+                       payload: EthernetKind::Eapol(eapol),
+                   })
+            }
+            EtherType::Ptp => {
+                let ptp = PtpMessage::parse(ethernet.payload).map_err(|e| e.at(14))?;
+                Ok(EthernetPacket {
+                       header: ethernet.header,
+                       payload: EthernetKind::Ptp(ptp),
+                   })
+            }
             EtherType::Unknown(_) => {
-                Err(ParseError::Unimplemented("only ipv4 parsing is supported at the moment"))
+                Err(ParseError::unimplemented(Layer::Ethernet,
+                                              12,
+                                              "unsupported EtherType"))
             }
         }
     }
 }
+
+#[test]
+fn frame_check_sequence() {
+    use arp;
+    use ipv4::Ipv4Address;
+    use HeapTxPacket;
+
+    let mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let ip = Ipv4Address::new(192, 168, 1, 2);
+    let request = arp::new_request_packet(mac, ip, ip);
+
+    let mut packet = HeapTxPacket::new(64);
+    request.write_out(&mut packet).unwrap();
+    write_fcs(&mut packet, 0).unwrap();
+    assert_eq!(packet.len(), 64);
+
+    // the CRC over a frame including its FCS is the constant residue
+    assert_eq!(::crc32::crc32(&packet), 0x2144df1c);
+}
+
+#[test]
+fn unpadded_frames() {
+    use arp;
+    use ipv4::Ipv4Address;
+    use {parse, HeapTxPacket};
+
+    let mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let ip = Ipv4Address::new(192, 168, 1, 2);
+    let request = arp::new_request_packet(mac, ip, ip);
+    let mut packet = HeapTxPacket::new(request.len());
+    request.write_out(&mut packet).unwrap();
+    let frame = &packet.as_slice()[..request.unpadded_len()];
+    assert_eq!(frame.len(), 42);
+
+    match parse(frame).unwrap().payload {
+        EthernetKind::Arp(arp) => assert_eq!(arp.src_mac, mac),
+        other => panic!("unexpected payload {:?}", other),
+    }
+    // truncated frames are still rejected by the layer that is cut off
+    assert_eq!(parse(&frame[..41]).unwrap_err().layer(), Layer::Arp);
+    assert_eq!(parse(&frame[..13]).unwrap_err().layer(), Layer::Ethernet);
+}
+
+#[test]
+fn parse_addresses() {
+    use HeapTxPacket;
+
+    let src = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let dst = EthernetAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    let frame = EthernetPacket::new(src, dst, EtherType::Unknown(0x88b5), &[0; 46][..]);
+    let frame = HeapTxPacket::write_out(frame).unwrap();
+    assert_eq!(&frame.as_slice()[..12], &[0x00, 0x11, 0x22, 0x33, 0x44, 0x55,
+                                          0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+
+    let packet = EthernetPacket::<&[u8]>::parse(frame.as_slice()).unwrap();
+    assert_eq!(packet.header.src_addr, src);
+    assert_eq!(packet.header.dst_addr, dst);
+}
+
+#[test]
+fn address_kinds() {
+    let mac = EthernetAddress::from_unique_id(&[0x1f, 0x00, 0x33, 0x00, 0x0d, 0x51, 0x35, 0x32,
+                                                0x31, 0x38, 0x33, 0x34]);
+    assert!(mac.is_unicast() && mac.is_locally_administered());
+    assert!(mac != EthernetAddress::from_unique_id(&[0x1f, 0x00, 0x33]));
+    assert!(!EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]).is_locally_administered());
+
+    let mdns = EthernetAddress::from_ipv4_multicast(Ipv4Address::new(224, 0, 0, 251));
+    assert_eq!(mdns, EthernetAddress::new([0x01, 0x00, 0x5e, 0x00, 0x00, 0xfb]));
+    assert_eq!(EthernetAddress::from_ipv4_multicast(Ipv4Address::new(239, 255, 1, 2)).oui(),
+               IPV4_MULTICAST_OUI);
+    let mut all_nodes = [0; 16];
+    all_nodes[0] = 0xff;
+    all_nodes[1] = 0x02;
+    all_nodes[15] = 0x01;
+    assert_eq!(EthernetAddress::from_ipv6_multicast(Ipv6Address::new(all_nodes)),
+               EthernetAddress::new([0x33, 0x33, 0x00, 0x00, 0x00, 0x01]));
+    assert!(mdns.is_multicast() && !mdns.is_unicast());
+}
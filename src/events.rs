@@ -0,0 +1,92 @@
+//! Stack events for applications that poll in a superloop instead of registering
+//! callbacks, see `Interface::enable_events`.
+
+use alloc::VecDeque;
+use ethernet::EthernetAddress;
+use ipv4::{ConnectionId, Ipv4Address};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackEvent {
+    /// A DHCP client obtained a lease. The interface doesn't run DHCP itself, so the
+    /// client reports it with `Interface::push_event`.
+    DhcpBound(Ipv4Address),
+    /// Another host sent an ARP packet from our address.
+    ArpConflict { mac: EthernetAddress },
+    /// The address probed by `Interface::probe_address` isn't used by another host
+    /// and was configured.
+    AddressAvailable(Ipv4Address),
+    /// The address probed or defended by `Interface::probe_address` is used by the
+    /// host `mac`, so it was given up. A DHCP client declines it with
+    /// `dhcp::new_decline_msg` and acquires another one.
+    AddressConflict { ip: Ipv4Address, mac: EthernetAddress },
+    /// A TCP connection completed the handshake.
+    TcpConnected(ConnectionId),
+    /// A connection that was reported by `TcpConnected` was closed, reset, or
+    /// aborted.
+    TcpClosed(ConnectionId),
+    LinkChanged { up: bool },
+}
+
+/// A ring buffer of events. When it is full, the oldest event is overwritten.
+#[derive(Debug, Clone)]
+pub struct EventQueue {
+    events: VecDeque<StackEvent>,
+    capacity: usize,
+    lost: u32,
+}
+
+impl EventQueue {
+    /// A capacity of 0 drops all events.
+    pub fn new(capacity: usize) -> EventQueue {
+        EventQueue {
+            events: VecDeque::with_capacity(capacity),
+            capacity,
+            lost: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn push(&mut self, event: StackEvent) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+            self.lost = self.lost.wrapping_add(1);
+        }
+        self.events.push_back(event);
+    }
+
+    /// Returns the oldest event.
+    pub fn pop(&mut self) -> Option<StackEvent> {
+        self.events.pop_front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// The number of events that were overwritten before they were popped.
+    pub fn lost(&self) -> u32 {
+        self.lost
+    }
+}
+
+#[test]
+fn overwrites_oldest() {
+    let mut queue = EventQueue::new(2);
+    for &up in &[false, true, false] {
+        queue.push(StackEvent::LinkChanged { up });
+    }
+    assert_eq!(queue.lost(), 1);
+    assert_eq!(queue.pop(), Some(StackEvent::LinkChanged { up: true }));
+    assert_eq!(queue.pop(), Some(StackEvent::LinkChanged { up: false }));
+    assert_eq!(queue.pop(), None);
+}
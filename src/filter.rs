@@ -0,0 +1,204 @@
+use byteorder::{ByteOrder, NetworkEndian};
+use crc32::crc32;
+use ethernet::{EthernetAddress, EtherType};
+use ipv4::IpProtocol;
+use parse::Layer;
+
+/// The CRC-32 of a MAC address, from which the hash filters of most Ethernet
+/// controllers take the bits that select a bin. Which bits, and whether they are
+/// reversed, depends on the controller.
+pub fn multicast_hash(mac: EthernetAddress) -> u32 {
+    crc32(&mac.as_bytes())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    Drop,
+}
+
+/// Cheap accept/drop decisions on raw frames, meant to be run before `parse`.
+///
+/// Only the header fields needed for the configured rules are read, so rejected
+/// frames can be discarded directly in the receive interrupt. A `None` list accepts
+/// any value.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameFilter<'a> {
+    /// Accept frames regardless of their destination MAC.
+    pub promiscuous: bool,
+    pub mac: EthernetAddress,
+    pub accept_broadcast: bool,
+    pub accept_all_multicast: bool,
+    /// Multicast addresses that are accepted if `accept_all_multicast` is false.
+    pub multicast: &'a [EthernetAddress],
+    pub ether_types: Option<&'a [EtherType]>,
+    /// Accepted protocols of IPv4 packets.
+    pub ip_protocols: Option<&'a [IpProtocol]>,
+    /// Accepted destination ports of UDP and TCP packets.
+    pub ports: Option<&'a [u16]>,
+    /// Accepted layers above Ethernet and, for IPv4 packets, above IPv4. Frames of
+    /// unknown protocols are dropped.
+    pub layers: Option<&'a [Layer]>,
+}
+
+impl<'a> FrameFilter<'a> {
+    /// Creates a filter that accepts frames for `mac` and broadcast frames.
+    pub fn new(mac: EthernetAddress) -> Self {
+        FrameFilter {
+            promiscuous: false,
+            mac,
+            accept_broadcast: true,
+            accept_all_multicast: false,
+            multicast: &[],
+            ether_types: None,
+            ip_protocols: None,
+            ports: None,
+            layers: None,
+        }
+    }
+
+    pub fn check(&self, frame: &[u8]) -> Verdict {
+        if self.accepts(frame) {
+            Verdict::Accept
+        } else {
+            Verdict::Drop
+        }
+    }
+
+    fn accepts(&self, frame: &[u8]) -> bool {
+        if frame.len() < 14 {
+            return false;
+        }
+
+        let dst_mac = EthernetAddress::from_bytes(&frame[0..6]);
+        if !self.promiscuous && !self.accepts_mac(dst_mac) {
+            return false;
+        }
+
+        let ether_type = EtherType::from_number(NetworkEndian::read_u16(&frame[12..14]));
+        if let Some(ether_types) = self.ether_types {
+            if !ether_types.contains(&ether_type) {
+                return false;
+            }
+        }
+        if !self.accepts_layer(Layer::from_ether_type(ether_type)) {
+            return false;
+        }
+        if ether_type != EtherType::Ipv4 ||
+           (self.ip_protocols.is_none() && self.ports.is_none() && self.layers.is_none()) {
+            return true;
+        }
+
+        let ip = &frame[14..];
+        if ip.len() < 20 {
+            return false;
+        }
+        let protocol = IpProtocol::from_number(ip[9]);
+        if let Some(ip_protocols) = self.ip_protocols {
+            if !ip_protocols.contains(&protocol) {
+                return false;
+            }
+        }
+        if !self.accepts_layer(Layer::from_ip_protocol(protocol)) {
+            return false;
+        }
+
+        match (self.ports, protocol) {
+            (Some(ports), IpProtocol::Udp) |
+            (Some(ports), IpProtocol::Tcp) => {
+                let fragment_offset = NetworkEndian::read_u16(&ip[6..8]) & 0x1fff;
+                if fragment_offset != 0 {
+                    // later fragments carry no transport header, so we can't decide
+                    return true;
+                }
+                let header_len = usize::from(ip[0] & 0xf) * 4;
+                if ip.len() < header_len + 4 {
+                    return false;
+                }
+                let dst_port = NetworkEndian::read_u16(&ip[(header_len + 2)..(header_len + 4)]);
+                ports.contains(&dst_port)
+            }
+            _ => true,
+        }
+    }
+
+    fn accepts_layer(&self, layer: Option<Layer>) -> bool {
+        match (self.layers, layer) {
+            (None, _) => true,
+            (Some(layers), Some(layer)) => layers.contains(&layer),
+            (Some(_), None) => false,
+        }
+    }
+
+    fn accepts_mac(&self, mac: EthernetAddress) -> bool {
+        if mac == self.mac {
+            true
+        } else if mac.is_broadcast() {
+            self.accept_broadcast
+        } else if mac.is_multicast() {
+            self.accept_all_multicast || self.multicast.contains(&mac)
+        } else {
+            false
+        }
+    }
+}
+
+#[test]
+fn filter_frames() {
+    use arp;
+    use dhcp;
+    use ipv4::Ipv4Address;
+    use udp;
+    use {HeapTxPacket, WriteOut};
+
+    let mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let other_mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0x11, 0x22, 0x33]);
+    let multicast_mac = EthernetAddress::new([0x01, 0x00, 0x5e, 0x00, 0x00, 0xfb]);
+    let ip = Ipv4Address::new(192, 168, 1, 2);
+
+    fn serialize<T: WriteOut>(packet: T) -> HeapTxPacket {
+        let mut tx_packet = HeapTxPacket::new(packet.len());
+        packet.write_out(&mut tx_packet).unwrap();
+        tx_packet
+    }
+
+    let arp = serialize(arp::new_request_packet(other_mac, ip, ip));
+    let dhcp = serialize(dhcp::new_discover_msg(other_mac));
+    let unicast_udp = serialize(udp::new_udp_packet(other_mac, mac, ip, ip, 1000, 7, &[1, 2][..]));
+    let foreign_udp = serialize(udp::new_udp_packet(mac, other_mac, ip, ip, 1000, 7, &[1][..]));
+    let multicast_udp =
+        serialize(udp::new_udp_packet(other_mac, multicast_mac, ip, ip, 5353, 5353, &[1][..]));
+
+    let mut filter = FrameFilter::new(mac);
+    assert_eq!(filter.check(&arp), Verdict::Accept);
+    assert_eq!(filter.check(&dhcp), Verdict::Accept);
+    assert_eq!(filter.check(&unicast_udp), Verdict::Accept);
+    assert_eq!(filter.check(&foreign_udp), Verdict::Drop);
+    assert_eq!(filter.check(&multicast_udp), Verdict::Drop);
+    assert_eq!(filter.check(&[0; 10]), Verdict::Drop);
+
+    let multicast = [multicast_mac];
+    filter.multicast = &multicast;
+    assert_eq!(filter.check(&multicast_udp), Verdict::Accept);
+
+    let ports = [68, 5353];
+    filter.ports = Some(&ports);
+    assert_eq!(filter.check(&dhcp), Verdict::Drop);
+    assert_eq!(filter.check(&unicast_udp), Verdict::Drop);
+    assert_eq!(filter.check(&multicast_udp), Verdict::Accept);
+    assert_eq!(filter.check(&arp), Verdict::Accept);
+
+    let layers = [Layer::Ipv4, Layer::Udp];
+    filter.layers = Some(&layers);
+    assert_eq!(filter.check(&arp), Verdict::Drop);
+    assert_eq!(filter.check(&multicast_udp), Verdict::Accept);
+    filter.layers = None;
+
+    let ether_types = [EtherType::Ipv4];
+    filter.ether_types = Some(&ether_types);
+    assert_eq!(filter.check(&arp), Verdict::Drop);
+
+    filter.promiscuous = true;
+    filter.ports = None;
+    assert_eq!(filter.check(&foreign_udp), Verdict::Accept);
+}
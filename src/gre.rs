@@ -0,0 +1,156 @@
+use {TxPacket, WriteOut};
+use byteorder::{ByteOrder, NetworkEndian};
+use ethernet::{EthernetAddress, EthernetPacket, EtherType};
+use ip_checksum::{self, ChecksummedTxPacket};
+use ipv4::{Ipv4Address, Ipv4Packet};
+
+/// Encapsulates `inner` in a GRE tunnel packet from `tunnel_src` to `tunnel_dst`.
+pub fn new_gre_tunnel_packet<T>(src_mac: EthernetAddress,
+                                dst_mac: EthernetAddress,
+                                tunnel_src: Ipv4Address,
+                                tunnel_dst: Ipv4Address,
+                                inner: Ipv4Packet<T>)
+                                -> EthernetPacket<Ipv4Packet<GrePacket<Ipv4Packet<T>>>> {
+    EthernetPacket::new_ipv4(src_mac,
+                             dst_mac,
+                             Ipv4Packet::new_gre(tunnel_src, tunnel_dst, GrePacket::new_ipv4(inner)))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GreHeader {
+    /// Whether the checksum field is present.
+    pub checksum: bool,
+    pub protocol_type: EtherType,
+}
+
+/// A plain GRE packet according to [RFC 2784][rfc2784].
+///
+/// [rfc2784]: https://tools.ietf.org/html/rfc2784
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GrePacket<T> {
+    pub header: GreHeader,
+    pub payload: T,
+}
+
+impl<T> GrePacket<Ipv4Packet<T>> {
+    pub fn new_ipv4(ip: Ipv4Packet<T>) -> Self {
+        GrePacket {
+            header: GreHeader {
+                checksum: false,
+                protocol_type: EtherType::Ipv4,
+            },
+            payload: ip,
+        }
+    }
+}
+
+impl<T> GrePacket<T> {
+    fn header_len(&self) -> usize {
+        if self.header.checksum { 8 } else { 4 }
+    }
+}
+
+impl<T: WriteOut> WriteOut for GrePacket<T> {
+    fn len(&self) -> usize {
+        self.header_len() + self.payload.len()
+    }
+
+    fn write_out<P: TxPacket>(&self, packet: &mut P) -> Result<(), ()> {
+        let mut packet = ChecksummedTxPacket::new(packet);
+
+        packet.push_u16(if self.header.checksum { 1 << 15 } else { 0 })?; // flags and version
+        packet.push_u16(self.header.protocol_type.number())?;
+        let checksum_idx = if self.header.checksum {
+            let idx = packet.push_u16(0)?; // checksum
+            packet.push_u16(0)?; // reserved
+            Some(idx)
+        } else {
+            None
+        };
+
+        let payload_start = packet.len();
+        self.payload.write_out(&mut packet)?;
+        let payload_end = packet.len();
+        packet.mark_payload(payload_start..payload_end);
+
+        if let Some(checksum_idx) = checksum_idx {
+            let checksum = !packet.checksum();
+            packet.set_u16(checksum_idx, checksum);
+        }
+
+        Ok(())
+    }
+}
+
+use parse::{Layer, Parse, ParseError};
+
+impl<'a> Parse<'a> for GrePacket<&'a [u8]> {
+    fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
+        if data.len() < 4 {
+            return Err(ParseError::truncated(Layer::Gre, data.len()));
+        }
+        let flags = NetworkEndian::read_u16(&data[0..2]);
+        if flags & 0x7 != 0 {
+            return Err(ParseError::malformed(Layer::Gre, 1, "unsupported GRE version"));
+        }
+        if flags & 0x7ff8 != 0 {
+            return Err(ParseError::unimplemented(Layer::Gre,
+                                                 0,
+                                                 "GRE routing, key, and sequence number fields"));
+        }
+
+        let checksum = flags & (1 << 15) != 0;
+        let header_len = if checksum { 8 } else { 4 };
+        if data.len() < header_len {
+            return Err(ParseError::truncated(Layer::Gre, data.len()));
+        }
+        if checksum && ip_checksum::data(data) != 0xffff {
+            return Err(ParseError::checksum(Layer::Gre, 4));
+        }
+
+        Ok(GrePacket {
+               header: GreHeader {
+                   checksum,
+                   protocol_type: EtherType::from_number(NetworkEndian::read_u16(&data[2..4])),
+               },
+               payload: &data[header_len..],
+           })
+    }
+}
+
+#[test]
+fn tunnel_round_trip() {
+    use ipv4::{IpProtocol, Ipv4Kind};
+    use parse::parse;
+    use ethernet::EthernetKind;
+    use HeapTxPacket;
+
+    let mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let inner = Ipv4Packet::new_raw(Ipv4Address::new(10, 0, 0, 1),
+                                    Ipv4Address::new(10, 0, 0, 2),
+                                    IpProtocol::Unknown(253),
+                                    &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13][..]);
+    let mut tunnel = new_gre_tunnel_packet(mac,
+                                           mac,
+                                           Ipv4Address::new(192, 168, 1, 2),
+                                           Ipv4Address::new(192, 168, 1, 1),
+                                           inner);
+    tunnel.payload.payload.header.checksum = true;
+
+    let mut packet = HeapTxPacket::new(tunnel.len());
+    tunnel.write_out(&mut packet).unwrap();
+    assert_eq!(&packet[34..38], &[0x80, 0x00, 0x08, 0x00]);
+
+    let gre = match parse(&packet).unwrap().payload {
+        EthernetKind::Ipv4(Ipv4Packet { payload: Ipv4Kind::Gre(gre), .. }) => gre,
+        other => panic!("unexpected packet {:?}", other),
+    };
+    assert_eq!(gre.header, tunnel.payload.payload.header);
+
+    let inner = Ipv4Packet::<Ipv4Kind>::parse(gre.payload).unwrap();
+    assert_eq!(inner.header.src_addr, Ipv4Address::new(10, 0, 0, 1));
+    match inner.payload {
+        Ipv4Kind::Unknown(253, data) => assert_eq!(data.len(), 13),
+        other => panic!("unexpected inner packet {:?}", other),
+    }
+}
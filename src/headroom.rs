@@ -0,0 +1,203 @@
+//! Writing the payload of a frame before its headers.
+//!
+//! The application fills the payload buffer behind a reserved headroom, e.g. while
+//! the destination is still resolved, and the headers are written into the headroom
+//! afterwards. Like the pbufs of lwIP, this avoids copying the payload when the
+//! header sizes are only known late.
+
+use core::ops::Range;
+use {TxPacket, WriteError, WriteOut};
+use scatter_gather::ExternalPayload;
+
+/// Enough headroom for Ethernet, IPv4 and TCP headers with the maximum options.
+pub const MAX_HEADERS_LEN: usize = 14 + 60 + 60;
+
+/// A frame buffer that reserves `headroom` bytes in front of the payload.
+pub struct HeadroomBuffer<B> {
+    buf: B,
+    headroom: usize,
+    payload_len: usize,
+    payload_range: Option<Range<usize>>,
+}
+
+impl<B: AsMut<[u8]>> HeadroomBuffer<B> {
+    /// Fails if `buf` is shorter than `headroom`.
+    pub fn new(mut buf: B, headroom: usize) -> Result<Self, ()> {
+        if headroom > buf.as_mut().len() {
+            return Err(());
+        }
+        Ok(HeadroomBuffer {
+               buf,
+               headroom,
+               payload_len: 0,
+               payload_range: None,
+           })
+    }
+
+    pub fn headroom(&self) -> usize {
+        self.headroom
+    }
+
+    /// The space behind the headroom. Call `set_payload_len` after writing to it.
+    pub fn payload_buffer(&mut self) -> &mut [u8] {
+        &mut self.buf.as_mut()[self.headroom..]
+    }
+
+    /// Fails if `len` exceeds the payload buffer.
+    pub fn set_payload_len(&mut self, len: usize) -> Result<(), ()> {
+        if self.headroom + len > self.buf.as_mut().len() {
+            return Err(());
+        }
+        self.payload_len = len;
+        Ok(())
+    }
+
+    /// A placeholder for the written payload, to be used as the innermost payload of
+    /// the packet passed to `prepend`.
+    pub fn payload(&mut self) -> ExternalPayload {
+        let start = self.headroom;
+        ExternalPayload::new(&self.buf.as_mut()[start..(start + self.payload_len)])
+    }
+
+    /// The range of the payload of the innermost layer in the frame returned by the
+    /// last `prepend`.
+    pub fn payload_range(&self) -> Option<Range<usize>> {
+        self.payload_range.clone()
+    }
+
+    /// Writes the headers of `packet` into the headroom and its trailers, like the
+    /// frame check sequence, behind the payload. Returns the complete frame.
+    ///
+    /// Fails if the headers don't fit into the headroom or if the innermost payload of
+    /// `packet` isn't `payload()`.
+    pub fn prepend<T: WriteOut>(&mut self, packet: &T) -> Result<&[u8], WriteError> {
+        let needed = packet.required_capacity();
+        if needed > self.buf.as_mut().len() {
+            return Err(WriteError::BufferFull { needed });
+        }
+
+        self.payload_range = None;
+        let mut tx_packet = HeadroomTxPacket {
+            buf: self.buf.as_mut(),
+            headroom: self.headroom,
+            payload_len: self.payload_len,
+            header_len: None,
+            len: 0,
+            payload_range: None,
+        };
+        packet.write_out(&mut tx_packet).map_err(|()| WriteError::Failed)?;
+        match tx_packet.header_len {
+            Some(header_len) => {
+                let start = tx_packet.headroom - header_len;
+                self.payload_range = tx_packet.payload_range;
+                Ok(&tx_packet.buf[start..(start + tx_packet.len)])
+            }
+            None => Err(WriteError::Failed),
+        }
+    }
+}
+
+/// Writes the headers to the start of the buffer until the external payload is
+/// pushed, and then moves them in front of the payload, so that the frame is
+/// contiguous for the headers and trailers that are written afterwards.
+struct HeadroomTxPacket<'a> {
+    buf: &'a mut [u8],
+    headroom: usize,
+    payload_len: usize,
+    /// Set when the payload was pushed.
+    header_len: Option<usize>,
+    len: usize,
+    payload_range: Option<Range<usize>>,
+}
+
+impl<'a> HeadroomTxPacket<'a> {
+    /// The buffer position of the written byte `index`.
+    fn position(&self, index: usize) -> usize {
+        match self.header_len {
+            Some(header_len) => self.headroom - header_len + index,
+            None => index,
+        }
+    }
+}
+
+impl<'a> TxPacket for HeadroomTxPacket<'a> {
+    fn push_bytes(&mut self, bytes: &[u8]) -> Result<usize, ()> {
+        let index = self.len;
+        let start = self.position(index);
+        let limit = match self.header_len {
+            Some(_) => self.buf.len(),
+            None => self.headroom,
+        };
+        if start + bytes.len() > limit {
+            return Err(());
+        }
+        self.buf[start..(start + bytes.len())].copy_from_slice(bytes);
+        self.len += bytes.len();
+        Ok(index)
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn push_external(&mut self, len: usize, _checksum: u16) -> Result<usize, ()> {
+        if self.header_len.is_some() || len != self.payload_len {
+            return Err(());
+        }
+        let index = self.len;
+        for i in (0..index).rev() {
+            self.buf[self.headroom - index + i] = self.buf[i];
+        }
+        self.header_len = Some(index);
+        self.len += len;
+        Ok(index)
+    }
+
+    fn get_bytes(&mut self, index: usize, len: usize) -> &[u8] {
+        let start = self.position(index);
+        &self.buf[start..(start + len)]
+    }
+
+    fn set_bytes(&mut self, index: usize, bytes: &[u8]) {
+        let start = self.position(index);
+        self.buf[start..(start + bytes.len())].copy_from_slice(bytes);
+    }
+
+    fn mark_payload(&mut self, range: Range<usize>) {
+        if self.payload_range.is_none() {
+            self.payload_range = Some(range);
+        }
+    }
+}
+
+#[test]
+fn prepend_headers() {
+    use ethernet::EthernetAddress;
+    use ipv4::Ipv4Address;
+    use udp;
+    use HeapTxPacket;
+
+    let mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let ip = Ipv4Address::new(192, 168, 1, 2);
+    let data = b"hello";
+
+    let copied = udp::new_udp_packet(mac, mac, ip, ip, 1000, 7, &data[..]);
+    let mut reference = HeapTxPacket::new(copied.len());
+    copied.write_out(&mut reference).unwrap();
+
+    let mut buffer = HeadroomBuffer::new([0; 128], MAX_HEADERS_LEN - 60).unwrap();
+    buffer.payload_buffer()[..data.len()].copy_from_slice(data);
+    buffer.set_payload_len(data.len()).unwrap();
+    let payload = buffer.payload();
+    let packet = udp::new_udp_packet(mac, mac, ip, ip, 1000, 7, payload);
+    assert_eq!(buffer.prepend(&packet).unwrap(), reference.as_slice());
+    assert_eq!(buffer.payload_range(), reference.payload_range());
+
+    // the headers don't fit into the headroom
+    let mut buffer = HeadroomBuffer::new([0; 128], 20).unwrap();
+    buffer.set_payload_len(data.len()).unwrap();
+    let packet = udp::new_udp_packet(mac, mac, ip, ip, 1000, 7, buffer.payload());
+    assert_eq!(buffer.prepend(&packet), Err(WriteError::Failed));
+    assert_eq!(buffer.set_payload_len(109).err(), Some(()));
+    assert!(HeadroomBuffer::new([0; 16], 20).is_err());
+}
@@ -0,0 +1,396 @@
+//! A minimal HTTP/1.1 server side for device configuration pages.
+//!
+//! `Request` parses a request from the received data of a connection, e.g. the
+//! receive buffer of a `TcpConnection` (see `peek`), which keeps segments together.
+//! Responses implement `WriteOut`, so they can be written into any `TxPacket` and
+//! passed to `TcpConnection::send`. Either a whole `Response` is sent, or a
+//! `ResponseHead` followed by the body in pieces, which doesn't need a buffer for the
+//! whole body.
+
+use {TxPacket, WriteOut};
+use core::str;
+use parse::{Layer, Parse, ParseError};
+
+pub const HTTP_PORT: u16 = 80;
+
+/// The terminating chunk of a chunked body, without trailers.
+pub const LAST_CHUNK: &[u8] = b"0\r\n\r\n";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method<'a> {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Other(&'a str),
+}
+
+impl<'a> Method<'a> {
+    fn from_str(method: &'a str) -> Method<'a> {
+        match method {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            other => Method::Other(other),
+        }
+    }
+}
+
+/// A complete request. Bodies are only supported with a `Content-Length`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Request<'a> {
+    pub method: Method<'a>,
+    /// The request target, including the query.
+    pub target: &'a str,
+    /// 0 for HTTP/1.0, 1 for HTTP/1.1.
+    pub minor_version: u8,
+    pub headers: Headers<'a>,
+    /// The length of the request line and the headers, including the empty line.
+    pub head_len: usize,
+    pub body: &'a [u8],
+}
+
+impl<'a> Request<'a> {
+    pub fn path(&self) -> &'a str {
+        self.target.split('?').next().unwrap_or("")
+    }
+
+    pub fn query(&self) -> Option<&'a str> {
+        self.target.splitn(2, '?').nth(1)
+    }
+
+    /// The number of bytes of the request. Following data belongs to the next
+    /// request of the connection.
+    pub fn len(&self) -> usize {
+        self.head_len + self.body.len()
+    }
+
+    /// The client keeps the connection open for more requests.
+    pub fn keep_alive(&self) -> bool {
+        match self.headers.get("Connection") {
+            Some(value) if value.eq_ignore_ascii_case("close") => false,
+            Some(value) if value.eq_ignore_ascii_case("keep-alive") => true,
+            _ => self.minor_version >= 1,
+        }
+    }
+}
+
+/// An incomplete request is `Truncated`, so the caller can wait for more data.
+impl<'a> Parse<'a> for Request<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
+        let head_end = match data.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(position) => position,
+            None => return Err(ParseError::truncated(Layer::Http, data.len())),
+        };
+        let head = str::from_utf8(&data[..head_end])
+            .map_err(|e| ParseError::malformed(Layer::Http, e.valid_up_to(), "invalid UTF-8"))?;
+        let (request_line, header_block) = match head.find("\r\n") {
+            Some(end) => (&head[..end], &head[(end + 2)..]),
+            None => (head, ""),
+        };
+
+        let mut parts = request_line.split(' ');
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version)) if parts.next().is_none() => {
+                (method, target, version)
+            }
+            _ => return Err(ParseError::malformed(Layer::Http, 0, "invalid request line")),
+        };
+        let minor_version = match version {
+            "HTTP/1.0" => 0,
+            "HTTP/1.1" => 1,
+            _ => {
+                let offset = request_line.len() - version.len();
+                return Err(ParseError::unimplemented(Layer::Http, offset, "unsupported version"));
+            }
+        };
+
+        let mut content_length = 0;
+        let mut content_length_offset = 0;
+        let mut offset = request_line.len() + 2;
+        for line in header_block.split("\r\n").filter(|_| !header_block.is_empty()) {
+            let (name, value) = match split_header(line) {
+                Some(header) => header,
+                None => return Err(ParseError::malformed(Layer::Http, offset, "invalid header")),
+            };
+            if name.eq_ignore_ascii_case("Content-Length") {
+                content_length = value
+                    .parse()
+                    .map_err(|_| ParseError::malformed(Layer::Http, offset, "invalid length"))?;
+                content_length_offset = offset;
+            } else if name.eq_ignore_ascii_case("Transfer-Encoding") {
+                return Err(ParseError::unimplemented(Layer::Http, offset, "chunked request"));
+            }
+            offset += line.len() + 2;
+        }
+
+        let head_len = head_end + 4;
+        let len = match head_len.checked_add(content_length) {
+            Some(len) => len,
+            None => {
+                return Err(ParseError::malformed(Layer::Http,
+                                                 content_length_offset,
+                                                 "invalid length"))
+            }
+        };
+        if data.len() < len {
+            return Err(ParseError::truncated(Layer::Http, data.len()));
+        }
+        Ok(Request {
+               method: Method::from_str(method),
+               target,
+               minor_version,
+               headers: Headers(header_block),
+               head_len,
+               body: &data[head_len..len],
+           })
+    }
+}
+
+fn split_header(line: &str) -> Option<(&str, &str)> {
+    let colon = match line.find(':') {
+        Some(colon) => colon,
+        None => return None,
+    };
+    let name = &line[..colon];
+    if name.is_empty() || name.ends_with(' ') {
+        return None;
+    }
+    Some((name, line[(colon + 1)..].trim()))
+}
+
+/// The header lines of a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Headers<'a>(&'a str);
+
+impl<'a> Headers<'a> {
+    /// The value of the first header with `name`, which is case-insensitive.
+    pub fn get(&self, name: &str) -> Option<&'a str> {
+        self.iter().find(|&(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, value)| value)
+    }
+
+    /// The names and values of all headers. The parser checked the syntax.
+    pub fn iter(&self) -> impl Iterator<Item = (&'a str, &'a str)> {
+        self.0.split("\r\n").filter_map(split_header)
+    }
+}
+
+/// How the length of a response body is announced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyLength {
+    ContentLength(usize),
+    /// The body is sent as `Chunk`s, followed by `LAST_CHUNK`.
+    Chunked,
+}
+
+/// The status line and headers of a response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResponseHead<'a> {
+    pub status: u16,
+    /// Additional headers, e.g. `Content-Type`.
+    pub headers: &'a [(&'a str, &'a str)],
+    pub body: BodyLength,
+    /// Announces that the server closes the connection after the response.
+    pub close: bool,
+}
+
+impl<'a> ResponseHead<'a> {
+    pub fn new(status: u16, headers: &'a [(&'a str, &'a str)], body: BodyLength) -> Self {
+        ResponseHead {
+            status,
+            headers,
+            body,
+            close: false,
+        }
+    }
+}
+
+impl<'a> WriteOut for ResponseHead<'a> {
+    fn len(&self) -> usize {
+        let headers: usize =
+            self.headers.iter().map(|&(name, value)| name.len() + 2 + value.len() + 2).sum();
+        let body = match self.body {
+            BodyLength::ContentLength(len) => "Content-Length: \r\n".len() + number_len(len, 10),
+            BodyLength::Chunked => "Transfer-Encoding: chunked\r\n".len(),
+        };
+        let close = if self.close { "Connection: close\r\n".len() } else { 0 };
+        "HTTP/1.1 000 \r\n".len() + reason_phrase(self.status).len() + headers + body + close + 2
+    }
+
+    fn write_out<P: TxPacket>(&self, packet: &mut P) -> Result<(), ()> {
+        if self.status < 100 || self.status > 999 {
+            return Err(());
+        }
+        packet.push_bytes(b"HTTP/1.1 ")?;
+        push_number(packet, usize::from(self.status), 10)?;
+        packet.push_byte(b' ')?;
+        packet.push_bytes(reason_phrase(self.status).as_bytes())?;
+        packet.push_bytes(b"\r\n")?;
+        for &(name, value) in self.headers {
+            packet.push_bytes(name.as_bytes())?;
+            packet.push_bytes(b": ")?;
+            packet.push_bytes(value.as_bytes())?;
+            packet.push_bytes(b"\r\n")?;
+        }
+        match self.body {
+            BodyLength::ContentLength(len) => {
+                packet.push_bytes(b"Content-Length: ")?;
+                push_number(packet, len, 10)?;
+                packet.push_bytes(b"\r\n")?;
+            }
+            BodyLength::Chunked => {
+                packet.push_bytes(b"Transfer-Encoding: chunked\r\n")?;
+            }
+        }
+        if self.close {
+            packet.push_bytes(b"Connection: close\r\n")?;
+        }
+        packet.push_bytes(b"\r\n")?;
+        Ok(())
+    }
+}
+
+/// A response with the whole body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Response<'a, T> {
+    pub head: ResponseHead<'a>,
+    pub body: T,
+}
+
+impl<'a, T: WriteOut> Response<'a, T> {
+    pub fn new(status: u16, headers: &'a [(&'a str, &'a str)], body: T) -> Self {
+        Response {
+            head: ResponseHead::new(status, headers, BodyLength::ContentLength(body.len())),
+            body,
+        }
+    }
+}
+
+impl<'a, T: WriteOut> WriteOut for Response<'a, T> {
+    fn len(&self) -> usize {
+        self.head.len() + self.body.len()
+    }
+
+    fn write_out<P: TxPacket>(&self, packet: &mut P) -> Result<(), ()> {
+        self.head.write_out(packet)?;
+        self.body.write_out(packet)
+    }
+}
+
+/// A piece of a chunked body. Empty chunks are not allowed, they would end the body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunk<T>(pub T);
+
+impl<T: WriteOut> WriteOut for Chunk<T> {
+    fn len(&self) -> usize {
+        number_len(self.0.len(), 16) + 2 + self.0.len() + 2
+    }
+
+    fn write_out<P: TxPacket>(&self, packet: &mut P) -> Result<(), ()> {
+        if self.0.len() == 0 {
+            return Err(());
+        }
+        push_number(packet, self.0.len(), 16)?;
+        packet.push_bytes(b"\r\n")?;
+        self.0.write_out(packet)?;
+        packet.push_bytes(b"\r\n")?;
+        Ok(())
+    }
+}
+
+/// The reason phrase of common status codes, empty for others.
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+fn number_len(mut value: usize, radix: usize) -> usize {
+    let mut len = 1;
+    while value >= radix {
+        value /= radix;
+        len += 1;
+    }
+    len
+}
+
+fn push_number<P: TxPacket>(packet: &mut P, value: usize, radix: usize) -> Result<(), ()> {
+    let mut digits = [0; 20];
+    let len = number_len(value, radix);
+    let mut rest = value;
+    for digit in digits[..len].iter_mut().rev() {
+        *digit = b"0123456789abcdef"[rest % radix];
+        rest /= radix;
+    }
+    packet.push_bytes(&digits[..len])?;
+    Ok(())
+}
+
+#[test]
+fn requests_and_responses() {
+    use HeapTxPacket;
+
+    let data = b"POST /config?save=1 HTTP/1.1\r\nHost: 192.168.1.1\r\n\
+                 content-length: 7\r\n\r\nip=dhcpGET / HTTP/1.0\r\n\r\n";
+    assert_eq!(Request::parse(&data[..50]), Err(ParseError::truncated(Layer::Http, 50)));
+    assert_eq!(Request::parse(&data[..70]), Err(ParseError::truncated(Layer::Http, 70)));
+    let request = Request::parse(data).unwrap();
+    assert_eq!(request.method, Method::Post);
+    assert_eq!((request.path(), request.query()), ("/config", Some("save=1")));
+    assert_eq!(request.headers.get("host"), Some("192.168.1.1"));
+    assert_eq!(request.body, b"ip=dhcp");
+    assert!(request.keep_alive());
+
+    // the next request of the connection
+    let next = Request::parse(&data[request.len()..]).unwrap();
+    assert_eq!((next.method, next.target, next.body), (Method::Get, "/", &[][..]));
+    assert!(!next.keep_alive());
+    assert_eq!(next.headers.iter().count(), 0);
+
+    // a length that overflows the end of the body
+    let huge = format!("GET / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", usize::max_value());
+    assert_eq!(Request::parse(huge.as_bytes()),
+               Err(ParseError::malformed(Layer::Http, 16, "invalid length")));
+
+    let headers = [("Content-Type", "text/plain")];
+    let response = Response::new(200, &headers, &b"saved"[..]);
+    let mut packet = HeapTxPacket::new(response.len());
+    response.write_out(&mut packet).unwrap();
+    let expected = b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n\r\n\
+                     saved";
+    assert_eq!(packet.as_slice(), &expected[..]);
+    assert_eq!(packet.len(), response.len());
+
+    // a streamed body
+    let mut head = ResponseHead::new(404, &[], BodyLength::Chunked);
+    head.close = true;
+    let mut packet = HeapTxPacket::new(128);
+    head.write_out(&mut packet).unwrap();
+    let chunk = Chunk(&[b'x'; 26][..]);
+    chunk.write_out(&mut packet).unwrap();
+    packet.push_bytes(LAST_CHUNK).unwrap();
+    let expected = b"HTTP/1.1 404 Not Found\r\nTransfer-Encoding: chunked\r\n\
+                     Connection: close\r\n\r\n1a\r\nxxxxxxxxxxxxxxxxxxxxxxxxxx\r\n0\r\n\r\n";
+    assert_eq!(packet.as_slice(), &expected[..]);
+    assert_eq!(head.len() + chunk.len() + LAST_CHUNK.len(), expected.len());
+}
@@ -1,5 +1,5 @@
 use {TxPacket, WriteOut};
-use ip_checksum;
+use ip_checksum::ChecksummedTxPacket;
 use byteorder::{ByteOrder, NetworkEndian};
 use ethernet::{EthernetAddress, EthernetPacket};
 use ipv4::{Ipv4Address, Ipv4Packet};
@@ -8,6 +8,109 @@ use ipv4::{Ipv4Address, Ipv4Packet};
 pub enum IcmpType {
     EchoRequest { id: u16, sequence_number: u16 },
     EchoReply { id: u16, sequence_number: u16 },
+    /// Packets for the destination of the quoted datagram should be sent to
+    /// `gateway`. The code selects network (0), host (1), or type of service
+    /// specific (2, 3) redirects.
+    Redirect { code: u8, gateway: Ipv4Address },
+    /// The quoted datagram has an invalid header field. `pointer` is the offset of
+    /// the offending byte in the quoted header.
+    ParameterProblem { code: u8, pointer: u8 },
+    /// The quoted datagram couldn't be delivered. `next_hop_mtu` is only sent for
+    /// `UNREACHABLE_FRAGMENTATION_NEEDED`.
+    DestinationUnreachable { code: u8, next_hop_mtu: u16 },
+    /// The TTL (code 0) or the reassembly time (code 1) of the quoted datagram
+    /// expired.
+    TimeExceeded { code: u8 },
+}
+
+impl IcmpType {
+    /// Returns true for the error messages, which are never answered with another
+    /// error.
+    pub fn is_error(&self) -> bool {
+        match *self {
+            IcmpType::EchoRequest { .. } | IcmpType::EchoReply { .. } => false,
+            _ => true,
+        }
+    }
+}
+
+/// Returns true if the ICMP type number is an error message: destination
+/// unreachable, source quench, redirect, time exceeded, or parameter problem.
+pub fn is_error_type(type_: u8) -> bool {
+    match type_ {
+        3 | 4 | 5 | 11 | 12 => true,
+        _ => false,
+    }
+}
+
+/// Redirect code for all packets to the host of the quoted datagram.
+pub const REDIRECT_HOST: u8 = 1;
+
+/// Destination unreachable code of datagrams that need fragmentation but have the
+/// don't fragment flag set, used for path MTU discovery.
+pub const UNREACHABLE_FRAGMENTATION_NEEDED: u8 = 4;
+
+/// Destination unreachable code of datagrams for an unsupported protocol.
+pub const UNREACHABLE_PROTOCOL: u8 = 2;
+
+/// Destination unreachable code of datagrams to a closed port.
+pub const UNREACHABLE_PORT: u8 = 3;
+
+/// Returns the start of a received IPv4 datagram that is quoted in an error
+/// message: the header and the first 8 bytes of the payload (RFC 792).
+pub fn quote(datagram: &[u8]) -> &[u8] {
+    if datagram.len() < 20 {
+        return datagram;
+    }
+    let header_len = usize::from(datagram[0] & 0xf) * 4;
+    let total_len = usize::from(NetworkEndian::read_u16(&datagram[2..4]));
+    let len = usize::min(header_len + 8, usize::min(total_len, datagram.len()));
+    &datagram[..len]
+}
+
+/// A token bucket that limits generated ICMP messages to bursts of `capacity`
+/// messages. A token is added every `interval` milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenBucket {
+    capacity: u32,
+    interval: u64,
+    tokens: u32,
+    last_refill: u64,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket.
+    pub fn new(capacity: u32, interval: u64) -> TokenBucket {
+        TokenBucket {
+            capacity,
+            interval,
+            tokens: capacity,
+            last_refill: 0,
+        }
+    }
+
+    /// Takes a token for a message at `now`. Returns false if the bucket is empty,
+    /// so the message must not be sent.
+    pub fn take(&mut self, now: u64) -> bool {
+        if self.interval > 0 {
+            let refills = now.saturating_sub(self.last_refill) / self.interval;
+            if refills > 0 {
+                let tokens = u64::from(self.tokens) + refills;
+                self.tokens = u64::min(tokens, u64::from(self.capacity)) as u32;
+                self.last_refill += refills * self.interval;
+            }
+        } else {
+            self.tokens = self.capacity;
+        }
+        if self.tokens == self.capacity {
+            self.last_refill = now;
+        }
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -16,6 +119,18 @@ pub struct IcmpPacket<T> {
     pub data: T,
 }
 
+impl<T> IcmpPacket<T> {
+    pub fn echo_request(id: u16, sequence_number: u16, data: T) -> IcmpPacket<T> {
+        IcmpPacket {
+            type_: IcmpType::EchoRequest {
+                id,
+                sequence_number,
+            },
+            data,
+        }
+    }
+}
+
 impl<T: Clone> IcmpPacket<T> {
     pub fn echo_reply(&self) -> IcmpPacket<T> {
         let (id, sequence_number) = match self.type_ {
@@ -47,13 +162,92 @@ impl<T: Clone> IcmpPacket<T> {
     }
 }
 
-impl<T: AsRef<[u8]>> WriteOut for IcmpPacket<T> {
+pub fn new_echo_request_packet<'a>(src_mac: EthernetAddress,
+                                   dst_mac: EthernetAddress,
+                                   src_ip: Ipv4Address,
+                                   dst_ip: Ipv4Address,
+                                   id: u16,
+                                   sequence_number: u16,
+                                   payload: EchoPayload<'a>)
+                                   -> EthernetPacket<Ipv4Packet<IcmpPacket<EchoPayload<'a>>>> {
+    let request = IcmpPacket::echo_request(id, sequence_number, payload);
+    EthernetPacket::new_ipv4(src_mac,
+                             dst_mac,
+                             Ipv4Packet::new_icmp(src_ip, dst_ip, request))
+}
+
+/// Generated echo request data of a fixed length, filled by repeating `pattern`.
+///
+/// An empty pattern fills the payload with zeros.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EchoPayload<'a> {
+    pub len: usize,
+    pub pattern: &'a [u8],
+}
+
+impl<'a> EchoPayload<'a> {
+    pub fn new(len: usize, pattern: &'a [u8]) -> Self {
+        EchoPayload { len, pattern }
+    }
+
+    fn byte(&self, index: usize) -> u8 {
+        if self.pattern.is_empty() {
+            0
+        } else {
+            self.pattern[index % self.pattern.len()]
+        }
+    }
+
+    /// Checks whether `data` has the expected length and fill pattern.
+    pub fn matches(&self, data: &[u8]) -> bool {
+        data.len() == self.len && data.iter().enumerate().all(|(i, &b)| b == self.byte(i))
+    }
+}
+
+impl<'a> WriteOut for EchoPayload<'a> {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn write_out<P: TxPacket>(&self, packet: &mut P) -> Result<(), ()> {
+        let zeros = [0; 16];
+        let pattern = if self.pattern.is_empty() {
+            &zeros[..]
+        } else {
+            self.pattern
+        };
+
+        let mut remaining = self.len;
+        while remaining > 0 {
+            let chunk = usize::min(remaining, pattern.len());
+            packet.push_bytes(&pattern[..chunk])?;
+            remaining -= chunk;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> IcmpPacket<&'a [u8]> {
+    /// Checks whether this packet is the echo reply for the given request.
+    pub fn is_echo_reply_to(&self, request: &IcmpPacket<EchoPayload>) -> bool {
+        match (self.type_, request.type_) {
+            (IcmpType::EchoReply { id, sequence_number },
+             IcmpType::EchoRequest { id: request_id, sequence_number: request_sequence_number }) => {
+                id == request_id && sequence_number == request_sequence_number &&
+                request.data.matches(self.data)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl<T: WriteOut> WriteOut for IcmpPacket<T> {
     fn len(&self) -> usize {
-        self.data.as_ref().len() + 4 * 2
+        self.data.len() + 4 * 2
     }
 
     fn write_out<P: TxPacket>(&self, packet: &mut P) -> Result<(), ()> {
-        let start_index = packet.len();
+        let mut packet = ChecksummedTxPacket::new(packet);
 
         match self.type_ {
             IcmpType::EchoRequest { .. } => {
@@ -64,6 +258,22 @@ impl<T: AsRef<[u8]>> WriteOut for IcmpPacket<T> {
                 packet.push_byte(0)?; // type
                 packet.push_byte(0)?; // code
             }
+            IcmpType::Redirect { code, .. } => {
+                packet.push_byte(5)?; // type
+                packet.push_byte(code)?;
+            }
+            IcmpType::ParameterProblem { code, .. } => {
+                packet.push_byte(12)?; // type
+                packet.push_byte(code)?;
+            }
+            IcmpType::DestinationUnreachable { code, .. } => {
+                packet.push_byte(3)?; // type
+                packet.push_byte(code)?;
+            }
+            IcmpType::TimeExceeded { code } => {
+                packet.push_byte(11)?; // type
+                packet.push_byte(code)?;
+            }
         }
 
         let checksum_idx = packet.push_u16(0)?; // checksum
@@ -80,23 +290,41 @@ impl<T: AsRef<[u8]>> WriteOut for IcmpPacket<T> {
                 packet.push_u16(id)?;
                 packet.push_u16(sequence_number)?;
             }
+            IcmpType::Redirect { gateway, .. } => {
+                packet.push_bytes(&gateway.as_bytes())?;
+            }
+            IcmpType::ParameterProblem { pointer, .. } => {
+                packet.push_bytes(&[pointer, 0, 0, 0])?;
+            }
+            IcmpType::DestinationUnreachable { code, next_hop_mtu } => {
+                packet.push_u16(0)?;
+                packet.push_u16(match code {
+                                    UNREACHABLE_FRAGMENTATION_NEEDED => next_hop_mtu,
+                                    _ => 0,
+                                })?;
+            }
+            IcmpType::TimeExceeded { .. } => {
+                packet.push_bytes(&[0; 4])?;
+            }
         }
 
-        packet.push_bytes(self.data.as_ref())?;
-        let end_index = packet.len();
+        self.data.write_out(&mut packet)?;
 
-        // calculate Icmp checksum
-        let checksum = !ip_checksum::data(&packet[start_index..end_index]);
+        // Icmp checksum
+        let checksum = !packet.checksum();
         packet.set_u16(checksum_idx, checksum);
 
         Ok(())
     }
 }
 
-use parse::{Parse, ParseError};
+use parse::{Layer, Parse, ParseError};
 
 impl<'a> Parse<'a> for IcmpPacket<&'a [u8]> {
     fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
+        if data.len() < 8 {
+            return Err(ParseError::truncated(Layer::Icmp, data.len()));
+        }
         let type_code = (data[0], data[1]);
 
         let type_ = match type_code {
@@ -106,7 +334,28 @@ impl<'a> Parse<'a> for IcmpPacket<&'a [u8]> {
                     sequence_number: NetworkEndian::read_u16(&data[6..8]),
                 }
             }
-            _ => return Err(ParseError::Unimplemented("Unknown ICMP packet type")),
+            (0, 0) => {
+                IcmpType::EchoReply {
+                    id: NetworkEndian::read_u16(&data[4..6]),
+                    sequence_number: NetworkEndian::read_u16(&data[6..8]),
+                }
+            }
+            (5, code) => {
+                IcmpType::Redirect {
+                    code,
+                    gateway: Ipv4Address::from_bytes(&data[4..8]),
+                }
+            }
+            (12, code) => IcmpType::ParameterProblem { code, pointer: data[4] },
+            (3, code) => {
+                let next_hop_mtu = match code {
+                    UNREACHABLE_FRAGMENTATION_NEEDED => NetworkEndian::read_u16(&data[6..8]),
+                    _ => 0,
+                };
+                IcmpType::DestinationUnreachable { code, next_hop_mtu }
+            }
+            (11, code) => IcmpType::TimeExceeded { code },
+            _ => return Err(ParseError::unimplemented(Layer::Icmp, 0, "Unknown ICMP packet type")),
         };
 
         Ok(IcmpPacket {
@@ -115,3 +364,170 @@ impl<'a> Parse<'a> for IcmpPacket<&'a [u8]> {
            })
     }
 }
+
+/// A received ICMP message of any type, like `Ipv4Kind` for IPv4 payloads.
+///
+/// The error messages carry the `quoted` start of the datagram that caused them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IcmpKind<'a> {
+    EchoRequest {
+        id: u16,
+        sequence_number: u16,
+        data: &'a [u8],
+    },
+    EchoReply {
+        id: u16,
+        sequence_number: u16,
+        data: &'a [u8],
+    },
+    DestinationUnreachable {
+        code: u8,
+        /// The MTU of the next hop for `UNREACHABLE_FRAGMENTATION_NEEDED` (RFC 1191),
+        /// otherwise 0.
+        next_hop_mtu: u16,
+        quoted: &'a [u8],
+    },
+    Redirect {
+        code: u8,
+        gateway: Ipv4Address,
+        quoted: &'a [u8],
+    },
+    TimeExceeded { code: u8, quoted: &'a [u8] },
+    ParameterProblem {
+        code: u8,
+        pointer: u8,
+        quoted: &'a [u8],
+    },
+    /// Other types, with the rest of the header in the first four bytes of `data`.
+    Unknown { type_: u8, code: u8, data: &'a [u8] },
+}
+
+impl<'a> IcmpKind<'a> {
+    /// The message as an `IcmpPacket`, e.g. to answer it with `echo_reply_packet`.
+    /// Returns `None` for the types that `IcmpType` doesn't cover.
+    pub fn packet(&self) -> Option<IcmpPacket<&'a [u8]>> {
+        let (type_, data) = match *self {
+            IcmpKind::EchoRequest { id, sequence_number, data } => {
+                (IcmpType::EchoRequest { id, sequence_number }, data)
+            }
+            IcmpKind::EchoReply { id, sequence_number, data } => {
+                (IcmpType::EchoReply { id, sequence_number }, data)
+            }
+            IcmpKind::Redirect { code, gateway, quoted } => {
+                (IcmpType::Redirect { code, gateway }, quoted)
+            }
+            IcmpKind::ParameterProblem { code, pointer, quoted } => {
+                (IcmpType::ParameterProblem { code, pointer }, quoted)
+            }
+            IcmpKind::DestinationUnreachable { code, next_hop_mtu, quoted } => {
+                (IcmpType::DestinationUnreachable { code, next_hop_mtu }, quoted)
+            }
+            IcmpKind::TimeExceeded { code, quoted } => (IcmpType::TimeExceeded { code }, quoted),
+            IcmpKind::Unknown { .. } => return None,
+        };
+        Some(IcmpPacket { type_, data })
+    }
+}
+
+impl<'a> Parse<'a> for IcmpKind<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
+        if data.len() < 8 {
+            return Err(ParseError::truncated(Layer::Icmp, data.len()));
+        }
+        let quoted = &data[8..];
+        Ok(match data[0] {
+               0 | 3 | 5 | 8 | 11 | 12 => {
+                   let packet = IcmpPacket::parse(data)?;
+                   match packet.type_ {
+                       IcmpType::EchoRequest { id, sequence_number } => {
+                           IcmpKind::EchoRequest { id, sequence_number, data: quoted }
+                       }
+                       IcmpType::EchoReply { id, sequence_number } => {
+                           IcmpKind::EchoReply { id, sequence_number, data: quoted }
+                       }
+                       IcmpType::Redirect { code, gateway } => {
+                           IcmpKind::Redirect { code, gateway, quoted }
+                       }
+                       IcmpType::ParameterProblem { code, pointer } => {
+                           IcmpKind::ParameterProblem { code, pointer, quoted }
+                       }
+                       IcmpType::DestinationUnreachable { code, next_hop_mtu } => {
+                           IcmpKind::DestinationUnreachable { code, next_hop_mtu, quoted }
+                       }
+                       IcmpType::TimeExceeded { code } => IcmpKind::TimeExceeded { code, quoted },
+                   }
+               }
+               type_ => IcmpKind::Unknown { type_, code: data[1], data: &data[4..] },
+           })
+    }
+}
+
+#[test]
+fn echo_payload_pattern() {
+    use HeapTxPacket;
+
+    let request = IcmpPacket::echo_request(0x1234, 7, EchoPayload::new(10, &[0xaa, 0xbb, 0xcc]));
+    let mut packet = HeapTxPacket::new(request.len());
+    request.write_out(&mut packet).unwrap();
+
+    let data = packet.as_slice();
+    assert_eq!(&data[8..],
+               &[0xaa, 0xbb, 0xcc, 0xaa, 0xbb, 0xcc, 0xaa, 0xbb, 0xcc, 0xaa]);
+
+    let parsed_request = IcmpPacket::parse(data).unwrap();
+    let reply = parsed_request.echo_reply();
+    let mut reply_packet = HeapTxPacket::new(reply.len());
+    reply.write_out(&mut reply_packet).unwrap();
+
+    let parsed_reply = IcmpPacket::parse(reply_packet.as_slice()).unwrap();
+    assert!(parsed_reply.is_echo_reply_to(&request));
+
+    let other = IcmpPacket::echo_request(0x1234, 7, EchoPayload::new(10, &[0xaa]));
+    assert!(!parsed_reply.is_echo_reply_to(&other));
+}
+
+#[test]
+fn redirect_and_parameter_problem() {
+    use HeapTxPacket;
+
+    // the quoted datagram is the IPv4 header and the first 8 payload bytes
+    let quoted = [0x45, 0x00, 0x00, 0x1c, 0x00, 0x00, 0x00, 0x00, 0x40, 0x11, 0x00, 0x00,
+                  192, 168, 1, 2, 8, 8, 8, 8, 0x03, 0xe8, 0x00, 0x35, 0x00, 0x08, 0x00, 0x00];
+    let gateway = Ipv4Address::new(192, 168, 1, 254);
+
+    for &type_ in &[IcmpType::Redirect { code: REDIRECT_HOST, gateway },
+                    IcmpType::ParameterProblem { code: 0, pointer: 9 }] {
+        let message = IcmpPacket { type_, data: &quoted[..] };
+        let mut packet = HeapTxPacket::new(message.len());
+        message.write_out(&mut packet).unwrap();
+        assert_eq!(::ip_checksum::data(packet.as_slice()), 0xffff);
+
+        let parsed = IcmpPacket::parse(packet.as_slice()).unwrap();
+        assert_eq!(parsed, message);
+    }
+}
+
+#[test]
+fn message_kinds() {
+    // fragmentation needed, with a next-hop MTU of 1400
+    let mut data = vec![3, UNREACHABLE_FRAGMENTATION_NEEDED, 0, 0, 0, 0, 0x05, 0x78];
+    data.extend_from_slice(&[0x45; 28]);
+    match IcmpKind::parse(&data).unwrap() {
+        IcmpKind::DestinationUnreachable { code, next_hop_mtu, quoted } => {
+            assert_eq!((code, next_hop_mtu, quoted.len()), (4, 1400, 28));
+        }
+        other => panic!("unexpected message {:?}", other),
+    }
+    data[0] = 13; // timestamp request
+    assert_eq!(IcmpKind::parse(&data).unwrap(),
+               IcmpKind::Unknown {
+                   type_: 13,
+                   code: 4,
+                   data: &data[4..],
+               });
+    assert_eq!(IcmpKind::parse(&data).unwrap().packet(), None);
+
+    let request = [8, 0, 0, 0, 0x12, 0x34, 0, 1, 0xaa];
+    let kind = IcmpKind::parse(&request).unwrap();
+    assert_eq!(kind.packet(), Some(IcmpPacket::parse(&request).unwrap()));
+}
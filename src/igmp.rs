@@ -0,0 +1,173 @@
+//! IGMPv3 membership reports and queries according to [RFC 3376][rfc3376].
+//!
+//! Reports are always sent in the IGMPv3 format, which also allows joining
+//! source-specific multicast (SSM) channels. Queries of all versions are parsed.
+//!
+//! [rfc3376]: https://tools.ietf.org/html/rfc3376
+
+use {TxPacket, WriteOut};
+use ip_checksum::{self, ChecksummedTxPacket};
+use ipv4::Ipv4Address;
+
+/// The destination of queries to all hosts.
+pub const ALL_SYSTEMS: Ipv4Address = Ipv4Address::new(224, 0, 0, 1);
+
+/// The destination of IGMPv3 reports.
+pub const ALL_IGMPV3_ROUTERS: Ipv4Address = Ipv4Address::new(224, 0, 0, 22);
+
+const TYPE_QUERY: u8 = 0x11;
+const TYPE_V3_REPORT: u8 = 0x22;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordType {
+    /// The current state, in reports that answer queries.
+    ModeIsInclude,
+    ModeIsExclude,
+    /// State changes, in unsolicited reports.
+    ChangeToInclude,
+    ChangeToExclude,
+    AllowNewSources,
+    BlockOldSources,
+}
+
+impl RecordType {
+    fn number(&self) -> u8 {
+        match *self {
+            RecordType::ModeIsInclude => 1,
+            RecordType::ModeIsExclude => 2,
+            RecordType::ChangeToInclude => 3,
+            RecordType::ChangeToExclude => 4,
+            RecordType::AllowNewSources => 5,
+            RecordType::BlockOldSources => 6,
+        }
+    }
+}
+
+/// The reception state of one group. Joining a group for any source is an
+/// exclude record without sources, leaving it an include record without sources.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupRecord<'a> {
+    pub record_type: RecordType,
+    pub group: Ipv4Address,
+    pub sources: &'a [Ipv4Address],
+}
+
+impl<'a> GroupRecord<'a> {
+    fn len(&self) -> usize {
+        8 + 4 * self.sources.len()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MembershipReport<'a> {
+    pub records: &'a [GroupRecord<'a>],
+}
+
+impl<'a> WriteOut for MembershipReport<'a> {
+    fn len(&self) -> usize {
+        8 + self.records.iter().map(|record| record.len()).sum::<usize>()
+    }
+
+    fn write_out<P: TxPacket>(&self, packet: &mut P) -> Result<(), ()> {
+        let mut packet = ChecksummedTxPacket::new(packet);
+
+        packet.push_byte(TYPE_V3_REPORT)?;
+        packet.push_byte(0)?; // reserved
+        let checksum_idx = packet.push_u16(0)?;
+        packet.push_u16(0)?; // reserved
+        packet.push_u16(self.records.len() as u16)?;
+        for record in self.records {
+            packet.push_byte(record.record_type.number())?;
+            packet.push_byte(0)?; // auxiliary data length
+            packet.push_u16(record.sources.len() as u16)?;
+            packet.push_bytes(&record.group.as_bytes())?;
+            for source in record.sources {
+                packet.push_bytes(&source.as_bytes())?;
+            }
+        }
+
+        let checksum = !packet.checksum();
+        packet.set_u16(checksum_idx, checksum);
+        Ok(())
+    }
+}
+
+/// A received IGMP message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IgmpMessage {
+    /// A general query if `group` is 0.0.0.0, otherwise a group-specific one. The
+    /// source list of IGMPv3 queries is ignored.
+    Query {
+        group: Ipv4Address,
+        /// In tenths of a second.
+        max_response_time: u16,
+    },
+    /// Reports of other hosts and unknown types.
+    Other(u8),
+}
+
+use parse::{Layer, Parse, ParseError};
+
+impl<'a> Parse<'a> for IgmpMessage {
+    fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
+        if data.len() < 8 {
+            return Err(ParseError::truncated(Layer::Igmp, data.len()));
+        }
+        if ip_checksum::data(data) != 0xffff {
+            return Err(ParseError::checksum(Layer::Igmp, 2));
+        }
+        if data[0] != TYPE_QUERY {
+            return Ok(IgmpMessage::Other(data[0]));
+        }
+
+        // IGMPv3 queries encode large times in a floating point format
+        let code = u16::from(data[1]);
+        let max_response_time = if data.len() >= 12 && code >= 128 {
+            let mantissa = code & 0x0f;
+            let exponent = (code >> 4) & 0x07;
+            (mantissa | 0x10) << (exponent + 3)
+        } else if code == 0 {
+            100 // IGMPv1
+        } else {
+            code
+        };
+        Ok(IgmpMessage::Query {
+               group: Ipv4Address::from_bytes(&data[4..8]),
+               max_response_time,
+           })
+    }
+}
+
+#[test]
+fn report_format() {
+    use byteorder::{ByteOrder, NetworkEndian};
+    use ipv4::Ipv4Packet;
+    use testing::assert_serializes_to;
+
+    let source = Ipv4Address::new(10, 0, 0, 1);
+    let records = [GroupRecord {
+                       record_type: RecordType::AllowNewSources,
+                       group: Ipv4Address::new(232, 1, 2, 3),
+                       sources: &[source],
+                   }];
+    let report = MembershipReport { records: &records };
+    assert_serializes_to(&report,
+                         &[0x22, 0, 0xe4, 0xf7, 0, 0, 0, 1, 5, 0, 0, 1, 232, 1, 2, 3, 10, 0, 0, 1]);
+
+    // with router alert and a TTL of 1
+    let ip = Ipv4Packet::new_igmp_report(Ipv4Address::new(192, 168, 1, 2), report);
+    let mut packet = ::HeapTxPacket::new(ip.len());
+    ip.write_out(&mut packet).unwrap();
+    assert_eq!(packet[0], 0x46);
+    assert_eq!(packet[8], 1);
+    assert_eq!(&packet[20..24], &[0x94, 4, 0, 0]);
+
+    let mut query = vec![0x11, 100, 0, 0, 0, 0, 0, 0];
+    let checksum = !ip_checksum::data(&query);
+    NetworkEndian::write_u16(&mut query[2..4], checksum);
+    assert_eq!(IgmpMessage::parse(&query),
+               Ok(IgmpMessage::Query {
+                      group: Ipv4Address::new(0, 0, 0, 0),
+                      max_response_time: 100,
+                  }));
+}
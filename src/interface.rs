@@ -0,0 +1,2111 @@
+#[cfg(any(test, feature = "tcp"))]
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::{BTreeMap, Vec, VecDeque};
+#[cfg(any(test, feature = "tcp"))]
+use alloc::BTreeSet;
+use acd::{Acd, AcdEvent, DefensePolicy};
+use arp::{self, ArpOperation, ArpPacket};
+#[cfg(any(test, feature = "icmp"))]
+use byteorder::{ByteOrder, NetworkEndian};
+use ethernet::{self, EthernetAddress, EthernetHeader, EthernetKind, EthernetPacket, EtherType};
+use events::{EventQueue, StackEvent};
+#[cfg(any(test, feature = "icmp"))]
+use icmp::{self, IcmpKind, IcmpPacket, IcmpType, TokenBucket};
+use filter::{FrameFilter, Verdict};
+use igmp::{GroupRecord, IgmpMessage, MembershipReport, RecordType, ALL_SYSTEMS};
+use ipv4::{IpProtocol, Ipv4Address, Ipv4Header, Ipv4Kind, Ipv4Packet};
+use parse::{parse, Layer, Parse, ParseError};
+use ports::PortAllocator;
+use retry::{Backoff, Policy};
+use stats::Stats;
+use ipv4::Endpoint;
+#[cfg(any(test, feature = "tcp"))]
+use ipv4::ConnectionId;
+#[cfg(any(test, feature = "tcp"))]
+use tcp::{TcpConnection, TcpKind, TcpListener, TcpPacket, TcpState};
+use udp::{UdpKind, UdpPacket};
+use {HeapTxPacket, TxPacket, WriteOut};
+
+/// Handles the payload of an IPv4 packet and optionally returns a response payload,
+/// which is sent back to the sender with the same protocol number.
+pub type RawHandler = Box<FnMut(&Ipv4Header, &[u8]) -> Option<Vec<u8>>>;
+
+/// Handles the payload of an Ethernet frame and optionally returns a response
+/// payload, which is sent back to the sender with the same EtherType.
+pub type EtherTypeHandler = Box<FnMut(&EthernetHeader, &[u8]) -> Option<Vec<u8>>>;
+
+#[cfg(any(test, feature = "tcp"))]
+pub type TcpHandler = Box<for<'d> FnMut(&TcpConnection, &'d [u8]) -> Option<Cow<'d, [u8]>>>;
+
+pub type LinkHandler = Box<FnMut(bool)>;
+
+/// Programs the multicast filter of the hardware with the accepted addresses, see
+/// `Interface::on_multicast_filter_change`.
+pub type MulticastFilterHandler = Box<FnMut(&[EthernetAddress]) -> bool>;
+
+/// What happens to TCP connections when the link goes down.
+#[cfg(any(test, feature = "tcp"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkDownPolicy {
+    /// Keep the connections and probe them when the link comes back up. This is the
+    /// default, so that short outages go unnoticed.
+    Probe,
+    /// Abort all connections, reporting `TcpEvent::Aborted`.
+    Abort,
+}
+
+/// How echo requests to the address of the interface are answered.
+#[cfg(any(test, feature = "icmp"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EchoPolicy {
+    /// Answer every request. This is the default.
+    Respond,
+    Ignore,
+    /// Answer at most `count` requests per `interval` milliseconds, measured with the
+    /// time of the last `poll`.
+    RateLimit { count: u32, interval: u64 },
+}
+
+/// A callback that sees every received and transmitted frame, see `Interface::set_tap`.
+pub type Tap = fn(&[u8], Direction);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Rx,
+    Tx,
+}
+
+/// Why a packet could not be sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    /// The destination is outside of the local subnet and no gateway is set.
+    NoRoute,
+    /// Too many packets are waiting for ARP resolution.
+    QueueFull,
+    LinkDown,
+    /// The IPv4 packet, or the payload of another frame, is larger than the MTU of
+    /// the interface. Fragmentation is not supported.
+    ExceedsMtu { len: usize, mtu: usize },
+    /// Serialization failed.
+    Failed,
+}
+
+/// The maximum number of received datagrams that are queued per bound UDP port.
+pub const UDP_QUEUE_LEN: usize = 8;
+
+/// The time in milliseconds after which ARP cache entries are dropped.
+pub const ARP_CACHE_TIMEOUT: u64 = 5 * 60_000;
+
+/// The maximum number of IPv4 packets that wait for ARP resolution.
+pub const MAX_PENDING_PACKETS: usize = 8;
+
+/// Unanswered ARP requests are repeated every second, and the waiting packets are
+/// dropped after the second retry.
+pub const DEFAULT_ARP_RETRY_POLICY: Policy = Policy {
+    initial_delay: 1000,
+    backoff: Backoff::Constant,
+    max_delay: 1000,
+    max_retries: 2,
+    jitter_percent: 0,
+};
+
+/// The maximum number of frames to this host that wait for the next `poll`.
+pub const LOOPBACK_QUEUE_LEN: usize = 8;
+
+/// The default number of gratuitous ARPs sent on link up or address change
+/// (ANNOUNCE_NUM of RFC 5227).
+pub const DEFAULT_ANNOUNCE_COUNT: u8 = 2;
+
+/// The default time between two gratuitous ARPs in milliseconds (ANNOUNCE_INTERVAL
+/// of RFC 5227).
+pub const DEFAULT_ANNOUNCE_INTERVAL: u64 = 2000;
+
+/// The default burst of generated ICMP messages, see `Interface::set_icmp_rate_limit`.
+#[cfg(any(test, feature = "icmp"))]
+pub const DEFAULT_ICMP_BURST: u32 = 10;
+
+/// The default time in milliseconds after which another ICMP message may be sent.
+#[cfg(any(test, feature = "icmp"))]
+pub const DEFAULT_ICMP_INTERVAL: u64 = 100;
+
+/// A network interface with a single MAC and IPv4 address.
+///
+/// Received frames are passed to `receive`, which parses them and dispatches the
+/// contents to the registered handlers. Frames to be sent are fetched with
+/// `next_frame`, or with `poll`, which also drives all timers.
+///
+/// Without the `tcp` or `icmp` feature, the packets of that protocol are passed to
+/// the raw handlers, like those of unknown protocols.
+pub struct Interface {
+    mac: EthernetAddress,
+    ip: Ipv4Address,
+    netmask: Ipv4Address,
+    gateway: Option<Ipv4Address>,
+    raw_handlers: BTreeMap<u8, RawHandler>,
+    ether_type_handlers: BTreeMap<u16, EtherTypeHandler>,
+    /// Resolved addresses and the time they were learned.
+    arp_cache: BTreeMap<Ipv4Address, (EthernetAddress, u64)>,
+    /// Serialized IPv4 packets and their next hop, waiting for ARP resolution.
+    pending: VecDeque<(Ipv4Address, Box<[u8]>)>,
+    /// Unanswered ARP requests by address: the retries so far, the current delay,
+    /// and the deadline of the next retry.
+    arp_requests: BTreeMap<Ipv4Address, (u8, u64, u64)>,
+    arp_retry: Policy,
+    /// An xorshift state for the jitter of the ARP retries.
+    random: u32,
+    tx_queue: VecDeque<Box<[u8]>>,
+    /// Frames to this host, which are received by the next `poll`.
+    loopback: VecDeque<Box<[u8]>>,
+    announce_count: u8,
+    announce_interval: u64,
+    /// The number of outstanding gratuitous ARPs and the time the next one is due.
+    announcements: Option<(u8, u64)>,
+    /// Probes or defends the address, see `probe_address`.
+    acd: Option<Acd>,
+    #[cfg(any(test, feature = "tcp"))]
+    tcp_listeners: Vec<(TcpListener, TcpHandler)>,
+    /// Received datagrams and their receive timestamps by local port.
+    udp_sockets: BTreeMap<u16, VecDeque<(Endpoint, Option<u64>, Box<[u8]>)>>,
+    /// The timestamp of the last `poll` call.
+    now: u64,
+    stats: Stats,
+    tap: Option<Tap>,
+    link_up: bool,
+    #[cfg(any(test, feature = "tcp"))]
+    link_down_policy: LinkDownPolicy,
+    link_handlers: Vec<LinkHandler>,
+    mtu: usize,
+    ports: PortAllocator,
+    accept_directed_broadcasts: bool,
+    /// Joined multicast groups and their sources, `None` for any source.
+    multicast_groups: BTreeMap<Ipv4Address, Option<Vec<Ipv4Address>>>,
+    /// The accepted multicast MAC addresses, and in `multicast_refs` how often each
+    /// one was added.
+    multicast_macs: Vec<EthernetAddress>,
+    multicast_refs: Vec<u32>,
+    multicast_filter_handler: Option<MulticastFilterHandler>,
+    /// Drop multicast frames to other addresses, because the hardware doesn't.
+    software_multicast_filter: bool,
+    events: EventQueue,
+    /// The connections that were reported by `StackEvent::TcpConnected`.
+    #[cfg(any(test, feature = "tcp"))]
+    connected: BTreeSet<ConnectionId>,
+    #[cfg(any(test, feature = "icmp"))]
+    echo_policy: EchoPolicy,
+    #[cfg(any(test, feature = "icmp"))]
+    max_echo_payload: Option<usize>,
+    /// The start of the current rate limit interval and the replies sent in it.
+    #[cfg(any(test, feature = "icmp"))]
+    echo_replies: (u64, u32),
+    /// Limits all generated ICMP messages, including echo replies.
+    #[cfg(any(test, feature = "icmp"))]
+    icmp_tokens: TokenBucket,
+}
+
+/// Folds the MAC address into a seed (FNV-1a).
+fn port_seed(mac: EthernetAddress) -> u32 {
+    mac.as_bytes().iter().fold(0x811c_9dc5, |hash, &byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(16_777_619)
+    })
+}
+
+impl Interface {
+    pub fn new(mac: EthernetAddress, ip: Ipv4Address) -> Interface {
+        Interface {
+            mac,
+            ip,
+            netmask: Ipv4Address::new(0, 0, 0, 0),
+            gateway: None,
+            raw_handlers: BTreeMap::new(),
+            ether_type_handlers: BTreeMap::new(),
+            arp_cache: BTreeMap::new(),
+            pending: VecDeque::new(),
+            arp_requests: BTreeMap::new(),
+            arp_retry: DEFAULT_ARP_RETRY_POLICY,
+            random: port_seed(mac) | 1,
+            tx_queue: VecDeque::new(),
+            loopback: VecDeque::new(),
+            announce_count: DEFAULT_ANNOUNCE_COUNT,
+            announce_interval: DEFAULT_ANNOUNCE_INTERVAL,
+            announcements: None,
+            acd: None,
+            #[cfg(any(test, feature = "tcp"))]
+            tcp_listeners: Vec::new(),
+            udp_sockets: BTreeMap::new(),
+            now: 0,
+            stats: Stats::new(),
+            tap: None,
+            link_up: true,
+            #[cfg(any(test, feature = "tcp"))]
+            link_down_policy: LinkDownPolicy::Probe,
+            link_handlers: Vec::new(),
+            mtu: ethernet::DEFAULT_MTU,
+            ports: PortAllocator::new(port_seed(mac)),
+            accept_directed_broadcasts: true,
+            multicast_groups: BTreeMap::new(),
+            multicast_macs: [EthernetAddress::from_ipv4_multicast(ALL_SYSTEMS)].to_vec(),
+            multicast_refs: [1].to_vec(),
+            multicast_filter_handler: None,
+            software_multicast_filter: false,
+            events: EventQueue::new(0),
+            #[cfg(any(test, feature = "tcp"))]
+            connected: BTreeSet::new(),
+            #[cfg(any(test, feature = "icmp"))]
+            echo_policy: EchoPolicy::Respond,
+            #[cfg(any(test, feature = "icmp"))]
+            max_echo_payload: None,
+            #[cfg(any(test, feature = "icmp"))]
+            echo_replies: (0, 0),
+            #[cfg(any(test, feature = "icmp"))]
+            icmp_tokens: TokenBucket::new(DEFAULT_ICMP_BURST, DEFAULT_ICMP_INTERVAL),
+        }
+    }
+
+    pub fn mac(&self) -> EthernetAddress {
+        self.mac
+    }
+
+    pub fn ip(&self) -> Ipv4Address {
+        self.ip
+    }
+
+    /// Changes the IPv4 address and announces it to the link.
+    pub fn set_ip(&mut self, ip: Ipv4Address, now: u64) {
+        self.ip = ip;
+        self.pending.clear();
+        self.arp_requests.clear();
+        self.link_up(now);
+    }
+
+    /// Probes `ip` for other hosts that use it before it is configured with `set_ip`
+    /// ([RFC 5227][rfc5227]), e.g. after a DHCP ACK. `StackEvent::AddressAvailable`
+    /// reports that it was configured.
+    ///
+    /// Afterwards, conflicts are resolved according to `policy`. If the address is
+    /// given up, it is removed and `StackEvent::AddressConflict` is reported.
+    ///
+    /// [rfc5227]: https://tools.ietf.org/html/rfc5227
+    pub fn probe_address(&mut self, ip: Ipv4Address, policy: DefensePolicy) {
+        let mut acd = Acd::new(self.mac, self.next_random());
+        acd.set_policy(policy);
+        acd.start(ip, self.now);
+        self.acd = Some(acd);
+    }
+
+    /// Configures the gratuitous ARPs that are sent by `link_up` and `set_ip`. A
+    /// `count` of 0 disables them.
+    pub fn set_announcements(&mut self, count: u8, interval: u64) {
+        self.announce_count = count;
+        self.announce_interval = interval;
+    }
+
+    /// Notifies the interface that the link came up, e.g. after a reboot or a cable
+    /// was plugged in.
+    ///
+    /// The first gratuitous ARP is queued immediately, so that switches and peers
+    /// update their tables. The remaining ones are sent by `poll`.
+    pub fn link_up(&mut self, now: u64) {
+        self.announcements = None;
+        if self.announce_count > 0 {
+            self.announcements = Some((self.announce_count, now));
+            self.poll_announcements(now);
+        }
+    }
+
+    /// Starts collecting `StackEvent`s in a queue of `capacity` events, which replaces
+    /// the current one. Events are dropped until this is called.
+    pub fn enable_events(&mut self, capacity: usize) {
+        self.events = EventQueue::new(capacity);
+    }
+
+    /// Returns the oldest stack event.
+    pub fn next_event(&mut self) -> Option<StackEvent> {
+        self.events.pop()
+    }
+
+    /// Adds an event of a protocol that runs outside of the interface, e.g.
+    /// `DhcpBound`.
+    pub fn push_event(&mut self, event: StackEvent) {
+        self.events.push(event);
+    }
+
+    pub fn events(&self) -> &EventQueue {
+        &self.events
+    }
+
+    pub fn is_link_up(&self) -> bool {
+        self.link_up
+    }
+
+    /// Reports a carrier change of the driver. Interfaces start with the link up.
+    ///
+    /// While the link is down, frames to be transmitted are dropped. When it comes
+    /// back up, the address is announced as by `link_up` and the TCP connections are
+    /// probed. The handlers registered by `on_link_change` are called afterwards, so
+    /// that e.g. a DHCP client can restart with a DISCOVER.
+    pub fn set_link_up(&mut self, up: bool) {
+        if up == self.link_up {
+            return;
+        }
+        self.link_up = up;
+        let now = self.now;
+        if up {
+            self.link_up(now);
+            #[cfg(any(test, feature = "tcp"))]
+            {
+                for &mut (ref mut listener, _) in &mut self.tcp_listeners {
+                    for connection in listener.connections() {
+                        connection.probe();
+                    }
+                }
+                self.flush_tcp();
+            }
+        } else {
+            self.tx_queue.clear();
+            self.pending.clear();
+            self.arp_requests.clear();
+            self.announcements = None;
+            #[cfg(any(test, feature = "tcp"))]
+            {
+                if self.link_down_policy == LinkDownPolicy::Abort {
+                    for &mut (ref mut listener, _) in &mut self.tcp_listeners {
+                        for connection in listener.connections() {
+                            connection.abort();
+                        }
+                        listener.poll(now);
+                    }
+                }
+            }
+        }
+        self.events.push(StackEvent::LinkChanged { up });
+        for handler in &mut self.link_handlers {
+            handler(up);
+        }
+    }
+
+    #[cfg(any(test, feature = "tcp"))]
+    pub fn set_link_down_policy(&mut self, policy: LinkDownPolicy) {
+        self.link_down_policy = policy;
+    }
+
+    /// Registers a callback that is invoked with the new state on every carrier
+    /// change.
+    pub fn on_link_change<F>(&mut self, handler: F)
+        where F: FnMut(bool) + 'static
+    {
+        self.link_handlers.push(Box::new(handler));
+    }
+
+    /// Drives all time-based parts of the stack and returns the next frame that is
+    /// ready to be transmitted. `now` is a monotonic timestamp in milliseconds.
+    ///
+    /// This covers ARP cache expiry, gratuitous ARPs, and the retransmission and
+    /// `TimeWait` timers of the added TCP listeners. Call it periodically, e.g. from
+    /// the main loop or a timer interrupt, until it returns `None`.
+    ///
+    /// Frames that were sent to the address of this host, or to 127.0.0.0/8, are
+    /// received here without going through the driver.
+    pub fn poll(&mut self, now: u64) -> Option<Box<[u8]>> {
+        self.now = now;
+        // frames queued by the responses are received by the next call
+        for _ in 0..self.loopback.len() {
+            let frame = self.loopback.pop_front().unwrap();
+            let _ = self.receive_frame(&frame, None, true);
+        }
+        self.arp_cache
+            .retain(|_, &mut (_, learned)| now.saturating_sub(learned) < ARP_CACHE_TIMEOUT);
+        self.poll_announcements(now);
+        self.poll_arp_requests(now);
+        if let Some(probe) = self.acd.as_mut().and_then(|acd| acd.poll(now)) {
+            let _ = self.queue_frame(probe);
+        }
+        self.handle_acd_event(now);
+        #[cfg(any(test, feature = "tcp"))]
+        {
+            for &mut (ref mut listener, _) in &mut self.tcp_listeners {
+                listener.poll(now);
+            }
+            self.flush_tcp();
+        }
+        self.next_frame()
+    }
+
+    fn poll_announcements(&mut self, now: u64) {
+        if let Some((remaining, deadline)) = self.announcements {
+            if now >= deadline {
+                let _ = self.queue_frame(arp::new_gratuitous_packet(self.mac, self.ip));
+                self.announcements = if remaining > 1 {
+                    Some((remaining - 1, now + self.announce_interval))
+                } else {
+                    None
+                };
+            }
+        }
+    }
+
+    /// Repeats the ARP requests that weren't answered in time, and drops the packets
+    /// that wait for an address after the last retry.
+    fn poll_arp_requests(&mut self, now: u64) {
+        let expired: Vec<_> = self.arp_requests
+            .iter()
+            .filter(|&(_, &(_, _, deadline))| now >= deadline)
+            .map(|(&ip, &(retries, delay, _))| (ip, retries, delay))
+            .collect();
+        for (ip, retries, delay) in expired {
+            if self.arp_retry.exhausted(retries) {
+                self.arp_requests.remove(&ip);
+                let len = self.pending.len();
+                self.pending.retain(|&(hop, _)| hop != ip);
+                for _ in self.pending.len()..len {
+                    self.stats.record_drop();
+                }
+            } else {
+                let delay = self.arp_retry.next_delay(delay);
+                let deadline = now + self.arp_jitter(delay);
+                self.arp_requests.insert(ip, (retries + 1, delay, deadline));
+                let _ = self.queue_frame(arp::new_request_packet(self.mac, self.ip, ip));
+            }
+        }
+    }
+
+    fn arp_jitter(&mut self, delay: u64) -> u64 {
+        let random = self.next_random();
+        self.arp_retry.jitter(delay, random)
+    }
+
+    fn next_random(&mut self) -> u32 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 17;
+        self.random ^= self.random << 5;
+        self.random
+    }
+
+    fn handle_acd_event(&mut self, now: u64) {
+        match self.acd.as_mut().and_then(Acd::poll_event) {
+            Some(AcdEvent::Available(ip)) => {
+                self.set_ip(ip, now);
+                self.events.push(StackEvent::AddressAvailable(ip));
+            }
+            Some(AcdEvent::Conflict { ip, mac, given_up: true }) => {
+                self.acd = None;
+                if self.ip == ip {
+                    self.ip = Ipv4Address::new(0, 0, 0, 0);
+                    self.pending.clear();
+                    self.arp_requests.clear();
+                    self.announcements = None;
+                }
+                self.events.push(StackEvent::AddressConflict { ip, mac });
+            }
+            _ => {}
+        }
+    }
+
+    /// Replaces `DEFAULT_ARP_RETRY_POLICY`.
+    pub fn set_arp_retry_policy(&mut self, policy: Policy) {
+        self.arp_retry = policy;
+    }
+
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// Sets the largest IPv4 packet that is sent and received, e.g. `JUMBO_MTU` for
+    /// links with jumbo frames. The default is `DEFAULT_MTU`.
+    ///
+    /// The maximum segment size of the TCP listeners is derived from it.
+    pub fn set_mtu(&mut self, mtu: usize) {
+        self.mtu = mtu;
+        #[cfg(any(test, feature = "tcp"))]
+        {
+            let mss = self.tcp_mss();
+            for &mut (ref mut listener, _) in &mut self.tcp_listeners {
+                listener.set_mss(mss);
+            }
+        }
+    }
+
+    /// The largest TCP payload that fits into the MTU, leaving room for the IPv4
+    /// header and a TCP header with the maximum options.
+    #[cfg(any(test, feature = "tcp"))]
+    fn tcp_mss(&self) -> usize {
+        usize::max(self.mtu.saturating_sub(20 + 60), 1)
+    }
+
+    /// Sets the netmask of the local subnet. The default of 0.0.0.0 treats all
+    /// destinations as local.
+    pub fn set_netmask(&mut self, netmask: Ipv4Address) {
+        self.netmask = netmask;
+    }
+
+    #[cfg(any(test, feature = "icmp"))]
+    pub fn set_echo_policy(&mut self, policy: EchoPolicy) {
+        self.echo_policy = policy;
+        self.echo_replies = (self.now, 0);
+    }
+
+    /// Limits the generated ICMP messages, errors and echo replies, to bursts of
+    /// `burst` messages, refilled by one message every `interval` milliseconds
+    /// (RFC 1812, section 4.3.2.8). A burst of 0 disables them.
+    #[cfg(any(test, feature = "icmp"))]
+    pub fn set_icmp_rate_limit(&mut self, burst: u32, interval: u64) {
+        self.icmp_tokens = TokenBucket::new(burst, interval);
+    }
+
+    /// Ignores echo requests with more than `len` bytes of data, so that large pings
+    /// don't take up transmit buffers. `None` answers requests up to the MTU.
+    #[cfg(any(test, feature = "icmp"))]
+    pub fn set_max_echo_payload(&mut self, len: Option<usize>) {
+        self.max_echo_payload = len;
+    }
+
+    /// Whether packets to the broadcast address of the local subnet are accepted. This
+    /// is the default, disable it to ignore directed broadcasts sent from other
+    /// networks, e.g. in smurf attacks.
+    pub fn set_accept_directed_broadcasts(&mut self, accept: bool) {
+        self.accept_directed_broadcasts = accept;
+    }
+
+    /// Returns true for the limited broadcast address and the broadcast address of
+    /// the local subnet. Packets to them are sent to the broadcast MAC address.
+    pub fn is_broadcast(&self, dst: Ipv4Address) -> bool {
+        dst.is_broadcast() || dst.is_directed_broadcast(self.ip, self.netmask)
+    }
+
+    /// Whether a received packet to `dst` is for this host. Loopback addresses are
+    /// only accepted from `local` frames.
+    fn accepts(&self, dst: Ipv4Address, local: bool) -> bool {
+        if dst.is_loopback() {
+            local
+        } else if dst.is_directed_broadcast(self.ip, self.netmask) {
+            self.accept_directed_broadcasts
+        } else {
+            // without an address, e.g. during DHCP, unicast replies are accepted too
+            dst == self.ip || dst.is_broadcast() || dst.is_multicast() ||
+            self.ip.is_unspecified()
+        }
+    }
+
+    /// Sets the router for destinations outside of the local subnet.
+    pub fn set_gateway(&mut self, gateway: Option<Ipv4Address>) {
+        self.gateway = gateway;
+    }
+
+    /// Joins `group` for all sources and reports it to the multicast routers.
+    pub fn join_multicast(&mut self, group: Ipv4Address) -> Result<(), SendError> {
+        if self.multicast_groups.insert(group, None).is_none() {
+            self.add_multicast_mac(EthernetAddress::from_ipv4_multicast(group));
+        }
+        self.send_igmp_report(&[GroupRecord {
+                                    record_type: RecordType::ChangeToExclude,
+                                    group,
+                                    sources: &[],
+                                }])
+    }
+
+    /// Joins the source-specific multicast channel of `source` and `group`. Does
+    /// nothing if the group was joined for all sources.
+    pub fn join_ssm(&mut self, group: Ipv4Address, source: Ipv4Address) -> Result<(), SendError> {
+        if !self.multicast_groups.contains_key(&group) {
+            self.add_multicast_mac(EthernetAddress::from_ipv4_multicast(group));
+        }
+        {
+            let sources = self.multicast_groups.entry(group).or_insert_with(|| Some(Vec::new()));
+            match *sources {
+                Some(ref mut sources) if !sources.contains(&source) => sources.push(source),
+                _ => return Ok(()),
+            }
+        }
+        self.send_igmp_report(&[GroupRecord {
+                                    record_type: RecordType::AllowNewSources,
+                                    group,
+                                    sources: &[source],
+                                }])
+    }
+
+    /// Leaves `source` of a channel joined by `join_ssm`.
+    pub fn leave_ssm(&mut self, group: Ipv4Address, source: Ipv4Address) -> Result<(), SendError> {
+        let removed = match self.multicast_groups.get_mut(&group) {
+            Some(&mut Some(ref mut sources)) => {
+                let len = sources.len();
+                sources.retain(|&s| s != source);
+                sources.len() < len
+            }
+            _ => false,
+        };
+        if !removed {
+            return Ok(());
+        }
+        if self.multicast_groups[&group].as_ref().map_or(false, |s| s.is_empty()) {
+            self.multicast_groups.remove(&group);
+            self.remove_multicast_mac(EthernetAddress::from_ipv4_multicast(group));
+        }
+        self.send_igmp_report(&[GroupRecord {
+                                    record_type: RecordType::BlockOldSources,
+                                    group,
+                                    sources: &[source],
+                                }])
+    }
+
+    /// Leaves `group` with all its sources.
+    pub fn leave_multicast(&mut self, group: Ipv4Address) -> Result<(), SendError> {
+        let removed = self.multicast_groups.remove(&group);
+        if removed.is_some() {
+            self.remove_multicast_mac(EthernetAddress::from_ipv4_multicast(group));
+        }
+        match removed {
+            Some(None) => {
+                self.send_igmp_report(&[GroupRecord {
+                                            record_type: RecordType::ChangeToInclude,
+                                            group,
+                                            sources: &[],
+                                        }])
+            }
+            Some(Some(sources)) => {
+                self.send_igmp_report(&[GroupRecord {
+                                            record_type: RecordType::BlockOldSources,
+                                            group,
+                                            sources: &sources,
+                                        }])
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Accepts frames to the multicast address `mac`, e.g. for mDNS or LLDP. The
+    /// addresses of joined IPv4 groups are added automatically.
+    ///
+    /// Addresses are reference counted, so that every `add_multicast_mac` needs a
+    /// `remove_multicast_mac` before frames to the address are dropped again.
+    pub fn add_multicast_mac(&mut self, mac: EthernetAddress) {
+        match self.multicast_macs.iter().position(|&m| m == mac) {
+            Some(index) => self.multicast_refs[index] += 1,
+            None => {
+                self.multicast_macs.push(mac);
+                self.multicast_refs.push(1);
+                self.update_multicast_filter();
+            }
+        }
+    }
+
+    pub fn remove_multicast_mac(&mut self, mac: EthernetAddress) {
+        let index = match self.multicast_macs.iter().position(|&m| m == mac) {
+            Some(index) => index,
+            None => return,
+        };
+        self.multicast_refs[index] -= 1;
+        if self.multicast_refs[index] == 0 {
+            self.multicast_macs.remove(index);
+            self.multicast_refs.remove(index);
+            self.update_multicast_filter();
+        }
+    }
+
+    /// The accepted multicast addresses, including the all-systems group.
+    pub fn multicast_macs(&self) -> &[EthernetAddress] {
+        &self.multicast_macs
+    }
+
+    /// Registers the callback of a driver that programs the multicast filter of the
+    /// hardware, e.g. a hash filter with `filter::multicast_hash`. It is invoked with
+    /// the accepted addresses now and whenever they change.
+    ///
+    /// It returns whether the hardware filters exactly. Otherwise, e.g. after hash
+    /// collisions or when the list doesn't fit into the perfect filter, multicast
+    /// frames to other addresses are dropped by `receive`.
+    pub fn on_multicast_filter_change<F>(&mut self, handler: F)
+        where F: FnMut(&[EthernetAddress]) -> bool + 'static
+    {
+        self.multicast_filter_handler = Some(Box::new(handler));
+        self.update_multicast_filter();
+    }
+
+    fn update_multicast_filter(&mut self) {
+        if let Some(ref mut handler) = self.multicast_filter_handler {
+            self.software_multicast_filter = !handler(&self.multicast_macs);
+        }
+    }
+
+    /// Answers a general (`group` 0.0.0.0) or group-specific query with the current
+    /// state. The answer is sent immediately instead of after a random delay.
+    fn handle_igmp_query(&mut self, group: Ipv4Address) {
+        let groups: Vec<_> = self.multicast_groups
+            .iter()
+            .filter(|&(&g, _)| group.is_unspecified() || g == group)
+            .map(|(&g, sources)| (g, sources.clone()))
+            .collect();
+        let records: Vec<_> = groups.iter()
+            .map(|&(group, ref sources)| match *sources {
+                     None => {
+                         GroupRecord {
+                             record_type: RecordType::ModeIsExclude,
+                             group,
+                             sources: &[],
+                         }
+                     }
+                     Some(ref sources) => {
+                         GroupRecord {
+                             record_type: RecordType::ModeIsInclude,
+                             group,
+                             sources,
+                         }
+                     }
+                 })
+            .collect();
+        if !records.is_empty() {
+            let _ = self.send_igmp_report(&records);
+        }
+    }
+
+    fn send_igmp_report(&mut self, records: &[GroupRecord]) -> Result<(), SendError> {
+        let report = MembershipReport { records };
+        self.send_ipv4(Ipv4Packet::new_igmp_report(self.ip, report))
+    }
+
+    /// Returns the traffic counters of the interface and its TCP listeners.
+    pub fn stats(&self) -> Stats {
+        let stats = self.stats;
+        #[cfg(any(test, feature = "tcp"))]
+        let stats = Stats {
+            tcp_retransmissions: self.tcp_listeners
+                .iter()
+                .fold(0, |sum, &(ref l, _)| sum.wrapping_add(l.retransmitted_segments())),
+            ..stats
+        };
+        stats
+    }
+
+    pub fn arp_cache_lookup(&self, ip: Ipv4Address) -> Option<EthernetAddress> {
+        self.arp_cache.get(&ip).map(|&(mac, _)| mac)
+    }
+
+    /// Adds an address mapping, e.g. for a peer that doesn't answer ARP requests.
+    /// Like learned entries, it expires after `ARP_CACHE_TIMEOUT`.
+    pub fn arp_cache_insert(&mut self, ip: Ipv4Address, mac: EthernetAddress) {
+        self.arp_cache.insert(ip, (mac, self.now));
+    }
+
+    /// The allocator for `allocate_port`, e.g. for changing the port range. It is
+    /// seeded from the MAC address, so it should be replaced by one with a random
+    /// seed if one is available.
+    pub fn port_allocator(&mut self) -> &mut PortAllocator {
+        &mut self.ports
+    }
+
+    /// Returns an ephemeral port that is neither bound for UDP nor used by a TCP
+    /// listener, for use as the source port of a new client connection or socket.
+    pub fn allocate_port(&mut self) -> Option<u16> {
+        let udp_sockets = &self.udp_sockets;
+        #[cfg(any(test, feature = "tcp"))]
+        let tcp_listeners = &self.tcp_listeners;
+        self.ports.allocate(|port| {
+            #[cfg(any(test, feature = "tcp"))]
+            {
+                if tcp_listeners.iter().any(|&(ref listener, _)| listener.local().port == port) {
+                    return true;
+                }
+            }
+            udp_sockets.contains_key(&port)
+        })
+    }
+
+    /// Adds a listener whose connections are driven by `receive` and `poll`.
+    ///
+    /// Received data is passed to `handler`, like in `TcpListener::handle_packet`.
+    /// Outgoing segments are sent automatically.
+    ///
+    /// The MSS of the listener is derived from the MTU, and its ISN key from the
+    /// random generator of the interface, which is seeded from the MAC address. A
+    /// random key can be set through `tcp_listener`.
+    #[cfg(any(test, feature = "tcp"))]
+    pub fn add_tcp_listener<F>(&mut self, mut listener: TcpListener, handler: F)
+        where for<'d> F: FnMut(&TcpConnection, &'d [u8]) -> Option<Cow<'d, [u8]>> + 'static
+    {
+        listener.set_mss(self.tcp_mss());
+        listener.set_isn_key(self.next_random());
+        self.tcp_listeners.push((listener, Box::new(handler)));
+    }
+
+    /// Returns the listener for a local port, e.g. to send data or fetch events.
+    #[cfg(any(test, feature = "tcp"))]
+    pub fn tcp_listener(&mut self, port: u16) -> Option<&mut TcpListener> {
+        self.tcp_listeners
+            .iter_mut()
+            .map(|&mut (ref mut listener, _)| listener)
+            .find(|listener| listener.local().port == port)
+    }
+
+    /// Starts queueing the datagrams received on a local UDP port, up to
+    /// `UDP_QUEUE_LEN`. Further datagrams are dropped until the queue is drained.
+    pub fn bind_udp(&mut self, port: u16) {
+        self.udp_sockets.entry(port).or_insert_with(VecDeque::new);
+    }
+
+    pub fn unbind_udp(&mut self, port: u16) {
+        self.udp_sockets.remove(&port);
+    }
+
+    /// Returns the oldest datagram received on a bound port and its sender.
+    pub fn receive_udp(&mut self, port: u16) -> Option<(Endpoint, Box<[u8]>)> {
+        self.receive_udp_timestamped(port).map(|(src, _, data)| (src, data))
+    }
+
+    /// Like `receive_udp`, but also returns the timestamp that was passed to
+    /// `receive_timestamped` with the frame.
+    pub fn receive_udp_timestamped(&mut self,
+                                   port: u16)
+                                   -> Option<(Endpoint, Option<u64>, Box<[u8]>)> {
+        self.udp_sockets.get_mut(&port).and_then(|queue| queue.pop_front())
+    }
+
+    /// Sends the queued segments of all TCP connections.
+    #[cfg(any(test, feature = "tcp"))]
+    fn flush_tcp(&mut self) {
+        let mut segments = Vec::new();
+        for &mut (ref mut listener, _) in &mut self.tcp_listeners {
+            while let Some(reset) = listener.next_reset() {
+                segments.push(reset);
+            }
+            for connection in listener.connections() {
+                let remote = connection.id().remote.addr;
+                while let Some(segment) = connection.next_segment() {
+                    segments.push((remote, segment));
+                }
+            }
+        }
+        for (remote, segment) in segments {
+            let _ = self.send_ipv4(Ipv4Packet::new_tcp(self.ip, remote, &segment));
+        }
+        self.update_tcp_events();
+    }
+
+    /// Reports connections that completed the handshake or went away since the last
+    /// call. The events of the listeners are left for the application.
+    #[cfg(any(test, feature = "tcp"))]
+    fn update_tcp_events(&mut self) {
+        if self.events.capacity() == 0 {
+            return;
+        }
+        let mut open = BTreeSet::new();
+        for &mut (ref mut listener, _) in &mut self.tcp_listeners {
+            for connection in listener.connections() {
+                match connection.state() {
+                    TcpState::Closed | TcpState::Listen | TcpState::SynSent |
+                    TcpState::SynReceived | TcpState::TimeWait => {}
+                    _ => {
+                        open.insert(connection.id());
+                    }
+                }
+            }
+        }
+        for &id in open.difference(&self.connected) {
+            self.events.push(StackEvent::TcpConnected(id));
+        }
+        for &id in self.connected.difference(&open) {
+            self.events.push(StackEvent::TcpClosed(id));
+        }
+        self.connected = open;
+    }
+
+    /// Installs a callback that is invoked with every frame passed to `receive` and
+    /// every frame returned by `next_frame` or `poll`, e.g. to mirror the traffic to
+    /// a debug UART or a pcap writer.
+    pub fn set_tap(&mut self, tap: Option<Tap>) {
+        self.tap = tap;
+    }
+
+    /// Returns the next frame that is ready to be transmitted.
+    pub fn next_frame(&mut self) -> Option<Box<[u8]>> {
+        let frame = self.tx_queue.pop_front();
+        if let Some(ref frame) = frame {
+            self.stats.record_tx(frame);
+            if let Some(tap) = self.tap {
+                tap(frame, Direction::Tx);
+            }
+        }
+        frame
+    }
+
+    /// Sends an IPv4 packet to its destination, or to the gateway if the destination
+    /// is outside of the local subnet.
+    ///
+    /// If the MAC address of the next hop is unknown, an ARP request is sent and the
+    /// packet is queued until the response arrives. It is dropped when the request
+    /// stays unanswered after the retries of the ARP retry policy.
+    pub fn send_ipv4<T: WriteOut>(&mut self, packet: Ipv4Packet<T>) -> Result<(), SendError> {
+        if packet.len() > self.mtu {
+            return Err(SendError::ExceedsMtu {
+                           len: packet.len(),
+                           mtu: self.mtu,
+                       });
+        }
+        let next_hop = self.next_hop(packet.header.dst_addr).ok_or(SendError::NoRoute)?;
+        match self.resolve(next_hop) {
+            Some(dst_mac) => self.queue_frame(EthernetPacket::new_ipv4(self.mac, dst_mac, packet)),
+            None => {
+                if self.pending.len() >= MAX_PENDING_PACKETS {
+                    self.stats.record_drop();
+                    return Err(SendError::QueueFull);
+                }
+                self.stats.record_arp_miss();
+                let mut ip_packet = HeapTxPacket::new(packet.len());
+                packet.write_out(&mut ip_packet).map_err(|()| SendError::Failed)?;
+
+                if !self.pending.iter().any(|&(hop, _)| hop == next_hop) {
+                    let request = arp::new_request_packet(self.mac, self.ip, next_hop);
+                    self.queue_frame(request)?;
+                    let delay = self.arp_retry.initial_delay;
+                    let deadline = self.now + self.arp_jitter(delay);
+                    self.arp_requests.insert(next_hop, (0, delay, deadline));
+                }
+                self.pending.push_back((next_hop, ip_packet.into_boxed_slice()));
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns true if `dst` is in the local subnet or a gateway is set.
+    pub fn has_route(&self, dst: Ipv4Address) -> bool {
+        self.next_hop(dst).is_some()
+    }
+
+    fn next_hop(&self, dst: Ipv4Address) -> Option<Ipv4Address> {
+        let (ip, netmask) = (self.ip.as_bytes(), self.netmask.as_bytes());
+        let dst_bytes = dst.as_bytes();
+        let local = (0..4).all(|i| dst_bytes[i] & netmask[i] == ip[i] & netmask[i]);
+        if local || dst.is_broadcast() || dst.is_multicast() || dst.is_loopback() {
+            Some(dst)
+        } else {
+            self.gateway
+        }
+    }
+
+    fn resolve(&self, ip: Ipv4Address) -> Option<EthernetAddress> {
+        if self.is_broadcast(ip) {
+            Some(EthernetAddress::broadcast())
+        } else if ip.is_multicast() {
+            Some(EthernetAddress::from_ipv4_multicast(ip))
+        } else if ip.is_loopback() || (ip == self.ip && !ip.is_unspecified()) {
+            Some(self.mac)
+        } else {
+            self.arp_cache_lookup(ip)
+        }
+    }
+
+    /// Serializes `frame` into the transmit queue, padded to the minimum Ethernet
+    /// frame size. Frames to our own MAC address go to the loopback queue instead,
+    /// which works without link. Payloads larger than the MTU are rejected.
+    fn queue_frame<T: WriteOut>(&mut self, frame: EthernetPacket<T>) -> Result<(), SendError> {
+        if frame.payload.len() > self.mtu {
+            return Err(SendError::ExceedsMtu {
+                           len: frame.payload.len(),
+                           mtu: self.mtu,
+                       });
+        }
+        let local = frame.header.dst_addr == self.mac;
+        if local && self.loopback.len() >= LOOPBACK_QUEUE_LEN {
+            self.stats.record_drop();
+            return Err(SendError::QueueFull);
+        }
+        if !local && !self.link_up {
+            return Err(SendError::LinkDown);
+        }
+        let mut tx_packet = HeapTxPacket::new(usize::max(frame.len(), 60));
+        frame.write_out(&mut tx_packet).map_err(|()| SendError::Failed)?;
+        let padding = tx_packet.capacity() - tx_packet.len();
+        tx_packet.push_bytes(&[0; 60][..padding]).map_err(|()| SendError::Failed)?;
+        if local {
+            self.loopback.push_back(tx_packet.into_boxed_slice());
+        } else {
+            self.tx_queue.push_back(tx_packet.into_boxed_slice());
+        }
+        Ok(())
+    }
+
+    fn handle_arp(&mut self, arp: &ArpPacket) {
+        let now = self.now;
+        if arp.src_ip == self.ip && arp.src_mac != self.mac && !self.ip.is_unspecified() {
+            self.events.push(StackEvent::ArpConflict { mac: arp.src_mac });
+            if let Some(defense) = self.acd.as_mut().and_then(|acd| acd.defend(arp.src_mac, now)) {
+                let _ = self.queue_frame(defense);
+            }
+        } else if let Some(ref mut acd) = self.acd {
+            acd.handle_arp(arp);
+        }
+        self.handle_acd_event(now);
+        if arp.dst_ip != self.ip {
+            return;
+        }
+        self.arp_cache.insert(arp.src_ip, (arp.src_mac, now));
+        if arp.operation == ArpOperation::Request {
+            let _ = self.queue_frame(arp.response_packet(self.mac));
+        }
+
+        // flush the packets that waited for this address
+        self.arp_requests.remove(&arp.src_ip);
+        let mut i = 0;
+        while i < self.pending.len() {
+            if self.pending[i].0 == arp.src_ip {
+                let (_, ip_packet) = self.pending.remove(i).unwrap();
+                let frame =
+                    EthernetPacket::new(self.mac, arp.src_mac, EtherType::Ipv4, &ip_packet[..]);
+                let _ = self.queue_frame(frame);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Registers a handler for IPv4 packets with the given protocol number.
+    ///
+    /// Only protocols that are not parsed by this crate (`Ipv4Kind::Unknown`) are
+    /// delivered, and IP-in-IP packets, see `ipip::decapsulate`. Replaces any handler
+    /// previously registered for the protocol.
+    pub fn register_raw_handler<F>(&mut self, protocol: u8, mut handler: F)
+        where F: FnMut(&Ipv4Header, &[u8]) + 'static
+    {
+        self.register_ip_protocol_handler(protocol, move |header, data| {
+            handler(header, data);
+            None
+        });
+    }
+
+    /// Like `register_raw_handler`, but the handler can answer with a payload.
+    pub fn register_ip_protocol_handler<F>(&mut self, protocol: u8, handler: F)
+        where F: FnMut(&Ipv4Header, &[u8]) -> Option<Vec<u8>> + 'static
+    {
+        self.raw_handlers.insert(protocol, Box::new(handler));
+    }
+
+    pub fn unregister_raw_handler(&mut self, protocol: u8) {
+        self.raw_handlers.remove(&protocol);
+    }
+
+    /// Registers a handler for frames with the given EtherType, e.g. for a
+    /// proprietary layer 2 protocol.
+    ///
+    /// Only EtherTypes that are not parsed by this crate (`EtherType::Unknown`) are
+    /// delivered. Replaces any handler previously registered for the EtherType.
+    pub fn register_ether_type_handler<F>(&mut self, ether_type: u16, handler: F)
+        where F: FnMut(&EthernetHeader, &[u8]) -> Option<Vec<u8>> + 'static
+    {
+        self.ether_type_handlers.insert(ether_type, Box::new(handler));
+    }
+
+    pub fn unregister_ether_type_handler(&mut self, ether_type: u16) {
+        self.ether_type_handlers.remove(&ether_type);
+    }
+
+    /// Passes a frame with an unknown EtherType to its handler. Returns false if
+    /// there is none.
+    fn handle_ether_type(&mut self, frame: &[u8]) -> bool {
+        let ethernet = match EthernetPacket::<&[u8]>::parse(frame) {
+            Ok(ethernet) => ethernet,
+            Err(_) => return false,
+        };
+        let number = match ethernet.header.ether_type {
+            EtherType::Unknown(number) => number,
+            _ => return false,
+        };
+        let response = match self.ether_type_handlers.get_mut(&number) {
+            Some(handler) => handler(&ethernet.header, ethernet.payload),
+            None => return false,
+        };
+        if let Some(response) = response {
+            let frame = EthernetPacket::new(self.mac,
+                                            ethernet.header.src_addr,
+                                            ethernet.header.ether_type,
+                                            &response[..]);
+            let _ = self.queue_frame(frame);
+        }
+        true
+    }
+
+    /// Frames that are longer than the MTU plus the Ethernet header and frame check
+    /// sequence are rejected.
+    pub fn receive(&mut self, frame: &[u8]) -> Result<(), ParseError> {
+        self.receive_frame(frame, None, false)
+    }
+
+    /// Like `receive`, with the arrival time of the frame, e.g. a hardware timestamp
+    /// in nanoseconds. It is passed on to UDP sockets and TCP connections.
+    pub fn receive_timestamped(&mut self, frame: &[u8], timestamp: u64) -> Result<(), ParseError> {
+        self.receive_frame(frame, Some(timestamp), false)
+    }
+
+    /// Answers an echo request to our address, as far as the echo policy allows.
+    #[cfg(any(test, feature = "icmp"))]
+    fn handle_echo_request(&mut self, header: &Ipv4Header, icmp: &IcmpKind) {
+        let request = match icmp.packet() {
+            Some(request) => request,
+            None => return,
+        };
+        if header.dst_addr != self.ip && !header.dst_addr.is_loopback() {
+            return;
+        }
+        if self.max_echo_payload.map_or(false, |max| request.data.len() > max) {
+            self.stats.record_dropped_echo_request();
+            return;
+        }
+        match self.echo_policy {
+            EchoPolicy::Respond => {}
+            EchoPolicy::Ignore => {
+                self.stats.record_dropped_echo_request();
+                return;
+            }
+            EchoPolicy::RateLimit { count, interval } => {
+                if self.now >= self.echo_replies.0 + interval {
+                    self.echo_replies = (self.now, 0);
+                }
+                if self.echo_replies.1 >= count {
+                    self.stats.record_dropped_echo_request();
+                    return;
+                }
+                self.echo_replies.1 += 1;
+            }
+        }
+        if !self.icmp_tokens.take(self.now) {
+            self.stats.record_dropped_echo_request();
+            return;
+        }
+        let reply = Ipv4Packet::new_icmp(header.dst_addr, header.src_addr, request.echo_reply());
+        let _ = self.send_ipv4(reply);
+    }
+
+    /// Passes a packet of a protocol that isn't handled by the stack to its raw
+    /// handler and sends the response, if any.
+    #[cfg_attr(not(any(test, feature = "icmp")), allow(unused_variables))]
+    fn handle_raw_packet(&mut self, frame: &[u8], header: &Ipv4Header, number: u8, data: &[u8]) {
+        let response = match self.raw_handlers.get_mut(&number) {
+            Some(handler) => handler(header, data),
+            #[cfg(any(test, feature = "icmp"))]
+            None if header.dst_addr == self.ip => {
+                let type_ = IcmpType::DestinationUnreachable {
+                    code: icmp::UNREACHABLE_PROTOCOL,
+                    next_hop_mtu: 0,
+                };
+                self.send_icmp_error(frame, type_);
+                None
+            }
+            None => None,
+        };
+        if let Some(response) = response {
+            let _ = self.send_ipv4(Ipv4Packet::new_raw(self.ip,
+                                                       header.src_addr,
+                                                       header.protocol(),
+                                                       &response[..]));
+        }
+    }
+
+    /// Answers a received Ethernet frame with an ICMP error message, e.g. a
+    /// `DestinationUnreachable` or `TimeExceeded`, that quotes its IPv4 datagram.
+    ///
+    /// Following RFC 1812, section 4.3.2.7, no error is sent for ICMP errors,
+    /// fragments other than the first, datagrams to broadcast or multicast addresses
+    /// or sent as link-layer broadcasts, and datagrams whose source isn't a single
+    /// host. Returns false if the error was suppressed or exceeded the rate limit.
+    #[cfg(any(test, feature = "icmp"))]
+    pub fn send_icmp_error(&mut self, frame: &[u8], type_: IcmpType) -> bool {
+        if frame.len() < 14 + 20 || frame[0] & 1 != 0 || self.ip.is_unspecified() {
+            return false;
+        }
+        if EtherType::from_number(NetworkEndian::read_u16(&frame[12..14])) != EtherType::Ipv4 {
+            return false;
+        }
+        let datagram = &frame[14..];
+        let header_len = usize::from(datagram[0] & 0xf) * 4;
+        let src = Ipv4Address::from_bytes(&datagram[12..16]);
+        let dst = Ipv4Address::from_bytes(&datagram[16..20]);
+        let fragment = NetworkEndian::read_u16(&datagram[6..8]) & 0x1fff != 0;
+        let icmp_error = datagram[9] == IpProtocol::Icmp.number() &&
+                         datagram.get(header_len).map_or(true, |&t| icmp::is_error_type(t));
+        let single_host = !(src.is_unspecified() || self.is_broadcast(src) ||
+                            src.is_multicast() || src.as_bytes()[0] >= 240);
+        if fragment || icmp_error || !single_host || self.is_broadcast(dst) ||
+           dst.is_multicast() {
+            return false;
+        }
+        if !self.icmp_tokens.take(self.now) {
+            self.stats.record_drop();
+            return false;
+        }
+        let message = IcmpPacket {
+            type_,
+            data: icmp::quote(datagram),
+        };
+        self.send_ipv4(Ipv4Packet::new_icmp(self.ip, src, message)).is_ok()
+    }
+
+    /// Receives a frame from the driver, or a `local` one from the loopback queue.
+    fn receive_frame(&mut self,
+                     frame: &[u8],
+                     timestamp: Option<u64>,
+                     local: bool)
+                     -> Result<(), ParseError> {
+        self.stats.record_rx(frame);
+        if let Some(tap) = self.tap {
+            tap(frame, Direction::Rx);
+        }
+        let max_len = self.mtu + ethernet::MAX_OVERHEAD;
+        if frame.len() > max_len {
+            let error = ParseError::malformed(Layer::Ethernet, max_len, "frame exceeds MTU");
+            self.stats.record_parse_error(&error);
+            return Err(error);
+        }
+        if self.software_multicast_filter && !local && frame.len() >= 6 &&
+           frame[0] & 1 != 0 {
+            let filter = FrameFilter {
+                multicast: &self.multicast_macs,
+                ..FrameFilter::new(self.mac)
+            };
+            if filter.check(frame) == Verdict::Drop {
+                self.stats.record_drop();
+                return Ok(());
+            }
+        }
+        if self.handle_ether_type(frame) {
+            return Ok(());
+        }
+        let packet = match parse(frame) {
+            Ok(packet) => packet,
+            Err(error) => {
+                self.stats.record_parse_error(&error);
+                return Err(error);
+            }
+        };
+        match packet.payload {
+            EthernetKind::Arp(arp) => self.handle_arp(&arp),
+            EthernetKind::Ipv4(ref ip) if !self.accepts(ip.header.dst_addr, local) => {}
+            EthernetKind::Ipv4(ip) => {
+                match ip.payload {
+                    Ipv4Kind::Igmp(IgmpMessage::Query { group, .. }) => {
+                        self.handle_igmp_query(group)
+                    }
+                    #[cfg(any(test, feature = "icmp"))]
+                    Ipv4Kind::Icmp(icmp @ IcmpKind::EchoRequest { .. }) => {
+                        self.handle_echo_request(&ip.header, &icmp)
+                    }
+                    Ipv4Kind::Unknown(number, data) => {
+                        self.handle_raw_packet(frame, &ip.header, number, data)
+                    }
+                    // tunneled packets are left to the handler of their protocol too
+                    Ipv4Kind::Ipv4(_, data) => {
+                        let number = IpProtocol::IpInIp.number();
+                        self.handle_raw_packet(frame, &ip.header, number, data)
+                    }
+                    Ipv4Kind::Udp(UdpPacket { header, payload: UdpKind::Unknown(data) }) |
+                    Ipv4Kind::Udp(UdpPacket { header, payload: UdpKind::Decoded(_, data) }) => {
+                        let src = Endpoint::new(ip.header.src_addr, header.src_port);
+                        if let Some(queue) = self.udp_sockets.get_mut(&header.dst_port) {
+                            if queue.len() < UDP_QUEUE_LEN {
+                                queue.push_back((src, timestamp, Box::from(data)));
+                            } else {
+                                self.stats.record_drop();
+                            }
+                        } else if ip.header.dst_addr == self.ip {
+                            #[cfg(any(test, feature = "icmp"))]
+                            {
+                                let type_ = IcmpType::DestinationUnreachable {
+                                    code: icmp::UNREACHABLE_PORT,
+                                    next_hop_mtu: 0,
+                                };
+                                self.send_icmp_error(frame, type_);
+                            }
+                        }
+                    }
+                    #[cfg(any(test, feature = "tcp"))]
+                    Ipv4Kind::Tcp(TcpPacket { header, payload: TcpKind::Unknown(data) }) => {
+                        if ip.header.dst_addr == self.ip || ip.header.dst_addr.is_loopback() {
+                            let tcp = TcpPacket {
+                                header,
+                                payload: data,
+                            };
+                            for &mut (ref mut listener, ref mut handler) in
+                                &mut self.tcp_listeners {
+                                listener.handle_timestamped_packet(ip.header.src_addr,
+                                                                   timestamp,
+                                                                   &tcp,
+                                                                   &mut **handler);
+                            }
+                            self.flush_tcp();
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn raw_handler() {
+    use core::cell::RefCell;
+    use std::rc::Rc;
+    use std::vec::Vec;
+    use ethernet::EthernetPacket;
+    use {HeapTxPacket, TxPacket, WriteOut};
+
+    let mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let ip = Ipv4Address::new(192, 168, 1, 2);
+    let mut interface = Interface::new(mac, ip);
+
+    let received = Rc::new(RefCell::new(Vec::new()));
+    let received_clone = received.clone();
+    interface.register_raw_handler(89, move |header, data| {
+        assert_eq!(header.protocol(), IpProtocol::Unknown(89));
+        received_clone.borrow_mut().extend_from_slice(data);
+    });
+
+    let payload = [1, 2, 3, 4, 5];
+    let ip_packet = Ipv4Packet::new_raw(Ipv4Address::new(192, 168, 1, 1),
+                                        ip,
+                                        IpProtocol::Unknown(89),
+                                        &payload[..]);
+    let frame = EthernetPacket::new_ipv4(EthernetAddress::broadcast(), mac, ip_packet);
+    let mut packet = HeapTxPacket::new(60);
+    frame.write_out(&mut packet).unwrap();
+    packet.push_bytes(&[0; 60][frame.len()..]).unwrap();
+
+    interface.receive(packet.as_slice()).unwrap();
+    assert_eq!(received.borrow().as_slice(), &payload);
+}
+
+#[test]
+fn raw_handler_ipip() {
+    use core::cell::RefCell;
+    use std::rc::Rc;
+    use std::vec::Vec;
+    use ethernet::EthernetPacket;
+    use udp::UdpPacket;
+    use HeapTxPacket;
+
+    let mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let ip = Ipv4Address::new(192, 168, 1, 2);
+    let mut interface = Interface::new(mac, ip);
+
+    let received = Rc::new(RefCell::new(Vec::new()));
+    let received_clone = received.clone();
+    interface.register_raw_handler(4, move |header, data| {
+        assert_eq!(header.protocol(), IpProtocol::IpInIp);
+        received_clone.borrow_mut().extend_from_slice(data);
+    });
+
+    let peer = Ipv4Address::new(10, 1, 0, 7);
+    let inner = Ipv4Packet::new_udp(peer, peer, UdpPacket::new(1000, 7, &b"echo"[..]));
+    let outer = Ipv4Packet::new_ipip(Ipv4Address::new(192, 168, 1, 1), ip, inner);
+    let frame = EthernetPacket::new_ipv4(EthernetAddress::broadcast(), mac, outer);
+    let frame = HeapTxPacket::write_out(frame).unwrap();
+
+    interface.receive(frame.as_slice()).unwrap();
+    assert_eq!(received.borrow().as_slice(), &frame.as_slice()[34..]);
+}
+
+#[test]
+fn send_ipv4_resolves_gateway() {
+    use arp::ArpPacket;
+    use parse::parse;
+    use udp::UdpPacket;
+
+    let mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let ip = Ipv4Address::new(192, 168, 1, 2);
+    let gateway = Ipv4Address::new(192, 168, 1, 1);
+    let gateway_mac = EthernetAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    let mut interface = Interface::new(mac, ip);
+    interface.set_netmask(Ipv4Address::new(255, 255, 255, 0));
+
+    let remote = Ipv4Address::new(8, 8, 8, 8);
+    let udp = || Ipv4Packet::new_udp(ip, remote, UdpPacket::new(1000, 53, &[1, 2, 3][..]));
+    assert!(interface.send_ipv4(udp()).is_err()); // no route
+    interface.set_gateway(Some(gateway));
+    interface.send_ipv4(udp()).unwrap();
+    interface.send_ipv4(udp()).unwrap();
+
+    // only one ARP request for the gateway
+    let request = interface.next_frame().unwrap();
+    match parse(&request).unwrap().payload {
+        EthernetKind::Arp(arp) => assert_eq!(arp.dst_ip, gateway),
+        other => panic!("unexpected packet {:?}", other),
+    }
+    assert!(interface.next_frame().is_none());
+
+    let reply = ArpPacket {
+        operation: ArpOperation::Response,
+        src_mac: gateway_mac,
+        dst_mac: mac,
+        src_ip: gateway,
+        dst_ip: ip,
+    };
+    let mut frame = HeapTxPacket::new(60);
+    EthernetPacket::new_arp(gateway_mac, mac, reply).write_out(&mut frame).unwrap();
+    frame.push_bytes(&[0; 18]).unwrap();
+    interface.receive(&frame).unwrap();
+    assert_eq!(interface.arp_cache_lookup(gateway), Some(gateway_mac));
+
+    for _ in 0..2 {
+        let frame = interface.next_frame().unwrap();
+        assert_eq!(&frame[0..6], &gateway_mac.as_bytes());
+        assert_eq!(&frame[30..34], &remote.as_bytes());
+    }
+    assert!(interface.next_frame().is_none());
+
+    // resolved addresses are sent directly
+    interface.send_ipv4(udp()).unwrap();
+    assert_eq!(&interface.next_frame().unwrap()[0..6], &gateway_mac.as_bytes());
+}
+
+#[test]
+fn link_up_announcements() {
+    use parse::parse;
+
+    let mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let ip = Ipv4Address::new(192, 168, 1, 2);
+    let mut interface = Interface::new(mac, ip);
+    interface.set_announcements(3, 1000);
+
+    let announced_ip = |frame: Box<[u8]>| match parse(&frame).unwrap().payload {
+        EthernetKind::Arp(arp) => {
+            assert_eq!(arp.src_ip, arp.dst_ip);
+            assert_eq!(arp.src_mac, mac);
+            arp.src_ip
+        }
+        other => panic!("unexpected packet {:?}", other),
+    };
+
+    interface.link_up(5000);
+    assert_eq!(announced_ip(interface.next_frame().unwrap()), ip);
+    assert!(interface.poll(5999).is_none());
+    assert_eq!(announced_ip(interface.poll(6000).unwrap()), ip);
+
+    // a new address restarts the burst
+    let new_ip = Ipv4Address::new(192, 168, 1, 3);
+    interface.set_ip(new_ip, 6500);
+    let mut announcements = 0;
+    for now in 6500..10000 {
+        while let Some(frame) = interface.poll(now) {
+            assert_eq!(announced_ip(frame), new_ip);
+            announcements += 1;
+        }
+    }
+    assert_eq!(announcements, 3);
+}
+
+#[test]
+fn poll_drives_timers() {
+    use ipv4::Endpoint;
+    use tcp::{TcpFlags, TcpListener, INITIAL_RETRANSMISSION_TIMEOUT};
+    use testing::{frames, tcp_ack_for};
+
+    let mac = EthernetAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    let ip = Ipv4Address::new(192, 168, 1, 1);
+    let client = Ipv4Address::new(192, 168, 1, 100);
+    let mut interface = Interface::new(mac, ip);
+    interface.add_tcp_listener(TcpListener::new(Endpoint::new(ip, 80), 4, 2), |_, _| None);
+    interface.arp_cache_insert(client, EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]));
+
+    let flags = |frame: Box<[u8]>| match parse(&frame).unwrap().payload {
+        EthernetKind::Ipv4(Ipv4Packet { payload: Ipv4Kind::Tcp(tcp), .. }) => {
+            tcp.header.flags
+        }
+        other => panic!("unexpected packet {:?}", other),
+    };
+
+    interface.receive(&hex!(frames::TCP_SYN)).unwrap();
+    let syn_ack = interface.poll(0).unwrap();
+    interface.receive(&tcp_ack_for(&syn_ack)).unwrap();
+    assert_eq!(flags(syn_ack), TcpFlags::SYN | TcpFlags::ACK);
+    assert!(interface.poll(0).is_none());
+
+    let remote = Endpoint::new(client, 0xc000);
+    interface.tcp_listener(80).unwrap().connection(remote).unwrap().send(b"hello");
+    assert_eq!(flags(interface.poll(0).unwrap()), TcpFlags::ACK | TcpFlags::PSH);
+    assert!(interface.poll(1).is_none());
+
+    // the unacknowledged data is retransmitted
+    let retransmission = interface.poll(INITIAL_RETRANSMISSION_TIMEOUT + 1).unwrap();
+    assert_eq!(flags(retransmission), TcpFlags::ACK | TcpFlags::PSH);
+
+    let stats = interface.stats();
+    assert_eq!((stats.tcp.rx, stats.tcp.tx), (2, 3));
+    assert_eq!(stats.tcp_retransmissions, 1);
+    assert!(interface.receive(&hex!(frames::TCP_SYN)[..40]).is_err());
+    assert_eq!(interface.stats().parse_errors, 1);
+
+    assert!(interface.arp_cache_lookup(client).is_some());
+    interface.poll(ARP_CACHE_TIMEOUT);
+    assert!(interface.arp_cache_lookup(client).is_none());
+}
+
+#[test]
+fn tap_sees_all_frames() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use testing::frames;
+
+    static RX: AtomicUsize = AtomicUsize::new(0);
+    static TX: AtomicUsize = AtomicUsize::new(0);
+    fn tap(frame: &[u8], direction: Direction) {
+        assert!(frame.len() >= 42);
+        match direction {
+            Direction::Rx => RX.fetch_add(1, Ordering::SeqCst),
+            Direction::Tx => TX.fetch_add(1, Ordering::SeqCst),
+        };
+    }
+
+    let mac = EthernetAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    let mut interface = Interface::new(mac, Ipv4Address::new(192, 168, 1, 1));
+    interface.set_tap(Some(tap));
+    interface.link_up(0);
+    assert!(interface.poll(0).is_some());
+    let _ = interface.receive(&hex!(frames::DNS_REPLY));
+    assert_eq!((RX.load(Ordering::SeqCst), TX.load(Ordering::SeqCst)), (1, 1));
+
+    interface.set_tap(None);
+    let _ = interface.receive(&hex!(frames::DNS_REPLY));
+    assert_eq!(RX.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn link_state_changes() {
+    use alloc::rc::Rc;
+    use core::cell::RefCell;
+    use ipv4::Endpoint;
+    use tcp::{TcpEvent, TcpListener};
+    use testing::{frames, tcp_ack_for};
+
+    let mac = EthernetAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    let ip = Ipv4Address::new(192, 168, 1, 1);
+    let client = Ipv4Address::new(192, 168, 1, 100);
+    let remote = Endpoint::new(client, 0xc000);
+    let mut interface = Interface::new(mac, ip);
+    interface.add_tcp_listener(TcpListener::new(Endpoint::new(ip, 80), 4, 2), |_, _| None);
+    interface.arp_cache_insert(client, EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]));
+    let changes = Rc::new(RefCell::new(Vec::new()));
+    let handler_changes = changes.clone();
+    interface.on_link_change(move |up| handler_changes.borrow_mut().push(up));
+
+    interface.receive(&hex!(frames::TCP_SYN)).unwrap();
+    let syn_ack = interface.poll(0).unwrap();
+    interface.receive(&tcp_ack_for(&syn_ack)).unwrap();
+    interface.tcp_listener(80).unwrap().connection(remote).unwrap().send(b"hello");
+
+    // nothing is sent while the link is down
+    interface.set_link_up(false);
+    assert!(interface.poll(10).is_none());
+    assert!(!interface.is_link_up());
+
+    // the address is announced and the connection probed when it comes back
+    interface.set_link_up(true);
+    let frames: Vec<_> = (0..).map(|_| interface.poll(20)).take_while(|f| f.is_some()).collect();
+    assert_eq!(frames.len(), 2);
+    assert!(interface.stats().arp.tx == 1 && interface.stats().tcp.tx == 2);
+    assert_eq!(*changes.borrow(), [false, true]);
+
+    interface.set_link_down_policy(LinkDownPolicy::Abort);
+    interface.set_link_up(false);
+    let listener = interface.tcp_listener(80).unwrap();
+    assert!(listener.connection(remote).is_none());
+    assert_eq!(listener.poll_event(), Some((remote, TcpEvent::Established)));
+    assert_eq!(listener.poll_event(), Some((remote, TcpEvent::Aborted)));
+}
+
+#[test]
+fn unknown_protocol_handlers() {
+    use ethernet::EthernetPacket;
+    use ipv4::IpProtocol;
+
+    let mac = EthernetAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    let ip = Ipv4Address::new(192, 168, 1, 1);
+    let peer_mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let peer = Ipv4Address::new(192, 168, 1, 100);
+    let mut interface = Interface::new(mac, ip);
+    interface.arp_cache_insert(peer, peer_mac);
+    fn padded<T: WriteOut>(frame: &T) -> Box<[u8]> {
+        let mut packet = HeapTxPacket::new(60);
+        frame.write_out(&mut packet).unwrap();
+        packet.push_bytes(&[0; 60][frame.len()..]).unwrap();
+        packet.into_boxed_slice()
+    }
+
+    // a local experimental EtherType
+    let request = EthernetPacket::new(peer_mac, mac, EtherType::Unknown(0x88b5), &b"ping"[..]);
+    assert!(interface.receive(&padded(&request)).is_err());
+    interface.register_ether_type_handler(0x88b5, |header, data| {
+        assert_eq!(&data[..4], b"ping");
+        assert_eq!(header.dst_addr, EthernetAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]));
+        Some(b"pong".to_vec())
+    });
+    interface.receive(&padded(&request)).unwrap();
+    let response = interface.next_frame().unwrap();
+    let ethernet = EthernetPacket::<&[u8]>::parse(&response).unwrap();
+    assert_eq!(ethernet.header.dst_addr, peer_mac);
+    assert_eq!(ethernet.header.ether_type, EtherType::Unknown(0x88b5));
+    assert_eq!(&ethernet.payload[..4], b"pong");
+
+    interface.register_ip_protocol_handler(253, |_, data| Some(data.to_vec()));
+    let echo = Ipv4Packet::new_raw(peer, ip, IpProtocol::Unknown(253), &[1, 2, 3][..]);
+    interface.receive(&padded(&EthernetPacket::new_ipv4(peer_mac, mac, echo))).unwrap();
+    match parse(&interface.next_frame().unwrap()).unwrap().payload {
+        EthernetKind::Ipv4(Ipv4Packet { header, payload: Ipv4Kind::Unknown(253, data) }) => {
+            assert_eq!((header.dst_addr, data), (peer, &[1, 2, 3][..]));
+        }
+        other => panic!("unexpected packet {:?}", other),
+    }
+}
+
+#[test]
+fn jumbo_frames() {
+    use ethernet::JUMBO_MTU;
+    use udp::UdpPacket;
+
+    let mac = EthernetAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    let ip = Ipv4Address::new(192, 168, 1, 1);
+    let peer_mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let peer = Ipv4Address::new(192, 168, 1, 100);
+    let mut interface = Interface::new(mac, ip);
+    interface.arp_cache_insert(peer, peer_mac);
+    let mut receiver = Interface::new(peer_mac, peer);
+
+    let data = [0x42; 8000];
+    let udp = || Ipv4Packet::new_udp(ip, peer, UdpPacket::new(1000, 1000, &data[..]));
+    assert_eq!(interface.send_ipv4(udp()),
+               Err(SendError::ExceedsMtu {
+                       len: 8028,
+                       mtu: 1500,
+                   }));
+
+    interface.set_mtu(JUMBO_MTU);
+    interface.send_ipv4(udp()).unwrap();
+    let frame = interface.next_frame().unwrap();
+    assert_eq!(frame.len(), 14 + 8028);
+    assert!(receiver.receive(&frame).is_err());
+    receiver.set_mtu(JUMBO_MTU);
+    receiver.receive(&frame).unwrap();
+}
+
+#[test]
+fn mtu_applies_to_all_frames() {
+    use ethernet::EthernetPacket;
+    use ipv4::Endpoint;
+    use tcp::TcpListener;
+    use testing::{frames, tcp_ack_for};
+    use HeapTxPacket;
+
+    let mac = EthernetAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    let ip = Ipv4Address::new(192, 168, 1, 1);
+    let client = Ipv4Address::new(192, 168, 1, 100);
+    let client_mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let mut interface = Interface::new(mac, ip);
+    interface.arp_cache_insert(client, client_mac);
+
+    // the TCP segments fit into the MTU
+    interface.add_tcp_listener(TcpListener::new(Endpoint::new(ip, 80), 4, 2), |_, _| None);
+    assert_eq!(interface.tcp_listener(80).unwrap().mss(), 1500 - 80);
+    interface.set_mtu(576);
+    interface.receive(&hex!(frames::TCP_SYN)).unwrap();
+    let syn_ack = interface.poll(0).unwrap();
+    interface.receive(&tcp_ack_for(&syn_ack)).unwrap();
+    let remote = Endpoint::new(client, 0xc000);
+    let connection = interface.tcp_listener(80).unwrap().connection(remote).unwrap();
+    assert_eq!(connection.mss(), 576 - 80);
+    connection.send(&[0; 1000]);
+    let mut segment = interface.poll(0);
+    while let Some(frame) = segment {
+        assert!(frame.len() <= 14 + 576);
+        segment = interface.next_frame();
+    }
+
+    // also the answers of an EtherType handler
+    let request = EthernetPacket::new(client_mac, mac, EtherType::Unknown(0x88b5), &[0; 46][..]);
+    let request = HeapTxPacket::write_out(request).unwrap();
+    interface.register_ether_type_handler(0x88b5, |_, _| Some(vec![0; 576]));
+    interface.receive(&request).unwrap();
+    assert_eq!(interface.next_frame().map(|frame| frame.len()), Some(14 + 576));
+    interface.register_ether_type_handler(0x88b5, |_, _| Some(vec![0; 577]));
+    interface.receive(&request).unwrap();
+    assert!(interface.next_frame().is_none());
+}
+
+#[test]
+fn directed_broadcasts() {
+    use udp::UdpPacket;
+
+    let mac = EthernetAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    let ip = Ipv4Address::new(192, 168, 1, 1);
+    let peer_mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let peer = Ipv4Address::new(192, 168, 1, 100);
+    let subnet_broadcast = Ipv4Address::new(192, 168, 1, 255);
+    let mut interface = Interface::new(mac, ip);
+    interface.set_netmask(Ipv4Address::new(255, 255, 255, 0));
+    interface.bind_udp(9);
+
+    let datagram = |dst| {
+        let udp = UdpPacket::new(9, 9, &[0; 20][..]);
+        let frame = EthernetPacket::new_ipv4(peer_mac, mac, Ipv4Packet::new_udp(peer, dst, udp));
+        HeapTxPacket::write_out(frame).unwrap()
+    };
+    interface.receive(&datagram(subnet_broadcast)).unwrap();
+    interface.receive(&datagram(Ipv4Address::new(192, 168, 1, 77))).unwrap();
+    assert!(interface.receive_udp(9).is_some());
+    assert!(interface.receive_udp(9).is_none());
+
+    interface.set_accept_directed_broadcasts(false);
+    interface.receive(&datagram(subnet_broadcast)).unwrap();
+    interface.receive(&datagram(Ipv4Address::broadcast())).unwrap();
+    assert!(interface.receive_udp(9).is_some());
+    assert!(interface.receive_udp(9).is_none());
+
+    // sent to the broadcast MAC address without an ARP request
+    let udp = UdpPacket::new(9, 9, &[1, 2, 3][..]);
+    interface.send_ipv4(Ipv4Packet::new_udp(ip, subnet_broadcast, udp)).unwrap();
+    let frame = interface.next_frame().unwrap();
+    assert_eq!(&frame[0..6], &EthernetAddress::broadcast().as_bytes());
+    assert!(interface.next_frame().is_none());
+}
+
+#[test]
+fn source_specific_multicast() {
+    use ipv4::IpProtocol;
+
+    let mac = EthernetAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    let ip = Ipv4Address::new(192, 168, 1, 2);
+    let group = Ipv4Address::new(232, 1, 2, 3);
+    let source = Ipv4Address::new(10, 0, 0, 1);
+    let mut interface = Interface::new(mac, ip);
+
+    // reports go to 224.0.0.22, the record follows the router alert option
+    let records = |frame: &[u8]| frame[14 + 24 + 8..][..12].to_vec();
+    interface.join_ssm(group, source).unwrap();
+    let report = interface.next_frame().unwrap();
+    assert_eq!(&report[0..6], &[0x01, 0x00, 0x5e, 0x00, 0x00, 0x16]);
+    assert_eq!(records(&report), [5, 0, 0, 1, 232, 1, 2, 3, 10, 0, 0, 1]);
+
+    // general queries are answered with the current state
+    let query = [0x11, 100, 0xee, 0x9b, 0, 0, 0, 0];
+    let query = Ipv4Packet::new_raw(Ipv4Address::new(192, 168, 1, 1),
+                                    ALL_SYSTEMS,
+                                    IpProtocol::Igmp,
+                                    &query[..]);
+    let mut frame = HeapTxPacket::write_out(EthernetPacket::new_ipv4(mac, mac, query)).unwrap()
+        .to_vec();
+    frame.resize(60, 0);
+    interface.receive(&frame).unwrap();
+    assert_eq!(records(&interface.next_frame().unwrap()), [1, 0, 0, 1, 232, 1, 2, 3, 10, 0, 0, 1]);
+
+    interface.leave_ssm(group, source).unwrap();
+    assert_eq!(records(&interface.next_frame().unwrap()), [6, 0, 0, 1, 232, 1, 2, 3, 10, 0, 0, 1]);
+    interface.receive(&frame).unwrap();
+    assert!(interface.next_frame().is_none());
+}
+
+#[test]
+fn loopback() {
+    use udp::UdpPacket;
+
+    let mac = EthernetAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    let ip = Ipv4Address::new(192, 168, 1, 1);
+    let localhost = Ipv4Address::new(127, 0, 0, 1);
+    let mut interface = Interface::new(mac, ip);
+    interface.set_netmask(Ipv4Address::new(255, 255, 255, 0));
+    interface.set_link_up(false);
+    interface.bind_udp(9);
+
+    let datagram = |dst| Ipv4Packet::new_udp(ip, dst, UdpPacket::new(1000, 9, &b"hi"[..]));
+    interface.send_ipv4(datagram(ip)).unwrap();
+    interface.send_ipv4(datagram(localhost)).unwrap();
+    assert!(interface.receive_udp(9).is_none());
+    assert!(interface.poll(0).is_none());
+    assert_eq!(&interface.receive_udp(9).unwrap().1[..], b"hi");
+    assert_eq!(&interface.receive_udp(9).unwrap().1[..], b"hi");
+    assert_eq!(interface.stats().tx_frames, 0);
+
+    // loopback addresses from the wire are dropped
+    let peer_mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let frame = EthernetPacket::new_ipv4(peer_mac, mac, datagram(localhost));
+    let mut frame = HeapTxPacket::write_out(frame).unwrap().to_vec();
+    frame.resize(60, 0); // padding
+    interface.receive(&frame).unwrap();
+    assert!(interface.receive_udp(9).is_none());
+}
+
+#[test]
+fn stack_events() {
+    use testing::{frames, tcp_ack_for};
+
+    let mac = EthernetAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    let ip = Ipv4Address::new(192, 168, 1, 1);
+    let client = Ipv4Address::new(192, 168, 1, 100);
+    let mut interface = Interface::new(mac, ip);
+    interface.arp_cache_insert(client, EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]));
+    interface.add_tcp_listener(TcpListener::new(Endpoint::new(ip, 80), 4, 2), |_, _| None);
+
+    let id = ConnectionId::new(Endpoint::new(ip, 80), Endpoint::new(client, 0xc000));
+    interface.receive(&hex!(frames::TCP_SYN)).unwrap();
+    let syn_ack = interface.poll(0).unwrap();
+
+    // nothing is collected before the queue is enabled
+    interface.set_link_up(false);
+    interface.enable_events(4);
+    interface.set_link_up(true);
+    assert_eq!(interface.next_event(), Some(StackEvent::LinkChanged { up: true }));
+    assert_eq!(interface.next_event(), None);
+
+    interface.receive(&tcp_ack_for(&syn_ack)).unwrap();
+    assert_eq!(interface.next_event(), Some(StackEvent::TcpConnected(id)));
+    interface.tcp_listener(80).unwrap().connection(id.remote).unwrap().abort();
+    interface.poll(0);
+    assert_eq!(interface.next_event(), Some(StackEvent::TcpClosed(id)));
+
+    // a gratuitous ARP of another host for our address
+    let other = EthernetAddress::new([0x00, 0xaa, 0xbb, 0xcc, 0xdd, 0xee]);
+    let arp = arp::new_gratuitous_packet(other, ip);
+    let mut frame = HeapTxPacket::write_out(arp).unwrap().to_vec();
+    frame.resize(60, 0); // padding
+    interface.receive(&frame).unwrap();
+    assert_eq!(interface.next_event(), Some(StackEvent::ArpConflict { mac: other }));
+}
+
+#[test]
+fn echo_policy() {
+    use icmp::{self, EchoPayload};
+
+    let mac = EthernetAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    let ip = Ipv4Address::new(192, 168, 1, 1);
+    let peer_mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let peer = Ipv4Address::new(192, 168, 1, 100);
+    let mut interface = Interface::new(mac, ip);
+    interface.arp_cache_insert(peer, peer_mac);
+    let ping = |interface: &mut Interface, len: usize| {
+        let payload = EchoPayload::new(len, b"abc");
+        let request = icmp::new_echo_request_packet(peer_mac, mac, peer, ip, 1, 1, payload);
+        let mut frame = HeapTxPacket::write_out(request).unwrap().to_vec();
+        frame.resize(usize::max(frame.len(), 60), 0); // padding
+        interface.receive(&frame).unwrap();
+        interface.poll(0).is_some()
+    };
+
+    assert!(ping(&mut interface, 8));
+    interface.set_max_echo_payload(Some(64));
+    assert!(!ping(&mut interface, 65));
+    interface.set_echo_policy(EchoPolicy::Ignore);
+    assert!(!ping(&mut interface, 8));
+    interface.set_echo_policy(EchoPolicy::RateLimit {
+                                  count: 2,
+                                  interval: 1000,
+                              });
+    assert_eq!((0..3).filter(|_| ping(&mut interface, 8)).count(), 2);
+    assert_eq!(interface.stats().dropped_echo_requests, 3);
+    assert_eq!(interface.stats().dropped_frames, 3);
+}
+
+#[test]
+fn multicast_filter() {
+    use alloc::rc::Rc;
+    use core::cell::RefCell;
+    use udp::UdpPacket;
+
+    let mac = EthernetAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    let group = Ipv4Address::new(239, 1, 2, 3);
+    let group_mac = EthernetAddress::from_ipv4_multicast(group);
+    let mut interface = Interface::new(mac, Ipv4Address::new(192, 168, 1, 2));
+    interface.bind_udp(5000);
+
+    // a hash filter that can't filter exactly
+    let programmed = Rc::new(RefCell::new(Vec::new()));
+    let hardware = programmed.clone();
+    interface.on_multicast_filter_change(move |macs| {
+                                             *hardware.borrow_mut() = macs.to_vec();
+                                             false
+                                         });
+    let all_systems = EthernetAddress::from_ipv4_multicast(ALL_SYSTEMS);
+    assert_eq!(*programmed.borrow(), [all_systems]);
+
+    let udp = UdpPacket::new(4000, 5000, &[1, 2, 3][..]);
+    let datagram = Ipv4Packet::new_udp(Ipv4Address::new(192, 168, 1, 1), group, udp);
+    let mut frame = HeapTxPacket::write_out(EthernetPacket::new_ipv4(mac, group_mac, datagram))
+        .unwrap()
+        .to_vec();
+    frame.resize(60, 0);
+    interface.receive(&frame).unwrap();
+    assert!(interface.receive_udp(5000).is_none());
+
+    interface.join_multicast(group).unwrap();
+    interface.add_multicast_mac(group_mac);
+    assert_eq!(*programmed.borrow(), [all_systems, group_mac]);
+    interface.receive(&frame).unwrap();
+    assert!(interface.receive_udp(5000).is_some());
+
+    // still added once
+    interface.leave_multicast(group).unwrap();
+    assert_eq!(interface.multicast_macs(), &[all_systems, group_mac]);
+    interface.remove_multicast_mac(group_mac);
+    assert_eq!(*programmed.borrow(), [all_systems]);
+}
+
+#[test]
+fn arp_retries() {
+    use arp::ArpOperation;
+    use ipv4::IpProtocol;
+    use retry::Policy;
+
+    let mac = EthernetAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    let mut interface = Interface::new(mac, Ipv4Address::new(192, 168, 1, 2));
+    interface.set_announcements(0, 0);
+    interface.set_arp_retry_policy(Policy::exponential(500, 1000, 2));
+    let is_request = |frame: Option<Box<[u8]>>| match frame {
+        Some(frame) => {
+            match parse(&frame).unwrap().payload {
+                EthernetKind::Arp(arp) => arp.operation == ArpOperation::Request,
+                _ => false,
+            }
+        }
+        None => false,
+    };
+
+    let packet = Ipv4Packet::new_raw(Ipv4Address::new(192, 168, 1, 2),
+                                     Ipv4Address::new(192, 168, 1, 3),
+                                     IpProtocol::Udp,
+                                     &[0u8; 8][..]);
+    interface.send_ipv4(packet).unwrap();
+    assert!(is_request(interface.poll(0)));
+    assert!(interface.poll(499).is_none());
+    assert!(is_request(interface.poll(500)));
+    assert!(interface.poll(1499).is_none());
+    assert!(is_request(interface.poll(1500)));
+    assert!(interface.poll(2500).is_none());
+    assert_eq!(interface.stats().dropped_frames, 1);
+}
+
+#[test]
+fn icmp_errors() {
+    use parse::parse;
+    use udp::UdpPacket;
+
+    let mac = EthernetAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    let ip = Ipv4Address::new(192, 168, 1, 1);
+    let peer_mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let peer = Ipv4Address::new(192, 168, 1, 100);
+    let mut interface = Interface::new(mac, ip);
+    interface.set_netmask(Ipv4Address::new(255, 255, 255, 0));
+    interface.arp_cache_insert(peer, peer_mac);
+    let send = |interface: &mut Interface, dst_mac: EthernetAddress, dst: Ipv4Address| {
+        let udp = UdpPacket::new(1000, 7, &[1, 2, 3][..]);
+        let packet =
+            EthernetPacket::new_ipv4(peer_mac, dst_mac, Ipv4Packet::new_udp(peer, dst, udp));
+        let mut frame = HeapTxPacket::write_out(packet).unwrap().to_vec();
+        frame.resize(60, 0); // padding
+        interface.receive(&frame).unwrap();
+        interface.next_frame()
+    };
+
+    // port unreachable, quoting the header and the first 8 bytes
+    let error = send(&mut interface, mac, ip).unwrap();
+    match parse(&error).unwrap().payload {
+        EthernetKind::Ipv4(reply) => {
+            assert_eq!(reply.header.dst_addr, peer);
+            match reply.payload {
+                Ipv4Kind::Icmp(IcmpKind::DestinationUnreachable { code, quoted, .. }) => {
+                    assert_eq!((code, quoted.len()), (icmp::UNREACHABLE_PORT, 28));
+                }
+                other => panic!("unexpected payload {:?}", other),
+            }
+        }
+        other => panic!("unexpected packet {:?}", other),
+    }
+
+    // no errors for broadcasts, and bursts of at most two
+    assert!(send(&mut interface, EthernetAddress::broadcast(), ip).is_none());
+    let subnet_broadcast = Ipv4Address::new(192, 168, 1, 255);
+    assert!(send(&mut interface, mac, subnet_broadcast).is_none());
+    interface.set_icmp_rate_limit(2, 500);
+    assert_eq!((0..3).filter(|_| send(&mut interface, mac, ip).is_some()).count(), 2);
+    interface.poll(500);
+    assert!(send(&mut interface, mac, ip).is_some());
+    assert!(send(&mut interface, mac, ip).is_none());
+}
+
+#[test]
+fn allocate_port() {
+    use ipv4::Endpoint;
+    use tcp::TcpListener;
+
+    let mac = EthernetAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    let ip = Ipv4Address::new(192, 168, 1, 1);
+    let mut interface = Interface::new(mac, ip);
+    interface.port_allocator().set_range(50000, 50002).unwrap();
+    interface.bind_udp(50000);
+    interface.add_tcp_listener(TcpListener::new(Endpoint::new(ip, 50001), 4, 2), |_, _| None);
+
+    for _ in 0..3 {
+        assert_eq!(interface.allocate_port(), Some(50002));
+    }
+    interface.bind_udp(50002);
+    assert_eq!(interface.allocate_port(), None);
+    interface.unbind_udp(50000);
+    assert_eq!(interface.allocate_port(), Some(50000));
+}
+
+#[test]
+fn address_conflict_detection() {
+    use acd::DefensePolicy;
+    use acd::{DEFEND_INTERVAL, PROBE_NUM};
+    use dhcp::{self, DhcpPacket, DhcpType};
+
+    let mac = EthernetAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    let other = EthernetAddress::new([0x00, 0xaa, 0xbb, 0xcc, 0xdd, 0xee]);
+    let ip = Ipv4Address::new(192, 168, 1, 2);
+    let server = Ipv4Address::new(192, 168, 1, 1);
+    let mut interface = Interface::new(mac, Ipv4Address::new(0, 0, 0, 0));
+    interface.enable_events(4);
+    let arp_packets = |interface: &mut Interface, now| {
+        let mut packets = Vec::new();
+        while let Some(frame) = interface.poll(now) {
+            if let EthernetKind::Arp(arp) = parse(&frame).unwrap().payload {
+                packets.push(arp);
+            }
+        }
+        packets
+    };
+
+    // the address is probed before it is configured
+    interface.probe_address(ip, DefensePolicy::Retreat);
+    let mut probes = 0;
+    for now in (0..100).map(|i| i * 100) {
+        for arp in arp_packets(&mut interface, now) {
+            if arp.src_ip.is_unspecified() && arp.dst_ip == ip {
+                assert_eq!(interface.ip(), Ipv4Address::new(0, 0, 0, 0));
+                probes += 1;
+            }
+        }
+    }
+    assert_eq!(probes, PROBE_NUM);
+    assert_eq!(interface.ip(), ip);
+    assert_eq!(interface.next_event(), Some(StackEvent::AddressAvailable(ip)));
+
+    // a conflict is defended once, and the address is given up on the next one
+    let mut conflict = HeapTxPacket::write_out(arp::new_gratuitous_packet(other, ip))
+        .unwrap()
+        .to_vec();
+    conflict.resize(60, 0); // padding
+    arp_packets(&mut interface, 20_000);
+    interface.receive(&conflict).unwrap();
+    assert_eq!(interface.next_event(), Some(StackEvent::ArpConflict { mac: other }));
+    assert!(arp_packets(&mut interface, 20_000)
+                .iter()
+                .any(|arp| arp.src_mac == mac && arp.src_ip == ip && arp.dst_ip == ip));
+    interface.poll(20_000 + DEFEND_INTERVAL - 1);
+    interface.receive(&conflict).unwrap();
+    assert_eq!(interface.next_event(), Some(StackEvent::ArpConflict { mac: other }));
+    assert_eq!(interface.next_event(),
+               Some(StackEvent::AddressConflict { ip, mac: other }));
+    assert_eq!(interface.ip(), Ipv4Address::new(0, 0, 0, 0));
+
+    // so a DHCP client declines it
+    let decline = HeapTxPacket::write_out(dhcp::new_decline_msg(mac, ip, server)).unwrap();
+    let decline = DhcpPacket::parse(&decline.as_slice()[42..]).unwrap();
+    assert_eq!(decline.operation,
+               DhcpType::Decline {
+                   ip,
+                   dhcp_server_ip: server,
+               });
+}
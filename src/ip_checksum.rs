@@ -1,7 +1,10 @@
 // from smoltcp
 
+use core::ops::Range;
 use byteorder::{ByteOrder, NetworkEndian};
 use ipv4::{Ipv4Address, IpProtocol};
+use packet_memory::MAX_READ_LEN;
+use TxPacket;
 
 fn propagate_carries(word: u32) -> u16 {
     let sum = (word >> 16) + (word & 0xffff);
@@ -9,20 +12,194 @@ fn propagate_carries(word: u32) -> u16 {
 }
 
 /// Compute an RFC 1071 compliant checksum (without the final complement).
-pub fn data(data: &[u8]) -> u16 {
-    let mut accum: u32 = 0;
-    let mut i = 0;
-    while i < data.len() {
-        let word;
-        if i + 2 <= data.len() {
-            word = NetworkEndian::read_u16(&data[i..i + 2]) as u32
+///
+/// Sums 32-bit words into a 64-bit accumulator and folds the carries once at the
+/// end, which is equivalent to summing 16-bit words. The byteorder reads are
+/// bytewise, so `data` doesn't need any particular alignment.
+pub fn data(mut data: &[u8]) -> u16 {
+    let mut accum: u64 = 0;
+
+    while data.len() >= 8 {
+        accum += u64::from(NetworkEndian::read_u32(&data[0..4]));
+        accum += u64::from(NetworkEndian::read_u32(&data[4..8]));
+        data = &data[8..];
+    }
+    if data.len() >= 4 {
+        accum += u64::from(NetworkEndian::read_u32(&data[0..4]));
+        data = &data[4..];
+    }
+    if data.len() >= 2 {
+        accum += u64::from(NetworkEndian::read_u16(&data[0..2]));
+        data = &data[2..];
+    }
+    if let Some(&byte) = data.first() {
+        accum += u64::from(byte) << 8;
+    }
+
+    let accum = (accum >> 32) + (accum & 0xffff_ffff);
+    let accum = (accum >> 32) + (accum & 0xffff_ffff);
+    propagate_carries(accum as u32)
+}
+
+/// An RFC 1071 checksum that is computed incrementally while bytes are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Checksum {
+    accum: u64,
+    odd: bool,
+}
+
+impl Checksum {
+    pub fn new() -> Self {
+        Checksum::default()
+    }
+
+    /// Adds bytes that directly follow the previously added bytes.
+    pub fn add_bytes(&mut self, mut bytes: &[u8]) {
+        if self.odd {
+            match bytes.split_first() {
+                Some((&first, rest)) => {
+                    self.accum += u64::from(first);
+                    bytes = rest;
+                }
+                None => return,
+            }
+        }
+        self.accum += u64::from(data(bytes));
+        self.odd = bytes.len() % 2 == 1;
+    }
+
+    /// Adds `len` bytes with the checksum `sum` that directly follow the previously
+    /// added bytes.
+    pub fn add_sum(&mut self, sum: u16, len: usize) {
+        self.accum += u64::from(if self.odd { sum.swap_bytes() } else { sum });
+        self.odd ^= len % 2 == 1;
+    }
+
+    /// Updates the checksum for `old` being overwritten with `new` at byte `offset`.
+    pub fn replace_bytes(&mut self, offset: usize, old: &[u8], new: &[u8]) {
+        let sum = |bytes| if offset % 2 == 0 {
+            data(bytes)
         } else {
-            word = (data[i] as u32) << 8
+            data(bytes).swap_bytes()
+        };
+        self.accum += u64::from(!sum(old));
+        self.accum += u64::from(sum(new));
+    }
+
+    /// Returns the checksum without the final complement.
+    pub fn sum(&self) -> u16 {
+        let accum = (self.accum >> 32) + (self.accum & 0xffff_ffff);
+        let accum = (accum >> 32) + (accum & 0xffff_ffff);
+        propagate_carries(accum as u32)
+    }
+}
+
+/// A `TxPacket` wrapper that checksums all bytes written through it.
+///
+/// This avoids a second pass over the payload for computing transport checksums.
+/// Bytes written before the wrapper was created are not included. Modifications must
+/// go through `set_bytes` (or the methods based on it) to be accounted for.
+pub struct ChecksummedTxPacket<'a, P: TxPacket + 'a> {
+    inner: &'a mut P,
+    start_index: usize,
+    /// The end of the checksummed bytes, if they don't extend to the end.
+    end_index: Option<usize>,
+    checksum: Checksum,
+}
+
+impl<'a, P: TxPacket> ChecksummedTxPacket<'a, P> {
+    pub fn new(inner: &'a mut P) -> Self {
+        let start_index = inner.len();
+        ChecksummedTxPacket {
+            inner,
+            start_index,
+            end_index: None,
+            checksum: Checksum::new(),
         }
-        accum += word;
-        i += 2;
     }
-    propagate_carries(accum)
+
+    /// Like `new`, but only checksums the first `len` bytes written through the
+    /// wrapper. External payloads that extend past them can't be pushed.
+    pub fn with_limit(inner: &'a mut P, len: usize) -> Self {
+        let mut packet = ChecksummedTxPacket::new(inner);
+        packet.end_index = Some(packet.start_index + len);
+        packet
+    }
+
+    /// The number of the `len` bytes at `index` that are checksummed, ignoring the
+    /// bytes before the start.
+    fn covered_len(&self, index: usize, len: usize) -> usize {
+        match self.end_index {
+            Some(end_index) => usize::min(len, end_index.saturating_sub(index)),
+            None => len,
+        }
+    }
+
+    /// Returns the checksum of the written bytes, without the final complement.
+    pub fn checksum(&self) -> u16 {
+        self.checksum.sum()
+    }
+}
+
+impl<'a, P: TxPacket> TxPacket for ChecksummedTxPacket<'a, P> {
+    fn push_bytes(&mut self, bytes: &[u8]) -> Result<usize, ()> {
+        let index = self.inner.push_bytes(bytes)?;
+        let covered_len = self.covered_len(index, bytes.len());
+        self.checksum.add_bytes(&bytes[..covered_len]);
+        Ok(index)
+    }
+
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    fn push_external(&mut self, len: usize, checksum: u16) -> Result<usize, ()> {
+        let covered_len = self.covered_len(self.inner.len(), len);
+        if covered_len != 0 && covered_len != len {
+            return Err(()); // the limit is inside the external payload
+        }
+        let index = self.inner.push_external(len, checksum)?;
+        if covered_len == len {
+            self.checksum.add_sum(checksum, len);
+        }
+        Ok(index)
+    }
+
+    fn set_bytes(&mut self, index: usize, bytes: &[u8]) {
+        let end = index + self.covered_len(index, bytes.len());
+        if end > self.start_index && end > index {
+            let mut start = usize::max(index, self.start_index);
+            while start < end {
+                let chunk_end = usize::min(end, start + MAX_READ_LEN);
+                let old = self.inner.get_bytes(start, chunk_end - start);
+                self.checksum.replace_bytes(start - self.start_index,
+                                            old,
+                                            &bytes[(start - index)..(chunk_end - index)]);
+                start = chunk_end;
+            }
+        }
+        self.inner.set_bytes(index, bytes);
+    }
+
+    fn get_bytes(&mut self, index: usize, len: usize) -> &[u8] {
+        self.inner.get_bytes(index, len)
+    }
+
+    fn mark_payload(&mut self, range: Range<usize>) {
+        self.inner.mark_payload(range)
+    }
+}
+
+/// Updates a checksum field (with the final complement) for `old` being
+/// overwritten with `new`, according to [RFC 1624][rfc1624]. Both must start at an
+/// even offset of the checksummed data.
+///
+/// [rfc1624]: https://tools.ietf.org/html/rfc1624
+pub fn update(checksum: u16, old: &[u8], new: &[u8]) -> u16 {
+    let mut sum = Checksum::new();
+    sum.add_sum(!checksum, 2);
+    sum.replace_bytes(0, old, new);
+    !sum.sum()
 }
 
 /// Combine several RFC 1071 compliant checksums.
@@ -49,3 +226,61 @@ pub fn pseudo_header(src_addr: &Ipv4Address,
               data(&dst_addr.as_bytes()),
               data(&proto_len[..])])
 }
+
+#[test]
+fn data_matches_wordwise_sum() {
+    fn reference(data: &[u8]) -> u16 {
+        let mut accum: u32 = 0;
+        for chunk in data.chunks(2) {
+            let word = if chunk.len() == 2 {
+                NetworkEndian::read_u16(chunk) as u32
+            } else {
+                (chunk[0] as u32) << 8
+            };
+            accum += word;
+        }
+        propagate_carries(accum)
+    }
+
+    let mut bytes = [0u8; 1500];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = (i * 7 + 0xf3) as u8;
+    }
+    for start in 0..4 {
+        for len in (0..64).chain(1490..1497) {
+            let slice = &bytes[start..start + len];
+            assert_eq!(data(slice), reference(slice), "start {} len {}", start, len);
+        }
+    }
+    assert_eq!(data(&[0xff; 1500]), 0xffff);
+}
+
+#[test]
+fn checksummed_tx_packet() {
+    use packet_memory::MemoryTxPacket;
+    use HeapTxPacket;
+
+    let mut packet = HeapTxPacket::new(64);
+    packet.push_bytes(&[0xde, 0xad, 0xbe]).unwrap();
+    {
+        let mut checksummed = ChecksummedTxPacket::new(&mut packet);
+        checksummed.push_bytes(&[0x12, 0x34, 0x56]).unwrap();
+        checksummed.push_bytes(&[0x78]).unwrap();
+        checksummed.push_bytes(&[0x9a, 0xbc, 0xde, 0xf0, 0x11]).unwrap();
+        checksummed.set_u16(4, 0xcafe);
+        checksummed.set_bytes(8, &[0x42, 0x43]);
+        checksummed.set_bytes(1, &[0x00, 0x00, 0x77]);
+        let sum = checksummed.checksum();
+        assert_eq!(sum, data(checksummed.get_bytes(3, 9)));
+    }
+
+    // overwrites that exceed a read of packet memory
+    let mut memory = [0; 200];
+    let mut packet = MemoryTxPacket::new(&mut memory[..], 0, 200);
+    let mut checksummed = ChecksummedTxPacket::new(&mut packet);
+    checksummed.push_bytes(&[0x11; 151]).unwrap();
+    checksummed.set_bytes(1, &[0xa5; 150]);
+    let sum = checksummed.checksum();
+    drop(checksummed);
+    assert_eq!(sum, data(&memory[..151]));
+}
@@ -0,0 +1,189 @@
+//! IP-in-IP tunnels ([RFC 2003][rfc2003]), e.g. a simple link to a central server
+//! across a network that only routes the addresses of the tunnel endpoints.
+//!
+//! `encapsulate` wraps a packet that is forwarded into the tunnel, `decapsulate`
+//! unwraps a received one. Neither changes the TTL of the inner packet: it is
+//! decremented with `decrement_ttl` by the forwarding before encapsulation, and again
+//! if the decapsulated packet is forwarded further. The outer packet has its own TTL.
+//!
+//! [rfc2003]: https://tools.ietf.org/html/rfc2003
+
+use byteorder::{ByteOrder, NetworkEndian};
+use ip_checksum;
+use ipv4::{IpProtocol, Ipv4Address, Ipv4Packet};
+use parse::{Parse, ParseError};
+
+/// The offset of the TTL in the IPv4 header.
+const TTL_OFFSET: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TunnelError {
+    /// The outer or inner packet isn't a valid IPv4 packet.
+    Parse(ParseError),
+    /// The received packet doesn't carry an IPv4 packet.
+    NotTunneled,
+    /// The TTL of the inner packet is exhausted, so it is dropped. A router would
+    /// answer with an ICMP Time Exceeded message.
+    TtlExpired,
+}
+
+/// Wraps `inner`, an IPv4 packet, into a packet between the tunnel endpoints
+/// `src_addr` and `dst_addr`. Padding after the inner packet is left out.
+///
+/// Packets with a TTL of 0 are rejected (RFC 2003, section 3.1).
+pub fn encapsulate(src_addr: Ipv4Address,
+                   dst_addr: Ipv4Address,
+                   inner: &[u8])
+                   -> Result<Ipv4Packet<&[u8]>, TunnelError> {
+    let len = packet_len(inner)?;
+    if inner[TTL_OFFSET] == 0 {
+        return Err(TunnelError::TtlExpired);
+    }
+    Ok(Ipv4Packet::new_raw(src_addr, dst_addr, IpProtocol::IpInIp, &inner[..len]))
+}
+
+/// Returns the inner packet of a received IP-in-IP packet.
+///
+/// Inner packets with a TTL of 0 are rejected (RFC 2003, section 3.1).
+pub fn decapsulate(packet: &[u8]) -> Result<&[u8], TunnelError> {
+    let outer = Ipv4Packet::<&[u8]>::parse(packet).map_err(TunnelError::Parse)?;
+    if outer.header.protocol() != IpProtocol::IpInIp {
+        return Err(TunnelError::NotTunneled);
+    }
+    let header_len = usize::from(outer.header_len());
+    let inner = Ipv4Packet::<&[u8]>::parse(outer.payload)
+        .map_err(|e| TunnelError::Parse(e.at(header_len)))?;
+    let len = usize::from(inner.header_len()) + inner.payload.len();
+    if outer.payload[TTL_OFFSET] == 0 {
+        return Err(TunnelError::TtlExpired);
+    }
+    Ok(&outer.payload[..len])
+}
+
+/// Decrements the TTL of the IPv4 packet in `packet` and updates its header
+/// checksum, as every router that forwards it does. Fails if the TTL is exhausted,
+/// so that the packet must not be forwarded.
+pub fn decrement_ttl(packet: &mut [u8]) -> Result<(), TunnelError> {
+    packet_len(packet)?;
+    let ttl = packet[TTL_OFFSET];
+    if ttl <= 1 {
+        return Err(TunnelError::TtlExpired);
+    }
+    // the TTL shares a 16-bit word of the checksum with the protocol
+    let protocol = packet[TTL_OFFSET + 1];
+    let checksum = NetworkEndian::read_u16(&packet[10..12]);
+    let checksum = ip_checksum::update(checksum, &[ttl, protocol], &[ttl - 1, protocol]);
+    NetworkEndian::write_u16(&mut packet[10..12], checksum);
+    packet[TTL_OFFSET] = ttl - 1;
+    Ok(())
+}
+
+/// The length of the IPv4 packet at the start of `data`.
+fn packet_len(data: &[u8]) -> Result<usize, TunnelError> {
+    let packet = Ipv4Packet::<&[u8]>::parse(data).map_err(TunnelError::Parse)?;
+    Ok(usize::from(packet.header_len()) + packet.payload.len())
+}
+
+#[test]
+fn tunnel() {
+    use alloc::Vec;
+    use ipv4::Ipv4Kind;
+    use udp::UdpPacket;
+    use {HeapTxPacket, WriteOut};
+
+    fn write<T: WriteOut>(packet: &T) -> Vec<u8> {
+        let mut data = HeapTxPacket::new(packet.len());
+        packet.write_out(&mut data).unwrap();
+        data.as_slice().to_vec()
+    }
+
+    let host = Ipv4Address::new(10, 0, 0, 5);
+    let peer = Ipv4Address::new(10, 1, 0, 7);
+    let endpoint = Ipv4Address::new(198, 51, 100, 1);
+    let server = Ipv4Address::new(203, 0, 113, 1);
+    let inner = Ipv4Packet::new_udp(host, peer, UdpPacket::new(1000, 7, &b"echo"[..]));
+    let tunneled = write(&Ipv4Packet::new_ipip(endpoint, server, inner));
+
+    let mut padded = write(&inner);
+    padded.extend_from_slice(&[0; 6]);
+    assert_eq!(write(&encapsulate(endpoint, server, &padded).unwrap()), tunneled);
+    match Ipv4Packet::<Ipv4Kind>::parse(&tunneled).unwrap().payload {
+        Ipv4Kind::Ipv4(ref packet, data) => {
+            assert_eq!(packet.header, inner.header);
+            assert_eq!(data, &tunneled[20..]);
+        }
+        ref other => panic!("unexpected payload {:?}", other),
+    }
+
+    let mut received = decapsulate(&tunneled).unwrap().to_vec();
+    assert_eq!(received, write(&inner));
+    assert_eq!(decapsulate(&received), Err(TunnelError::NotTunneled));
+    decrement_ttl(&mut received).unwrap();
+    assert_eq!(received[TTL_OFFSET], 63);
+    assert_eq!(ip_checksum::data(&received[..20]), 0xffff);
+
+    received[TTL_OFFSET] = 1;
+    assert_eq!(decrement_ttl(&mut received), Err(TunnelError::TtlExpired));
+    received[TTL_OFFSET] = 0;
+    assert_eq!(encapsulate(endpoint, server, &received).unwrap_err(),
+               TunnelError::TtlExpired);
+}
+
+#[test]
+fn tunnel_depth() {
+    use ethernet::{EthernetAddress, EthernetKind, EthernetPacket};
+    use ipv4::{Ipv4Header, Ipv4Kind, MAX_TUNNEL_DEPTH};
+    use udp::{UdpHeader, UdpPacket};
+    use {parse, parse_with, HeapTxPacket, PacketVisitor};
+
+    struct Headers {
+        ipv4: usize,
+        udp: usize,
+    }
+
+    impl<'a> PacketVisitor<'a> for Headers {
+        fn on_ipv4(&mut self, _: &Ipv4Header, _: &'a [u8]) {
+            self.ipv4 += 1;
+        }
+
+        fn on_udp(&mut self, _: &Ipv4Header, _: &UdpHeader, _: &'a [u8]) {
+            self.udp += 1;
+        }
+    }
+
+    let mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let ip = Ipv4Address::new(10, 0, 0, 5);
+    let udp = Ipv4Packet::new_udp(ip, ip, UdpPacket::new(1000, 7, &b"echo"[..]));
+    let twice = Ipv4Packet::new_ipip(ip, ip, Ipv4Packet::new_ipip(ip, ip, udp));
+    let nested = EthernetPacket::new_ipv4(mac, mac, twice);
+    let nested = HeapTxPacket::write_out(nested).unwrap().to_vec();
+    let too_deep = EthernetPacket::new_ipv4(mac, mac, Ipv4Packet::new_ipip(ip, ip, twice));
+    let too_deep = HeapTxPacket::write_out(too_deep).unwrap().to_vec();
+    assert_eq!(MAX_TUNNEL_DEPTH, 2);
+
+    let mut visitor = Headers { ipv4: 0, udp: 0 };
+    parse_with(&nested, &mut visitor).unwrap();
+    assert_eq!((visitor.ipv4, visitor.udp), (3, 1));
+    let mut visitor = Headers { ipv4: 0, udp: 0 };
+    parse_with(&too_deep, &mut visitor).unwrap();
+    assert_eq!((visitor.ipv4, visitor.udp), (3, 0));
+
+    let packet = parse(&too_deep).unwrap();
+    let ip = match packet.payload {
+        EthernetKind::Ipv4(ip) => ip,
+        other => panic!("unexpected payload {:?}", other),
+    };
+    let inner = match ip.payload {
+        Ipv4Kind::Ipv4(inner, _) => inner,
+        other => panic!("unexpected payload {:?}", other),
+    };
+    match inner.payload {
+        Ipv4Kind::Ipv4(ref innermost, _) => {
+            match innermost.payload {
+                Ipv4Kind::Unknown(4, data) => assert_eq!(data, &too_deep[74..]),
+                ref other => panic!("unexpected payload {:?}", other),
+            }
+        }
+        ref other => panic!("unexpected payload {:?}", other),
+    }
+}
@@ -1,15 +1,23 @@
 use {TxPacket, WriteOut, ip_checksum};
 use udp::UdpPacket;
+#[cfg(any(test, feature = "tcp"))]
 use tcp::TcpPacket;
-use icmp::IcmpPacket;
+#[cfg(any(test, feature = "icmp"))]
+use icmp::{IcmpKind, IcmpPacket};
+use gre::GrePacket;
+use udplite::UdpLitePacket;
+use igmp::{IgmpMessage, MembershipReport};
 use core::convert::TryInto;
+use byteorder::{ByteOrder, NetworkEndian};
 use core::fmt;
+#[cfg(any(test, feature = "alloc"))]
+use alloc::boxed::Box;
 
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Ipv4Address([u8; 4]);
 
 impl Ipv4Address {
-    pub fn new(a0: u8, a1: u8, a2: u8, a3: u8) -> Self {
+    pub const fn new(a0: u8, a1: u8, a2: u8, a3: u8) -> Self {
         Ipv4Address([a0, a1, a2, a3])
     }
 
@@ -22,6 +30,41 @@ impl Ipv4Address {
     pub fn as_bytes(&self) -> [u8; 4] {
         self.0
     }
+
+    /// The limited broadcast address 255.255.255.255.
+    pub const fn broadcast() -> Self {
+        Self::new(255, 255, 255, 255)
+    }
+
+    /// Returns true for 0.0.0.0, which hosts use before they have an address.
+    pub fn is_unspecified(&self) -> bool {
+        self.0 == [0; 4]
+    }
+
+    /// Returns true for 127.0.0.0/8, which never leaves the host.
+    pub fn is_loopback(&self) -> bool {
+        self.0[0] == 127
+    }
+
+    /// Returns true for the limited broadcast address.
+    pub fn is_broadcast(&self) -> bool {
+        self.0 == [255; 4]
+    }
+
+    /// Returns true for the broadcast address of the subnet of `ip`, e.g. for
+    /// 192.168.1.255 with 192.168.1.2/24. Subnets with less than two host bits don't
+    /// have one.
+    pub fn is_directed_broadcast(&self, ip: Ipv4Address, netmask: Ipv4Address) -> bool {
+        let host_mask = !NetworkEndian::read_u32(&netmask.0);
+        let addr = NetworkEndian::read_u32(&self.0);
+        let ip = NetworkEndian::read_u32(&ip.0);
+        host_mask >= 3 && !self.is_broadcast() && addr & host_mask == host_mask &&
+        addr & !host_mask == ip & !host_mask
+    }
+
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 0xf0 == 224
+    }
 }
 
 impl fmt::Debug for Ipv4Address {
@@ -30,11 +73,50 @@ impl fmt::Debug for Ipv4Address {
     }
 }
 
+/// An address and port of a TCP or UDP socket.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Endpoint {
+    pub addr: Ipv4Address,
+    pub port: u16,
+}
+
+impl Endpoint {
+    pub fn new(addr: Ipv4Address, port: u16) -> Self {
+        Endpoint { addr, port }
+    }
+}
+
+impl fmt::Debug for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}:{}", self.addr, self.port)
+    }
+}
+
+/// Identifies a connection by its local and remote endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ConnectionId {
+    pub local: Endpoint,
+    pub remote: Endpoint,
+}
+
+impl ConnectionId {
+    pub fn new(local: Endpoint, remote: Endpoint) -> Self {
+        ConnectionId { local, remote }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum IpProtocol {
     Icmp,
+    Igmp,
     Udp,
     Tcp,
+    Gre,
+    UdpLite,
+    /// IPv4 encapsulated in IPv4 ([RFC 2003][rfc2003]).
+    ///
+    /// [rfc2003]: https://tools.ietf.org/html/rfc2003
+    IpInIp,
     Unknown(u8),
 }
 
@@ -44,8 +126,12 @@ impl IpProtocol {
 
         match number {
             1 => Icmp,
+            2 => Igmp,
+            4 => IpInIp,
             6 => Tcp,
             17 => Udp,
+            47 => Gre,
+            136 => UdpLite,
             number => Unknown(number),
         }
     }
@@ -55,18 +141,213 @@ impl IpProtocol {
 
         match *self {
             Icmp => 1,
+            Igmp => 2,
+            IpInIp => 4,
             Tcp => 6,
             Udp => 17,
+            Gre => 47,
+            UdpLite => 136,
             Unknown(number) => number,
         }
     }
 }
 
+/// The raw options of an IPv4 header (at most 40 bytes).
+#[derive(Clone, Copy)]
+pub struct Ipv4Options {
+    len: u8,
+    bytes: [u8; 40],
+}
+
+impl Ipv4Options {
+    pub fn empty() -> Self {
+        Ipv4Options {
+            len: 0,
+            bytes: [0; 40],
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ()> {
+        if bytes.len() > 40 {
+            return Err(());
+        }
+        let mut options = Self::empty();
+        options.len = bytes.len() as u8;
+        options.bytes[..bytes.len()].copy_from_slice(bytes);
+        Ok(options)
+    }
+
+    /// Options that only contain a Router Alert, as required for IGMP.
+    pub fn router_alert() -> Self {
+        let mut options = Self::empty();
+        options.push(Ipv4Option::RouterAlert(0)).unwrap();
+        options
+    }
+
+    /// Appends an option. Fails if the options would exceed 40 bytes.
+    pub fn push(&mut self, option: Ipv4Option) -> Result<(), ()> {
+        let start = usize::from(self.len);
+        let len = option.len();
+        if start + len > self.bytes.len() {
+            return Err(());
+        }
+        let bytes = &mut self.bytes[start..(start + len)];
+        match option {
+            Ipv4Option::RouterAlert(value) => {
+                bytes[0] = OPTION_ROUTER_ALERT;
+                bytes[1] = 4;
+                NetworkEndian::write_u16(&mut bytes[2..4], value);
+            }
+            Ipv4Option::Unknown { kind, data } => {
+                bytes[0] = kind;
+                bytes[1] = (2 + data.len()) as u8;
+                bytes[2..].copy_from_slice(data);
+            }
+        }
+        self.len += len as u8;
+        Ok(())
+    }
+
+    /// Iterates over the options, skipping padding.
+    pub fn iter(&self) -> Ipv4OptionsIter {
+        Ipv4OptionsIter { bytes: self.as_bytes() }
+    }
+
+    /// The value of the Router Alert option, if present.
+    pub fn router_alert_value(&self) -> Option<u16> {
+        self.iter()
+            .filter_map(|option| match option {
+                            Ipv4Option::RouterAlert(value) => Some(value),
+                            _ => None,
+                        })
+            .next()
+    }
+
+    /// Checks that all option lengths are consistent, so that `iter` sees every
+    /// option. Returns the offset of the first invalid option.
+    fn validate(bytes: &[u8]) -> Result<(), usize> {
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                OPTION_END => break,
+                OPTION_NOP => i += 1,
+                _ => {
+                    match bytes.get(i + 1) {
+                        Some(&len) if len >= 2 && i + usize::from(len) <= bytes.len() => {
+                            i += usize::from(len)
+                        }
+                        _ => return Err(i),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..usize::from(self.len)]
+    }
+
+    pub fn len(&self) -> usize {
+        usize::from(self.len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The length in the header, padded to a multiple of 4 bytes.
+    fn padded_len(&self) -> u8 {
+        (self.len + 3) / 4 * 4
+    }
+}
+
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+/// Copied on fragmentation, class 0, number 20.
+const OPTION_ROUTER_ALERT: u8 = 0x94;
+
+/// An IPv4 option according to [RFC 791][rfc791].
+///
+/// [rfc791]: https://tools.ietf.org/html/rfc791
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ipv4Option<'a> {
+    /// [RFC 2113][rfc2113], the value 0 asks routers to examine the packet.
+    ///
+    /// [rfc2113]: https://tools.ietf.org/html/rfc2113
+    RouterAlert(u16),
+    /// An option that is preserved without being interpreted.
+    Unknown { kind: u8, data: &'a [u8] },
+}
+
+impl<'a> Ipv4Option<'a> {
+    fn len(&self) -> usize {
+        match *self {
+            Ipv4Option::RouterAlert(_) => 4,
+            Ipv4Option::Unknown { data, .. } => 2 + data.len(),
+        }
+    }
+}
+
+pub struct Ipv4OptionsIter<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Iterator for Ipv4OptionsIter<'a> {
+    type Item = Ipv4Option<'a>;
+
+    fn next(&mut self) -> Option<Ipv4Option<'a>> {
+        loop {
+            match self.bytes.first() {
+                None | Some(&OPTION_END) => return None,
+                Some(&OPTION_NOP) => self.bytes = &self.bytes[1..],
+                Some(&kind) => {
+                    let len = match self.bytes.get(1) {
+                        Some(&len) if len >= 2 && usize::from(len) <= self.bytes.len() => {
+                            usize::from(len)
+                        }
+                        _ => return None, // malformed
+                    };
+                    let data = &self.bytes[2..len];
+                    self.bytes = &self.bytes[len..];
+                    return Some(match kind {
+                                    OPTION_ROUTER_ALERT if data.len() == 2 => {
+                                        Ipv4Option::RouterAlert(NetworkEndian::read_u16(data))
+                                    }
+                                    _ => Ipv4Option::Unknown { kind, data },
+                                });
+                }
+            }
+        }
+    }
+}
+
+impl PartialEq for Ipv4Options {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+impl Eq for Ipv4Options {}
+
+impl fmt::Debug for Ipv4Options {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Ipv4Options({:?})", self.as_bytes())
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Ipv4Header {
     pub src_addr: Ipv4Address,
     pub dst_addr: Ipv4Address,
     protocol: IpProtocol,
+    pub options: Ipv4Options,
+}
+
+impl Ipv4Header {
+    pub fn protocol(&self) -> IpProtocol {
+        self.protocol
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -82,12 +363,14 @@ impl<T> Ipv4Packet<UdpPacket<T>> {
                 src_addr: src_addr,
                 dst_addr: dst_addr,
                 protocol: IpProtocol::Udp,
+                options: Ipv4Options::empty(),
             },
             payload: udp,
         }
     }
 }
 
+#[cfg(any(test, feature = "tcp"))]
 impl<'a, T> Ipv4Packet<&'a TcpPacket<T>> {
     pub fn new_tcp(src_addr: Ipv4Address, dst_addr: Ipv4Address, tcp: &'a TcpPacket<T>) -> Self {
         Ipv4Packet {
@@ -95,12 +378,31 @@ impl<'a, T> Ipv4Packet<&'a TcpPacket<T>> {
                 src_addr: src_addr,
                 dst_addr: dst_addr,
                 protocol: IpProtocol::Tcp,
+                options: Ipv4Options::empty(),
+            },
+            payload: tcp,
+        }
+    }
+}
+
+#[cfg(any(test, feature = "tcp"))]
+impl<T> Ipv4Packet<TcpPacket<T>> {
+    /// Like `new_tcp`, but takes ownership of the TCP packet, e.g. for segments that
+    /// are built outside of `TcpConnection`.
+    pub fn new_tcp_owned(src_addr: Ipv4Address, dst_addr: Ipv4Address, tcp: TcpPacket<T>) -> Self {
+        Ipv4Packet {
+            header: Ipv4Header {
+                src_addr: src_addr,
+                dst_addr: dst_addr,
+                protocol: IpProtocol::Tcp,
+                options: Ipv4Options::empty(),
             },
             payload: tcp,
         }
     }
 }
 
+#[cfg(any(test, feature = "icmp"))]
 impl<T> Ipv4Packet<IcmpPacket<T>> {
     pub fn new_icmp(src_addr: Ipv4Address, dst_addr: Ipv4Address, icmp: IcmpPacket<T>) -> Self {
         Ipv4Packet {
@@ -108,15 +410,95 @@ impl<T> Ipv4Packet<IcmpPacket<T>> {
                 src_addr: src_addr,
                 dst_addr: dst_addr,
                 protocol: IpProtocol::Icmp,
+                options: Ipv4Options::empty(),
             },
             payload: icmp,
         }
     }
 }
 
+impl<T> Ipv4Packet<GrePacket<T>> {
+    pub fn new_gre(src_addr: Ipv4Address, dst_addr: Ipv4Address, gre: GrePacket<T>) -> Self {
+        Ipv4Packet {
+            header: Ipv4Header {
+                src_addr: src_addr,
+                dst_addr: dst_addr,
+                protocol: IpProtocol::Gre,
+                options: Ipv4Options::empty(),
+            },
+            payload: gre,
+        }
+    }
+}
+
+impl<T> Ipv4Packet<Ipv4Packet<T>> {
+    /// Tunnels `inner` from `src_addr` to `dst_addr`, see the `ipip` module.
+    pub fn new_ipip(src_addr: Ipv4Address, dst_addr: Ipv4Address, inner: Ipv4Packet<T>) -> Self {
+        Ipv4Packet {
+            header: Ipv4Header {
+                src_addr: src_addr,
+                dst_addr: dst_addr,
+                protocol: IpProtocol::IpInIp,
+                options: Ipv4Options::empty(),
+            },
+            payload: inner,
+        }
+    }
+}
+
+impl<T> Ipv4Packet<UdpLitePacket<T>> {
+    pub fn new_udp_lite(src_addr: Ipv4Address,
+                        dst_addr: Ipv4Address,
+                        udp: UdpLitePacket<T>)
+                        -> Self {
+        Ipv4Packet {
+            header: Ipv4Header {
+                src_addr: src_addr,
+                dst_addr: dst_addr,
+                protocol: IpProtocol::UdpLite,
+                options: Ipv4Options::empty(),
+            },
+            payload: udp,
+        }
+    }
+}
+
+impl<'a> Ipv4Packet<MembershipReport<'a>> {
+    /// Creates a report to `ALL_IGMPV3_ROUTERS` with the Router Alert option.
+    pub fn new_igmp_report(src_addr: Ipv4Address, report: MembershipReport<'a>) -> Self {
+        Ipv4Packet {
+            header: Ipv4Header {
+                src_addr: src_addr,
+                dst_addr: ::igmp::ALL_IGMPV3_ROUTERS,
+                protocol: IpProtocol::Igmp,
+                options: Ipv4Options::router_alert(),
+            },
+            payload: report,
+        }
+    }
+}
+
 impl<T> Ipv4Packet<T> {
-    fn header_len(&self) -> u8 {
-        20
+    /// Creates a packet with an arbitrary protocol number and a raw payload.
+    pub fn new_raw(src_addr: Ipv4Address,
+                   dst_addr: Ipv4Address,
+                   protocol: IpProtocol,
+                   payload: T)
+                   -> Self {
+        Ipv4Packet {
+            header: Ipv4Header {
+                src_addr: src_addr,
+                dst_addr: dst_addr,
+                protocol: protocol,
+                options: Ipv4Options::empty(),
+            },
+            payload: payload,
+        }
+    }
+
+    /// The header length in bytes, including options and padding.
+    pub fn header_len(&self) -> u8 {
+        20 + self.header.options.padded_len()
     }
 }
 
@@ -132,17 +514,24 @@ impl<T: WriteOut> Ipv4Packet<T> {
         packet.push_u16(0)?; // identification
         packet.push_u16(1 << 14)?; // flags and fragment_offset (bit 14 == don't fragment)
 
-        packet.push_byte(64)?; // time to live
+        // IGMP messages must not leave the link
+        let ttl = if self.header.protocol == IpProtocol::Igmp { 1 } else { 64 };
+        packet.push_byte(ttl)?; // time to live
         packet.push_byte(self.header.protocol.number())?; // protocol
         let checksum_idx = packet.push_u16(0)?; // checksum
 
         packet.push_bytes(&self.header.src_addr.as_bytes())?;
         packet.push_bytes(&self.header.dst_addr.as_bytes())?;
 
+        let options = self.header.options.as_bytes();
+        packet.push_bytes(options)?;
+        let padding = usize::from(self.header.options.padded_len()) - options.len();
+        packet.push_bytes(&[0; 3][..padding])?; // end of options list
+
         let end_index = packet.len();
 
         // calculate ip checksum
-        let checksum = !ip_checksum::data(&packet[start_index..end_index]);
+        let checksum = !ip_checksum::data(packet.get_bytes(start_index, end_index - start_index));
         packet.set_u16(checksum_idx, checksum);
 
         Ok(())
@@ -154,74 +543,80 @@ impl<T: WriteOut> WriteOut for Ipv4Packet<T> {
         self.payload.len() + usize::from(self.header_len())
     }
 
-    default fn write_out<P: TxPacket>(&self, packet: &mut P) -> Result<(), ()> {
-        self.write_out_impl(packet)?;
-        self.payload.write_out(packet)
-    }
-}
-
-impl<T: WriteOut> WriteOut for Ipv4Packet<UdpPacket<T>> {
     fn write_out<P: TxPacket>(&self, packet: &mut P) -> Result<(), ()> {
         self.write_out_impl(packet)?;
 
-        let udp_start_index = packet.len();
+        let payload_start_index = packet.len();
         self.payload.write_out(packet)?;
-
-        // calculate udp checksum
-        let pseudo_header_checksum = !ip_checksum::pseudo_header(&self.header.src_addr,
-                                                                 &self.header.dst_addr,
-                                                                 self.header.protocol,
-                                                                 self.payload.len());
-
-        let udp_checksum_idx = udp_start_index + 3 * 2;
-        packet.update_u16(udp_checksum_idx, |checksum| {
-            let checksums = [checksum, pseudo_header_checksum];
-            ip_checksum::combine(&checksums)
-        });
-
-        Ok(())
-    }
-}
-
-impl<'a, T: WriteOut> WriteOut for Ipv4Packet<&'a TcpPacket<T>> {
-    fn write_out<P: TxPacket>(&self, packet: &mut P) -> Result<(), ()> {
-        self.write_out_impl(packet)?;
-
-        let tcp_start_index = packet.len();
-        self.payload.write_out(packet)?;
-
-        // calculate tcp checksum
-        let pseudo_header_checksum = !ip_checksum::pseudo_header(&self.header.src_addr,
-                                                                 &self.header.dst_addr,
-                                                                 self.header.protocol,
-                                                                 self.payload.len());
-
-        let tcp_checksum_idx = tcp_start_index + 16;
-        packet.update_u16(tcp_checksum_idx, |checksum| {
-            let checksums = [checksum, pseudo_header_checksum];
-            ip_checksum::combine(&checksums)
-        });
+        let payload_end_index = packet.len();
+        packet.mark_payload(payload_start_index..payload_end_index);
+
+        // add the pseudo header to the transport checksum
+        if let Some(offset) = self.payload.pseudo_header_checksum_offset() {
+            let pseudo_header_checksum = !ip_checksum::pseudo_header(&self.header.src_addr,
+                                                                     &self.header.dst_addr,
+                                                                     self.header.protocol,
+                                                                     self.payload.len());
+            let protocol = self.header.protocol;
+            packet.update_u16(payload_start_index + offset, |checksum| {
+                match (ip_checksum::combine(&[checksum, pseudo_header_checksum]), protocol) {
+                    // a zero UDP checksum means that there is no checksum, but 0xffff
+                    // is the same sum in ones' complement
+                    (0, IpProtocol::Udp) | (0, IpProtocol::UdpLite) => 0xffff,
+                    (checksum, _) => checksum,
+                }
+            });
+        }
 
         Ok(())
     }
 }
 
-use parse::{Parse, ParseError};
-use udp::UdpKind;
+use parse::{Layer, Parse, ParseError};
+use udp::{self, UdpKind};
+use udplite;
+#[cfg(any(test, feature = "tcp"))]
 use tcp::TcpKind;
 
 impl<'a> Parse<'a> for Ipv4Packet<&'a [u8]> {
     fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
         use byteorder::{ByteOrder, NetworkEndian};
 
-        let total_len = NetworkEndian::read_u16(&data[2..4]);
+        if data.len() < 20 {
+            return Err(ParseError::truncated(Layer::Ipv4, data.len()));
+        }
+        if data[0] >> 4 != 4 {
+            return Err(ParseError::malformed(Layer::Ipv4, 0, "IP version is not 4"));
+        }
+        let header_len = usize::from(data[0] & 0xf) * 4;
+        if header_len < 20 {
+            return Err(ParseError::malformed(Layer::Ipv4, 0, "IPv4 header length below 20 bytes"));
+        }
+        if data.len() < header_len {
+            return Err(ParseError::truncated(Layer::Ipv4, data.len()));
+        }
+
+        let total_len = usize::from(NetworkEndian::read_u16(&data[2..4]));
+        if total_len < header_len {
+            return Err(ParseError::malformed(Layer::Ipv4,
+                                             2,
+                                             "IPv4 total length smaller than header"));
+        }
+        if data.len() < total_len {
+            return Err(ParseError::truncated(Layer::Ipv4, data.len()));
+        }
+        if let Err(offset) = Ipv4Options::validate(&data[20..header_len]) {
+            return Err(ParseError::malformed(Layer::Ipv4, 20 + offset, "invalid IPv4 option"));
+        }
+
         Ok(Ipv4Packet {
                header: Ipv4Header {
                    src_addr: Ipv4Address::from_bytes(&data[12..16]),
                    dst_addr: Ipv4Address::from_bytes(&data[16..20]),
                    protocol: IpProtocol::from_number(data[9]),
+                   options: Ipv4Options::from_bytes(&data[20..header_len]).unwrap(),
                },
-               payload: &data[20..total_len as usize],
+               payload: &data[header_len..total_len],
            })
     }
 }
@@ -229,42 +624,97 @@ impl<'a> Parse<'a> for Ipv4Packet<&'a [u8]> {
 #[derive(Debug)]
 pub enum Ipv4Kind<'a> {
     Udp(UdpPacket<UdpKind<'a>>),
+    #[cfg(any(test, feature = "tcp"))]
     Tcp(TcpPacket<TcpKind<'a>>),
-    Icmp(IcmpPacket<&'a [u8]>),
+    #[cfg(any(test, feature = "icmp"))]
+    Icmp(IcmpKind<'a>),
+    Igmp(IgmpMessage),
+    /// A GRE packet, the tunneled packet is left unparsed.
+    Gre(GrePacket<&'a [u8]>),
+    UdpLite(UdpLitePacket<&'a [u8]>),
+    /// A tunneled IPv4 packet, up to `MAX_TUNNEL_DEPTH` levels deep, and its
+    /// unparsed bytes.
+    #[cfg(any(test, feature = "alloc"))]
+    Ipv4(Box<Ipv4Packet<Ipv4Kind<'a>>>, &'a [u8]),
+    /// Also TCP and ICMP packets if their features are disabled, and tunneled packets
+    /// without the `alloc` feature or nested too deep.
     Unknown(u8, &'a [u8]),
 }
 
+/// The number of IP-in-IP headers that are parsed into `Ipv4Kind::Ipv4`.
+pub const MAX_TUNNEL_DEPTH: usize = 2;
+
 impl<'a> Parse<'a> for Ipv4Packet<Ipv4Kind<'a>> {
     fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
-        let ip = Ipv4Packet::parse(data)?;
-        match ip.header.protocol {
-            IpProtocol::Udp => {
-                let udp = UdpPacket::parse(ip.payload)?;
-                Ok(Ipv4Packet {
-                       header: ip.header,
-                       payload: Ipv4Kind::Udp(udp),
-                   })
-            }
-            IpProtocol::Tcp => {
-                let tcp = TcpPacket::parse(ip.payload)?;
-                Ok(Ipv4Packet {
-                       header: ip.header,
-                       payload: Ipv4Kind::Tcp(tcp),
-                   })
-            }
-            IpProtocol::Icmp => {
-                let icmp = IcmpPacket::parse(ip.payload)?;
-                Ok(Ipv4Packet {
-                       header: ip.header,
-                       payload: Ipv4Kind::Icmp(icmp),
-                   })
-            }
-            IpProtocol::Unknown(number) => {
-                Ok(Ipv4Packet {
-                       header: ip.header,
-                       payload: Ipv4Kind::Unknown(number, ip.payload),
-                   })
-            }
+        parse_kind(data, 0)
+    }
+}
+
+/// Parses a packet inside of `tunnel_depth` IP-in-IP headers.
+#[cfg_attr(not(any(test, feature = "alloc")), allow(unused_variables))]
+fn parse_kind(data: &[u8], tunnel_depth: usize) -> Result<Ipv4Packet<Ipv4Kind>, ParseError> {
+    let ip = Ipv4Packet::parse(data)?;
+    let header_len = usize::from(ip.header_len());
+    match ip.header.protocol {
+        IpProtocol::Udp => {
+            udp::verify_checksum(&ip.header, ip.payload).map_err(|e| e.at(header_len))?;
+            let udp = UdpPacket::parse(ip.payload).map_err(|e| e.at(header_len))?;
+            Ok(Ipv4Packet {
+                   header: ip.header,
+                   payload: Ipv4Kind::Udp(udp),
+               })
+        }
+        #[cfg(any(test, feature = "tcp"))]
+        IpProtocol::Tcp => {
+            let tcp = TcpPacket::parse(ip.payload).map_err(|e| e.at(header_len))?;
+            Ok(Ipv4Packet {
+                   header: ip.header,
+                   payload: Ipv4Kind::Tcp(tcp),
+               })
+        }
+        #[cfg(any(test, feature = "icmp"))]
+        IpProtocol::Icmp => {
+            let icmp = IcmpKind::parse(ip.payload).map_err(|e| e.at(header_len))?;
+            Ok(Ipv4Packet {
+                   header: ip.header,
+                   payload: Ipv4Kind::Icmp(icmp),
+               })
+        }
+        IpProtocol::Igmp => {
+            let igmp = IgmpMessage::parse(ip.payload).map_err(|e| e.at(header_len))?;
+            Ok(Ipv4Packet {
+                   header: ip.header,
+                   payload: Ipv4Kind::Igmp(igmp),
+               })
+        }
+        IpProtocol::Gre => {
+            let gre = GrePacket::parse(ip.payload).map_err(|e| e.at(header_len))?;
+            Ok(Ipv4Packet {
+                   header: ip.header,
+                   payload: Ipv4Kind::Gre(gre),
+               })
+        }
+        IpProtocol::UdpLite => {
+            udplite::verify_checksum(&ip.header, ip.payload).map_err(|e| e.at(header_len))?;
+            let udp = UdpLitePacket::parse(ip.payload).map_err(|e| e.at(header_len))?;
+            Ok(Ipv4Packet {
+                   header: ip.header,
+                   payload: Ipv4Kind::UdpLite(udp),
+               })
+        }
+        #[cfg(any(test, feature = "alloc"))]
+        IpProtocol::IpInIp if tunnel_depth < MAX_TUNNEL_DEPTH => {
+            let inner = parse_kind(ip.payload, tunnel_depth + 1).map_err(|e| e.at(header_len))?;
+            Ok(Ipv4Packet {
+                   header: ip.header,
+                   payload: Ipv4Kind::Ipv4(Box::new(inner), ip.payload),
+               })
+        }
+        protocol => {
+            Ok(Ipv4Packet {
+                   header: ip.header,
+                   payload: Ipv4Kind::Unknown(protocol.number(), ip.payload),
+               })
         }
     }
 }
@@ -279,6 +729,7 @@ fn checksum() {
             src_addr: Ipv4Address::new(141, 52, 45, 122),
             dst_addr: Ipv4Address::new(255, 255, 255, 255),
             protocol: IpProtocol::Udp,
+            options: Ipv4Options::empty(),
         },
         payload: Empty,
     };
@@ -297,3 +748,114 @@ fn checksum() {
                HexDumpPrint(data),
                HexDumpPrint(reference_data));
 }
+
+#[test]
+fn parse_header_len() {
+    let mut data = [0x46, 0x00, 0x00, 0x1a, 0x00, 0x00, 0x40, 0x00, 0x01, 0x02, 0x00, 0x00,
+                    0xc0, 0xa8, 0x01, 0x01, 0xe0, 0x00, 0x00, 0x16, 0x94, 0x04, 0x00, 0x00,
+                    0xaa, 0xbb, 0x00, 0x00];
+    {
+        let ip = Ipv4Packet::<&[u8]>::parse(&data[..]).unwrap();
+        assert_eq!(ip.header.options.as_bytes(), &[0x94, 0x04, 0x00, 0x00]);
+        assert_eq!(ip.header.protocol(), IpProtocol::Igmp);
+        assert_eq!(ip.payload, &[0xaa, 0xbb]);
+    }
+
+    data[0] = 0x44;
+    assert_eq!(Ipv4Packet::<&[u8]>::parse(&data[..]).unwrap_err().offset(), 0);
+    data[0] = 0x66;
+    assert_eq!(Ipv4Packet::<&[u8]>::parse(&data[..]).unwrap_err().offset(), 0);
+    data[0] = 0x4f;
+    assert_eq!(Ipv4Packet::<&[u8]>::parse(&data[..]).unwrap_err(),
+               ParseError::truncated(Layer::Ipv4, data.len()));
+}
+
+#[test]
+fn router_alert() {
+    use HeapTxPacket;
+
+    let mut options = Ipv4Options::router_alert();
+    let unknown = Ipv4Option::Unknown {
+        kind: 0x88,
+        data: &[0x12, 0x34],
+    };
+    options.push(unknown).unwrap();
+    let mut ip = Ipv4Packet::new_raw(Ipv4Address::new(192, 168, 1, 2),
+                                     Ipv4Address::new(224, 0, 0, 22),
+                                     IpProtocol::Unknown(2),
+                                     &[0x22, 0x00][..]);
+    ip.header.options = options;
+    assert_eq!(ip.header_len(), 28);
+
+    let mut packet = HeapTxPacket::new(ip.len());
+    ip.write_out(&mut packet).unwrap();
+    assert_eq!(packet[0], 0x47);
+    assert_eq!(&packet.as_slice()[20..24], &[0x94, 0x04, 0x00, 0x00]);
+
+    let parsed = Ipv4Packet::<&[u8]>::parse(packet.as_slice()).unwrap();
+    assert_eq!(parsed.header.options.router_alert_value(), Some(0));
+    let mut iter = parsed.header.options.iter();
+    assert_eq!(iter.next(), Some(Ipv4Option::RouterAlert(0)));
+    assert_eq!(iter.next(), Some(unknown));
+    assert_eq!(iter.next(), None);
+    assert_eq!(parsed.payload, &[0x22, 0x00]);
+
+    packet[25] = 9; // option length past the header
+    assert_eq!(Ipv4Packet::<&[u8]>::parse(packet.as_slice()).unwrap_err(),
+               ParseError::malformed(Layer::Ipv4, 24, "invalid IPv4 option"));
+}
+
+#[test]
+fn tcp_pseudo_header_checksum() {
+    use tcp::{TcpFlags, TcpHeaderBuilder};
+    use testing::assert_serializes_to;
+
+    let tcp = TcpPacket {
+        header: TcpHeaderBuilder::new(0xc000, 80)
+            .sequence_number(0x0100_0001)
+            .ack_number(0x0000_2001)
+            .flags(TcpFlags::ACK)
+            .window_size(0xfaf0)
+            .build(),
+        payload: &[][..],
+    };
+    let src = Ipv4Address::new(192, 168, 1, 100);
+    let dst = Ipv4Address::new(192, 168, 1, 1);
+
+    // the ACK of `testing::frames::TCP_ACK`, with an identification of 0
+    let reference_data = [0x45, 0x00, 0x00, 0x28, 0x00, 0x00, 0x40, 0x00, 0x40, 0x06, 0xb7, 0x1a,
+                          0xc0, 0xa8, 0x01, 0x64, 0xc0, 0xa8, 0x01, 0x01, 0xc0, 0x00, 0x00, 0x50,
+                          0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x20, 0x01, 0x50, 0x10, 0xfa, 0xf0,
+                          0x4f, 0xdb, 0x00, 0x00];
+    assert_serializes_to(&Ipv4Packet::new_tcp(src, dst, &tcp), &reference_data);
+    assert_serializes_to(&Ipv4Packet::new_tcp_owned(src, dst, tcp), &reference_data);
+}
+
+#[test]
+fn parse_tcp_syn() {
+    use core::num::Wrapping;
+    use ethernet::EthernetKind;
+    use parse::parse;
+    use tcp::{TcpFlags, TcpKind};
+    use testing::frames;
+
+    let frame = hex!(frames::TCP_SYN);
+    let ip = match parse(&frame).unwrap().payload {
+        EthernetKind::Ipv4(ip) => ip,
+        other => panic!("unexpected packet {:?}", other),
+    };
+    assert_eq!(ip.header.src_addr, Ipv4Address::new(192, 168, 1, 100));
+    assert_eq!(ip.header.protocol, IpProtocol::Tcp);
+    let tcp = match ip.payload {
+        Ipv4Kind::Tcp(tcp) => tcp,
+        other => panic!("unexpected packet {:?}", other),
+    };
+    assert_eq!((tcp.header.src_port, tcp.header.dst_port), (0xc000, 80));
+    assert_eq!(tcp.header.sequence_number, Wrapping(0x0100_0000));
+    assert_eq!(tcp.header.flags, TcpFlags::SYN);
+    assert_eq!(tcp.header.window_size, 0xfaf0);
+    assert!(tcp.header.sack_permitted);
+    assert_eq!(tcp.header.timestamp, None);
+    let TcpKind::Unknown(payload) = tcp.payload;
+    assert!(payload.is_empty());
+}
@@ -0,0 +1,65 @@
+//! IPv6 addresses. Packets are not supported yet.
+
+use core::fmt;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Ipv6Address([u8; 16]);
+
+impl Ipv6Address {
+    pub fn new(bytes: [u8; 16]) -> Self {
+        Ipv6Address(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut inner = [0; 16];
+        inner.copy_from_slice(bytes);
+        Ipv6Address(inner)
+    }
+
+    /// Combines the upper 64 bits of `prefix` with an interface identifier.
+    pub fn from_prefix(prefix: Ipv6Address, interface_id: [u8; 8]) -> Self {
+        let mut inner = prefix.0;
+        inner[8..].copy_from_slice(&interface_id);
+        Ipv6Address(inner)
+    }
+
+    /// The link-local address (fe80::/64) with the given interface identifier.
+    pub fn link_local(interface_id: [u8; 8]) -> Self {
+        let mut prefix = [0; 16];
+        prefix[0] = 0xfe;
+        prefix[1] = 0x80;
+        Ipv6Address::from_prefix(Ipv6Address(prefix), interface_id)
+    }
+
+    pub fn as_bytes(&self) -> [u8; 16] {
+        self.0
+    }
+
+    pub fn is_link_local(&self) -> bool {
+        self.0[0] == 0xfe && self.0[1] & 0xc0 == 0x80
+    }
+
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] == 0xff
+    }
+
+    /// The solicited-node multicast address (ff02::1:ffxx:xxxx) that neighbor
+    /// solicitations for this address are sent to.
+    pub fn solicited_node(&self) -> Ipv6Address {
+        let mut inner = [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01, 0xff, 0, 0, 0];
+        inner[13..].copy_from_slice(&self.0[13..]);
+        Ipv6Address(inner)
+    }
+}
+
+impl fmt::Debug for Ipv6Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for i in 0..8 {
+            if i > 0 {
+                write!(f, ":")?;
+            }
+            write!(f, "{:x}", u16::from(self.0[2 * i]) << 8 | u16::from(self.0[2 * i + 1]))?;
+        }
+        Ok(())
+    }
+}
@@ -1,5 +1,4 @@
 #![feature(try_from)]
-#![feature(specialization)]
 #![feature(const_fn)]
 #![feature(conservative_impl_trait)]
 
@@ -11,41 +10,114 @@ extern crate alloc;
 
 extern crate byteorder;
 extern crate bit_field;
+#[cfg(feature = "heapless")]
+extern crate heapless;
+#[cfg(feature = "nb")]
+extern crate nb;
 
 #[cfg(test)]
 mod core {
     pub use std::*;
 }
 
-pub use parse::{parse, ParseError};
+pub use parse::{parse, parse_with, Layer, PacketVisitor, ParseError};
+#[doc(hidden)]
+pub use parse::parse_any;
 #[cfg(any(test, feature = "alloc"))]
 pub use heap_tx_packet::HeapTxPacket;
+#[cfg(feature = "heapless")]
+pub use heapless_tx_packet::HeaplessTxPacket;
 
-use core::ops::{Index, IndexMut, Range};
-use core::borrow::Borrow;
 use byteorder::{ByteOrder, NetworkEndian};
+use core::ops::Range;
 
-#[macro_use]
+#[cfg_attr(any(test, feature = "tcp"), macro_use)]
 extern crate bitflags_associated_constants;
 
+#[cfg(any(test, feature = "testing"))]
+#[macro_use]
+pub mod testing;
 pub mod ethernet;
 pub mod arp;
 pub mod ipv4;
+pub mod ipv6;
 pub mod udp;
+pub mod udplite;
+#[cfg(any(test, feature = "tcp"))]
 pub mod tcp;
+#[cfg(any(test, feature = "dhcp"))]
 pub mod dhcp;
+pub mod autoip;
+pub mod acd;
+pub mod slaac;
+pub mod sixlowpan;
+#[cfg(any(test, feature = "icmp"))]
 pub mod icmp;
-mod ip_checksum;
+pub mod igmp;
+#[cfg(any(test, feature = "coap"))]
+pub mod coap;
+#[cfg(any(test, feature = "mqttsn"))]
+pub mod mqttsn;
+#[cfg(any(test, feature = "syslog"))]
+pub mod syslog;
+#[cfg(any(test, feature = "sntp"))]
+pub mod sntp;
+#[cfg(any(test, feature = "modbus"))]
+pub mod modbus;
+#[cfg(any(test, feature = "snmp"))]
+pub mod snmp;
+#[cfg(any(test, feature = "dns"))]
+pub mod dns;
+#[cfg(any(test, feature = "mdns"))]
+pub mod mdns;
+#[cfg(any(test, feature = "http"))]
+pub mod http;
+pub mod filter;
+pub mod gre;
+pub mod ipip;
+pub mod eapol;
+pub mod ptp;
+pub mod scatter_gather;
+pub mod headroom;
+pub mod packet_memory;
+pub mod payload;
+pub mod ports;
+pub mod retry;
+#[cfg(any(test, feature = "alloc"))]
+pub mod nat;
+pub mod builder;
+#[cfg(any(test, feature = "alloc"))]
+pub mod owned;
+pub mod slip;
+#[cfg(any(test, feature = "alloc"))]
+pub mod interface;
+#[cfg(any(test, feature = "alloc"))]
+pub mod events;
+#[cfg(all(feature = "nb", any(test, feature = "alloc")))]
+pub mod socket;
+pub mod ip_checksum;
+pub mod tlv;
+pub mod crc32;
+pub mod stats;
 mod test;
 mod parse;
 
-pub trait TxPacket: Index<usize, Output=u8> + IndexMut<usize> + Index<Range<usize>, Output=[u8]>
-    + IndexMut<Range<usize>>
-{
+/// A packet buffer that is written front to back, with random access to the
+/// already written bytes (e.g. for filling in lengths and checksums).
+pub trait TxPacket {
     fn push_bytes(&mut self, bytes: &[u8]) -> Result<usize, ()>;
 
     fn len(&self) -> usize;
 
+    /// Returns `len` already written bytes starting at `index`.
+    ///
+    /// Callers read at most `packet_memory::MAX_READ_LEN` bytes at once, so that
+    /// packets in external memory can serve reads from a small buffer.
+    fn get_bytes(&mut self, index: usize, len: usize) -> &[u8];
+
+    /// Overwrites already written bytes starting at `index`.
+    fn set_bytes(&mut self, index: usize, bytes: &[u8]);
+
     fn push_byte(&mut self, value: u8) -> Result<usize, ()> {
         let bytes = [value];
         self.push_bytes(&bytes)
@@ -63,14 +135,6 @@ pub trait TxPacket: Index<usize, Output=u8> + IndexMut<usize> + Index<Range<usiz
         self.push_bytes(&bytes)
     }
 
-    fn get_bytes(&mut self, index: usize, len: usize) -> &[u8] {
-        &self[index..(index + len)]
-    }
-
-    fn set_bytes(&mut self, index: usize, bytes: &[u8]) {
-        self[index..(index + bytes.len())].copy_from_slice(bytes);
-    }
-
     fn set_u16(&mut self, index: usize, value: u16) {
         let mut bytes = [0, 0];
         NetworkEndian::write_u16(&mut bytes, value);
@@ -78,26 +142,285 @@ pub trait TxPacket: Index<usize, Output=u8> + IndexMut<usize> + Index<Range<usiz
     }
 
     fn update_u16<F>(&mut self, index: usize, f: F)
-        where F: FnOnce(u16) -> u16
+        where F: FnOnce(u16) -> u16,
+              Self: Sized
     {
         let value = NetworkEndian::read_u16(self.get_bytes(index, 2));
         let value = f(value);
         self.set_u16(index, value);
     }
+
+    /// Appends `len` bytes that are transmitted from an external buffer instead of
+    /// being written to this packet. `checksum` is the RFC 1071 sum of these bytes.
+    ///
+    /// Packets that can't refer to external data return an error.
+    fn push_external(&mut self, len: usize, checksum: u16) -> Result<usize, ()> {
+        let _ = (len, checksum);
+        Err(())
+    }
+
+    /// The number of bytes that can still be pushed, or `None` if unknown.
+    fn remaining_capacity(&self) -> Option<usize> {
+        None
+    }
+
+    /// Called by every layer after writing its payload, innermost layer first, so
+    /// that a packet can remember where e.g. the UDP payload lies.
+    fn mark_payload(&mut self, range: Range<usize>) {
+        let _ = range;
+    }
+}
+
+/// Forwards to the referenced packet, so that `&mut TxPacket` can be passed to
+/// `write_out`.
+impl<'a, P: TxPacket + ?Sized> TxPacket for &'a mut P {
+    fn push_bytes(&mut self, bytes: &[u8]) -> Result<usize, ()> {
+        (**self).push_bytes(bytes)
+    }
+
+    fn len(&self) -> usize {
+        (**self).len()
+    }
+
+    fn get_bytes(&mut self, index: usize, len: usize) -> &[u8] {
+        (**self).get_bytes(index, len)
+    }
+
+    fn set_bytes(&mut self, index: usize, bytes: &[u8]) {
+        (**self).set_bytes(index, bytes)
+    }
+
+    fn push_external(&mut self, len: usize, checksum: u16) -> Result<usize, ()> {
+        (**self).push_external(len, checksum)
+    }
+
+    fn remaining_capacity(&self) -> Option<usize> {
+        (**self).remaining_capacity()
+    }
+
+    fn mark_payload(&mut self, range: Range<usize>) {
+        (**self).mark_payload(range)
+    }
 }
 
 pub trait WriteOut {
+    /// The exact number of bytes that `write_out` pushes, including the padding and
+    /// frame check sequence added by lower layers.
     fn len(&self) -> usize;
+
     fn write_out<T: TxPacket>(&self, packet: &mut T) -> Result<(), ()>;
+
+    /// The buffer space needed to serialize this packet. Same as `len`.
+    fn required_capacity(&self) -> usize {
+        self.len()
+    }
+
+    /// The offset of a checksum field in the written bytes that also covers the IPv4
+    /// pseudo header, for transport layers like UDP and TCP. The enclosing
+    /// `Ipv4Packet` adds the pseudo header after writing the payload.
+    fn pseudo_header_checksum_offset(&self) -> Option<usize> {
+        None
+    }
 }
 
-impl<T> WriteOut for T where T: Borrow<[u8]> {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteError {
+    /// The packet doesn't fit into the remaining buffer space. Nothing was written.
+    BufferFull { needed: usize },
+    /// Serialization failed after the size check, e.g. because a layer doesn't
+    /// support external payloads.
+    Failed,
+}
+
+/// Serializes `data` into `packet` after checking that it fits completely.
+///
+/// Unlike calling `write_out` directly, a too small buffer never ends up with a
+/// partially written frame.
+pub fn write_packet<P, T>(packet: &mut P, data: &T) -> Result<(), WriteError>
+    where P: TxPacket,
+          T: WriteOut
+{
+    let needed = data.required_capacity();
+    if packet.remaining_capacity().map_or(false, |remaining| remaining < needed) {
+        return Err(WriteError::BufferFull { needed });
+    }
+    data.write_out(packet).map_err(|()| WriteError::Failed)
+}
+
+impl<'a, T: WriteOut + ?Sized> WriteOut for &'a T {
     fn len(&self) -> usize {
-        <[u8]>::len(self.borrow())
+        (**self).len()
     }
 
     fn write_out<P: TxPacket>(&self, packet: &mut P) -> Result<(), ()> {
-        packet.push_bytes(self.borrow()).map(|_| ())
+        (**self).write_out(packet)
+    }
+
+    fn required_capacity(&self) -> usize {
+        (**self).required_capacity()
+    }
+
+    fn pseudo_header_checksum_offset(&self) -> Option<usize> {
+        (**self).pseudo_header_checksum_offset()
+    }
+}
+
+/// An object-safe version of `WriteOut`, implemented for all `WriteOut` types.
+///
+/// Packets of different types can be stored as `&WriteOutDyn` or `Box<WriteOutDyn>`
+/// and written into a `&mut TxPacket`, e.g. by a driver task that doesn't know the
+/// packet types. The `WriteOutDyn` trait object implements `WriteOut` again, so it can
+/// be nested into other packets.
+pub trait WriteOutDyn {
+    fn len_dyn(&self) -> usize;
+
+    fn write_out_dyn(&self, packet: &mut TxPacket) -> Result<(), ()>;
+
+    fn required_capacity_dyn(&self) -> usize;
+
+    fn pseudo_header_checksum_offset_dyn(&self) -> Option<usize>;
+}
+
+impl<T: WriteOut> WriteOutDyn for T {
+    fn len_dyn(&self) -> usize {
+        self.len()
+    }
+
+    fn write_out_dyn(&self, mut packet: &mut TxPacket) -> Result<(), ()> {
+        self.write_out(&mut packet)
+    }
+
+    fn required_capacity_dyn(&self) -> usize {
+        self.required_capacity()
+    }
+
+    fn pseudo_header_checksum_offset_dyn(&self) -> Option<usize> {
+        self.pseudo_header_checksum_offset()
+    }
+}
+
+impl<'a> WriteOut for WriteOutDyn + 'a {
+    fn len(&self) -> usize {
+        self.len_dyn()
+    }
+
+    fn write_out<P: TxPacket>(&self, packet: &mut P) -> Result<(), ()> {
+        self.write_out_dyn(packet)
+    }
+
+    fn required_capacity(&self) -> usize {
+        self.required_capacity_dyn()
+    }
+
+    fn pseudo_header_checksum_offset(&self) -> Option<usize> {
+        self.pseudo_header_checksum_offset_dyn()
+    }
+}
+
+#[cfg(feature = "heapless")]
+mod heapless_tx_packet {
+    use core::ops::{Deref, Range};
+    use heapless::{ArrayLength, Vec};
+    use TxPacket;
+
+    /// A statically sized packet buffer, for serializing without `alloc`.
+    impl<N: ArrayLength<u8>> TxPacket for Vec<u8, N> {
+        fn push_bytes(&mut self, bytes: &[u8]) -> Result<usize, ()> {
+            let index = self.len();
+            self.extend_from_slice(bytes)?;
+            Ok(index)
+        }
+
+        fn len(&self) -> usize {
+            <[u8]>::len(self)
+        }
+
+        fn get_bytes(&mut self, index: usize, len: usize) -> &[u8] {
+            &self[index..(index + len)]
+        }
+
+        fn set_bytes(&mut self, index: usize, bytes: &[u8]) {
+            self[index..(index + bytes.len())].copy_from_slice(bytes);
+        }
+
+        fn remaining_capacity(&self) -> Option<usize> {
+            Some(self.capacity() - <[u8]>::len(self))
+        }
+    }
+
+    /// A statically sized packet buffer that remembers the payload range like
+    /// `HeapTxPacket`.
+    pub struct HeaplessTxPacket<N: ArrayLength<u8>> {
+        data: Vec<u8, N>,
+        payload_range: Option<Range<usize>>,
+    }
+
+    impl<N: ArrayLength<u8>> HeaplessTxPacket<N> {
+        pub fn new() -> Self {
+            HeaplessTxPacket {
+                data: Vec::new(),
+                payload_range: None,
+            }
+        }
+
+        pub fn into_inner(self) -> Vec<u8, N> {
+            self.data
+        }
+
+        /// See `HeapTxPacket::payload_range`.
+        pub fn payload_range(&self) -> Option<Range<usize>> {
+            self.payload_range.clone()
+        }
+
+        /// See `HeapTxPacket::payload_mut`.
+        pub fn payload_mut(&mut self) -> Option<&mut [u8]> {
+            match self.payload_range {
+                Some(ref range) => Some(&mut self.data[range.clone()]),
+                None => None,
+            }
+        }
+    }
+
+    impl<N: ArrayLength<u8>> Default for HeaplessTxPacket<N> {
+        fn default() -> Self {
+            HeaplessTxPacket::new()
+        }
+    }
+
+    impl<N: ArrayLength<u8>> TxPacket for HeaplessTxPacket<N> {
+        fn push_bytes(&mut self, bytes: &[u8]) -> Result<usize, ()> {
+            self.data.push_bytes(bytes)
+        }
+
+        fn len(&self) -> usize {
+            self.data.len()
+        }
+
+        fn get_bytes(&mut self, index: usize, len: usize) -> &[u8] {
+            &self.data[index..(index + len)]
+        }
+
+        fn set_bytes(&mut self, index: usize, bytes: &[u8]) {
+            self.data.set_bytes(index, bytes)
+        }
+
+        fn remaining_capacity(&self) -> Option<usize> {
+            self.data.remaining_capacity()
+        }
+
+        fn mark_payload(&mut self, range: Range<usize>) {
+            if self.payload_range.is_none() {
+                self.payload_range = Some(range);
+            }
+        }
+    }
+
+    impl<N: ArrayLength<u8>> Deref for HeaplessTxPacket<N> {
+        type Target = Vec<u8, N>;
+
+        fn deref(&self) -> &Vec<u8, N> {
+            &self.data
+        }
     }
 }
 
@@ -107,41 +430,81 @@ mod heap_tx_packet {
     use alloc::boxed::Box;
     use alloc::vec::Vec;
     use ethernet::EthernetPacket;
-    use {WriteOut, TxPacket};
+    use {write_packet, WriteError, WriteOut, TxPacket};
 
-    pub struct HeapTxPacket(Vec<u8>);
+    pub struct HeapTxPacket {
+        data: Vec<u8>,
+        payload_range: Option<Range<usize>>,
+    }
 
     impl HeapTxPacket {
         pub fn new(max_len: usize) -> HeapTxPacket {
-            HeapTxPacket(Vec::with_capacity(max_len))
+            HeapTxPacket {
+                data: Vec::with_capacity(max_len),
+                payload_range: None,
+            }
         }
 
-        pub fn write_out<T: WriteOut>(packet: EthernetPacket<T>) -> Result<HeapTxPacket, ()> {
-            let mut tx_packet = HeapTxPacket::new(packet.len());
-            packet.write_out(&mut tx_packet)?;
+        pub fn write_out<T: WriteOut>(packet: EthernetPacket<T>)
+                                      -> Result<HeapTxPacket, WriteError> {
+            let mut tx_packet = HeapTxPacket::new(packet.required_capacity());
+            write_packet(&mut tx_packet, &packet)?;
             Ok(tx_packet)
         }
 
         pub fn into_boxed_slice(self) -> Box<[u8]> {
-            self.0.into_boxed_slice()
+            self.data.into_boxed_slice()
+        }
+
+        /// The range of the payload of the innermost layer that was written, e.g. the
+        /// PTP message of a UDP datagram.
+        pub fn payload_range(&self) -> Option<Range<usize>> {
+            self.payload_range.clone()
+        }
+
+        /// The payload of the innermost layer, so that e.g. a timestamp can be inserted
+        /// just before transmission. Checksums that cover it aren't updated.
+        pub fn payload_mut(&mut self) -> Option<&mut [u8]> {
+            match self.payload_range {
+                Some(ref range) => Some(&mut self.data[range.clone()]),
+                None => None,
+            }
         }
     }
 
     impl TxPacket for HeapTxPacket {
         fn push_bytes(&mut self, bytes: &[u8]) -> Result<usize, ()> {
-            if self.0.capacity() - self.0.len() < bytes.len() {
+            if self.data.capacity() - self.data.len() < bytes.len() {
                 Err(())
             } else {
-                let index = self.0.len();
+                let index = self.data.len();
                 for &byte in bytes {
-                    self.0.push(byte);
+                    self.data.push(byte);
                 }
                 Ok(index)
             }
         }
 
         fn len(&self) -> usize {
-            self.0.len()
+            self.data.len()
+        }
+
+        fn get_bytes(&mut self, index: usize, len: usize) -> &[u8] {
+            &self.data[index..(index + len)]
+        }
+
+        fn set_bytes(&mut self, index: usize, bytes: &[u8]) {
+            self.data[index..(index + bytes.len())].copy_from_slice(bytes);
+        }
+
+        fn remaining_capacity(&self) -> Option<usize> {
+            Some(self.data.capacity() - self.data.len())
+        }
+
+        fn mark_payload(&mut self, range: Range<usize>) {
+            if self.payload_range.is_none() {
+                self.payload_range = Some(range);
+            }
         }
     }
 
@@ -149,7 +512,7 @@ mod heap_tx_packet {
         type Target = Vec<u8>;
 
         fn deref(&self) -> &Vec<u8> {
-            &self.0
+            &self.data
         }
     }
 
@@ -157,13 +520,13 @@ mod heap_tx_packet {
         type Output = u8;
 
         fn index(&self, index: usize) -> &u8 {
-            self.0.index(index)
+            self.data.index(index)
         }
     }
 
     impl IndexMut<usize> for HeapTxPacket {
         fn index_mut(&mut self, index: usize) -> &mut u8 {
-            self.0.index_mut(index)
+            self.data.index_mut(index)
         }
     }
 
@@ -171,13 +534,64 @@ mod heap_tx_packet {
         type Output = [u8];
 
         fn index(&self, index: Range<usize>) -> &[u8] {
-            self.0.index(index)
+            self.data.index(index)
         }
     }
 
     impl IndexMut<Range<usize>> for HeapTxPacket {
         fn index_mut(&mut self, index: Range<usize>) -> &mut [u8] {
-            self.0.index_mut(index)
+            self.data.index_mut(index)
         }
     }
 }
+
+#[test]
+fn dyn_packets() {
+    use alloc::boxed::Box;
+    use arp;
+    use ethernet::{EthernetAddress, EthernetPacket};
+    use ipv4::{IpProtocol, Ipv4Address, Ipv4Packet};
+    use udp::UdpPacket;
+
+    let mac = EthernetAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    let ip = Ipv4Address::new(192, 168, 1, 1);
+    let udp = Ipv4Packet::new_udp(ip, ip, UdpPacket::new(7, 7, &b"hello"[..]));
+    let frames: Vec<Box<WriteOutDyn>> = vec![Box::new(arp::new_gratuitous_packet(mac, ip)),
+                                             Box::new(EthernetPacket::new_ipv4(mac, mac, udp))];
+
+    for frame in &frames {
+        let mut packet = HeapTxPacket::new(frame.required_capacity_dyn());
+        frame.write_out_dyn(&mut packet).unwrap();
+        assert_eq!(packet.len(), frame.len_dyn());
+    }
+
+    // nested into another packet, the UDP checksum still covers the pseudo header
+    let payload: &WriteOutDyn = &UdpPacket::new(7, 7, &b"hello"[..]);
+    let mut packet = HeapTxPacket::new(64);
+    Ipv4Packet::new_raw(ip, ip, IpProtocol::Udp, payload).write_out(&mut packet).unwrap();
+    let mut reference = HeapTxPacket::new(64);
+    udp.write_out(&mut reference).unwrap();
+    assert_eq!(packet.as_slice(), reference.as_slice());
+    // the innermost payload is recorded, even through a trait object
+    assert_eq!(packet.payload_range(), Some(28..33));
+    assert_eq!(packet.payload_mut().map(|p| &p[..] == b"hello"), Some(true));
+}
+
+#[cfg(feature = "heapless")]
+#[test]
+fn heapless_packet() {
+    use heapless::consts::U64;
+    use ipv4::{Ipv4Address, Ipv4Packet};
+    use udp::UdpPacket;
+
+    let ip = Ipv4Address::new(192, 168, 1, 1);
+    let udp = Ipv4Packet::new_udp(ip, ip, UdpPacket::new(7, 7, &b"hello"[..]));
+    let mut packet = HeaplessTxPacket::<U64>::new();
+    udp.write_out(&mut packet).unwrap();
+    let mut reference = HeapTxPacket::new(64);
+    udp.write_out(&mut reference).unwrap();
+    assert_eq!(&packet[..], reference.as_slice());
+    assert_eq!(packet.payload_range(), Some(28..33));
+    packet.payload_mut().unwrap().copy_from_slice(b"world");
+    assert_eq!(&packet.into_inner()[28..], b"world");
+}
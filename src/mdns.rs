@@ -0,0 +1,406 @@
+//! A multicast DNS responder for the A record of `<hostname>.local` according to
+//! [RFC 6762][rfc6762].
+//!
+//! Before the name is used, it is probed three times. If another device answers for
+//! it, or wins the tie-break of a simultaneous probe, the responder renames itself
+//! to `<hostname>-2`, `<hostname>-3` and so on and probes again. An unique name is
+//! announced twice and then used to answer queries. A conflicting answer received
+//! later restarts probing as well.
+//!
+//! [rfc6762]: https://tools.ietf.org/html/rfc6762
+
+use alloc::{String, Vec, VecDeque};
+use byteorder::{ByteOrder, NetworkEndian};
+use core::fmt::Write;
+use dns::{self, CLASS_IN, FLAG_RESPONSE, HEADER_LEN, TYPE_A};
+use ipv4::Ipv4Address;
+use parse::{Layer, ParseError};
+
+pub const MDNS_PORT: u16 = 5353;
+
+/// The destination of all mDNS messages.
+pub const MDNS_GROUP: Ipv4Address = Ipv4Address::new(224, 0, 0, 251);
+
+/// The interval between probes in milliseconds.
+pub const PROBE_INTERVAL: u64 = 250;
+
+pub const PROBE_COUNT: u8 = 3;
+
+/// The interval between announcements in milliseconds.
+pub const ANNOUNCE_INTERVAL: u64 = 1000;
+
+pub const ANNOUNCE_COUNT: u8 = 2;
+
+/// The delay before probing again after losing a tie-break, in milliseconds.
+pub const TIE_BREAK_DELAY: u64 = 1000;
+
+/// The time to live of the A record in seconds.
+pub const RECORD_TTL: u32 = 120;
+
+/// The maximum length of a DNS label in bytes.
+const MAX_LABEL_LEN: usize = 63;
+
+const TYPE_ANY: u16 = 255;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+/// The cache-flush bit of records and the unicast-response bit of questions.
+const CLASS_FLAG: u16 = 0x8000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MdnsState {
+    /// Checking that no other device uses the name.
+    Probing,
+    Announcing,
+    /// Answering queries for the name.
+    Announced,
+}
+
+/// A record of a received message.
+struct Record {
+    name: String,
+    type_: u16,
+    class: u16,
+    ttl: u32,
+    data: Vec<u8>,
+}
+
+/// The parts of a received message that the responder looks at.
+struct Message {
+    response: bool,
+    questions: Vec<(String, u16)>,
+    answers: Vec<Record>,
+    authorities: Vec<Record>,
+}
+
+fn read_record(data: &[u8], offset: usize) -> Result<(Record, usize), ParseError> {
+    let (name, offset) = dns::read_name(data, offset)?;
+    let fields = data.get(offset..offset + 10)
+        .ok_or(ParseError::truncated(Layer::Dns, data.len()))?;
+    let len = usize::from(NetworkEndian::read_u16(&fields[8..10]));
+    let record_data = data.get(offset + 10..offset + 10 + len)
+        .ok_or(ParseError::truncated(Layer::Dns, data.len()))?;
+    let record = Record {
+        name,
+        type_: NetworkEndian::read_u16(&fields[0..2]),
+        class: NetworkEndian::read_u16(&fields[2..4]) & !CLASS_FLAG,
+        ttl: NetworkEndian::read_u32(&fields[4..8]),
+        data: record_data.to_vec(),
+    };
+    Ok((record, offset + 10 + len))
+}
+
+fn read_message(data: &[u8]) -> Result<Message, ParseError> {
+    if data.len() < HEADER_LEN {
+        return Err(ParseError::truncated(Layer::Dns, data.len()));
+    }
+    let count = |i: usize| NetworkEndian::read_u16(&data[4 + 2 * i..6 + 2 * i]);
+    let mut message = Message {
+        response: NetworkEndian::read_u16(&data[2..4]) & FLAG_RESPONSE != 0,
+        questions: Vec::new(),
+        answers: Vec::new(),
+        authorities: Vec::new(),
+    };
+
+    let mut offset = HEADER_LEN;
+    for _ in 0..count(0) {
+        let (name, end) = dns::read_name(data, offset)?;
+        let fields = data.get(end..end + 4)
+            .ok_or(ParseError::truncated(Layer::Dns, data.len()))?;
+        let class = NetworkEndian::read_u16(&fields[2..4]) & !CLASS_FLAG;
+        if class == CLASS_IN {
+            message.questions.push((name, NetworkEndian::read_u16(&fields[0..2])));
+        }
+        offset = end + 4;
+    }
+    for _ in 0..count(1) {
+        let (record, end) = read_record(data, offset)?;
+        message.answers.push(record);
+        offset = end;
+    }
+    for _ in 0..count(2) {
+        let (record, end) = read_record(data, offset)?;
+        message.authorities.push(record);
+        offset = end;
+    }
+    // the additional section is not needed
+    Ok(message)
+}
+
+/// Claims `<hostname>.local` for an address and answers queries for it.
+///
+/// Received mDNS datagrams are passed to `handle_message`, and the datagrams
+/// returned by `next_message` are sent from and to `MDNS_PORT` at `MDNS_GROUP`.
+#[derive(Debug)]
+pub struct MdnsResponder {
+    hostname: String,
+    /// The number appended to the host name after conflicts, starting at 2.
+    suffix: u32,
+    ip: Ipv4Address,
+    state: MdnsState,
+    /// The number of probes or announcements sent in the current state.
+    sent: u8,
+    /// When the next probe or announcement is due, `None` until the first poll.
+    deadline: Option<u64>,
+    unsent: VecDeque<Vec<u8>>,
+}
+
+impl MdnsResponder {
+    /// Creates a responder for `hostname`, a single label like `"sensor"`. Probing
+    /// starts on the first `poll`.
+    ///
+    /// Fails if `hostname` isn't a label of 1 to 63 bytes.
+    pub fn new(hostname: &str, ip: Ipv4Address) -> Result<MdnsResponder, ()> {
+        if hostname.is_empty() || hostname.len() > MAX_LABEL_LEN || hostname.contains('.') {
+            return Err(());
+        }
+        Ok(MdnsResponder {
+               hostname: hostname.to_ascii_lowercase(),
+               suffix: 1,
+               ip,
+               state: MdnsState::Probing,
+               sent: 0,
+               deadline: None,
+               unsent: VecDeque::new(),
+           })
+    }
+
+    /// The claimed name without the `.local` domain, e.g. `"sensor-2"` after a
+    /// conflict. Long names are shortened to make room for the number.
+    pub fn hostname(&self) -> String {
+        let mut hostname = self.hostname.clone();
+        if self.suffix > 1 {
+            let mut suffix = String::new();
+            let _ = write!(suffix, "-{}", self.suffix);
+            let mut len = usize::min(hostname.len(), MAX_LABEL_LEN - suffix.len());
+            while !hostname.is_char_boundary(len) {
+                len -= 1;
+            }
+            hostname.truncate(len);
+            hostname.push_str(&suffix);
+        }
+        hostname
+    }
+
+    /// Appends the claimed name in the wire format, which can't fail because the host
+    /// name is a valid label.
+    fn push_name(&self, message: &mut Vec<u8>) {
+        let hostname = self.hostname();
+        message.push(hostname.len() as u8);
+        message.extend_from_slice(hostname.as_bytes());
+        message.extend_from_slice(b"\x05local\x00");
+    }
+
+    fn name(&self) -> String {
+        let mut name = self.hostname();
+        name.push_str(".local");
+        name
+    }
+
+    pub fn state(&self) -> MdnsState {
+        self.state
+    }
+
+    /// Changes the address, which is probed and announced again.
+    pub fn set_ip(&mut self, ip: Ipv4Address) {
+        if ip != self.ip {
+            self.ip = ip;
+            self.restart(None);
+        }
+    }
+
+    /// Starts probing from the beginning at `deadline`, or on the next poll.
+    fn restart(&mut self, deadline: Option<u64>) {
+        self.state = MdnsState::Probing;
+        self.sent = 0;
+        self.deadline = deadline;
+        self.unsent.clear();
+    }
+
+    /// Sends the probes and announcements that are due.
+    pub fn poll(&mut self, now: u64) {
+        if self.deadline.map_or(false, |deadline| now < deadline) ||
+           self.state == MdnsState::Announced {
+            return;
+        }
+        if self.state == MdnsState::Probing && self.sent == PROBE_COUNT {
+            self.state = MdnsState::Announcing;
+            self.sent = 0;
+        }
+        match self.state {
+            MdnsState::Probing => {
+                let probe = self.probe();
+                self.unsent.push_back(probe);
+                self.deadline = Some(now + PROBE_INTERVAL);
+            }
+            MdnsState::Announcing => {
+                let announcement = self.response();
+                self.unsent.push_back(announcement);
+                self.deadline = Some(now + ANNOUNCE_INTERVAL);
+                if self.sent + 1 == ANNOUNCE_COUNT {
+                    self.state = MdnsState::Announced;
+                }
+            }
+            MdnsState::Announced => unreachable!(),
+        }
+        self.sent += 1;
+    }
+
+    /// Returns the next datagram to send to `MDNS_GROUP`.
+    pub fn next_message(&mut self) -> Option<Vec<u8>> {
+        self.unsent.pop_front()
+    }
+
+    /// Handles a received mDNS datagram. Invalid messages are ignored.
+    pub fn handle_message(&mut self, data: &[u8], now: u64) {
+        let message = match read_message(data) {
+            Ok(message) => message,
+            Err(_) => return,
+        };
+        let name = self.name();
+        let ours = |record: &Record| record.name == name && record.class == CLASS_IN;
+        let own_data = self.ip.as_bytes();
+
+        // another device uses the name, unless it is a copy of our own record
+        let conflict = message.answers
+            .iter()
+            .any(|record| ours(record) && (record.type_ != TYPE_A || record.data != own_data));
+        if message.response && conflict {
+            self.suffix += 1;
+            self.restart(Some(now));
+            return;
+        }
+        if message.response {
+            return;
+        }
+
+        if self.state == MdnsState::Probing {
+            // a simultaneous probe for the same name, the lexicographically later
+            // record wins
+            let lost = message.authorities
+                .iter()
+                .filter(|record| ours(record))
+                .any(|record| (record.type_, &record.data[..]) > (TYPE_A, &own_data[..]));
+            if lost {
+                self.restart(Some(now + TIE_BREAK_DELAY));
+            }
+            return;
+        }
+
+        let asked = message.questions
+            .iter()
+            .any(|&(ref question, type_)| {
+                         *question == name && (type_ == TYPE_A || type_ == TYPE_ANY)
+                     });
+        // known-answer suppression
+        let known = message.answers
+            .iter()
+            .any(|record| {
+                     ours(record) && record.type_ == TYPE_A && record.data == own_data &&
+                     record.ttl >= RECORD_TTL / 2
+                 });
+        if asked && !known {
+            let response = self.response();
+            self.unsent.push_back(response);
+        }
+    }
+
+    fn push_header(&self, message: &mut Vec<u8>, flags: u16, counts: [u16; 4]) {
+        let mut header = [0; HEADER_LEN];
+        NetworkEndian::write_u16(&mut header[2..4], flags);
+        for (i, &count) in counts.iter().enumerate() {
+            NetworkEndian::write_u16(&mut header[4 + 2 * i..6 + 2 * i], count);
+        }
+        message.extend_from_slice(&header);
+    }
+
+    fn push_record(&self, message: &mut Vec<u8>, class: u16) {
+        self.push_name(message);
+        let mut fields = [0; 10];
+        NetworkEndian::write_u16(&mut fields[0..2], TYPE_A);
+        NetworkEndian::write_u16(&mut fields[2..4], class);
+        NetworkEndian::write_u32(&mut fields[4..8], RECORD_TTL);
+        NetworkEndian::write_u16(&mut fields[8..10], 4);
+        message.extend_from_slice(&fields);
+        message.extend_from_slice(&self.ip.as_bytes());
+    }
+
+    /// A query for any record of the name, with the proposed record in the
+    /// authority section.
+    fn probe(&self) -> Vec<u8> {
+        let mut message = Vec::new();
+        self.push_header(&mut message, 0, [1, 0, 1, 0]);
+        self.push_name(&mut message);
+        let mut question = [0; 4];
+        NetworkEndian::write_u16(&mut question[0..2], TYPE_ANY);
+        NetworkEndian::write_u16(&mut question[2..4], CLASS_IN);
+        message.extend_from_slice(&question);
+        self.push_record(&mut message, CLASS_IN);
+        message
+    }
+
+    /// An announcement or answer, which replaces cached records of other devices.
+    fn response(&self) -> Vec<u8> {
+        let mut message = Vec::new();
+        self.push_header(&mut message, FLAG_RESPONSE | FLAG_AUTHORITATIVE, [0, 1, 0, 0]);
+        self.push_record(&mut message, CLASS_IN | CLASS_FLAG);
+        message
+    }
+}
+
+#[test]
+fn probe_announce_and_rename() {
+    let ip = Ipv4Address::new(192, 168, 1, 20);
+    let other = Ipv4Address::new(192, 168, 1, 30);
+    assert!(MdnsResponder::new("sensor.lab", ip).is_err());
+    assert!(MdnsResponder::new("", ip).is_err());
+    let mut responder = MdnsResponder::new("Sensor", ip).unwrap();
+    let mut now = 0;
+    for _ in 0..PROBE_COUNT {
+        responder.poll(now);
+        let probe = responder.next_message().unwrap();
+        let message = read_message(&probe).unwrap();
+        assert_eq!(message.questions, vec![(String::from("sensor.local"), TYPE_ANY)]);
+        assert_eq!(message.authorities[0].data, ip.as_bytes());
+        responder.poll(now + 1);
+        assert_eq!(responder.next_message(), None);
+        now += PROBE_INTERVAL;
+    }
+
+    // another device answers with the same name
+    let conflicting = MdnsResponder::new("sensor", other).unwrap();
+    let answer = conflicting.response();
+    responder.handle_message(&answer, now);
+    assert_eq!(responder.hostname(), "sensor-2");
+    assert_eq!(responder.state(), MdnsState::Probing);
+
+    // the echo of our own probe isn't a conflict
+    for _ in 0..PROBE_COUNT {
+        responder.poll(now);
+        let probe = responder.next_message().unwrap();
+        responder.handle_message(&probe, now);
+        now += PROBE_INTERVAL;
+    }
+    for _ in 0..ANNOUNCE_COUNT {
+        responder.poll(now);
+        let announcement = read_message(&responder.next_message().unwrap()).unwrap();
+        assert!(announcement.response);
+        assert_eq!(announcement.answers[0].name, "sensor-2.local");
+        now += ANNOUNCE_INTERVAL;
+    }
+    assert_eq!(responder.state(), MdnsState::Announced);
+
+    let mut query = Vec::new();
+    conflicting.push_header(&mut query, 0, [1, 0, 0, 0]);
+    dns::push_name(&mut query, "Sensor-2.local").unwrap();
+    query.extend_from_slice(&[0, 1, 0, 1]);
+    responder.handle_message(&query, now);
+    let answer = read_message(&responder.next_message().unwrap()).unwrap();
+    assert_eq!(answer.answers[0].data, ip.as_bytes());
+
+    // a renamed long name stays a valid label
+    let long = ["s"; MAX_LABEL_LEN].concat();
+    let mut responder = MdnsResponder::new(&long, ip).unwrap();
+    responder.suffix = 12;
+    assert_eq!(responder.hostname(), ["s"; MAX_LABEL_LEN - 3].concat() + "-12");
+    let probe = responder.probe();
+    assert_eq!(read_message(&probe).unwrap().questions[0].0, responder.name());
+}
@@ -0,0 +1,278 @@
+//! Modbus-TCP framing: the MBAP header and splitting the TCP byte stream into
+//! frames.
+
+use {HeapTxPacket, TxPacket, WriteOut};
+use byteorder::{ByteOrder, NetworkEndian};
+use alloc::borrow::Cow;
+use alloc::{Vec, VecDeque};
+
+pub const MODBUS_PORT: u16 = 502;
+
+/// The maximum length of a PDU (function code and data).
+pub const MAX_PDU_LEN: usize = 253;
+
+const MBAP_HEADER_LEN: usize = 7;
+
+/// A Modbus-TCP application data unit: the MBAP header fields and the PDU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModbusFrame<'a> {
+    pub transaction_id: u16,
+    pub unit_id: u8,
+    pub pdu: &'a [u8],
+}
+
+impl<'a> ModbusFrame<'a> {
+    pub fn function_code(&self) -> u8 {
+        self.pdu[0]
+    }
+
+    /// Returns the exception code if this is an exception response.
+    pub fn exception(&self) -> Option<u8> {
+        if self.pdu[0] & 0x80 != 0 {
+            self.pdu.get(1).cloned()
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a> WriteOut for ModbusFrame<'a> {
+    fn len(&self) -> usize {
+        MBAP_HEADER_LEN + self.pdu.len()
+    }
+
+    fn write_out<T: TxPacket>(&self, packet: &mut T) -> Result<(), ()> {
+        packet.push_u16(self.transaction_id)?;
+        packet.push_u16(0)?; // protocol id
+        packet.push_u16((self.pdu.len() + 1) as u16)?; // length, including unit id
+        packet.push_byte(self.unit_id)?;
+        packet.push_bytes(self.pdu)?;
+        Ok(())
+    }
+}
+
+use parse::{Layer, Parse, ParseError};
+
+impl<'a> Parse<'a> for ModbusFrame<'a> {
+    /// Parses the frame at the start of `data`, trailing bytes are ignored.
+    fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
+        if data.len() < MBAP_HEADER_LEN {
+            return Err(ParseError::truncated(Layer::Modbus, data.len()));
+        }
+        if NetworkEndian::read_u16(&data[2..4]) != 0 {
+            return Err(ParseError::malformed(Layer::Modbus, 2, "protocol id is not modbus"));
+        }
+        let len = usize::from(NetworkEndian::read_u16(&data[4..6]));
+        if len < 2 || len > MAX_PDU_LEN + 1 {
+            return Err(ParseError::malformed(Layer::Modbus, 4, "invalid MBAP length"));
+        }
+        let end = MBAP_HEADER_LEN - 1 + len;
+        if data.len() < end {
+            return Err(ParseError::truncated(Layer::Modbus, data.len()));
+        }
+        Ok(ModbusFrame {
+               transaction_id: NetworkEndian::read_u16(&data[0..2]),
+               unit_id: data[6],
+               pdu: &data[MBAP_HEADER_LEN..end],
+           })
+    }
+}
+
+/// Reassembles Modbus frames from the data of a TCP connection.
+#[derive(Debug, Default)]
+pub struct ModbusStream {
+    buffer: Vec<u8>,
+    /// The length of the frame returned by the last `next_frame` call.
+    consumed: usize,
+}
+
+impl ModbusStream {
+    pub fn new() -> Self {
+        ModbusStream::default()
+    }
+
+    /// Appends received TCP data.
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Returns the next complete frame.
+    ///
+    /// An invalid header can't be resynchronized, so the buffered data is discarded
+    /// and the error returned. The connection should be closed in this case.
+    pub fn next_frame(&mut self) -> Option<Result<ModbusFrame, ParseError>> {
+        self.buffer.drain(..self.consumed);
+        self.consumed = 0;
+
+        let frame_len = ModbusFrame::parse(&self.buffer).map(|frame| frame.len());
+        match frame_len {
+            Ok(len) => {
+                self.consumed = len;
+                Some(ModbusFrame::parse(&self.buffer))
+            }
+            Err(ParseError::Truncated { .. }) => None,
+            Err(err) => {
+                self.buffer.clear();
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+/// Answers Modbus requests, for use in the `TcpConnection::handle_packet` callback.
+pub struct ModbusServer<F> {
+    stream: ModbusStream,
+    handler: F,
+}
+
+impl<F> ModbusServer<F>
+    where F: FnMut(&ModbusFrame) -> Option<Vec<u8>>
+{
+    /// `handler` returns the response PDU for a request, or `None` to not respond.
+    pub fn new(handler: F) -> Self {
+        ModbusServer {
+            stream: ModbusStream::new(),
+            handler,
+        }
+    }
+
+    /// Processes received TCP data and returns the framed responses to all requests
+    /// that are complete now.
+    ///
+    /// Fails if the data can't be framed, see `ModbusStream::next_frame`. The
+    /// connection should be closed then, without responding to earlier requests.
+    pub fn handle_data(&mut self,
+                       data: &[u8])
+                       -> Result<Option<Cow<'static, [u8]>>, ParseError> {
+        self.stream.push(data);
+        let mut responses = Vec::new();
+        while let Some(request) = self.stream.next_frame() {
+            let request = request?;
+            if let Some(pdu) = (self.handler)(&request) {
+                let response = ModbusFrame {
+                    transaction_id: request.transaction_id,
+                    unit_id: request.unit_id,
+                    pdu: &pdu,
+                };
+                push_frame(&mut responses, &response);
+            }
+        }
+        if responses.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(Cow::Owned(responses)))
+        }
+    }
+}
+
+/// Sends requests and matches responses to them by transaction id.
+#[derive(Debug, Default)]
+pub struct ModbusClient {
+    stream: ModbusStream,
+    next_transaction_id: u16,
+    pending: VecDeque<u16>,
+}
+
+impl ModbusClient {
+    pub fn new() -> Self {
+        ModbusClient::default()
+    }
+
+    /// Frames a request for `TcpConnection::send` and registers its transaction id.
+    pub fn request(&mut self, unit_id: u8, pdu: &[u8]) -> Vec<u8> {
+        let transaction_id = self.next_transaction_id;
+        self.next_transaction_id = self.next_transaction_id.wrapping_add(1);
+        self.pending.push_back(transaction_id);
+
+        let mut data = Vec::new();
+        push_frame(&mut data,
+                   &ModbusFrame {
+                        transaction_id,
+                        unit_id,
+                        pdu,
+                    });
+        data
+    }
+
+    /// The number of requests without a response.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Appends received TCP data.
+    pub fn receive(&mut self, data: &[u8]) {
+        self.stream.push(data);
+    }
+
+    /// Returns the next response to a pending request. Responses with unknown
+    /// transaction ids are dropped.
+    pub fn next_response(&mut self) -> Option<Result<ModbusFrame, ParseError>> {
+        loop {
+            let transaction_id = match self.stream.next_frame() {
+                None => return None,
+                Some(Err(err)) => return Some(Err(err)),
+                Some(Ok(frame)) => frame.transaction_id,
+            };
+            if let Some(index) = self.pending.iter().position(|&id| id == transaction_id) {
+                self.pending.remove(index);
+                break;
+            }
+        }
+        // the frame is still at the start of the buffer
+        self.stream.consumed = 0;
+        self.stream.next_frame()
+    }
+}
+
+fn push_frame(data: &mut Vec<u8>, frame: &ModbusFrame) {
+    let mut packet = HeapTxPacket::new(frame.len());
+    frame.write_out(&mut packet).unwrap();
+    data.extend_from_slice(&packet);
+}
+
+#[test]
+fn reassembly_and_transaction_matching() {
+    let mut client = ModbusClient::new();
+    // read holding registers 0 and 1
+    let first = client.request(1, &[0x03, 0x00, 0x00, 0x00, 0x02]);
+    let second = client.request(1, &[0x03, 0x00, 0x10, 0x00, 0x01]);
+    assert_eq!(&first[..7], &[0x00, 0x00, 0x00, 0x00, 0x00, 0x06, 0x01]);
+
+    let mut server = ModbusServer::new(|request: &ModbusFrame| {
+        assert_eq!(request.function_code(), 0x03);
+        let count = request.pdu[4];
+        let mut response = vec![0x03, 2 * count];
+        for i in 0..count {
+            response.extend_from_slice(&[0x00, i]);
+        }
+        Some(response)
+    });
+
+    // the first request is split across two segments, the second directly follows
+    assert_eq!(server.handle_data(&first[..4]), Ok(None));
+    let mut data = first[4..].to_vec();
+    data.extend_from_slice(&second);
+    let responses = server.handle_data(&data).unwrap().unwrap().into_owned();
+
+    // an unsolicited response is dropped
+    client.receive(&[0x00, 0x42, 0x00, 0x00, 0x00, 0x03, 0x01, 0x83, 0x02]);
+    client.receive(&responses);
+    {
+        let response = client.next_response().unwrap().unwrap();
+        assert_eq!(response.transaction_id, 0);
+        assert_eq!(response.pdu, &[0x03, 0x04, 0x00, 0x00, 0x00, 0x01]);
+    }
+    {
+        let response = client.next_response().unwrap().unwrap();
+        assert_eq!(response.transaction_id, 1);
+        assert_eq!(response.exception(), None);
+    }
+    assert!(client.next_response().is_none());
+    assert_eq!(client.pending(), 0);
+
+    // a frame with another protocol id can't be framed
+    let mut invalid = client.request(1, &[0x03, 0x00, 0x00, 0x00, 0x01]);
+    invalid[3] = 1;
+    assert_eq!(server.handle_data(&invalid),
+               Err(ParseError::malformed(Layer::Modbus, 2, "protocol id is not modbus")));
+}
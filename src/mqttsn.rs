@@ -0,0 +1,489 @@
+use {TxPacket, WriteOut};
+use byteorder::{ByteOrder, NetworkEndian};
+use bit_field::BitField;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QoS {
+    AtMostOnce,
+    AtLeastOnce,
+    ExactlyOnce,
+    /// QoS -1: publish without a connection to the gateway.
+    NoConnection,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TopicIdType {
+    Normal,
+    Predefined,
+    Short,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MqttSnFlags {
+    pub dup: bool,
+    pub qos: QoS,
+    pub retain: bool,
+    pub will: bool,
+    pub clean_session: bool,
+    pub topic_id_type: TopicIdType,
+}
+
+impl Default for MqttSnFlags {
+    fn default() -> Self {
+        MqttSnFlags {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            will: false,
+            clean_session: false,
+            topic_id_type: TopicIdType::Normal,
+        }
+    }
+}
+
+impl MqttSnFlags {
+    pub fn from_bits(bits: u8) -> Result<Self, ParseError> {
+        Ok(MqttSnFlags {
+               dup: bits.get_bit(7),
+               qos: match bits.get_bits(5..7) {
+                   0 => QoS::AtMostOnce,
+                   1 => QoS::AtLeastOnce,
+                   2 => QoS::ExactlyOnce,
+                   _ => QoS::NoConnection,
+               },
+               retain: bits.get_bit(4),
+               will: bits.get_bit(3),
+               clean_session: bits.get_bit(2),
+               topic_id_type: match bits.get_bits(0..2) {
+                   0 => TopicIdType::Normal,
+                   1 => TopicIdType::Predefined,
+                   2 => TopicIdType::Short,
+                   _ => {
+                       return Err(ParseError::malformed(Layer::MqttSn,
+                                                        0,
+                                                        "reserved MQTT-SN topic id type"))
+                   }
+               },
+           })
+    }
+
+    pub fn bits(&self) -> u8 {
+        let mut bits = 0u8;
+        bits.set_bit(7, self.dup);
+        bits.set_bits(5..7,
+                      match self.qos {
+                          QoS::AtMostOnce => 0,
+                          QoS::AtLeastOnce => 1,
+                          QoS::ExactlyOnce => 2,
+                          QoS::NoConnection => 3,
+                      });
+        bits.set_bit(4, self.retain);
+        bits.set_bit(3, self.will);
+        bits.set_bit(2, self.clean_session);
+        bits.set_bits(0..2,
+                      match self.topic_id_type {
+                          TopicIdType::Normal => 0,
+                          TopicIdType::Predefined => 1,
+                          TopicIdType::Short => 2,
+                      });
+        bits
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReturnCode {
+    Accepted,
+    Congestion,
+    InvalidTopicId,
+    NotSupported,
+    Unknown(u8),
+}
+
+impl ReturnCode {
+    pub fn from_number(number: u8) -> ReturnCode {
+        match number {
+            0 => ReturnCode::Accepted,
+            1 => ReturnCode::Congestion,
+            2 => ReturnCode::InvalidTopicId,
+            3 => ReturnCode::NotSupported,
+            other => ReturnCode::Unknown(other),
+        }
+    }
+
+    pub fn number(&self) -> u8 {
+        match *self {
+            ReturnCode::Accepted => 0,
+            ReturnCode::Congestion => 1,
+            ReturnCode::InvalidTopicId => 2,
+            ReturnCode::NotSupported => 3,
+            ReturnCode::Unknown(number) => number,
+        }
+    }
+}
+
+/// The topic of a SUBSCRIBE message, depending on the topic id type flag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Topic<'a> {
+    /// A topic name (`TopicIdType::Normal`) or a two character short name.
+    Name(&'a [u8]),
+    /// A predefined topic id.
+    Id(u16),
+}
+
+/// An MQTT-SN message, see the [MQTT-SN specification][spec].
+///
+/// The specification assigns no UDP port, so the port of the gateway has to be
+/// configured by the application. 1883 is taken by MQTT over TCP.
+///
+/// [spec]: http://mqtt.org/new/wp-content/uploads/2009/06/MQTT-SN_spec_v1.2.pdf
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MqttSnPacket<'a> {
+    Connect {
+        flags: MqttSnFlags,
+        duration: u16,
+        client_id: &'a [u8],
+    },
+    ConnAck { return_code: ReturnCode },
+    Register {
+        topic_id: u16,
+        msg_id: u16,
+        topic_name: &'a [u8],
+    },
+    RegAck {
+        topic_id: u16,
+        msg_id: u16,
+        return_code: ReturnCode,
+    },
+    Publish {
+        flags: MqttSnFlags,
+        topic_id: u16,
+        msg_id: u16,
+        data: &'a [u8],
+    },
+    PubAck {
+        topic_id: u16,
+        msg_id: u16,
+        return_code: ReturnCode,
+    },
+    Subscribe {
+        flags: MqttSnFlags,
+        msg_id: u16,
+        topic: Topic<'a>,
+    },
+    SubAck {
+        flags: MqttSnFlags,
+        topic_id: u16,
+        msg_id: u16,
+        return_code: ReturnCode,
+    },
+}
+
+impl<'a> MqttSnPacket<'a> {
+    fn msg_type(&self) -> u8 {
+        match *self {
+            MqttSnPacket::Connect { .. } => 0x04,
+            MqttSnPacket::ConnAck { .. } => 0x05,
+            MqttSnPacket::Register { .. } => 0x0a,
+            MqttSnPacket::RegAck { .. } => 0x0b,
+            MqttSnPacket::Publish { .. } => 0x0c,
+            MqttSnPacket::PubAck { .. } => 0x0d,
+            MqttSnPacket::Subscribe { .. } => 0x12,
+            MqttSnPacket::SubAck { .. } => 0x13,
+        }
+    }
+
+    fn body_len(&self) -> usize {
+        match *self {
+            MqttSnPacket::Connect { client_id, .. } => 4 + client_id.len(),
+            MqttSnPacket::ConnAck { .. } => 1,
+            MqttSnPacket::Register { topic_name, .. } => 4 + topic_name.len(),
+            MqttSnPacket::RegAck { .. } => 5,
+            MqttSnPacket::Publish { data, .. } => 5 + data.len(),
+            MqttSnPacket::PubAck { .. } => 5,
+            MqttSnPacket::Subscribe { topic: Topic::Name(name), .. } => 3 + name.len(),
+            MqttSnPacket::Subscribe { topic: Topic::Id(_), .. } => 5,
+            MqttSnPacket::SubAck { .. } => 6,
+        }
+    }
+}
+
+impl<'a> WriteOut for MqttSnPacket<'a> {
+    fn len(&self) -> usize {
+        let len = self.body_len() + 2;
+        if len > 255 { len + 2 } else { len }
+    }
+
+    fn write_out<P: TxPacket>(&self, packet: &mut P) -> Result<(), ()> {
+        let len = self.len();
+        if len > 0xffff {
+            return Err(());
+        } else if len > 255 {
+            packet.push_byte(0x01)?;
+            packet.push_u16(len as u16)?;
+        } else {
+            packet.push_byte(len as u8)?;
+        }
+        packet.push_byte(self.msg_type())?;
+
+        match *self {
+            MqttSnPacket::Connect { flags, duration, client_id } => {
+                packet.push_byte(flags.bits())?;
+                packet.push_byte(0x01)?; // protocol id
+                packet.push_u16(duration)?;
+                packet.push_bytes(client_id)?;
+            }
+            MqttSnPacket::ConnAck { return_code } => {
+                packet.push_byte(return_code.number())?;
+            }
+            MqttSnPacket::Register { topic_id, msg_id, topic_name } => {
+                packet.push_u16(topic_id)?;
+                packet.push_u16(msg_id)?;
+                packet.push_bytes(topic_name)?;
+            }
+            MqttSnPacket::RegAck { topic_id, msg_id, return_code } |
+            MqttSnPacket::PubAck { topic_id, msg_id, return_code } => {
+                packet.push_u16(topic_id)?;
+                packet.push_u16(msg_id)?;
+                packet.push_byte(return_code.number())?;
+            }
+            MqttSnPacket::Publish { flags, topic_id, msg_id, data } => {
+                packet.push_byte(flags.bits())?;
+                packet.push_u16(topic_id)?;
+                packet.push_u16(msg_id)?;
+                packet.push_bytes(data)?;
+            }
+            MqttSnPacket::Subscribe { flags, msg_id, topic } => {
+                packet.push_byte(flags.bits())?;
+                packet.push_u16(msg_id)?;
+                match topic {
+                    Topic::Name(name) => packet.push_bytes(name)?,
+                    Topic::Id(id) => packet.push_u16(id)?,
+                };
+            }
+            MqttSnPacket::SubAck { flags, topic_id, msg_id, return_code } => {
+                packet.push_byte(flags.bits())?;
+                packet.push_u16(topic_id)?;
+                packet.push_u16(msg_id)?;
+                packet.push_byte(return_code.number())?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+use parse::{Layer, Parse, ParseError};
+
+impl<'a> Parse<'a> for MqttSnPacket<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
+        if data.len() < 2 {
+            return Err(ParseError::truncated(Layer::MqttSn, data.len()));
+        }
+        let (len, header_len) = if data[0] == 0x01 {
+            if data.len() < 4 {
+                return Err(ParseError::truncated(Layer::MqttSn, data.len()));
+            }
+            (usize::from(NetworkEndian::read_u16(&data[1..3])), 3)
+        } else {
+            (usize::from(data[0]), 1)
+        };
+        if len < header_len + 1 {
+            return Err(ParseError::malformed(Layer::MqttSn, 0, "invalid MQTT-SN length"));
+        }
+        if data.len() < len {
+            return Err(ParseError::truncated(Layer::MqttSn, data.len()));
+        }
+
+        let msg_type = data[header_len];
+        let body_start = header_len + 1;
+        let body = &data[body_start..len];
+        let flags_at = |index: usize| {
+            MqttSnFlags::from_bits(body[index]).map_err(|e| e.at(body_start + index))
+        };
+
+        let min_len = match msg_type {
+            0x04 => 4,
+            0x05 => 1,
+            0x0a | 0x0b | 0x0d => 4,
+            0x0c | 0x13 => 5,
+            0x12 => 3,
+            _ => {
+                return Err(ParseError::unimplemented(Layer::MqttSn,
+                                                     header_len,
+                                                     "unknown MQTT-SN message type"))
+            }
+        };
+        if body.len() < min_len {
+            return Err(ParseError::truncated(Layer::MqttSn, data.len()));
+        }
+        let u16_at = |index: usize| NetworkEndian::read_u16(&body[index..(index + 2)]);
+        let return_code_at = |index: usize| {
+            body.get(index)
+                .map(|&code| ReturnCode::from_number(code))
+                .ok_or(ParseError::truncated(Layer::MqttSn, data.len()))
+        };
+
+        let packet = match msg_type {
+            0x04 => {
+                if body[1] != 0x01 {
+                    return Err(ParseError::malformed(Layer::MqttSn,
+                                                     body_start + 1,
+                                                     "unknown MQTT-SN protocol id"));
+                }
+                MqttSnPacket::Connect {
+                    flags: flags_at(0)?,
+                    duration: u16_at(2),
+                    client_id: &body[4..],
+                }
+            }
+            0x05 => MqttSnPacket::ConnAck { return_code: return_code_at(0)? },
+            0x0a => {
+                MqttSnPacket::Register {
+                    topic_id: u16_at(0),
+                    msg_id: u16_at(2),
+                    topic_name: &body[4..],
+                }
+            }
+            0x0b => {
+                MqttSnPacket::RegAck {
+                    topic_id: u16_at(0),
+                    msg_id: u16_at(2),
+                    return_code: return_code_at(4)?,
+                }
+            }
+            0x0c => {
+                MqttSnPacket::Publish {
+                    flags: flags_at(0)?,
+                    topic_id: u16_at(1),
+                    msg_id: u16_at(3),
+                    data: &body[5..],
+                }
+            }
+            0x0d => {
+                MqttSnPacket::PubAck {
+                    topic_id: u16_at(0),
+                    msg_id: u16_at(2),
+                    return_code: return_code_at(4)?,
+                }
+            }
+            0x12 => {
+                let flags = flags_at(0)?;
+                let topic = match flags.topic_id_type {
+                    TopicIdType::Predefined if body.len() == 5 => Topic::Id(u16_at(3)),
+                    TopicIdType::Predefined => {
+                        return Err(ParseError::malformed(Layer::MqttSn,
+                                                         body_start + 3,
+                                                         "invalid MQTT-SN predefined topic id"))
+                    }
+                    _ => Topic::Name(&body[3..]),
+                };
+                MqttSnPacket::Subscribe {
+                    flags,
+                    msg_id: u16_at(1),
+                    topic,
+                }
+            }
+            0x13 => {
+                MqttSnPacket::SubAck {
+                    flags: flags_at(0)?,
+                    topic_id: u16_at(1),
+                    msg_id: u16_at(3),
+                    return_code: return_code_at(5)?,
+                }
+            }
+            _ => unreachable!(),
+        };
+        Ok(packet)
+    }
+}
+
+#[cfg(test)]
+fn round_trip(packet: MqttSnPacket) -> ::alloc::vec::Vec<u8> {
+    use HeapTxPacket;
+
+    let mut tx_packet = HeapTxPacket::new(packet.len());
+    packet.write_out(&mut tx_packet).unwrap();
+    assert_eq!(tx_packet.len(), packet.len());
+    assert_eq!(MqttSnPacket::parse(tx_packet.as_slice()), Ok(packet));
+    tx_packet.to_vec()
+}
+
+#[test]
+fn publish() {
+    let flags = MqttSnFlags {
+        qos: QoS::AtLeastOnce,
+        retain: true,
+        ..MqttSnFlags::default()
+    };
+    let publish = MqttSnPacket::Publish {
+        flags,
+        topic_id: 0x0102,
+        msg_id: 0x0304,
+        data: b"23.5",
+    };
+    assert_eq!(round_trip(publish),
+               &[0x0b, 0x0c, 0x30, 0x01, 0x02, 0x03, 0x04, b'2', b'3', b'.', b'5']);
+
+    let data = [0x42; 300];
+    let long_publish = MqttSnPacket::Publish {
+        flags,
+        topic_id: 1,
+        msg_id: 2,
+        data: &data,
+    };
+    assert_eq!(&round_trip(long_publish)[..4], &[0x01, 0x01, 0x35, 0x0c]);
+
+    let data = ::alloc::vec![0x42; 0x10000];
+    let too_long = MqttSnPacket::Publish {
+        flags,
+        topic_id: 1,
+        msg_id: 2,
+        data: &data,
+    };
+    let mut tx_packet = ::HeapTxPacket::new(too_long.len());
+    assert_eq!(too_long.write_out(&mut tx_packet), Err(()));
+}
+
+#[test]
+fn connect_register_subscribe() {
+    let connect = MqttSnPacket::Connect {
+        flags: MqttSnFlags {
+            clean_session: true,
+            ..MqttSnFlags::default()
+        },
+        duration: 60,
+        client_id: b"sensor-1",
+    };
+    assert_eq!(&round_trip(connect)[..6], &[0x0e, 0x04, 0x04, 0x01, 0x00, 0x3c]);
+
+    round_trip(MqttSnPacket::ConnAck { return_code: ReturnCode::Accepted });
+    round_trip(MqttSnPacket::Register {
+                   topic_id: 0,
+                   msg_id: 1,
+                   topic_name: b"plant/line1/temp",
+               });
+    round_trip(MqttSnPacket::RegAck {
+                   topic_id: 7,
+                   msg_id: 1,
+                   return_code: ReturnCode::Accepted,
+               });
+    round_trip(MqttSnPacket::Subscribe {
+                   flags: MqttSnFlags::default(),
+                   msg_id: 2,
+                   topic: Topic::Name(b"plant/+/cmd"),
+               });
+    round_trip(MqttSnPacket::Subscribe {
+                   flags: MqttSnFlags {
+                       topic_id_type: TopicIdType::Predefined,
+                       ..MqttSnFlags::default()
+                   },
+                   msg_id: 3,
+                   topic: Topic::Id(42),
+               });
+    round_trip(MqttSnPacket::SubAck {
+                   flags: MqttSnFlags::default(),
+                   topic_id: 42,
+                   msg_id: 3,
+                   return_code: ReturnCode::InvalidTopicId,
+               });
+}
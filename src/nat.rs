@@ -0,0 +1,310 @@
+//! Masquerading of TCP and UDP flows from an internal network behind a single
+//! external address (NAPT, [RFC 3022][rfc3022]).
+//!
+//! `Nat::outbound` rewrites the source of a packet to the upstream network to the
+//! external address and a port of its flow, and `Nat::inbound` maps the replies back
+//! to the internal host. Only the addresses, ports, and checksums are changed; all
+//! other header fields, the options, and the payload are forwarded as they are. The
+//! checksums are updated incrementally, so the payload isn't read.
+//!
+//! Every flow (protocol, internal and remote endpoint) gets its own external port,
+//! and only its remote endpoint can send to it. Flows expire after a protocol
+//! specific idle time, TCP connections aren't tracked further.
+//!
+//! [rfc3022]: https://tools.ietf.org/html/rfc3022
+
+use alloc::{BTreeMap, Vec};
+use byteorder::{ByteOrder, NetworkEndian};
+use ip_checksum;
+use ipv4::{Endpoint, IpProtocol, Ipv4Address};
+use ports::PortAllocator;
+
+/// The idle time in milliseconds after which UDP flows expire (RFC 4787, REQ-5).
+pub const UDP_FLOW_TIMEOUT: u64 = 2 * 60_000;
+
+/// The idle time in milliseconds after which TCP flows expire (RFC 5382, REQ-5).
+pub const TCP_FLOW_TIMEOUT: u64 = 124 * 60_000;
+
+/// The number of flows that are tracked at once.
+pub const MAX_FLOWS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatError {
+    /// The packet isn't an IPv4 packet with a complete TCP or UDP header.
+    Malformed,
+    /// The packet is neither TCP nor UDP.
+    UnsupportedProtocol(IpProtocol),
+    /// A fragment other than the first, which carries no ports.
+    Fragment,
+    /// An inbound packet doesn't belong to any flow.
+    NoFlow,
+    /// All flows or external ports are in use.
+    Exhausted,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Flow {
+    internal: Endpoint,
+    remote: Endpoint,
+    last_used: u64,
+}
+
+/// The fields of a packet that are rewritten.
+struct Fields {
+    header_len: usize,
+    protocol: IpProtocol,
+    src: Endpoint,
+    dst: Endpoint,
+    /// The offset of the checksum in the transport header.
+    checksum_offset: usize,
+}
+
+fn fields(packet: &[u8]) -> Result<Fields, NatError> {
+    if packet.len() < 20 || packet[0] >> 4 != 4 {
+        return Err(NatError::Malformed);
+    }
+    let header_len = usize::from(packet[0] & 0xf) * 4;
+    let total_len = usize::from(NetworkEndian::read_u16(&packet[2..4]));
+    if header_len < 20 || total_len < header_len || total_len > packet.len() {
+        return Err(NatError::Malformed);
+    }
+    if NetworkEndian::read_u16(&packet[6..8]) & 0x1fff != 0 {
+        return Err(NatError::Fragment);
+    }
+    let protocol = IpProtocol::from_number(packet[9]);
+    let (min_len, checksum_offset) = match protocol {
+        IpProtocol::Tcp => (20, 16),
+        IpProtocol::Udp => (8, 6),
+        protocol => return Err(NatError::UnsupportedProtocol(protocol)),
+    };
+    if total_len < header_len + min_len {
+        return Err(NatError::Malformed);
+    }
+    let transport = &packet[header_len..];
+    Ok(Fields {
+           header_len,
+           protocol,
+           src: Endpoint::new(Ipv4Address::from_bytes(&packet[12..16]),
+                              NetworkEndian::read_u16(&transport[0..2])),
+           dst: Endpoint::new(Ipv4Address::from_bytes(&packet[16..20]),
+                              NetworkEndian::read_u16(&transport[2..4])),
+           checksum_offset,
+       })
+}
+
+/// Replaces the source (`src` true) or destination endpoint and updates the IPv4
+/// and transport checksums.
+fn rewrite(packet: &mut [u8], fields: &Fields, src: bool, new: Endpoint) {
+    let (old, addr_offset, port_offset) = if src {
+        (fields.src, 12, fields.header_len)
+    } else {
+        (fields.dst, 16, fields.header_len + 2)
+    };
+    let old_addr = old.addr.as_bytes();
+    let new_addr = new.addr.as_bytes();
+    let mut old_port = [0; 2];
+    let mut new_port = [0; 2];
+    NetworkEndian::write_u16(&mut old_port, old.port);
+    NetworkEndian::write_u16(&mut new_port, new.port);
+
+    let header_checksum = NetworkEndian::read_u16(&packet[10..12]);
+    let header_checksum = ip_checksum::update(header_checksum, &old_addr, &new_addr);
+    NetworkEndian::write_u16(&mut packet[10..12], header_checksum);
+
+    // the addresses are part of the pseudo header
+    let offset = fields.header_len + fields.checksum_offset;
+    let checksum = NetworkEndian::read_u16(&packet[offset..offset + 2]);
+    let udp = fields.protocol == IpProtocol::Udp;
+    if !(udp && checksum == 0) {
+        let checksum = ip_checksum::update(checksum, &old_addr, &new_addr);
+        let mut checksum = ip_checksum::update(checksum, &old_port, &new_port);
+        if udp && checksum == 0 {
+            checksum = 0xffff; // 0 means no checksum
+        }
+        NetworkEndian::write_u16(&mut packet[offset..offset + 2], checksum);
+    }
+
+    packet[addr_offset..addr_offset + 4].copy_from_slice(&new_addr);
+    packet[port_offset..port_offset + 2].copy_from_slice(&new_port);
+}
+
+#[derive(Debug)]
+pub struct Nat {
+    external: Ipv4Address,
+    ports: PortAllocator,
+    /// Flows by protocol number and external port.
+    flows: BTreeMap<(u8, u16), Flow>,
+    /// External ports by protocol number, internal, and remote endpoint.
+    ports_by_flow: BTreeMap<(u8, Endpoint, Endpoint), u16>,
+}
+
+impl Nat {
+    /// Creates a table for the external address `external`. The external ports are
+    /// chosen randomly, starting from `seed`.
+    pub fn new(external: Ipv4Address, seed: u32) -> Nat {
+        Nat {
+            external,
+            ports: PortAllocator::new(seed),
+            flows: BTreeMap::new(),
+            ports_by_flow: BTreeMap::new(),
+        }
+    }
+
+    pub fn external(&self) -> Ipv4Address {
+        self.external
+    }
+
+    /// Changes the external address, e.g. after a new DHCP lease. All flows are
+    /// dropped, since their peers can't reach the old address anymore.
+    pub fn set_external(&mut self, external: Ipv4Address) {
+        self.external = external;
+        self.flows.clear();
+        self.ports_by_flow.clear();
+    }
+
+    /// Sets the range of external ports, by default the dynamic port range. See
+    /// `PortAllocator::set_range`.
+    pub fn set_port_range(&mut self, first: u16, last: u16) -> Result<(), ()> {
+        self.ports.set_range(first, last)
+    }
+
+    /// The number of tracked flows.
+    pub fn len(&self) -> usize {
+        self.flows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flows.is_empty()
+    }
+
+    /// Rewrites the source of `packet`, an IPv4 packet from the internal network, to
+    /// the external address and the port of its flow. Creates the flow if necessary.
+    pub fn outbound(&mut self, packet: &mut [u8], now: u64) -> Result<(), NatError> {
+        let fields = fields(packet)?;
+        let protocol = fields.protocol.number();
+        let key = (protocol, fields.src, fields.dst);
+        let port = match self.ports_by_flow.get(&key) {
+            Some(&port) => port,
+            None => self.add_flow(protocol, fields.src, fields.dst, now)?,
+        };
+        if let Some(flow) = self.flows.get_mut(&(protocol, port)) {
+            flow.last_used = now;
+        }
+        rewrite(packet, &fields, true, Endpoint::new(self.external, port));
+        Ok(())
+    }
+
+    /// Rewrites the destination of `packet`, an IPv4 packet to the external address,
+    /// to the internal endpoint of its flow, and returns it.
+    ///
+    /// Packets from other hosts than the remote endpoint of the flow are rejected.
+    pub fn inbound(&mut self, packet: &mut [u8], now: u64) -> Result<Endpoint, NatError> {
+        let fields = fields(packet)?;
+        if fields.dst.addr != self.external {
+            return Err(NatError::NoFlow);
+        }
+        let internal = match self.flows.get_mut(&(fields.protocol.number(), fields.dst.port)) {
+            Some(ref mut flow) if flow.remote == fields.src => {
+                flow.last_used = now;
+                flow.internal
+            }
+            _ => return Err(NatError::NoFlow),
+        };
+        rewrite(packet, &fields, false, internal);
+        Ok(internal)
+    }
+
+    /// Drops the flows that were idle for longer than their timeout.
+    pub fn poll(&mut self, now: u64) {
+        let expired: Vec<_> = self.flows
+            .iter()
+            .filter(|&(&(protocol, _), flow)| now >= flow.last_used + timeout(protocol))
+            .map(|(&key, flow)| (key, flow.internal, flow.remote))
+            .collect();
+        for ((protocol, port), internal, remote) in expired {
+            self.flows.remove(&(protocol, port));
+            self.ports_by_flow.remove(&(protocol, internal, remote));
+        }
+    }
+
+    fn add_flow(&mut self,
+                protocol: u8,
+                internal: Endpoint,
+                remote: Endpoint,
+                now: u64)
+                -> Result<u16, NatError> {
+        if self.flows.len() >= MAX_FLOWS {
+            self.poll(now);
+            if self.flows.len() >= MAX_FLOWS {
+                return Err(NatError::Exhausted);
+            }
+        }
+        let port = {
+            let flows = &self.flows;
+            self.ports.allocate(|port| flows.contains_key(&(protocol, port)))
+        };
+        let port = port.ok_or(NatError::Exhausted)?;
+        self.flows.insert((protocol, port),
+                          Flow {
+                              internal,
+                              remote,
+                              last_used: now,
+                          });
+        self.ports_by_flow.insert((protocol, internal, remote), port);
+        Ok(port)
+    }
+}
+
+fn timeout(protocol: u8) -> u64 {
+    if protocol == IpProtocol::Tcp.number() {
+        TCP_FLOW_TIMEOUT
+    } else {
+        UDP_FLOW_TIMEOUT
+    }
+}
+
+#[test]
+fn masquerade_udp() {
+    use {HeapTxPacket, WriteOut};
+    use ipv4::Ipv4Packet;
+    use udp::UdpPacket;
+
+    let internal = Endpoint::new(Ipv4Address::new(10, 0, 0, 5), 5353);
+    let remote = Endpoint::new(Ipv4Address::new(198, 51, 100, 7), 53);
+    let external = Ipv4Address::new(203, 0, 113, 1);
+    let datagram = |src: Endpoint, dst: Endpoint| {
+        let udp = UdpPacket::new(src.port, dst.port, &b"query"[..]);
+        let ip = Ipv4Packet::new_udp(src.addr, dst.addr, udp);
+        let mut packet = HeapTxPacket::new(ip.len());
+        ip.write_out(&mut packet).unwrap();
+        packet.as_slice().to_vec()
+    };
+    let checksums_valid = |packet: &[u8]| {
+        let src = Ipv4Address::from_bytes(&packet[12..16]);
+        let dst = Ipv4Address::from_bytes(&packet[16..20]);
+        let udp = &packet[20..];
+        let pseudo_header = ip_checksum::pseudo_header(&src, &dst, IpProtocol::Udp, udp.len());
+        ip_checksum::data(&packet[..20]) == 0xffff &&
+        ip_checksum::combine(&[ip_checksum::data(udp), pseudo_header]) == 0xffff
+    };
+
+    let mut nat = Nat::new(external, 7);
+    let mut packet = datagram(internal, remote);
+    nat.outbound(&mut packet, 0).unwrap();
+    assert!(checksums_valid(&packet));
+    assert_eq!(Ipv4Address::from_bytes(&packet[12..16]), external);
+    let port = NetworkEndian::read_u16(&packet[20..22]);
+    assert_eq!(&packet[28..], b"query");
+
+    // the reply reaches the internal host, other hosts can't use the port
+    let mut reply = datagram(remote, Endpoint::new(external, port));
+    assert_eq!(nat.inbound(&mut reply, 1000), Ok(internal));
+    assert!(checksums_valid(&reply));
+    assert_eq!(reply, datagram(remote, internal));
+    let stranger = Endpoint::new(Ipv4Address::new(192, 0, 2, 99), 53);
+    let mut spoofed = datagram(stranger, Endpoint::new(external, port));
+    assert_eq!(nat.inbound(&mut spoofed, 1000), Err(NatError::NoFlow));
+
+    nat.poll(1000 + UDP_FLOW_TIMEOUT);
+    assert!(nat.is_empty());
+}
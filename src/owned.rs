@@ -0,0 +1,436 @@
+//! Conversions from parsed packets, which borrow the receive buffer, to owned
+//! packets that can be queued after the buffer was handed back to the driver.
+//!
+//! With `alloc`, `ToOwnedPacket` copies whole parse trees, e.g.
+//! `EthernetPacket<EthernetKind>` becomes `EthernetPacket<OwnedEthernetKind>`.
+
+#[cfg(any(test, feature = "alloc"))]
+use alloc::Vec;
+#[cfg(any(test, feature = "alloc"))]
+use alloc::boxed::Box;
+#[cfg(any(test, feature = "alloc"))]
+use arp::ArpPacket;
+#[cfg(any(test, all(feature = "alloc", feature = "dhcp")))]
+use dhcp::{DhcpConfig, DhcpPacket, DhcpType, HardwareAddress, RelayAgentInfo};
+#[cfg(any(test, feature = "alloc"))]
+use eapol::{EapCode, EapPacket, EapType, EapolBody, EapolPacket};
+#[cfg(any(test, feature = "alloc"))]
+use ethernet::EthernetKind;
+#[cfg(any(test, all(feature = "alloc", feature = "icmp")))]
+use icmp::IcmpKind;
+#[cfg(any(test, feature = "alloc"))]
+use igmp::IgmpMessage;
+#[cfg(any(test, all(feature = "alloc", any(feature = "icmp", feature = "dhcp"))))]
+use ipv4::Ipv4Address;
+#[cfg(any(test, feature = "alloc"))]
+use ipv4::Ipv4Kind;
+#[cfg(any(test, feature = "alloc"))]
+use parse::Layer;
+#[cfg(any(test, feature = "alloc"))]
+use ptp::PtpMessage;
+#[cfg(any(test, all(feature = "alloc", feature = "tcp")))]
+use tcp::TcpKind;
+#[cfg(any(test, feature = "alloc"))]
+use udp::UdpKind;
+use ethernet::EthernetPacket;
+use gre::GrePacket;
+#[cfg(any(test, feature = "icmp"))]
+use icmp::IcmpPacket;
+use ipv4::Ipv4Packet;
+#[cfg(any(test, feature = "tcp"))]
+use tcp::TcpPacket;
+use udp::UdpPacket;
+use udplite::UdpLitePacket;
+
+/// A packet that borrows its data and can be copied to the heap.
+#[cfg(any(test, feature = "alloc"))]
+pub trait ToOwnedPacket {
+    type Owned;
+
+    fn to_owned_packet(&self) -> Self::Owned;
+}
+
+macro_rules! impl_to_owned {
+    ($packet:ident, $header:ident, $payload:ident) => {
+        #[cfg(any(test, feature = "alloc"))]
+        impl<'a> ToOwnedPacket for $packet<&'a [u8]> {
+            type Owned = $packet<Vec<u8>>;
+
+            fn to_owned_packet(&self) -> Self::Owned {
+                $packet {
+                    $header: self.$header,
+                    $payload: self.$payload.to_vec(),
+                }
+            }
+        }
+    }
+}
+
+impl_to_owned!(EthernetPacket, header, payload);
+impl_to_owned!(Ipv4Packet, header, payload);
+impl_to_owned!(UdpPacket, header, payload);
+#[cfg(any(test, feature = "tcp"))]
+impl_to_owned!(TcpPacket, header, payload);
+#[cfg(any(test, feature = "icmp"))]
+impl_to_owned!(IcmpPacket, type_, data);
+impl_to_owned!(GrePacket, header, payload);
+impl_to_owned!(UdpLitePacket, header, payload);
+
+#[cfg(any(test, feature = "alloc"))]
+#[derive(Debug, Clone)]
+pub enum OwnedEthernetKind {
+    Ipv4(Ipv4Packet<OwnedIpv4Kind>),
+    Arp(ArpPacket),
+    Rarp(ArpPacket),
+    Eapol(OwnedEapolPacket),
+    Ptp(PtpMessage),
+    Unknown(Vec<u8>),
+}
+
+#[cfg(any(test, feature = "alloc"))]
+#[derive(Debug, Clone)]
+pub enum OwnedIpv4Kind {
+    Udp(UdpPacket<OwnedUdpKind>),
+    #[cfg(any(test, feature = "tcp"))]
+    Tcp(TcpPacket<Vec<u8>>),
+    #[cfg(any(test, feature = "icmp"))]
+    Icmp(OwnedIcmpKind),
+    Igmp(IgmpMessage),
+    Gre(GrePacket<Vec<u8>>),
+    UdpLite(UdpLitePacket<Vec<u8>>),
+    Ipv4(Box<Ipv4Packet<OwnedIpv4Kind>>),
+    Unknown(u8, Vec<u8>),
+}
+
+/// An owned `IcmpKind`.
+#[cfg(any(test, all(feature = "alloc", feature = "icmp")))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OwnedIcmpKind {
+    EchoRequest {
+        id: u16,
+        sequence_number: u16,
+        data: Vec<u8>,
+    },
+    EchoReply {
+        id: u16,
+        sequence_number: u16,
+        data: Vec<u8>,
+    },
+    DestinationUnreachable {
+        code: u8,
+        next_hop_mtu: u16,
+        quoted: Vec<u8>,
+    },
+    Redirect {
+        code: u8,
+        gateway: Ipv4Address,
+        quoted: Vec<u8>,
+    },
+    TimeExceeded { code: u8, quoted: Vec<u8> },
+    ParameterProblem {
+        code: u8,
+        pointer: u8,
+        quoted: Vec<u8>,
+    },
+    Unknown { type_: u8, code: u8, data: Vec<u8> },
+}
+
+#[cfg(any(test, feature = "alloc"))]
+#[derive(Debug, Clone)]
+pub enum OwnedUdpKind {
+    #[cfg(any(test, feature = "dhcp"))]
+    Dhcp(OwnedDhcpPacket),
+    Decoded(Layer, Vec<u8>),
+    Unknown(Vec<u8>),
+}
+
+/// An owned `DhcpPacket`, `as_packet` borrows it again.
+#[cfg(any(test, all(feature = "alloc", feature = "dhcp")))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OwnedDhcpPacket {
+    pub hardware_address: HardwareAddress,
+    pub client_id: Option<Vec<u8>>,
+    pub transaction_id: u32,
+    pub operation: DhcpType,
+    pub broadcast: bool,
+    pub relay_agent_ip: Ipv4Address,
+    pub relay_agent_info: Option<OwnedRelayAgentInfo>,
+    pub config: DhcpConfig,
+    pub parameter_request_list: Option<Vec<u8>>,
+    pub domain_search: Option<Vec<u8>>,
+}
+
+#[cfg(any(test, all(feature = "alloc", feature = "dhcp")))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OwnedRelayAgentInfo {
+    pub circuit_id: Option<Vec<u8>>,
+    pub remote_id: Option<Vec<u8>>,
+}
+
+#[cfg(any(test, all(feature = "alloc", feature = "dhcp")))]
+impl OwnedDhcpPacket {
+    pub fn as_packet(&self) -> DhcpPacket {
+        let relay_agent_info = self.relay_agent_info.as_ref().map(|info| {
+            RelayAgentInfo {
+                circuit_id: info.circuit_id.as_ref().map(|id| &id[..]),
+                remote_id: info.remote_id.as_ref().map(|id| &id[..]),
+            }
+        });
+        DhcpPacket {
+            hardware_address: self.hardware_address,
+            client_id: self.client_id.as_ref().map(|id| &id[..]),
+            transaction_id: self.transaction_id,
+            operation: self.operation,
+            broadcast: self.broadcast,
+            relay_agent_ip: self.relay_agent_ip,
+            relay_agent_info,
+            config: self.config,
+            parameter_request_list: self.parameter_request_list.as_ref().map(|list| &list[..]),
+            domain_search: self.domain_search.as_ref().map(|list| &list[..]),
+        }
+    }
+}
+
+/// An owned `EapolPacket`, `as_packet` borrows it again.
+#[cfg(any(test, feature = "alloc"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OwnedEapolPacket {
+    pub version: u8,
+    pub body: OwnedEapolBody,
+}
+
+#[cfg(any(test, feature = "alloc"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OwnedEapolBody {
+    Eap {
+        code: EapCode,
+        identifier: u8,
+        type_: Option<EapType>,
+        type_data: Vec<u8>,
+    },
+    Start,
+    Logoff,
+    Unknown(u8, Vec<u8>),
+}
+
+#[cfg(any(test, feature = "alloc"))]
+impl OwnedEapolPacket {
+    pub fn as_packet(&self) -> EapolPacket {
+        let body = match self.body {
+            OwnedEapolBody::Eap { code, identifier, type_, ref type_data } => {
+                EapolBody::Eap(EapPacket {
+                                   code,
+                                   identifier,
+                                   type_,
+                                   type_data,
+                               })
+            }
+            OwnedEapolBody::Start => EapolBody::Start,
+            OwnedEapolBody::Logoff => EapolBody::Logoff,
+            OwnedEapolBody::Unknown(packet_type, ref data) => {
+                EapolBody::Unknown(packet_type, data)
+            }
+        };
+        EapolPacket {
+            version: self.version,
+            body,
+        }
+    }
+}
+
+#[cfg(any(test, all(feature = "alloc", feature = "dhcp")))]
+impl<'a> ToOwnedPacket for DhcpPacket<'a> {
+    type Owned = OwnedDhcpPacket;
+
+    fn to_owned_packet(&self) -> OwnedDhcpPacket {
+        let relay_agent_info = self.relay_agent_info.map(|info| {
+            OwnedRelayAgentInfo {
+                circuit_id: info.circuit_id.map(|id| id.to_vec()),
+                remote_id: info.remote_id.map(|id| id.to_vec()),
+            }
+        });
+        OwnedDhcpPacket {
+            hardware_address: self.hardware_address,
+            client_id: self.client_id.map(|id| id.to_vec()),
+            transaction_id: self.transaction_id,
+            operation: self.operation,
+            broadcast: self.broadcast,
+            relay_agent_ip: self.relay_agent_ip,
+            relay_agent_info,
+            config: self.config,
+            parameter_request_list: self.parameter_request_list.map(|list| list.to_vec()),
+            domain_search: self.domain_search.map(|list| list.to_vec()),
+        }
+    }
+}
+
+#[cfg(any(test, feature = "alloc"))]
+impl<'a> ToOwnedPacket for EapolPacket<'a> {
+    type Owned = OwnedEapolPacket;
+
+    fn to_owned_packet(&self) -> OwnedEapolPacket {
+        let body = match self.body {
+            EapolBody::Eap(ref eap) => {
+                OwnedEapolBody::Eap {
+                    code: eap.code,
+                    identifier: eap.identifier,
+                    type_: eap.type_,
+                    type_data: eap.type_data.to_vec(),
+                }
+            }
+            EapolBody::Start => OwnedEapolBody::Start,
+            EapolBody::Logoff => OwnedEapolBody::Logoff,
+            EapolBody::Unknown(packet_type, data) => {
+                OwnedEapolBody::Unknown(packet_type, data.to_vec())
+            }
+        };
+        OwnedEapolPacket {
+            version: self.version,
+            body,
+        }
+    }
+}
+
+#[cfg(any(test, feature = "alloc"))]
+impl<'a> ToOwnedPacket for UdpPacket<UdpKind<'a>> {
+    type Owned = UdpPacket<OwnedUdpKind>;
+
+    fn to_owned_packet(&self) -> Self::Owned {
+        let payload = match self.payload {
+            #[cfg(any(test, feature = "dhcp"))]
+            UdpKind::Dhcp(ref dhcp) => OwnedUdpKind::Dhcp(dhcp.to_owned_packet()),
+            UdpKind::Decoded(layer, data) => OwnedUdpKind::Decoded(layer, data.to_vec()),
+            UdpKind::Unknown(data) => OwnedUdpKind::Unknown(data.to_vec()),
+        };
+        UdpPacket {
+            header: self.header,
+            payload,
+        }
+    }
+}
+
+#[cfg(any(test, all(feature = "alloc", feature = "tcp")))]
+impl<'a> ToOwnedPacket for TcpPacket<TcpKind<'a>> {
+    type Owned = TcpPacket<Vec<u8>>;
+
+    fn to_owned_packet(&self) -> Self::Owned {
+        let payload = match self.payload {
+            TcpKind::Unknown(data) => data.to_vec(),
+        };
+        TcpPacket {
+            header: self.header,
+            payload,
+        }
+    }
+}
+
+#[cfg(any(test, all(feature = "alloc", feature = "icmp")))]
+impl<'a> ToOwnedPacket for IcmpKind<'a> {
+    type Owned = OwnedIcmpKind;
+
+    fn to_owned_packet(&self) -> OwnedIcmpKind {
+        match *self {
+            IcmpKind::EchoRequest { id, sequence_number, data } => {
+                OwnedIcmpKind::EchoRequest { id, sequence_number, data: data.to_vec() }
+            }
+            IcmpKind::EchoReply { id, sequence_number, data } => {
+                OwnedIcmpKind::EchoReply { id, sequence_number, data: data.to_vec() }
+            }
+            IcmpKind::DestinationUnreachable { code, next_hop_mtu, quoted } => {
+                OwnedIcmpKind::DestinationUnreachable {
+                    code,
+                    next_hop_mtu,
+                    quoted: quoted.to_vec(),
+                }
+            }
+            IcmpKind::Redirect { code, gateway, quoted } => {
+                OwnedIcmpKind::Redirect { code, gateway, quoted: quoted.to_vec() }
+            }
+            IcmpKind::TimeExceeded { code, quoted } => {
+                OwnedIcmpKind::TimeExceeded { code, quoted: quoted.to_vec() }
+            }
+            IcmpKind::ParameterProblem { code, pointer, quoted } => {
+                OwnedIcmpKind::ParameterProblem { code, pointer, quoted: quoted.to_vec() }
+            }
+            IcmpKind::Unknown { type_, code, data } => {
+                OwnedIcmpKind::Unknown { type_, code, data: data.to_vec() }
+            }
+        }
+    }
+}
+
+#[cfg(any(test, feature = "alloc"))]
+impl<'a> ToOwnedPacket for Ipv4Packet<Ipv4Kind<'a>> {
+    type Owned = Ipv4Packet<OwnedIpv4Kind>;
+
+    fn to_owned_packet(&self) -> Self::Owned {
+        let payload = match self.payload {
+            Ipv4Kind::Udp(ref udp) => OwnedIpv4Kind::Udp(udp.to_owned_packet()),
+            #[cfg(any(test, feature = "tcp"))]
+            Ipv4Kind::Tcp(ref tcp) => OwnedIpv4Kind::Tcp(tcp.to_owned_packet()),
+            #[cfg(any(test, feature = "icmp"))]
+            Ipv4Kind::Icmp(ref icmp) => OwnedIpv4Kind::Icmp(icmp.to_owned_packet()),
+            Ipv4Kind::Igmp(igmp) => OwnedIpv4Kind::Igmp(igmp),
+            Ipv4Kind::Gre(ref gre) => OwnedIpv4Kind::Gre(gre.to_owned_packet()),
+            Ipv4Kind::UdpLite(ref udp) => OwnedIpv4Kind::UdpLite(udp.to_owned_packet()),
+            Ipv4Kind::Ipv4(ref inner, _) => {
+                OwnedIpv4Kind::Ipv4(Box::new(inner.to_owned_packet()))
+            }
+            Ipv4Kind::Unknown(protocol, data) => OwnedIpv4Kind::Unknown(protocol, data.to_vec()),
+        };
+        Ipv4Packet {
+            header: self.header,
+            payload,
+        }
+    }
+}
+
+#[cfg(any(test, feature = "alloc"))]
+impl<'a> ToOwnedPacket for EthernetPacket<EthernetKind<'a>> {
+    type Owned = EthernetPacket<OwnedEthernetKind>;
+
+    fn to_owned_packet(&self) -> Self::Owned {
+        let payload = match self.payload {
+            EthernetKind::Ipv4(ref ip) => OwnedEthernetKind::Ipv4(ip.to_owned_packet()),
+            EthernetKind::Arp(arp) => OwnedEthernetKind::Arp(arp),
+            EthernetKind::Rarp(arp) => OwnedEthernetKind::Rarp(arp),
+            EthernetKind::Eapol(ref eapol) => OwnedEthernetKind::Eapol(eapol.to_owned_packet()),
+            EthernetKind::Ptp(message) => OwnedEthernetKind::Ptp(message),
+            EthernetKind::Unknown(data) => OwnedEthernetKind::Unknown(data.to_vec()),
+        };
+        EthernetPacket {
+            header: self.header,
+            payload,
+        }
+    }
+}
+
+#[test]
+fn outlives_receive_buffer() {
+    use ethernet::EthernetAddress;
+    use parse::Parse;
+    use testing::frames;
+
+    let owned = {
+        let data = hex!(frames::DHCP_OFFER);
+        let packet = EthernetPacket::<EthernetKind>::parse(&data).unwrap();
+        packet.to_owned_packet()
+    };
+    assert_eq!(owned.header.src_addr, EthernetAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]));
+    let udp = match owned.payload {
+        OwnedEthernetKind::Ipv4(Ipv4Packet { payload: OwnedIpv4Kind::Udp(ref udp), .. }) => udp,
+        ref other => panic!("unexpected payload {:?}", other),
+    };
+    let dhcp = match udp.payload {
+        OwnedUdpKind::Dhcp(ref dhcp) => dhcp,
+        ref other => panic!("unexpected payload {:?}", other),
+    };
+    match dhcp.as_packet().operation {
+        DhcpType::Offer { ip, .. } => assert_eq!(ip, Ipv4Address::new(192, 168, 1, 100)),
+        other => panic!("unexpected operation {:?}", other),
+    }
+
+    let data = [1, 2, 3];
+    let udp = UdpPacket::new(1000, 53, &data[..]).to_owned_packet();
+    assert_eq!(udp.payload, vec![1, 2, 3]);
+}
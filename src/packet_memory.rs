@@ -0,0 +1,177 @@
+use core::ops::Range;
+use TxPacket;
+
+/// Packet buffer memory outside of the MCU, e.g. the SRAM of an ENC28J60 or W5500
+/// that is accessed through a SPI register window.
+pub trait PacketMemory {
+    fn read(&mut self, addr: usize, buf: &mut [u8]);
+    fn write(&mut self, addr: usize, buf: &[u8]);
+}
+
+impl<'a, M: PacketMemory + ?Sized> PacketMemory for &'a mut M {
+    fn read(&mut self, addr: usize, buf: &mut [u8]) {
+        (**self).read(addr, buf)
+    }
+
+    fn write(&mut self, addr: usize, buf: &[u8]) {
+        (**self).write(addr, buf)
+    }
+}
+
+/// Plain MCU RAM, for serializing into a fixed buffer without `alloc`.
+impl PacketMemory for [u8] {
+    fn read(&mut self, addr: usize, buf: &mut [u8]) {
+        buf.copy_from_slice(&self[addr..(addr + buf.len())]);
+    }
+
+    fn write(&mut self, addr: usize, buf: &[u8]) {
+        self[addr..(addr + buf.len())].copy_from_slice(buf);
+    }
+}
+
+/// The maximum number of bytes that `TxPacket::get_bytes` is called with. This covers
+/// a full IPv4 header with options, longer reads are split into chunks.
+pub const MAX_READ_LEN: usize = 64;
+
+/// A `TxPacket` that serializes directly into `PacketMemory` instead of staging the
+/// packet in MCU RAM.
+///
+/// The packet starts at address `start` and may use up to `capacity` bytes. Reads
+/// for checksum and length updates go through a small scratch buffer.
+pub struct MemoryTxPacket<M: PacketMemory> {
+    memory: M,
+    start: usize,
+    len: usize,
+    capacity: usize,
+    scratch: [u8; MAX_READ_LEN],
+    payload_range: Option<Range<usize>>,
+}
+
+impl<M: PacketMemory> MemoryTxPacket<M> {
+    pub fn new(memory: M, start: usize, capacity: usize) -> Self {
+        MemoryTxPacket {
+            memory,
+            start,
+            len: 0,
+            capacity,
+            scratch: [0; MAX_READ_LEN],
+            payload_range: None,
+        }
+    }
+
+    /// The range of the payload of the innermost layer, relative to `start`.
+    pub fn payload_range(&self) -> Option<Range<usize>> {
+        self.payload_range.clone()
+    }
+
+    pub fn into_inner(self) -> M {
+        self.memory
+    }
+}
+
+impl<M: PacketMemory> TxPacket for MemoryTxPacket<M> {
+    fn push_bytes(&mut self, bytes: &[u8]) -> Result<usize, ()> {
+        if self.capacity - self.len < bytes.len() {
+            return Err(());
+        }
+        let index = self.len;
+        self.memory.write(self.start + index, bytes);
+        self.len += bytes.len();
+        Ok(index)
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn get_bytes(&mut self, index: usize, len: usize) -> &[u8] {
+        assert!(index + len <= self.len);
+        assert!(len <= MAX_READ_LEN, "read of {} bytes exceeds scratch buffer", len);
+        self.memory.read(self.start + index, &mut self.scratch[..len]);
+        &self.scratch[..len]
+    }
+
+    fn set_bytes(&mut self, index: usize, bytes: &[u8]) {
+        assert!(index + bytes.len() <= self.len);
+        self.memory.write(self.start + index, bytes);
+    }
+
+    fn remaining_capacity(&self) -> Option<usize> {
+        Some(self.capacity - self.len)
+    }
+
+    fn mark_payload(&mut self, range: Range<usize>) {
+        if self.payload_range.is_none() {
+            self.payload_range = Some(range);
+        }
+    }
+}
+
+#[test]
+fn serialize_into_packet_memory() {
+    use ethernet::EthernetAddress;
+    use ipv4::Ipv4Address;
+    use udp;
+    use {HeapTxPacket, WriteOut};
+
+    struct Sram {
+        bytes: [u8; 256],
+        writes: usize,
+    }
+
+    impl PacketMemory for Sram {
+        fn read(&mut self, addr: usize, buf: &mut [u8]) {
+            buf.copy_from_slice(&self.bytes[addr..(addr + buf.len())]);
+        }
+
+        fn write(&mut self, addr: usize, buf: &[u8]) {
+            self.bytes[addr..(addr + buf.len())].copy_from_slice(buf);
+            self.writes += 1;
+        }
+    }
+
+    let mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let ip = Ipv4Address::new(192, 168, 1, 2);
+    let udp = udp::new_udp_packet(mac, mac, ip, ip, 1000, 7, &[1, 2, 3, 4, 5][..]);
+
+    let mut reference = HeapTxPacket::new(udp.len());
+    udp.write_out(&mut reference).unwrap();
+
+    let mut sram = Sram {
+        bytes: [0; 256],
+        writes: 0,
+    };
+    {
+        let mut packet = MemoryTxPacket::new(&mut sram, 16, 100);
+        udp.write_out(&mut packet).unwrap();
+        assert_eq!(packet.len(), reference.len());
+        assert_eq!(packet.payload_range(), reference.payload_range());
+        assert!(packet.push_bytes(&[0; 100]).is_err());
+    }
+    assert_eq!(&sram.bytes[16..(16 + reference.len())], reference.as_slice());
+    assert!(sram.writes > 0);
+}
+
+#[test]
+fn buffer_full() {
+    use ethernet::EthernetAddress;
+    use ipv4::Ipv4Address;
+    use {udp, write_packet, WriteError, WriteOut};
+
+    let mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let ip = Ipv4Address::new(192, 168, 1, 2);
+    let udp = udp::new_udp_packet(mac, mac, ip, ip, 1000, 7, &[1, 2, 3, 4, 5][..]);
+
+    let mut buf = [0xff; 64];
+    {
+        let mut packet = MemoryTxPacket::new(&mut buf[..], 0, 40);
+        assert_eq!(write_packet(&mut packet, &udp),
+                   Err(WriteError::BufferFull { needed: udp.len() }));
+        assert_eq!(packet.len(), 0);
+    }
+    assert!(buf.iter().all(|&b| b == 0xff));
+
+    let mut packet = MemoryTxPacket::new(&mut buf[..], 0, 64);
+    write_packet(&mut packet, &udp).unwrap();
+    assert_eq!(packet.len(), udp.required_capacity());
+}
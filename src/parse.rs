@@ -1,16 +1,583 @@
-use ethernet::{EthernetPacket, EthernetKind};
+use ethernet::{EthernetPacket, EthernetHeader, EthernetKind, EtherType};
+use arp::ArpPacket;
+use eapol::EapolPacket;
+use ipv4::{IpProtocol, Ipv4Header, Ipv4Packet, MAX_TUNNEL_DEPTH};
+#[cfg(any(test, feature = "icmp"))]
+use icmp::{IcmpKind, IcmpPacket};
+use udp::{self, UdpDecoder, UdpHeader, UdpPacket};
+#[cfg(any(test, feature = "tcp"))]
+use tcp::{TcpHeader, TcpPacket};
+use gre::{GreHeader, GrePacket};
+use udplite::{self, UdpLiteHeader, UdpLitePacket};
 
 pub trait Parse<'a>: Sized {
     fn parse(data: &'a [u8]) -> Result<Self, ParseError>;
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// A protocol layer, as named in parse errors, statistics, frame filters and the
+/// visitor API.
+///
+/// With the `compact-errors` feature, `Debug` prints the number of the layer, see
+/// `ParseError::code`.
+#[cfg_attr(not(feature = "compact-errors"), derive(Debug))]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Layer {
+    Ethernet,
+    Arp,
+    Eapol,
+    Ipv4,
+    Icmp,
+    Igmp,
+    Udp,
+    Tcp,
+    Gre,
+    UdpLite,
+    Dhcp,
+    Coap,
+    MqttSn,
+    Modbus,
+    Snmp,
+    Dns,
+    Ptp,
+    Ntp,
+    Http,
+    SixLowpan,
+    /// A protocol of the application, e.g. of its own `UdpDecoder`.
+    Other,
+}
+
+impl Layer {
+    /// The layer above Ethernet for frames of `ether_type`, if it is known.
+    pub fn from_ether_type(ether_type: EtherType) -> Option<Layer> {
+        match ether_type {
+            EtherType::Ipv4 => Some(Layer::Ipv4),
+            EtherType::Arp | EtherType::Rarp => Some(Layer::Arp),
+            EtherType::Eapol => Some(Layer::Eapol),
+            EtherType::Ptp => Some(Layer::Ptp),
+            EtherType::Unknown(_) => None,
+        }
+    }
+
+    /// The layer above IPv4 for packets of `protocol`, if it is known.
+    pub fn from_ip_protocol(protocol: IpProtocol) -> Option<Layer> {
+        match protocol {
+            IpProtocol::Icmp => Some(Layer::Icmp),
+            IpProtocol::Igmp => Some(Layer::Igmp),
+            IpProtocol::Udp => Some(Layer::Udp),
+            IpProtocol::Tcp => Some(Layer::Tcp),
+            IpProtocol::Gre => Some(Layer::Gre),
+            IpProtocol::UdpLite => Some(Layer::UdpLite),
+            IpProtocol::IpInIp => Some(Layer::Ipv4),
+            IpProtocol::Unknown(_) => None,
+        }
+    }
+}
+
+/// A parse error with the failing layer and the byte offset of the offending field.
+///
+/// Offsets returned by the top-level `parse` function are relative to the start of
+/// the frame. Errors of a `Parse` impl that is called directly are relative to the
+/// passed data.
+///
+/// With the `compact-errors` feature, the reasons are empty, so that their strings
+/// aren't linked, and `Debug` prints the `code` and the offset.
+#[cfg_attr(not(feature = "compact-errors"), derive(Debug))]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub enum ParseError {
-    Unimplemented(&'static str),
-    Malformed(&'static str),
-    Truncated(usize),
+    Unimplemented {
+        layer: Layer,
+        offset: usize,
+        reason: &'static str,
+    },
+    Malformed {
+        layer: Layer,
+        offset: usize,
+        reason: &'static str,
+    },
+    /// The data ended at `offset`, before the end of the layer.
+    Truncated { layer: Layer, offset: usize },
+    /// The checksum at `offset` doesn't match the data.
+    Checksum { layer: Layer, offset: usize },
+}
+
+#[cfg(not(feature = "compact-errors"))]
+#[inline(always)]
+fn reason(reason: &'static str) -> &'static str {
+    reason
+}
+
+#[cfg(feature = "compact-errors")]
+#[inline(always)]
+fn reason(_reason: &'static str) -> &'static str {
+    ""
+}
+
+impl ParseError {
+    #[inline]
+    pub fn unimplemented(layer: Layer, offset: usize, reason: &'static str) -> ParseError {
+        ParseError::Unimplemented {
+            layer,
+            offset,
+            reason: self::reason(reason),
+        }
+    }
+
+    #[inline]
+    pub fn malformed(layer: Layer, offset: usize, reason: &'static str) -> ParseError {
+        ParseError::Malformed {
+            layer,
+            offset,
+            reason: self::reason(reason),
+        }
+    }
+
+    pub fn truncated(layer: Layer, offset: usize) -> ParseError {
+        ParseError::Truncated { layer, offset }
+    }
+
+    pub fn checksum(layer: Layer, offset: usize) -> ParseError {
+        ParseError::Checksum { layer, offset }
+    }
+
+    pub fn layer(&self) -> Layer {
+        match *self {
+            ParseError::Unimplemented { layer, .. } |
+            ParseError::Malformed { layer, .. } |
+            ParseError::Truncated { layer, .. } |
+            ParseError::Checksum { layer, .. } => layer,
+        }
+    }
+
+    pub fn offset(&self) -> usize {
+        match *self {
+            ParseError::Unimplemented { offset, .. } |
+            ParseError::Malformed { offset, .. } |
+            ParseError::Truncated { offset, .. } |
+            ParseError::Checksum { offset, .. } => offset,
+        }
+    }
+
+    /// A numeric code of the kind of error (high byte: 1 unimplemented, 2 malformed,
+    /// 3 truncated, 4 checksum) and the layer (low byte, in declaration order).
+    pub fn code(&self) -> u16 {
+        let kind = match *self {
+            ParseError::Unimplemented { .. } => 1,
+            ParseError::Malformed { .. } => 2,
+            ParseError::Truncated { .. } => 3,
+            ParseError::Checksum { .. } => 4,
+        };
+        kind << 8 | self.layer() as u16
+    }
+
+    /// Makes the offset relative to an outer packet in which the failing layer starts
+    /// at `base`.
+    pub fn at(mut self, base: usize) -> ParseError {
+        match self {
+            ParseError::Unimplemented { ref mut offset, .. } |
+            ParseError::Malformed { ref mut offset, .. } |
+            ParseError::Truncated { ref mut offset, .. } |
+            ParseError::Checksum { ref mut offset, .. } => *offset += base,
+        }
+        self
+    }
+}
+
+#[cfg(feature = "compact-errors")]
+impl ::core::fmt::Debug for Layer {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        write!(f, "L{}", *self as u8)
+    }
+}
+
+#[cfg(feature = "compact-errors")]
+impl ::core::fmt::Debug for ParseError {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        write!(f, "E{:04x}@{}", self.code(), self.offset())
+    }
 }
 
 pub fn parse(data: &[u8]) -> Result<EthernetPacket<EthernetKind>, ParseError> {
     EthernetPacket::parse(data)
 }
+
+/// Runs `data` through every `Parse` impl and through `parse_with`.
+///
+/// This is the entry point of the fuzz targets: errors are fine, but panics and
+/// endless loops are bugs.
+#[doc(hidden)]
+pub fn parse_any(data: &[u8]) {
+    use eapol::EapPacket;
+    use ipv4::Ipv4Kind;
+    use udp::UdpKind;
+
+    struct Everything;
+    impl<'a> PacketVisitor<'a> for Everything {}
+
+    let _ = parse(data);
+    let _ = parse_with(data, &mut Everything);
+    let _ = EthernetPacket::<&[u8]>::parse(data);
+    let _ = ArpPacket::parse(data);
+    let _ = EapolPacket::parse(data);
+    let _ = EapPacket::parse(data);
+    let _ = Ipv4Packet::<&[u8]>::parse(data);
+    let _ = Ipv4Packet::<Ipv4Kind>::parse(data);
+    let _ = ::igmp::IgmpMessage::parse(data);
+    let _ = UdpPacket::<&[u8]>::parse(data);
+    let _ = UdpPacket::<UdpKind>::parse(data);
+    let _ = GrePacket::<&[u8]>::parse(data);
+    let _ = UdpLitePacket::<&[u8]>::parse(data);
+    let _ = ::ptp::PtpMessage::parse(data);
+    let _ = ::sixlowpan::SixLowpanKind::parse(data);
+    #[cfg(any(test, feature = "icmp"))]
+    {
+        let _ = IcmpPacket::<&[u8]>::parse(data);
+        let _ = IcmpKind::parse(data);
+    }
+    #[cfg(any(test, feature = "tcp"))]
+    {
+        let _ = TcpPacket::<&[u8]>::parse(data);
+        let _ = TcpPacket::<::tcp::TcpKind>::parse(data);
+    }
+    #[cfg(any(test, feature = "dhcp"))]
+    let _ = ::dhcp::DhcpPacket::parse(data);
+    #[cfg(any(test, feature = "sntp"))]
+    let _ = ::sntp::NtpPacket::parse(data);
+    #[cfg(any(test, feature = "coap"))]
+    let _ = ::coap::CoapMessage::parse(data);
+    #[cfg(any(test, feature = "mqttsn"))]
+    let _ = ::mqttsn::MqttSnPacket::parse(data);
+    #[cfg(any(test, feature = "modbus"))]
+    let _ = ::modbus::ModbusFrame::parse(data);
+    #[cfg(any(test, feature = "snmp"))]
+    let _ = ::snmp::SnmpRequest::parse(data);
+    #[cfg(any(test, feature = "dns"))]
+    let _ = ::dns::DnsResponse::parse(data);
+}
+
+/// Callbacks for `parse_with`.
+///
+/// All methods do nothing by default, so a visitor only implements the layers it is
+/// interested in. Layers for which `visits` returns false are not parsed at all, so
+/// they can't cause errors either.
+///
+/// Without the `icmp` and `tcp` features, `on_icmp` and `on_tcp` don't exist and the
+/// packets are only passed to `on_ipv4`.
+#[allow(unused_variables)]
+pub trait PacketVisitor<'a> {
+    fn visits(&self, layer: Layer) -> bool {
+        true
+    }
+
+    fn on_ethernet(&mut self, header: &EthernetHeader, payload: &'a [u8]) {}
+    /// Also called for RARP frames, which use the ARP packet format.
+    fn on_arp(&mut self, ethernet: &EthernetHeader, arp: &ArpPacket) {}
+    fn on_eapol(&mut self, ethernet: &EthernetHeader, eapol: &EapolPacket<'a>) {}
+    /// Called for the outer and then for the inner header of IP-in-IP packets.
+    fn on_ipv4(&mut self, header: &Ipv4Header, payload: &'a [u8]) {}
+    #[cfg(any(test, feature = "icmp"))]
+    fn on_icmp(&mut self, ip: &Ipv4Header, icmp: &IcmpKind<'a>) {}
+    /// Called with the unparsed payload of every datagram, before its decoder.
+    fn on_udp(&mut self, ip: &Ipv4Header, udp: &UdpHeader, payload: &'a [u8]) {}
+    /// Returning false skips the verification of UDP checksums, e.g. for trusted
+    /// local traffic. `parse` always verifies them.
+    fn verifies_udp_checksums(&self) -> bool {
+        true
+    }
+    /// The parsers for UDP payloads by port, by default none. A visitor can return
+    /// `udp::DECODERS` and its own protocols. A payload that its decoder rejects
+    /// fails `parse_with`.
+    fn udp_decoders(&self) -> &[UdpDecoder] {
+        &[]
+    }
+    /// Called after `on_udp` for payloads that the decoder of their port accepted,
+    /// with the layer of the decoder.
+    fn on_udp_protocol(&mut self,
+                       ip: &Ipv4Header,
+                       udp: &UdpHeader,
+                       layer: Layer,
+                       payload: &'a [u8]) {
+    }
+    #[cfg(any(test, feature = "tcp"))]
+    fn on_tcp(&mut self, ip: &Ipv4Header, tcp: &TcpHeader, payload: &'a [u8]) {}
+    fn on_gre(&mut self, ip: &Ipv4Header, gre: &GreHeader, payload: &'a [u8]) {}
+    fn on_udp_lite(&mut self, ip: &Ipv4Header, udp: &UdpLiteHeader, payload: &'a [u8]) {}
+}
+
+/// Parses a frame and passes each layer to `visitor` instead of building the
+/// nested `EthernetKind` tree.
+pub fn parse_with<'a, V>(data: &'a [u8], visitor: &mut V) -> Result<(), ParseError>
+    where V: PacketVisitor<'a>
+{
+    let ethernet = EthernetPacket::<&[u8]>::parse(data)?;
+    visitor.on_ethernet(&ethernet.header, ethernet.payload);
+
+    match ethernet.header.ether_type {
+        EtherType::Arp | EtherType::Rarp if visitor.visits(Layer::Arp) => {
+            let arp = ArpPacket::parse(ethernet.payload).map_err(|e| e.at(14))?;
+            visitor.on_arp(&ethernet.header, &arp);
+        }
+        EtherType::Eapol if visitor.visits(Layer::Eapol) => {
+            let eapol = EapolPacket::parse(ethernet.payload).map_err(|e| e.at(14))?;
+            visitor.on_eapol(&ethernet.header, &eapol);
+        }
+        EtherType::Ipv4 if visitor.visits(Layer::Ipv4) => {
+            visit_ipv4(ethernet.payload, visitor, 0).map_err(|e| e.at(14))?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Visits a packet inside of `tunnel_depth` IP-in-IP headers.
+fn visit_ipv4<'a, V>(data: &'a [u8], visitor: &mut V, tunnel_depth: usize) -> Result<(), ParseError>
+    where V: PacketVisitor<'a>
+{
+    let ip = Ipv4Packet::<&[u8]>::parse(data)?;
+    visitor.on_ipv4(&ip.header, ip.payload);
+
+    let header_len = usize::from(ip.header_len());
+    match ip.header.protocol() {
+        #[cfg(any(test, feature = "icmp"))]
+        IpProtocol::Icmp if visitor.visits(Layer::Icmp) => {
+            let icmp = IcmpKind::parse(ip.payload).map_err(|e| e.at(header_len))?;
+            visitor.on_icmp(&ip.header, &icmp);
+        }
+        IpProtocol::Udp if visitor.visits(Layer::Udp) => {
+            if visitor.verifies_udp_checksums() {
+                udp::verify_checksum(&ip.header, ip.payload).map_err(|e| e.at(header_len))?;
+            }
+            let udp = UdpPacket::<&[u8]>::parse(ip.payload).map_err(|e| e.at(header_len))?;
+            visitor.on_udp(&ip.header, &udp.header, udp.payload);
+            let decoder = udp::find_decoder(visitor.udp_decoders(), &udp.header).cloned();
+            if let Some(decoder) = decoder {
+                if visitor.visits(decoder.layer) {
+                    (decoder.parse)(udp.payload).map_err(|e| e.at(header_len + 8))?;
+                    visitor.on_udp_protocol(&ip.header, &udp.header, decoder.layer, udp.payload);
+                }
+            }
+        }
+        #[cfg(any(test, feature = "tcp"))]
+        IpProtocol::Tcp if visitor.visits(Layer::Tcp) => {
+            let tcp = TcpPacket::<&[u8]>::parse(ip.payload).map_err(|e| e.at(header_len))?;
+            visitor.on_tcp(&ip.header, &tcp.header, tcp.payload);
+        }
+        IpProtocol::Gre if visitor.visits(Layer::Gre) => {
+            let gre = GrePacket::parse(ip.payload).map_err(|e| e.at(header_len))?;
+            visitor.on_gre(&ip.header, &gre.header, gre.payload);
+        }
+        IpProtocol::UdpLite if visitor.visits(Layer::UdpLite) => {
+            udplite::verify_checksum(&ip.header, ip.payload).map_err(|e| e.at(header_len))?;
+            let udp = UdpLitePacket::parse(ip.payload).map_err(|e| e.at(header_len))?;
+            visitor.on_udp_lite(&ip.header, &udp.header, udp.payload);
+        }
+        IpProtocol::IpInIp if tunnel_depth < MAX_TUNNEL_DEPTH => {
+            visit_ipv4(ip.payload, visitor, tunnel_depth + 1).map_err(|e| e.at(header_len))?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[test]
+fn error_offsets_are_relative_to_frame() {
+    use arp;
+    use ethernet::EthernetAddress;
+    use ipv4::Ipv4Address;
+    use {HeapTxPacket, TxPacket, WriteOut};
+
+    let mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let ip = Ipv4Address::new(192, 168, 1, 2);
+    let request = arp::new_request_packet(mac, ip, ip);
+
+    let mut packet = HeapTxPacket::new(60);
+    request.write_out(&mut packet).unwrap();
+    packet.push_bytes(&[0; 18]).unwrap();
+    packet.set_u16(20, 42); // invalid ARP operation
+
+    let error = parse(&packet).unwrap_err();
+    assert_eq!(error.layer(), Layer::Arp);
+    assert_eq!(error.offset(), 20);
+
+    assert_eq!(parse(&packet.as_slice()[..30]).unwrap_err(),
+               ParseError::Truncated {
+                   layer: Layer::Arp,
+                   offset: 30,
+               });
+    assert_eq!(parse(&packet.as_slice()[..10]).unwrap_err().code(), 0x0300);
+}
+
+#[test]
+fn visitor() {
+    use ethernet::EthernetAddress;
+    use ipv4::Ipv4Address;
+    use {arp, udp, HeapTxPacket, TxPacket, WriteOut};
+
+    struct UdpPorts<'a> {
+        ports: Vec<(u16, &'a [u8])>,
+        arp_packets: usize,
+    }
+
+    impl<'a> PacketVisitor<'a> for UdpPorts<'a> {
+        fn visits(&self, layer: Layer) -> bool {
+            layer != Layer::Arp
+        }
+
+        fn on_arp(&mut self, _: &EthernetHeader, _: &ArpPacket) {
+            self.arp_packets += 1;
+        }
+
+        fn on_udp(&mut self, _: &Ipv4Header, udp: &UdpHeader, payload: &'a [u8]) {
+            self.ports.push((udp.dst_port, payload));
+        }
+    }
+
+    let mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let ip = Ipv4Address::new(192, 168, 1, 2);
+    let udp = udp::new_udp_packet(mac, mac, ip, ip, 1000, 7, &[1, 2, 3][..]);
+    let mut udp_frame = HeapTxPacket::new(60);
+    udp.write_out(&mut udp_frame).unwrap();
+    let padding = 60 - udp_frame.len();
+    udp_frame.push_bytes(&vec![0; padding]).unwrap();
+
+    let mut arp_frame = HeapTxPacket::new(60);
+    arp::new_request_packet(mac, ip, ip).write_out(&mut arp_frame).unwrap();
+    arp_frame.push_bytes(&[0; 18]).unwrap();
+    arp_frame.set_u16(20, 42); // invalid ARP operation, but ARP isn't visited
+
+    let mut visitor = UdpPorts {
+        ports: Vec::new(),
+        arp_packets: 0,
+    };
+    parse_with(&udp_frame, &mut visitor).unwrap();
+    parse_with(&arp_frame, &mut visitor).unwrap();
+    assert_eq!(visitor.ports, vec![(7, &[1, 2, 3][..])]);
+    assert_eq!(visitor.arp_packets, 0);
+}
+
+#[test]
+fn skip_udp_checksums() {
+    use ethernet::EthernetAddress;
+    use ipv4::Ipv4Address;
+    use {udp, HeapTxPacket};
+
+    struct Unverified(usize);
+
+    impl<'a> PacketVisitor<'a> for Unverified {
+        fn on_udp(&mut self, _: &Ipv4Header, _: &UdpHeader, _: &'a [u8]) {
+            self.0 += 1;
+        }
+
+        fn verifies_udp_checksums(&self) -> bool {
+            false
+        }
+    }
+
+    struct Verified;
+    impl<'a> PacketVisitor<'a> for Verified {}
+
+    let mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let ip = Ipv4Address::new(192, 168, 1, 2);
+    let udp = udp::new_udp_packet(mac, mac, ip, ip, 1000, 7, &[1, 2, 3][..]);
+    let mut frame = HeapTxPacket::write_out(udp).unwrap().to_vec();
+    frame[42] ^= 0x01;
+
+    assert_eq!(parse_with(&frame, &mut Verified),
+               Err(ParseError::checksum(Layer::Udp, 40)));
+    let mut visitor = Unverified(0);
+    parse_with(&frame, &mut visitor).unwrap();
+    assert_eq!(visitor.0, 1);
+}
+
+#[test]
+fn udp_decoders() {
+    use ethernet::EthernetAddress;
+    use ipv4::Ipv4Address;
+    use testing::frames;
+    use HeapTxPacket;
+
+    struct Protocols<'a> {
+        decoders: Vec<UdpDecoder>,
+        payloads: Vec<(Layer, &'a [u8])>,
+    }
+
+    impl<'a> PacketVisitor<'a> for Protocols<'a> {
+        fn udp_decoders(&self) -> &[UdpDecoder] {
+            &self.decoders
+        }
+
+        fn on_udp_protocol(&mut self,
+                           _: &Ipv4Header,
+                           _: &UdpHeader,
+                           layer: Layer,
+                           payload: &'a [u8]) {
+            self.payloads.push((layer, payload));
+        }
+    }
+
+    // TFTP, with the opcodes of RFC 1350
+    fn parse_tftp(data: &[u8]) -> Result<(), ParseError> {
+        if data.len() < 2 {
+            Err(ParseError::truncated(Layer::Other, data.len()))
+        } else if data[0] != 0 || data[1] == 0 || data[1] > 5 {
+            Err(ParseError::malformed(Layer::Other, 0, "invalid opcode"))
+        } else {
+            Ok(())
+        }
+    }
+
+    let mut decoders = udp::DECODERS.to_vec();
+    decoders.push(UdpDecoder {
+                      port: 69,
+                      layer: Layer::Other,
+                      parse: parse_tftp,
+                  });
+    let mut visitor = Protocols {
+        decoders,
+        payloads: Vec::new(),
+    };
+    let mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let ip = Ipv4Address::new(192, 168, 1, 2);
+    let tftp = |request: &'static [u8]| {
+        let frame = udp::new_udp_packet(mac, mac, ip, ip, 1000, 69, request);
+        HeapTxPacket::write_out(frame).unwrap().to_vec()
+    };
+
+    let dns_reply = hex!(frames::DNS_REPLY);
+    let read_request = tftp(b"\x00\x01boot.bin\x00octet\x00");
+    parse_with(&dns_reply, &mut visitor).unwrap();
+    parse_with(&read_request, &mut visitor).unwrap();
+    assert_eq!(visitor.payloads.len(), 2);
+    assert_eq!(visitor.payloads[0], (Layer::Dns, &dns_reply[42..]));
+    assert_eq!(visitor.payloads[1], (Layer::Other, &read_request[42..]));
+
+    let error = parse_with(&tftp(b"\x00\x09"), &mut visitor).unwrap_err();
+    assert_eq!((error.layer(), error.offset()), (Layer::Other, 42));
+
+    // without decoders, e.g. by default, any payload is accepted
+    struct Plain;
+    impl<'a> PacketVisitor<'a> for Plain {}
+    let frame = udp::new_udp_packet(mac, mac, ip, ip, 123, 1000, &b"junk"[..]);
+    let frame = HeapTxPacket::write_out(frame).unwrap().to_vec();
+    parse_with(&frame, &mut Plain).unwrap();
+    let mut visitor = Protocols {
+        decoders: udp::DECODERS.to_vec(),
+        payloads: Vec::new(),
+    };
+    assert_eq!(parse_with(&frame, &mut visitor).unwrap_err().layer(), Layer::Ntp);
+}
+
+#[test]
+fn truncated_input() {
+    use testing::frames;
+
+    for frame in &[frames::DHCP_OFFER, frames::DNS_REPLY, frames::TCP_SYN] {
+        let frame = hex!(frame);
+        for len in 0..frame.len() {
+            parse_any(&frame[..len]);
+            parse_any(&frame[14..len.max(14)]);
+            parse_any(&frame[34..len.max(34)]);
+        }
+    }
+    assert_eq!(ArpPacket::parse(&[0, 1, 8, 0, 6, 4, 0, 1]).unwrap_err(),
+               ParseError::truncated(Layer::Arp, 8));
+}
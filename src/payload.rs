@@ -0,0 +1,100 @@
+//! `WriteOut` impls for raw byte payloads.
+//!
+//! Byte slices, `Vec<u8>`, `Box<[u8]>` and `Cow<[u8]>` are written as they are, and
+//! `Slices` joins several of them, e.g. a header kept by the application and a data
+//! buffer. References to any `WriteOut` are `WriteOut` too, so packets can borrow
+//! their payload. Fixed arrays and `heapless::Vec`s are written as slices, e.g.
+//! `&buffer[..]`.
+
+use {TxPacket, WriteOut};
+#[cfg(any(test, feature = "alloc"))]
+use alloc::borrow::Cow;
+#[cfg(any(test, feature = "alloc"))]
+use alloc::boxed::Box;
+#[cfg(any(test, feature = "alloc"))]
+use alloc::Vec;
+
+impl WriteOut for [u8] {
+    fn len(&self) -> usize {
+        <[u8]>::len(self)
+    }
+
+    fn write_out<P: TxPacket>(&self, packet: &mut P) -> Result<(), ()> {
+        packet.push_bytes(self).map(|_| ())
+    }
+}
+
+#[cfg(any(test, feature = "alloc"))]
+impl WriteOut for Vec<u8> {
+    fn len(&self) -> usize {
+        <[u8]>::len(self)
+    }
+
+    fn write_out<P: TxPacket>(&self, packet: &mut P) -> Result<(), ()> {
+        packet.push_bytes(self).map(|_| ())
+    }
+}
+
+#[cfg(any(test, feature = "alloc"))]
+impl WriteOut for Box<[u8]> {
+    fn len(&self) -> usize {
+        <[u8]>::len(self)
+    }
+
+    fn write_out<P: TxPacket>(&self, packet: &mut P) -> Result<(), ()> {
+        packet.push_bytes(self).map(|_| ())
+    }
+}
+
+#[cfg(any(test, feature = "alloc"))]
+impl<'a> WriteOut for Cow<'a, [u8]> {
+    fn len(&self) -> usize {
+        <[u8]>::len(self)
+    }
+
+    fn write_out<P: TxPacket>(&self, packet: &mut P) -> Result<(), ()> {
+        packet.push_bytes(self).map(|_| ())
+    }
+}
+
+/// The concatenation of the byte slices returned by an iterator. The iterator is
+/// cloned for each pass over the data, so it should be cheap to clone, like
+/// `slice::Iter` or `iter::Chain` of them.
+#[derive(Debug, Clone, Copy)]
+pub struct Slices<I>(pub I);
+
+impl<'a, I> WriteOut for Slices<I>
+    where I: Iterator<Item = &'a [u8]> + Clone
+{
+    fn len(&self) -> usize {
+        self.0.clone().map(|slice| slice.len()).sum()
+    }
+
+    fn write_out<P: TxPacket>(&self, packet: &mut P) -> Result<(), ()> {
+        for slice in self.0.clone() {
+            packet.push_bytes(slice)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn byte_sources() {
+    use udp::UdpPacket;
+    use HeapTxPacket;
+
+    fn serialize<T: WriteOut>(payload: T) -> Vec<u8> {
+        let udp = UdpPacket::new(1000, 2000, payload);
+        let mut packet = HeapTxPacket::new(udp.len());
+        udp.write_out(&mut packet).unwrap();
+        packet.as_slice().to_vec()
+    }
+
+    let reference = serialize(&b"hello world"[..]);
+    assert_eq!(serialize(b"hello world".to_vec()), reference);
+    assert_eq!(serialize(&b"hello world".to_vec()), reference);
+    assert_eq!(serialize(Cow::Borrowed(&b"hello world"[..])), reference);
+    let parts = [&b"hello"[..], b" ", b"world"];
+    assert_eq!(serialize(Slices(parts.iter().cloned())), reference);
+    assert_eq!(serialize(UdpPacket::new(1, 2, &b"ab"[..])).len(), 8 + 8 + 2);
+}
@@ -0,0 +1,99 @@
+//! Allocation of ephemeral source ports.
+//!
+//! The search starts at a random offset in the configured range, like algorithm 1
+//! of [RFC 6056][rfc6056], and skips the most recently allocated ports, so that a
+//! reconnecting client doesn't reuse a port that the peer may still associate with
+//! the old connection.
+//!
+//! [rfc6056]: https://tools.ietf.org/html/rfc6056
+
+/// The first port of the dynamic range assigned by IANA.
+pub const DEFAULT_FIRST_PORT: u16 = 49152;
+
+pub const DEFAULT_LAST_PORT: u16 = 65535;
+
+/// The number of recently allocated ports that are avoided.
+pub const RECENT_PORTS: usize = 8;
+
+#[derive(Debug, Clone)]
+pub struct PortAllocator {
+    first: u16,
+    last: u16,
+    random: u32,
+    recent: [u16; RECENT_PORTS],
+    next_recent: usize,
+}
+
+impl PortAllocator {
+    /// Creates an allocator for the IANA dynamic range. `seed` should differ between
+    /// devices and reboots.
+    pub fn new(seed: u32) -> PortAllocator {
+        PortAllocator {
+            first: DEFAULT_FIRST_PORT,
+            last: DEFAULT_LAST_PORT,
+            random: seed | 1,
+            recent: [0; RECENT_PORTS],
+            next_recent: 0,
+        }
+    }
+
+    /// Restricts the allocated ports to `first` through `last`, both inclusive. Fails
+    /// if the range is empty or includes port 0.
+    pub fn set_range(&mut self, first: u16, last: u16) -> Result<(), ()> {
+        if first == 0 || first > last {
+            return Err(());
+        }
+        self.first = first;
+        self.last = last;
+        Ok(())
+    }
+
+    pub fn range(&self) -> (u16, u16) {
+        (self.first, self.last)
+    }
+
+    fn next_random(&mut self) -> u32 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 17;
+        self.random ^= self.random << 5;
+        self.random
+    }
+
+    /// Returns a port for which `in_use` returns false. Recently allocated ports are
+    /// only returned if all others are in use.
+    pub fn allocate<F>(&mut self, in_use: F) -> Option<u16>
+        where F: Fn(u16) -> bool
+    {
+        let count = u32::from(self.last - self.first) + 1;
+        let offset = self.next_random() % count;
+        let candidates = || {
+            (0..count).map(|i| self.first + ((offset + i) % count) as u16)
+        };
+        let port = candidates()
+            .find(|&port| !in_use(port) && !self.recent.contains(&port))
+            .or_else(|| candidates().find(|&port| !in_use(port)));
+        if let Some(port) = port {
+            self.recent[self.next_recent] = port;
+            self.next_recent = (self.next_recent + 1) % RECENT_PORTS;
+        }
+        port
+    }
+}
+
+#[test]
+fn avoids_used_and_recent_ports() {
+    let mut ports = PortAllocator::new(42);
+    assert!(ports.set_range(0, 10).is_err() && ports.set_range(50009, 50000).is_err());
+    ports.set_range(50000, 50009).unwrap();
+    let mut allocated = [0; 10];
+    for i in 0..10 {
+        let port = ports.allocate(|port| allocated.contains(&port)).unwrap();
+        assert!(port >= 50000 && port <= 50009);
+        allocated[i] = port;
+    }
+    assert_eq!(ports.allocate(|port| allocated.contains(&port)), None);
+
+    // the last eight ports are released, but the two older ones are reused first
+    let port = ports.allocate(|_| false).unwrap();
+    assert!(allocated[..2].contains(&port));
+}
@@ -0,0 +1,334 @@
+//! Event and general messages of the Precision Time Protocol (IEEE 1588-2008), for
+//! the delay request-response mechanism of ordinary clocks.
+//!
+//! Messages are sent over UDP, Sync and Delay_Req to `EVENT_PORT` and Follow_Up and
+//! Delay_Resp to `GENERAL_PORT`, or directly over Ethernet with `EtherType::Ptp`.
+//!
+//! For hardware timestamping, the driver inserts the transmit time into the
+//! serialized message with `insert_timestamp` or `add_correction`. Over UDP, such
+//! messages must be sent without UDP checksum, since the checksum wouldn't cover the
+//! inserted bytes.
+
+use {TxPacket, WriteOut};
+use byteorder::{ByteOrder, NetworkEndian};
+use ethernet::EthernetAddress;
+use ipv4::Ipv4Address;
+use parse::{Layer, Parse, ParseError};
+
+pub const EVENT_PORT: u16 = 319;
+pub const GENERAL_PORT: u16 = 320;
+
+/// The multicast group of all PTP messages except peer delay messages.
+pub const PRIMARY_MULTICAST_ADDRESS: Ipv4Address = Ipv4Address::new(224, 0, 1, 129);
+
+/// The Ethernet destination of PTP messages sent without UDP.
+pub const PRIMARY_MULTICAST_MAC: EthernetAddress =
+    EthernetAddress::new([0x01, 0x1b, 0x19, 0x00, 0x00, 0x00]);
+
+/// The offset of the timestamp in Sync, Delay_Req, Follow_Up, and Delay_Resp
+/// messages.
+pub const TIMESTAMP_OFFSET: usize = 34;
+
+const CORRECTION_OFFSET: usize = 8;
+const HEADER_LEN: usize = 34;
+const PTP_VERSION: u8 = 2;
+/// The flag of Sync messages whose timestamp follows in a Follow_Up.
+pub const FLAG_TWO_STEP: u16 = 0x0200;
+
+/// Seconds and nanoseconds since the PTP epoch. The seconds are 48 bits wide.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct PtpTimestamp {
+    pub seconds: u64,
+    pub nanoseconds: u32,
+}
+
+impl PtpTimestamp {
+    pub fn from_nanos(nanos: u64) -> Self {
+        PtpTimestamp {
+            seconds: nanos / 1_000_000_000,
+            nanoseconds: (nanos % 1_000_000_000) as u32,
+        }
+    }
+
+    pub fn as_nanos(&self) -> u64 {
+        self.seconds * 1_000_000_000 + u64::from(self.nanoseconds)
+    }
+
+    fn to_bytes(&self) -> [u8; 10] {
+        let mut bytes = [0; 10];
+        NetworkEndian::write_u16(&mut bytes[0..2], (self.seconds >> 32) as u16);
+        NetworkEndian::write_u32(&mut bytes[2..6], self.seconds as u32);
+        NetworkEndian::write_u32(&mut bytes[6..10], self.nanoseconds);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        PtpTimestamp {
+            seconds: u64::from(NetworkEndian::read_u16(&bytes[0..2])) << 32 |
+                     u64::from(NetworkEndian::read_u32(&bytes[2..6])),
+            nanoseconds: NetworkEndian::read_u32(&bytes[6..10]),
+        }
+    }
+}
+
+/// Computes the mean path delay and the offset of the local clock from the master
+/// in nanoseconds.
+///
+/// `t1` is the origin time of the Sync, `t2` its local receive time, `t3` the local
+/// transmit time of the Delay_Req, and `t4` its receive time from the Delay_Resp.
+pub fn delay_and_offset(t1: PtpTimestamp,
+                        t2: PtpTimestamp,
+                        t3: PtpTimestamp,
+                        t4: PtpTimestamp)
+                        -> (i64, i64) {
+    let master_to_slave = t2.as_nanos() as i64 - t1.as_nanos() as i64;
+    let slave_to_master = t4.as_nanos() as i64 - t3.as_nanos() as i64;
+    let delay = (master_to_slave + slave_to_master) / 2;
+    (delay, master_to_slave - delay)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortIdentity {
+    pub clock_identity: [u8; 8],
+    pub port_number: u16,
+}
+
+impl PortIdentity {
+    /// The identity of port 1 of an ordinary clock, derived from its MAC address.
+    pub fn from_mac(mac: EthernetAddress) -> Self {
+        let mac = mac.as_bytes();
+        PortIdentity {
+            clock_identity: [mac[0], mac[1], mac[2], 0xff, 0xfe, mac[3], mac[4], mac[5]],
+            port_number: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PtpBody {
+    Sync { origin_timestamp: PtpTimestamp },
+    DelayReq { origin_timestamp: PtpTimestamp },
+    FollowUp { precise_origin_timestamp: PtpTimestamp },
+    DelayResp {
+        receive_timestamp: PtpTimestamp,
+        requesting_port: PortIdentity,
+    },
+}
+
+impl PtpBody {
+    fn message_type(&self) -> u8 {
+        match *self {
+            PtpBody::Sync { .. } => 0x0,
+            PtpBody::DelayReq { .. } => 0x1,
+            PtpBody::FollowUp { .. } => 0x8,
+            PtpBody::DelayResp { .. } => 0x9,
+        }
+    }
+
+    /// The obsolete control field of PTPv1, which is still sent.
+    fn control(&self) -> u8 {
+        match *self {
+            PtpBody::Sync { .. } => 0,
+            PtpBody::DelayReq { .. } => 1,
+            PtpBody::FollowUp { .. } => 2,
+            PtpBody::DelayResp { .. } => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PtpMessage {
+    pub domain: u8,
+    pub flags: u16,
+    /// Nanoseconds multiplied by 2^16.
+    pub correction: i64,
+    pub source_port: PortIdentity,
+    pub sequence_id: u16,
+    pub log_message_interval: i8,
+    pub body: PtpBody,
+}
+
+impl PtpMessage {
+    pub fn new(source_port: PortIdentity, sequence_id: u16, body: PtpBody) -> Self {
+        let log_message_interval = match body {
+            PtpBody::DelayReq { .. } | PtpBody::DelayResp { .. } => 0x7f,
+            _ => 0,
+        };
+        PtpMessage {
+            domain: 0,
+            flags: 0,
+            correction: 0,
+            source_port,
+            sequence_id,
+            log_message_interval,
+            body,
+        }
+    }
+
+    /// A two-step Sync, whose precise timestamp is sent in a Follow_Up.
+    pub fn two_step_sync(source_port: PortIdentity, sequence_id: u16) -> Self {
+        let sync = PtpBody::Sync { origin_timestamp: PtpTimestamp::default() };
+        let mut message = PtpMessage::new(source_port, sequence_id, sync);
+        message.flags |= FLAG_TWO_STEP;
+        message
+    }
+
+    /// The UDP port the message is sent to.
+    pub fn port(&self) -> u16 {
+        match self.body {
+            PtpBody::Sync { .. } | PtpBody::DelayReq { .. } => EVENT_PORT,
+            _ => GENERAL_PORT,
+        }
+    }
+}
+
+impl WriteOut for PtpMessage {
+    fn len(&self) -> usize {
+        match self.body {
+            PtpBody::DelayResp { .. } => HEADER_LEN + 20,
+            _ => HEADER_LEN + 10,
+        }
+    }
+
+    fn write_out<P: TxPacket>(&self, packet: &mut P) -> Result<(), ()> {
+        let mut header = [0; HEADER_LEN];
+        header[0] = self.body.message_type();
+        header[1] = PTP_VERSION;
+        NetworkEndian::write_u16(&mut header[2..4], self.len() as u16);
+        header[4] = self.domain;
+        NetworkEndian::write_u16(&mut header[6..8], self.flags);
+        NetworkEndian::write_i64(&mut header[8..16], self.correction);
+        header[20..28].copy_from_slice(&self.source_port.clock_identity);
+        NetworkEndian::write_u16(&mut header[28..30], self.source_port.port_number);
+        NetworkEndian::write_u16(&mut header[30..32], self.sequence_id);
+        header[32] = self.body.control();
+        header[33] = self.log_message_interval as u8;
+        packet.push_bytes(&header)?;
+
+        match self.body {
+            PtpBody::Sync { origin_timestamp: timestamp } |
+            PtpBody::DelayReq { origin_timestamp: timestamp } |
+            PtpBody::FollowUp { precise_origin_timestamp: timestamp } => {
+                packet.push_bytes(&timestamp.to_bytes())?;
+            }
+            PtpBody::DelayResp { receive_timestamp, requesting_port } => {
+                packet.push_bytes(&receive_timestamp.to_bytes())?;
+                packet.push_bytes(&requesting_port.clock_identity)?;
+                packet.push_u16(requesting_port.port_number)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Parse<'a> for PtpMessage {
+    fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
+        if data.len() < HEADER_LEN {
+            return Err(ParseError::truncated(Layer::Ptp, data.len()));
+        }
+        if data[1] & 0xf != PTP_VERSION {
+            return Err(ParseError::unimplemented(Layer::Ptp, 1, "only PTPv2 is supported"));
+        }
+        let len = usize::from(NetworkEndian::read_u16(&data[2..4]));
+        if len < HEADER_LEN + 10 {
+            return Err(ParseError::malformed(Layer::Ptp, 2, "invalid message length"));
+        }
+        if data.len() < len {
+            return Err(ParseError::truncated(Layer::Ptp, data.len()));
+        }
+
+        let timestamp = PtpTimestamp::from_bytes(&data[HEADER_LEN..HEADER_LEN + 10]);
+        let body = match data[0] & 0xf {
+            0x0 => PtpBody::Sync { origin_timestamp: timestamp },
+            0x1 => PtpBody::DelayReq { origin_timestamp: timestamp },
+            0x8 => PtpBody::FollowUp { precise_origin_timestamp: timestamp },
+            0x9 if len >= HEADER_LEN + 20 => {
+                PtpBody::DelayResp {
+                    receive_timestamp: timestamp,
+                    requesting_port: port_identity(&data[HEADER_LEN + 10..HEADER_LEN + 20]),
+                }
+            }
+            0x9 => return Err(ParseError::malformed(Layer::Ptp, 2, "invalid message length")),
+            _ => return Err(ParseError::unimplemented(Layer::Ptp, 0, "unsupported message type")),
+        };
+
+        Ok(PtpMessage {
+               domain: data[4],
+               flags: NetworkEndian::read_u16(&data[6..8]),
+               correction: NetworkEndian::read_i64(&data[8..16]),
+               source_port: port_identity(&data[20..30]),
+               sequence_id: NetworkEndian::read_u16(&data[30..32]),
+               log_message_interval: data[33] as i8,
+               body,
+           })
+    }
+}
+
+fn port_identity(data: &[u8]) -> PortIdentity {
+    let mut clock_identity = [0; 8];
+    clock_identity.copy_from_slice(&data[..8]);
+    PortIdentity {
+        clock_identity,
+        port_number: NetworkEndian::read_u16(&data[8..10]),
+    }
+}
+
+/// Overwrites the timestamp of a serialized message that starts at `message_index`,
+/// e.g. with the hardware transmit time of a one-step Sync.
+pub fn insert_timestamp<P: TxPacket>(packet: &mut P,
+                                     message_index: usize,
+                                     timestamp: PtpTimestamp) {
+    packet.set_bytes(message_index + TIMESTAMP_OFFSET, &timestamp.to_bytes());
+}
+
+/// Adds `nanoseconds` to the correction field of a serialized message that starts
+/// at `message_index`, e.g. the residence time in a transparent clock.
+pub fn add_correction<P: TxPacket>(packet: &mut P, message_index: usize, nanoseconds: i64) {
+    let index = message_index + CORRECTION_OFFSET;
+    let correction = NetworkEndian::read_i64(packet.get_bytes(index, 8));
+    let mut bytes = [0; 8];
+    NetworkEndian::write_i64(&mut bytes, correction.wrapping_add(nanoseconds << 16));
+    packet.set_bytes(index, &bytes);
+}
+
+#[test]
+fn delay_request_response() {
+    use ethernet::EthernetPacket;
+    use HeapTxPacket;
+
+    let master_mac = EthernetAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    let master = PortIdentity::from_mac(master_mac);
+    let slave = PortIdentity::from_mac(EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]));
+    let t1 = PtpTimestamp::from_nanos(1_000_000_000_000);
+
+    // one-step Sync with the timestamp inserted by the driver
+    let sync = PtpMessage::new(master, 7, PtpBody::Sync { origin_timestamp: Default::default() });
+    let frame = EthernetPacket::new_ptp(master_mac, PRIMARY_MULTICAST_MAC, sync);
+    let mut packet = HeapTxPacket::new(frame.len());
+    frame.write_out(&mut packet).unwrap();
+    let message_index = packet.payload_range().unwrap().start;
+    assert_eq!(message_index, 14);
+    insert_timestamp(&mut packet, message_index, t1);
+    add_correction(&mut packet, message_index, 5);
+    let parsed = PtpMessage::parse(&packet.as_slice()[14..]).unwrap();
+    assert_eq!(parsed.body, PtpBody::Sync { origin_timestamp: t1 });
+    assert_eq!((parsed.correction, parsed.sequence_id, parsed.source_port), (5 << 16, 7, master));
+    assert_eq!(NetworkEndian::read_u16(&packet.as_slice()[12..14]), 0x88f7);
+
+    let t4 = PtpTimestamp::from_nanos(1_000_000_900_000);
+    let response = PtpMessage::new(master,
+                                   3,
+                                   PtpBody::DelayResp {
+                                       receive_timestamp: t4,
+                                       requesting_port: slave,
+                                   });
+    assert_eq!(response.port(), GENERAL_PORT);
+    let mut packet = HeapTxPacket::new(response.len());
+    response.write_out(&mut packet).unwrap();
+    assert_eq!(PtpMessage::parse(packet.as_slice()), Ok(response));
+
+    // the slave clock is 100 us ahead, the path delay is 400 us
+    let t2 = PtpTimestamp::from_nanos(1_000_000_500_000);
+    let t3 = PtpTimestamp::from_nanos(1_000_000_600_000);
+    assert_eq!(delay_and_offset(t1, t2, t3, t4), (400_000, 100_000));
+}
@@ -0,0 +1,91 @@
+//! Retry and backoff policies for the timers of ARP resolution, the DNS resolver, and
+//! TCP retransmissions.
+//!
+//! Each of them starts with a default policy that can be replaced, e.g. with fewer
+//! and slower retries on a battery-powered device.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backoff {
+    /// The same delay before every retry.
+    Constant,
+    /// The delay doubles with every retry.
+    Exponential,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Policy {
+    /// The delay before the first retry in milliseconds.
+    pub initial_delay: u64,
+    pub backoff: Backoff,
+    /// The upper bound of the delay in milliseconds.
+    pub max_delay: u64,
+    /// The number of retries after the first attempt, before giving up.
+    pub max_retries: u8,
+    /// Randomizes every delay by up to this percentage in either direction, so that
+    /// devices that failed at the same time don't retry in lockstep.
+    pub jitter_percent: u8,
+}
+
+impl Policy {
+    pub fn constant(delay: u64, max_retries: u8) -> Policy {
+        Policy {
+            initial_delay: delay,
+            backoff: Backoff::Constant,
+            max_delay: delay,
+            max_retries,
+            jitter_percent: 0,
+        }
+    }
+
+    pub fn exponential(initial_delay: u64, max_delay: u64, max_retries: u8) -> Policy {
+        Policy {
+            initial_delay,
+            backoff: Backoff::Exponential,
+            max_delay,
+            max_retries,
+            jitter_percent: 0,
+        }
+    }
+
+    pub fn with_jitter(self, percent: u8) -> Policy {
+        Policy { jitter_percent: u8::min(percent, 100), ..self }
+    }
+
+    /// The delay that follows `delay`.
+    pub fn next_delay(&self, delay: u64) -> u64 {
+        match self.backoff {
+            Backoff::Constant => delay,
+            Backoff::Exponential => u64::min(delay.saturating_mul(2), self.max_delay),
+        }
+    }
+
+    /// The delay before retry number `retry`, starting at 0, without jitter.
+    pub fn delay(&self, retry: u8) -> u64 {
+        (0..retry).fold(self.initial_delay, |delay, _| self.next_delay(delay))
+    }
+
+    /// Randomizes `delay` according to `jitter_percent` with a random number.
+    pub fn jitter(&self, delay: u64, random: u32) -> u64 {
+        let range = delay * u64::from(self.jitter_percent) / 100;
+        delay - range + u64::from(random) % (2 * range + 1)
+    }
+
+    /// Returns true if `retries` retries were made, so the next timeout gives up.
+    pub fn exhausted(&self, retries: u8) -> bool {
+        retries >= self.max_retries
+    }
+}
+
+#[test]
+fn backoff() {
+    let policy = Policy::exponential(1000, 5000, 4);
+    let delays: Vec<u64> = (0..5).map(|retry| policy.delay(retry)).collect();
+    assert_eq!(delays, [1000, 2000, 4000, 5000, 5000]);
+    assert!(!policy.exhausted(3) && policy.exhausted(4));
+    assert_eq!(Policy::constant(500, 2).delay(2), 500);
+
+    let policy = policy.with_jitter(10);
+    assert_eq!(policy.jitter(1000, 0), 900);
+    assert_eq!(policy.jitter(1000, 200), 1100);
+    assert_eq!(policy.jitter(1000, 201), 900);
+}
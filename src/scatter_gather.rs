@@ -0,0 +1,146 @@
+use core::ops::Range;
+use {TxPacket, WriteOut};
+use ip_checksum;
+
+/// A payload that stays in an external buffer, e.g. a DMA buffer of the application.
+///
+/// Writing it only reserves its length and folds its checksum into the enclosing
+/// transport checksums, so it must be the last part of a packet. It can only be
+/// written to packets that support `push_external`, such as `ScatterGatherTxPacket`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExternalPayload {
+    pub len: usize,
+    /// The RFC 1071 checksum of the payload, without the final complement.
+    pub checksum: u16,
+}
+
+impl ExternalPayload {
+    pub fn new(data: &[u8]) -> Self {
+        ExternalPayload {
+            len: data.len(),
+            checksum: ip_checksum::data(data),
+        }
+    }
+}
+
+impl WriteOut for ExternalPayload {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn write_out<P: TxPacket>(&self, packet: &mut P) -> Result<(), ()> {
+        packet.push_external(self.len, self.checksum).map(|_| ())
+    }
+}
+
+/// A `TxPacket` that writes the headers to `inner` and only records the position of
+/// an `ExternalPayload`.
+///
+/// A driver with scatter-gather DMA transmits `headers()` followed by the external
+/// buffer, without copying the payload.
+pub struct ScatterGatherTxPacket<P: TxPacket> {
+    inner: P,
+    external: Option<ExternalPayload>,
+    payload_range: Option<Range<usize>>,
+}
+
+impl<P: TxPacket> ScatterGatherTxPacket<P> {
+    pub fn new(inner: P) -> Self {
+        ScatterGatherTxPacket {
+            inner,
+            external: None,
+            payload_range: None,
+        }
+    }
+
+    pub fn headers(&self) -> &P {
+        &self.inner
+    }
+
+    pub fn external_payload(&self) -> Option<ExternalPayload> {
+        self.external
+    }
+
+    /// The range of the payload of the innermost layer, which may cover the external
+    /// payload.
+    pub fn payload_range(&self) -> Option<Range<usize>> {
+        self.payload_range.clone()
+    }
+
+    pub fn into_parts(self) -> (P, Option<ExternalPayload>) {
+        (self.inner, self.external)
+    }
+}
+
+impl<P: TxPacket> TxPacket for ScatterGatherTxPacket<P> {
+    fn push_bytes(&mut self, bytes: &[u8]) -> Result<usize, ()> {
+        if self.external.is_some() {
+            return Err(()); // nothing can follow the external payload
+        }
+        self.inner.push_bytes(bytes)
+    }
+
+    fn len(&self) -> usize {
+        self.inner.len() + self.external.map(|e| e.len).unwrap_or(0)
+    }
+
+    fn push_external(&mut self, len: usize, checksum: u16) -> Result<usize, ()> {
+        if self.external.is_some() {
+            return Err(());
+        }
+        self.external = Some(ExternalPayload { len, checksum });
+        Ok(self.inner.len())
+    }
+
+    fn get_bytes(&mut self, index: usize, len: usize) -> &[u8] {
+        self.inner.get_bytes(index, len)
+    }
+
+    fn set_bytes(&mut self, index: usize, bytes: &[u8]) {
+        self.inner.set_bytes(index, bytes)
+    }
+
+    fn mark_payload(&mut self, range: Range<usize>) {
+        if self.payload_range.is_none() {
+            self.payload_range = Some(range.clone());
+        }
+        // `inner` only knows payloads that were copied into it
+        if range.end <= self.inner.len() {
+            self.inner.mark_payload(range);
+        }
+    }
+}
+
+#[test]
+fn udp_with_external_payload() {
+    use ethernet::EthernetAddress;
+    use ipv4::Ipv4Address;
+    use udp;
+    use HeapTxPacket;
+
+    let mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let ip = Ipv4Address::new(192, 168, 1, 2);
+    let payload = [0x42; 101];
+
+    let copied = udp::new_udp_packet(mac, mac, ip, ip, 1000, 7, &payload[..]);
+    let mut reference = HeapTxPacket::new(copied.len());
+    copied.write_out(&mut reference).unwrap();
+
+    let external = udp::new_udp_packet(mac, mac, ip, ip, 1000, 7, ExternalPayload::new(&payload));
+    let mut packet = ScatterGatherTxPacket::new(HeapTxPacket::new(64));
+    external.write_out(&mut packet).unwrap();
+    assert_eq!(packet.len(), reference.len());
+    assert_eq!(packet.payload_range(), reference.payload_range());
+    assert_eq!(packet.headers().payload_range(), None);
+
+    let (headers, external_payload) = packet.into_parts();
+    assert_eq!(external_payload.unwrap().len, payload.len());
+    assert_eq!(headers.as_slice(), &reference.as_slice()[..headers.len()]);
+
+    let mut packet = ScatterGatherTxPacket::new(HeapTxPacket::new(200));
+    copied.write_out(&mut packet).unwrap();
+    assert_eq!(packet.headers().payload_range(), reference.payload_range());
+
+    // plain packets can't refer to external data
+    assert!(external.write_out(&mut HeapTxPacket::new(200)).is_err());
+}
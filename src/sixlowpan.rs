@@ -0,0 +1,664 @@
+//! The 6LoWPAN adaptation layer for IPv6 over IEEE 802.15.4 frames: IPHC header
+//! compression ([RFC 6282][rfc6282]) and fragmentation ([RFC 4944][rfc4944]).
+//!
+//! The crate doesn't build IPv6 packets itself, so the functions work on serialized
+//! packets. `IphcPacket` compresses a packet for a frame between two link-layer
+//! addresses and `decompress` restores it. Compressed packets that don't fit into a
+//! frame are split by `fragments` and put back together by a `Reassembler`.
+//!
+//! Only stateless compression is implemented: addresses are compressed if they are
+//! link-local or multicast, or derived from the link-layer addresses, and the next
+//! header is always carried inline.
+//!
+//! [rfc6282]: https://tools.ietf.org/html/rfc6282
+//! [rfc4944]: https://tools.ietf.org/html/rfc4944
+
+#[cfg(any(test, feature = "alloc"))]
+use alloc::Vec;
+use byteorder::{ByteOrder, NetworkEndian};
+use parse::{Layer, Parse, ParseError};
+use {TxPacket, WriteOut};
+
+/// The dispatch byte of uncompressed IPv6 packets.
+pub const DISPATCH_IPV6: u8 = 0x41;
+
+/// The length of the uncompressed IPv6 header.
+const IPV6_HEADER_LEN: usize = 40;
+
+/// The time in milliseconds after which incomplete datagrams are dropped.
+pub const REASSEMBLY_TIMEOUT: u64 = 60_000;
+
+/// The number of datagrams that are reassembled at the same time.
+pub const MAX_REASSEMBLIES: usize = 4;
+
+/// The smallest frame payload that `fragments` accepts: a subsequent fragment header
+/// and 8 bytes of data.
+pub const MIN_FRAGMENT_LEN: usize = 13;
+
+/// An IEEE 802.15.4 address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkAddress {
+    Short(u16),
+    /// An EUI-64.
+    Extended([u8; 8]),
+}
+
+impl LinkAddress {
+    /// The IPv6 interface identifier derived from the address (RFC 4944, section 6).
+    pub fn interface_id(&self) -> [u8; 8] {
+        match *self {
+            LinkAddress::Short(address) => {
+                [0, 0, 0, 0xff, 0xfe, 0, (address >> 8) as u8, address as u8]
+            }
+            LinkAddress::Extended(mut eui64) => {
+                eui64[0] ^= 0x02; // universal/local bit
+                eui64
+            }
+        }
+    }
+}
+
+const LINK_LOCAL_PREFIX: [u8; 8] = [0xfe, 0x80, 0, 0, 0, 0, 0, 0];
+
+/// Compresses an address into `inline` and returns the address mode and the number
+/// of inline bytes.
+fn compress_address(address: &[u8], link: LinkAddress, inline: &mut [u8]) -> (u8, usize) {
+    if address[0] == 0xff {
+        // multicast, the M bit is set by the caller
+        if address[1] == 0x02 && address[2..15].iter().all(|&b| b == 0) {
+            inline[0] = address[15];
+            (3, 1)
+        } else if address[2..13].iter().all(|&b| b == 0) {
+            inline[0] = address[1];
+            inline[1..4].copy_from_slice(&address[13..16]);
+            (2, 4)
+        } else if address[2..11].iter().all(|&b| b == 0) {
+            inline[0] = address[1];
+            inline[1..6].copy_from_slice(&address[11..16]);
+            (1, 6)
+        } else {
+            inline[..16].copy_from_slice(address);
+            (0, 16)
+        }
+    } else if address[..8] != LINK_LOCAL_PREFIX {
+        inline[..16].copy_from_slice(address);
+        (0, 16)
+    } else if address[8..] == link.interface_id() {
+        (3, 0)
+    } else if address[8..14] == [0, 0, 0, 0xff, 0xfe, 0] {
+        inline[..2].copy_from_slice(&address[14..16]);
+        (2, 2)
+    } else {
+        inline[..8].copy_from_slice(&address[8..16]);
+        (1, 8)
+    }
+}
+
+/// An IPv6 packet that is written with a compressed IPHC header.
+#[derive(Debug, Clone, Copy)]
+pub struct IphcPacket<'a> {
+    header: [u8; IPV6_HEADER_LEN],
+    header_len: usize,
+    payload: &'a [u8],
+}
+
+impl<'a> IphcPacket<'a> {
+    /// Compresses the header of `ipv6`, a serialized IPv6 packet, for a frame from
+    /// `src` to `dst`. Fails if it isn't a complete IPv6 packet.
+    pub fn new(ipv6: &'a [u8], src: LinkAddress, dst: LinkAddress) -> Result<Self, ()> {
+        if ipv6.len() < IPV6_HEADER_LEN || ipv6[0] >> 4 != 6 {
+            return Err(());
+        }
+        let payload_len = usize::from(NetworkEndian::read_u16(&ipv6[4..6]));
+        if IPV6_HEADER_LEN + payload_len > ipv6.len() {
+            return Err(());
+        }
+
+        let mut header = [0; IPV6_HEADER_LEN];
+        let mut len = 2;
+        let traffic_class = (ipv6[0] & 0x0f) << 4 | ipv6[1] >> 4;
+        let flow_label = NetworkEndian::read_u32(&ipv6[0..4]) & 0x000f_ffff;
+        // inline, the ECN bits come before the DSCP
+        let (ecn, dscp) = (traffic_class & 0x03, traffic_class >> 2);
+        let tf = if traffic_class == 0 && flow_label == 0 {
+            3
+        } else if flow_label == 0 {
+            header[len] = ecn << 6 | dscp;
+            len += 1;
+            2
+        } else if dscp == 0 {
+            NetworkEndian::write_u32(&mut header[len..len + 4],
+                                     u32::from(ecn) << 30 | flow_label << 8);
+            len += 3;
+            1
+        } else {
+            header[len] = ecn << 6 | dscp;
+            NetworkEndian::write_u32(&mut header[len + 1..len + 5], flow_label << 8);
+            len += 4;
+            0
+        };
+        header[len] = ipv6[6]; // next header
+        len += 1;
+        let hlim = match ipv6[7] {
+            1 => 1,
+            64 => 2,
+            255 => 3,
+            hop_limit => {
+                header[len] = hop_limit;
+                len += 1;
+                0
+            }
+        };
+        let (sam, sam_len) = compress_address(&ipv6[8..24], src, &mut header[len..]);
+        len += sam_len;
+        let (dam, dam_len) = compress_address(&ipv6[24..40], dst, &mut header[len..]);
+        len += dam_len;
+        let multicast = if ipv6[24] == 0xff { 1 } else { 0 };
+
+        header[0] = 0b011 << 5 | tf << 3 | hlim;
+        header[1] = sam << 4 | multicast << 3 | dam;
+        Ok(IphcPacket {
+               header,
+               header_len: len,
+               payload: &ipv6[IPV6_HEADER_LEN..IPV6_HEADER_LEN + payload_len],
+           })
+    }
+
+    /// The number of bytes that the compression saves.
+    pub fn saved_len(&self) -> usize {
+        IPV6_HEADER_LEN - self.header_len
+    }
+}
+
+impl<'a> WriteOut for IphcPacket<'a> {
+    fn len(&self) -> usize {
+        self.header_len + self.payload.len()
+    }
+
+    fn write_out<T: TxPacket>(&self, packet: &mut T) -> Result<(), ()> {
+        packet.push_bytes(&self.header[..self.header_len])?;
+        packet.push_bytes(self.payload)?;
+        Ok(())
+    }
+}
+
+/// Takes `len` bytes from `data` at `offset`.
+fn take<'a>(data: &'a [u8], offset: &mut usize, len: usize) -> Result<&'a [u8], ParseError> {
+    if *offset + len > data.len() {
+        return Err(ParseError::truncated(Layer::SixLowpan, data.len()));
+    }
+    *offset += len;
+    Ok(&data[*offset - len..*offset])
+}
+
+fn decompress_address(mode: u8,
+                      multicast: bool,
+                      link: LinkAddress,
+                      data: &[u8],
+                      offset: &mut usize)
+                      -> Result<[u8; 16], ParseError> {
+    let mut address = [0; 16];
+    if multicast {
+        address[0] = 0xff;
+        match mode {
+            0 => address.copy_from_slice(take(data, offset, 16)?),
+            1 => {
+                let inline = take(data, offset, 6)?;
+                address[1] = inline[0];
+                address[11..].copy_from_slice(&inline[1..]);
+            }
+            2 => {
+                let inline = take(data, offset, 4)?;
+                address[1] = inline[0];
+                address[13..].copy_from_slice(&inline[1..]);
+            }
+            _ => {
+                address[1] = 0x02;
+                address[15] = take(data, offset, 1)?[0];
+            }
+        }
+        return Ok(address);
+    }
+    address[..8].copy_from_slice(&LINK_LOCAL_PREFIX);
+    match mode {
+        0 => address.copy_from_slice(take(data, offset, 16)?),
+        1 => address[8..].copy_from_slice(take(data, offset, 8)?),
+        2 => {
+            address[11..14].copy_from_slice(&[0xff, 0xfe, 0]);
+            address[14..].copy_from_slice(take(data, offset, 2)?);
+        }
+        _ => address[8..].copy_from_slice(&link.interface_id()),
+    }
+    Ok(address)
+}
+
+/// Restores the IPv6 packet of an IPHC-compressed `data` from a frame of `src` to
+/// `dst` into `out`, and returns its length.
+///
+/// The payload length is taken from the length of `data`.
+pub fn decompress(data: &[u8],
+                  src: LinkAddress,
+                  dst: LinkAddress,
+                  out: &mut [u8])
+                  -> Result<usize, ParseError> {
+    if data.len() < 2 {
+        return Err(ParseError::truncated(Layer::SixLowpan, data.len()));
+    }
+    if data[0] >> 5 != 0b011 {
+        return Err(ParseError::malformed(Layer::SixLowpan, 0, "not an IPHC header"));
+    }
+    if data[1] & 0x80 != 0 || data[1] & 0x40 != 0 || data[1] & 0x04 != 0 {
+        return Err(ParseError::unimplemented(Layer::SixLowpan, 1, "context-based compression"));
+    }
+    if data[0] & 0x04 != 0 {
+        return Err(ParseError::unimplemented(Layer::SixLowpan,
+                                             0,
+                                             "next header compression"));
+    }
+
+    let mut offset = 2;
+    let (traffic_class, flow_label) = match (data[0] >> 3) & 0x03 {
+        0 => {
+            let inline = take(data, &mut offset, 4)?;
+            let flow_label = NetworkEndian::read_u32(inline) & 0x000f_ffff;
+            (inline[0] << 2 | inline[0] >> 6, flow_label)
+        }
+        1 => {
+            let inline = take(data, &mut offset, 3)?;
+            let flow_label = (NetworkEndian::read_u32(&[inline[0], inline[1], inline[2], 0]) >>
+                              8) & 0x000f_ffff;
+            (inline[0] >> 6, flow_label)
+        }
+        2 => {
+            let inline = take(data, &mut offset, 1)?[0];
+            (inline << 2 | inline >> 6, 0)
+        }
+        _ => (0, 0),
+    };
+    let next_header = take(data, &mut offset, 1)?[0];
+    let hop_limit = match data[0] & 0x03 {
+        0 => take(data, &mut offset, 1)?[0],
+        1 => 1,
+        2 => 64,
+        _ => 255,
+    };
+    let src_address = decompress_address((data[1] >> 4) & 0x03, false, src, data, &mut offset)?;
+    let multicast = data[1] & 0x08 != 0;
+    let dst_address = decompress_address(data[1] & 0x03, multicast, dst, data, &mut offset)?;
+
+    let payload = &data[offset..];
+    let len = IPV6_HEADER_LEN + payload.len();
+    if len > out.len() || payload.len() > usize::from(u16::max_value()) {
+        return Err(ParseError::malformed(Layer::SixLowpan, 0, "packet exceeds the buffer"));
+    }
+    NetworkEndian::write_u32(&mut out[0..4],
+                             6 << 28 | u32::from(traffic_class) << 20 | flow_label);
+    NetworkEndian::write_u16(&mut out[4..6], payload.len() as u16);
+    out[6] = next_header;
+    out[7] = hop_limit;
+    out[8..24].copy_from_slice(&src_address);
+    out[24..40].copy_from_slice(&dst_address);
+    out[IPV6_HEADER_LEN..len].copy_from_slice(payload);
+    Ok(len)
+}
+
+/// The header of a fragment (RFC 4944, section 5.3).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FragmentHeader {
+    /// The size of the uncompressed IPv6 packet.
+    pub datagram_size: u16,
+    pub tag: u16,
+    /// The offset in the uncompressed packet in units of 8 bytes, `None` for the
+    /// first fragment.
+    pub offset: Option<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fragment<'a> {
+    pub header: FragmentHeader,
+    /// The compressed packet in the first fragment, otherwise a part of the rest of
+    /// the uncompressed packet.
+    pub data: &'a [u8],
+}
+
+impl<'a> WriteOut for Fragment<'a> {
+    fn len(&self) -> usize {
+        let header_len = if self.header.offset.is_some() { 5 } else { 4 };
+        header_len + self.data.len()
+    }
+
+    fn write_out<T: TxPacket>(&self, packet: &mut T) -> Result<(), ()> {
+        let dispatch = if self.header.offset.is_some() { 0b11100 } else { 0b11000 };
+        packet.push_u16(dispatch << 11 | self.header.datagram_size & 0x07ff)?;
+        packet.push_u16(self.header.tag)?;
+        if let Some(offset) = self.header.offset {
+            packet.push_byte(offset)?;
+        }
+        packet.push_bytes(self.data)?;
+        Ok(())
+    }
+}
+
+/// The payload of an IEEE 802.15.4 frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SixLowpanKind<'a> {
+    /// An uncompressed IPv6 packet.
+    Ipv6(&'a [u8]),
+    /// An IPHC-compressed IPv6 packet, see `decompress`.
+    Iphc(&'a [u8]),
+    Fragment(Fragment<'a>),
+}
+
+impl<'a> Parse<'a> for SixLowpanKind<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
+        if data.is_empty() {
+            return Err(ParseError::truncated(Layer::SixLowpan, 0));
+        }
+        let dispatch = data[0];
+        if dispatch == DISPATCH_IPV6 {
+            return Ok(SixLowpanKind::Ipv6(&data[1..]));
+        }
+        if dispatch >> 5 == 0b011 {
+            return Ok(SixLowpanKind::Iphc(data));
+        }
+        let header_len = match dispatch >> 3 {
+            0b11000 => 4,
+            0b11100 => 5,
+            _ => return Err(ParseError::unimplemented(Layer::SixLowpan, 0, "unknown dispatch")),
+        };
+        if data.len() < header_len {
+            return Err(ParseError::truncated(Layer::SixLowpan, data.len()));
+        }
+        let header = FragmentHeader {
+            datagram_size: NetworkEndian::read_u16(&data[0..2]) & 0x07ff,
+            tag: NetworkEndian::read_u16(&data[2..4]),
+            offset: if header_len == 5 { Some(data[4]) } else { None },
+        };
+        Ok(SixLowpanKind::Fragment(Fragment {
+                                       header,
+                                       data: &data[header_len..],
+                                   }))
+    }
+}
+
+/// Splits a compressed packet into fragments of at most `max_len` bytes, see
+/// `fragments`.
+#[derive(Debug, Clone)]
+pub struct Fragments<'a> {
+    data: &'a [u8],
+    /// The number of bytes that the compression saved.
+    saved_len: usize,
+    tag: u16,
+    max_len: usize,
+    index: usize,
+}
+
+/// Splits `data`, a serialized `packet`, into fragments with the given tag that fit
+/// into frame payloads of `max_len` bytes. The tag should differ for each packet.
+///
+/// Packets that fit into a single frame should be sent without fragmentation. Fails
+/// if `max_len` is less than `MIN_FRAGMENT_LEN`.
+pub fn fragments<'a>(packet: &IphcPacket,
+                     data: &'a [u8],
+                     tag: u16,
+                     max_len: usize)
+                     -> Result<Fragments<'a>, ()> {
+    if max_len < MIN_FRAGMENT_LEN {
+        return Err(());
+    }
+    Ok(Fragments {
+           data,
+           saved_len: packet.saved_len(),
+           tag,
+           max_len,
+           index: 0,
+       })
+}
+
+impl<'a> Iterator for Fragments<'a> {
+    type Item = Fragment<'a>;
+
+    fn next(&mut self) -> Option<Fragment<'a>> {
+        if self.index >= self.data.len() {
+            return None;
+        }
+        let datagram_size = (self.data.len() + self.saved_len) as u16;
+        let start = self.index;
+        // the offset of the following fragment must be a multiple of 8
+        let (offset, end) = if start == 0 {
+            let end = usize::min(self.data.len(), self.max_len - 4);
+            if end < self.data.len() {
+                (None, end - (end + self.saved_len) % 8)
+            } else {
+                (None, end)
+            }
+        } else {
+            let uncompressed = start + self.saved_len;
+            let end = usize::min(self.data.len(), start + (self.max_len - 5) / 8 * 8);
+            (Some((uncompressed / 8) as u8), end)
+        };
+        self.index = end;
+        Some(Fragment {
+                 header: FragmentHeader {
+                     datagram_size,
+                     tag: self.tag,
+                     offset,
+                 },
+                 data: &self.data[start..end],
+             })
+    }
+}
+
+#[cfg(any(test, feature = "alloc"))]
+#[derive(Debug)]
+struct Reassembly {
+    src: LinkAddress,
+    dst: LinkAddress,
+    tag: u16,
+    data: Vec<u8>,
+    /// The received ranges of `data`.
+    ranges: Vec<(usize, usize)>,
+    received: usize,
+    deadline: u64,
+}
+
+/// Puts fragmented packets back together.
+#[cfg(any(test, feature = "alloc"))]
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    reassemblies: Vec<Reassembly>,
+}
+
+#[cfg(any(test, feature = "alloc"))]
+impl Reassembler {
+    pub fn new() -> Reassembler {
+        Reassembler::default()
+    }
+
+    /// Adds a fragment of a frame from `src` to `dst`, and returns the IPv6 packet
+    /// once it is complete.
+    ///
+    /// If the fragment overlaps a different one that was received before, the packet
+    /// is dropped. When `MAX_REASSEMBLIES` packets are incomplete, the oldest one is
+    /// dropped for a new one.
+    pub fn add(&mut self,
+               fragment: &Fragment,
+               src: LinkAddress,
+               dst: LinkAddress,
+               now: u64)
+               -> Result<Option<Vec<u8>>, ParseError> {
+        let header = fragment.header;
+        let size = usize::from(header.datagram_size);
+        if size < IPV6_HEADER_LEN {
+            return Err(ParseError::malformed(Layer::SixLowpan, 0, "datagram size too small"));
+        }
+        let position = self.reassemblies.iter().position(|r| {
+            r.src == src && r.dst == dst && r.tag == header.tag && r.data.len() == size
+        });
+        let index = match position {
+            Some(index) => index,
+            None => {
+                if self.reassemblies.len() >= MAX_REASSEMBLIES {
+                    self.reassemblies.remove(0);
+                }
+                let mut data = Vec::with_capacity(size);
+                data.resize(size, 0);
+                self.reassemblies.push(Reassembly {
+                                           src,
+                                           dst,
+                                           tag: header.tag,
+                                           data,
+                                           ranges: Vec::new(),
+                                           received: 0,
+                                           deadline: now + REASSEMBLY_TIMEOUT,
+                                       });
+                self.reassemblies.len() - 1
+            }
+        };
+
+        let range = {
+            let reassembly = &mut self.reassemblies[index];
+            match header.offset {
+                None => {
+                    let len = decompress(fragment.data, src, dst, &mut reassembly.data)?;
+                    (0, len)
+                }
+                Some(offset) => {
+                    let start = usize::from(offset) * 8;
+                    let end = start + fragment.data.len();
+                    if end > size {
+                        return Err(ParseError::malformed(Layer::SixLowpan,
+                                                         4,
+                                                         "fragment exceeds the datagram"));
+                    }
+                    (start, end)
+                }
+            }
+        };
+        let reassembly = &mut self.reassemblies[index];
+        if reassembly.ranges.contains(&range) {
+            return Ok(None); // duplicate
+        }
+        if reassembly.ranges.iter().any(|&(start, end)| range.0 < end && start < range.1) {
+            self.reassemblies.remove(index);
+            return Ok(None);
+        }
+        if let Some(offset) = header.offset {
+            let start = usize::from(offset) * 8;
+            reassembly.data[start..range.1].copy_from_slice(fragment.data);
+        }
+        reassembly.ranges.push(range);
+        reassembly.received += range.1 - range.0;
+        if reassembly.received < size {
+            return Ok(None);
+        }
+
+        let mut reassembly = self.reassemblies.remove(index);
+        let payload_len = (size - IPV6_HEADER_LEN) as u16;
+        NetworkEndian::write_u16(&mut reassembly.data[4..6], payload_len);
+        Ok(Some(reassembly.data))
+    }
+
+    /// Drops the packets whose fragments didn't arrive within `REASSEMBLY_TIMEOUT`.
+    pub fn poll(&mut self, now: u64) {
+        self.reassemblies.retain(|reassembly| now < reassembly.deadline);
+    }
+}
+
+#[cfg(test)]
+fn ipv6_packet(src: [u8; 16], dst: [u8; 16], payload_len: usize) -> Vec<u8> {
+    let mut packet = [0x60, 0, 0, 0, 0, 0, 17, 64].to_vec();
+    NetworkEndian::write_u16(&mut packet[4..6], payload_len as u16);
+    packet.extend_from_slice(&src);
+    packet.extend_from_slice(&dst);
+    packet.extend((0..payload_len).map(|i| i as u8));
+    packet
+}
+
+#[cfg(test)]
+fn serialize<T: WriteOut>(packet: &T) -> Vec<u8> {
+    use HeapTxPacket;
+
+    let mut data = HeapTxPacket::new(packet.len());
+    packet.write_out(&mut data).unwrap();
+    data.as_slice().to_vec()
+}
+
+#[test]
+fn compression() {
+    let src = LinkAddress::Extended([0x00, 0x12, 0x4b, 0x00, 0x01, 0x02, 0x03, 0x04]);
+    let dst = LinkAddress::Short(0x1234);
+    let mut link_local = [0; 16];
+    link_local[..8].copy_from_slice(&LINK_LOCAL_PREFIX);
+    link_local[8..].copy_from_slice(&src.interface_id());
+    let all_nodes = [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+    let global = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x42];
+
+    // (source, destination, compressed header length)
+    for &(src_address, dst_address, header_len) in &[(link_local, all_nodes, 4),
+                                                      (global, all_nodes, 20),
+                                                      (link_local, global, 19)] {
+        let mut packet = ipv6_packet(src_address, dst_address, 8);
+        packet[1] = 0x0a; // flow label
+        let iphc = IphcPacket::new(&packet, src, dst).unwrap();
+        let data = serialize(&iphc);
+        assert_eq!(data.len(), header_len + 8 + 3);
+        assert_eq!(SixLowpanKind::parse(&data), Ok(SixLowpanKind::Iphc(&data[..])));
+
+        let mut out = [0; 128];
+        let len = decompress(&data, src, dst, &mut out).unwrap();
+        assert_eq!(&out[..len], &packet[..]);
+    }
+
+    // traffic class and flow label inline, with the ECN bits moved to the front
+    let mut packet = ipv6_packet(link_local, all_nodes, 8);
+    packet[0..4].copy_from_slice(&[0x6b, 0x9a, 0xbc, 0xde]);
+    let data = serialize(&IphcPacket::new(&packet, src, dst).unwrap());
+    assert_eq!(&data[2..6], &[0x6e, 0x0a, 0xbc, 0xde]);
+    let mut out = [0; 128];
+    let len = decompress(&data, src, dst, &mut out).unwrap();
+    assert_eq!(&out[..len], &packet[..]);
+}
+
+#[test]
+fn fragmentation() {
+    let src = LinkAddress::Short(0x0001);
+    let dst = LinkAddress::Short(0x0002);
+    let mut address = [0; 16];
+    address[..8].copy_from_slice(&LINK_LOCAL_PREFIX);
+    address[8..].copy_from_slice(&dst.interface_id());
+    let packet = ipv6_packet([0; 16], address, 300);
+    let iphc = IphcPacket::new(&packet, src, dst).unwrap();
+    let data = serialize(&iphc);
+
+    assert_eq!(fragments(&iphc, &data, 7, MIN_FRAGMENT_LEN - 1).err(), Some(()));
+    assert_eq!(fragments(&iphc, &data, 7, MIN_FRAGMENT_LEN).unwrap().count(), 41);
+    let frames: Vec<_> = fragments(&iphc, &data, 7, 80)
+        .unwrap()
+        .map(|fragment| serialize(&fragment))
+        .collect();
+    assert!(frames.iter().all(|frame| frame.len() <= 80));
+    assert_eq!(frames.len(), 5);
+
+    let mut reassembler = Reassembler::new();
+    let mut reassembled = None;
+    for frame in frames.iter().rev().chain(&frames[1..2]) {
+        let fragment = match SixLowpanKind::parse(frame).unwrap() {
+            SixLowpanKind::Fragment(fragment) => fragment,
+            other => panic!("unexpected payload {:?}", other),
+        };
+        assert_eq!(fragment.header.datagram_size, 340);
+        if let Some(packet) = reassembler.add(&fragment, src, dst, 0).unwrap() {
+            reassembled = Some(packet);
+        }
+    }
+    assert_eq!(reassembled, Some(packet));
+
+    let mut short = match SixLowpanKind::parse(&frames[1]).unwrap() {
+        SixLowpanKind::Fragment(fragment) => fragment,
+        other => panic!("unexpected payload {:?}", other),
+    };
+    short.header.datagram_size = 39;
+    assert_eq!(reassembler.add(&short, src, dst, 0),
+               Err(ParseError::malformed(Layer::SixLowpan, 0, "datagram size too small")));
+}
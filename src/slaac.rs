@@ -0,0 +1,275 @@
+//! IPv6 stateless address autoconfiguration (RFC 4862).
+//!
+//! `Slaac` forms a link-local address from the MAC address and global addresses
+//! from the prefixes of router advertisements, runs duplicate address detection
+//! (DAD) on them, and expires them according to the prefix lifetimes.
+//!
+//! The crate doesn't parse ICMPv6 yet, so the application passes the prefix
+//! information options of received router advertisements to `handle_prefix`, sends
+//! the neighbor solicitations that `poll` asks for, and reports duplicates with
+//! `handle_duplicate`.
+
+use ethernet::EthernetAddress;
+use ipv6::Ipv6Address;
+
+/// The number of neighbor solicitations sent for DAD.
+pub const DUP_ADDR_DETECT_TRANSMITS: u8 = 1;
+
+/// The time between two DAD solicitations, and after the last one, in milliseconds.
+pub const RETRANS_TIMER: u64 = 1000;
+
+/// The number of addresses, including the link-local one.
+pub const MAX_ADDRESSES: usize = 4;
+
+/// Received lifetimes below this are only accepted if they extend the remaining
+/// valid lifetime, so that forged advertisements can't expire addresses quickly.
+const TWO_HOURS: u64 = 2 * 60 * 60 * 1000;
+
+const INFINITE_LIFETIME: u32 = 0xffff_ffff;
+
+/// The contents of a prefix information option of a router advertisement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrefixInformation {
+    pub prefix: Ipv6Address,
+    pub prefix_len: u8,
+    /// The A flag. Only autonomous prefixes are used for address configuration.
+    pub autonomous: bool,
+    /// Lifetimes in seconds. 0xffffffff is infinite.
+    pub valid_lifetime: u32,
+    pub preferred_lifetime: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressState {
+    /// DAD is running. `sent` solicitations were sent, the next step is due at
+    /// `deadline`.
+    Tentative { sent: u8, deadline: u64 },
+    Preferred,
+    /// The preferred lifetime expired. The address is still valid for existing
+    /// connections, but shouldn't be used for new ones.
+    Deprecated,
+    /// DAD found another node with the address.
+    Duplicate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlaacAddress {
+    pub address: Ipv6Address,
+    pub state: AddressState,
+    /// Timestamps in milliseconds, `None` for infinite lifetimes.
+    pub preferred_until: Option<u64>,
+    pub valid_until: Option<u64>,
+}
+
+impl SlaacAddress {
+    /// Whether the address can be used as a source address.
+    pub fn is_usable(&self) -> bool {
+        match self.state {
+            AddressState::Preferred | AddressState::Deprecated => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Slaac {
+    interface_id: [u8; 8],
+    addresses: [Option<SlaacAddress>; MAX_ADDRESSES],
+}
+
+/// Forms the modified EUI-64 interface identifier of a MAC address.
+pub fn interface_id(mac: EthernetAddress) -> [u8; 8] {
+    let mac = mac.as_bytes();
+    [mac[0] ^ 0x02, mac[1], mac[2], 0xff, 0xfe, mac[3], mac[4], mac[5]]
+}
+
+fn deadline(now: u64, lifetime: u32) -> Option<u64> {
+    if lifetime == INFINITE_LIFETIME {
+        None
+    } else {
+        Some(now + u64::from(lifetime) * 1000)
+    }
+}
+
+impl Slaac {
+    pub fn new(mac: EthernetAddress) -> Slaac {
+        Slaac {
+            interface_id: interface_id(mac),
+            addresses: [None; MAX_ADDRESSES],
+        }
+    }
+
+    /// Starts DAD for the link-local address, e.g. when the link came up. All
+    /// previously configured addresses are dropped.
+    pub fn start(&mut self, now: u64) {
+        self.addresses = [None; MAX_ADDRESSES];
+        let link_local = Ipv6Address::link_local(self.interface_id);
+        self.add(link_local, None, None, now);
+    }
+
+    fn add(&mut self,
+           address: Ipv6Address,
+           preferred_until: Option<u64>,
+           valid_until: Option<u64>,
+           now: u64) {
+        if let Some(slot) = self.addresses.iter_mut().find(|a| a.is_none()) {
+            *slot = Some(SlaacAddress {
+                             address,
+                             state: AddressState::Tentative {
+                                 sent: 0,
+                                 deadline: now,
+                             },
+                             preferred_until,
+                             valid_until,
+                         });
+        }
+    }
+
+    /// Processes a prefix information option of a received router advertisement.
+    pub fn handle_prefix(&mut self, info: &PrefixInformation, now: u64) {
+        if !info.autonomous || info.prefix.is_link_local() ||
+           info.preferred_lifetime > info.valid_lifetime {
+            return;
+        }
+        // interface identifiers are 64 bits
+        if info.prefix_len != 64 {
+            return;
+        }
+
+        let address = Ipv6Address::from_prefix(info.prefix, self.interface_id);
+        let preferred_until = deadline(now, info.preferred_lifetime);
+        let received_valid_until = deadline(now, info.valid_lifetime);
+        let existing = self.addresses.iter_mut().filter_map(|a| a.as_mut()).find(|a| {
+            a.address == address
+        });
+        match existing {
+            Some(existing) => {
+                existing.preferred_until = preferred_until;
+                let remaining = existing.valid_until.map(|until| until.saturating_sub(now));
+                existing.valid_until = match (received_valid_until, remaining) {
+                    (None, _) => None,
+                    (Some(until), _) if until - now > TWO_HOURS => Some(until),
+                    (Some(until), Some(remaining)) if until - now > remaining => Some(until),
+                    (_, Some(remaining)) if remaining <= TWO_HOURS => existing.valid_until,
+                    _ => Some(now + TWO_HOURS),
+                };
+                if existing.state == AddressState::Deprecated &&
+                   preferred_until.map_or(true, |until| until > now) {
+                    existing.state = AddressState::Preferred;
+                }
+            }
+            None if info.valid_lifetime > 0 => {
+                self.add(address, preferred_until, received_valid_until, now)
+            }
+            None => {}
+        }
+    }
+
+    /// Reports that another node uses a tentative address, i.e. it answered a DAD
+    /// solicitation or is probing the same address.
+    pub fn handle_duplicate(&mut self, address: Ipv6Address) {
+        for entry in self.addresses.iter_mut().filter_map(|a| a.as_mut()) {
+            if entry.address == address {
+                if let AddressState::Tentative { .. } = entry.state {
+                    entry.state = AddressState::Duplicate;
+                }
+            }
+        }
+    }
+
+    /// Advances DAD and the lifetimes. Returns the target address of a neighbor
+    /// solicitation that has to be sent from the unspecified address to the
+    /// solicited-node multicast address of the target.
+    pub fn poll(&mut self, now: u64) -> Option<Ipv6Address> {
+        for slot in self.addresses.iter_mut() {
+            let expired = slot.map_or(false, |a| a.valid_until.map_or(false, |u| now >= u));
+            if expired {
+                *slot = None;
+            }
+        }
+
+        let mut solicitation = None;
+        for entry in self.addresses.iter_mut().filter_map(|a| a.as_mut()) {
+            match entry.state {
+                AddressState::Tentative { sent, deadline } if now >= deadline => {
+                    if sent == DUP_ADDR_DETECT_TRANSMITS {
+                        entry.state = AddressState::Preferred;
+                    } else if solicitation.is_none() {
+                        entry.state = AddressState::Tentative {
+                            sent: sent + 1,
+                            deadline: now + RETRANS_TIMER,
+                        };
+                        solicitation = Some(entry.address);
+                    }
+                }
+                AddressState::Preferred => {
+                    if entry.preferred_until.map_or(false, |until| now >= until) {
+                        entry.state = AddressState::Deprecated;
+                    }
+                }
+                _ => {}
+            }
+        }
+        solicitation
+    }
+
+    pub fn addresses<'a>(&'a self) -> impl Iterator<Item = &'a SlaacAddress> {
+        self.addresses.iter().filter_map(|a| a.as_ref())
+    }
+
+    /// The preferred global address, or the link-local one if there is none.
+    pub fn preferred_address(&self) -> Option<Ipv6Address> {
+        let preferred = || {
+            self.addresses().filter(|a| a.state == AddressState::Preferred).map(|a| a.address)
+        };
+        preferred().find(|a| !a.is_link_local()).or_else(|| preferred().next())
+    }
+}
+
+#[test]
+fn autoconfiguration() {
+    let mac = EthernetAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    let mut slaac = Slaac::new(mac);
+    slaac.start(0);
+
+    let link_local = slaac.poll(0).unwrap();
+    assert_eq!(format!("{:?}", link_local), "fe80:0:0:0:211:22ff:fe33:4455");
+    assert_eq!(slaac.preferred_address(), None);
+    assert_eq!(slaac.poll(RETRANS_TIMER), None);
+    assert_eq!(slaac.preferred_address(), Some(link_local));
+
+    let mut prefix = [0; 16];
+    prefix[..8].copy_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 1]);
+    let mut info = PrefixInformation {
+        prefix: Ipv6Address::new(prefix),
+        prefix_len: 64,
+        autonomous: true,
+        valid_lifetime: 3 * 60 * 60,
+        preferred_lifetime: 60 * 60,
+    };
+    slaac.handle_prefix(&info, 2000);
+    let global = slaac.poll(2000).unwrap();
+    assert_eq!(format!("{:?}", global), "2001:db8:0:1:211:22ff:fe33:4455");
+    slaac.poll(3000);
+    assert_eq!(slaac.preferred_address(), Some(global));
+
+    // a short valid lifetime doesn't cut the remaining one below two hours
+    info.valid_lifetime = 60;
+    info.preferred_lifetime = 60;
+    slaac.handle_prefix(&info, 4000);
+    let address = *slaac.addresses().find(|a| a.address == global).unwrap();
+    assert_eq!(address.valid_until, Some(4000 + TWO_HOURS));
+    slaac.poll(64_000);
+    assert_eq!(slaac.addresses().find(|a| a.address == global).unwrap().state,
+               AddressState::Deprecated);
+    assert_eq!(slaac.preferred_address(), Some(link_local));
+    slaac.poll(4000 + TWO_HOURS);
+    assert_eq!(slaac.addresses().count(), 1);
+
+    // a duplicate is never used
+    slaac.handle_prefix(&info, 5000);
+    let tentative = slaac.poll(5000).unwrap();
+    slaac.handle_duplicate(tentative);
+    slaac.poll(10_000);
+    assert!(!slaac.addresses().find(|a| a.address == tentative).unwrap().is_usable());
+}
@@ -0,0 +1,194 @@
+//! [RFC 1055][rfc1055] SLIP framing, for sending IPv4 packets over a serial line.
+//!
+//! [rfc1055]: https://tools.ietf.org/html/rfc1055
+
+use ipv4::{Ipv4Kind, Ipv4Packet};
+use packet_memory::MemoryTxPacket;
+use parse::{Parse, ParseError};
+use {write_packet, WriteOut};
+
+pub const END: u8 = 0xc0;
+pub const ESC: u8 = 0xdb;
+pub const ESC_END: u8 = 0xdc;
+pub const ESC_ESC: u8 = 0xdd;
+
+/// The transmit side of a serial line, e.g. a UART.
+pub trait SerialWrite {
+    fn write_byte(&mut self, byte: u8) -> Result<(), ()>;
+}
+
+impl<'a, S: SerialWrite> SerialWrite for &'a mut S {
+    fn write_byte(&mut self, byte: u8) -> Result<(), ()> {
+        (**self).write_byte(byte)
+    }
+}
+
+/// Writes `data` as a single SLIP frame.
+///
+/// The frame is preceded by an `END` byte too, which flushes any line noise
+/// received by the peer.
+pub fn write_frame<S: SerialWrite>(serial: &mut S, data: &[u8]) -> Result<(), ()> {
+    serial.write_byte(END)?;
+    for &byte in data {
+        match byte {
+            END => {
+                serial.write_byte(ESC)?;
+                serial.write_byte(ESC_END)?;
+            }
+            ESC => {
+                serial.write_byte(ESC)?;
+                serial.write_byte(ESC_ESC)?;
+            }
+            _ => serial.write_byte(byte)?,
+        }
+    }
+    serial.write_byte(END)
+}
+
+/// Serializes `packet` into `buf` and writes it as a SLIP frame.
+pub fn send_ipv4<S, T>(serial: &mut S, packet: &Ipv4Packet<T>, buf: &mut [u8]) -> Result<(), ()>
+    where S: SerialWrite,
+          T: WriteOut,
+          Ipv4Packet<T>: WriteOut
+{
+    let len = packet.len();
+    {
+        let capacity = buf.len();
+        let mut tx_packet = MemoryTxPacket::new(&mut *buf, 0, capacity);
+        write_packet(&mut tx_packet, packet).map_err(|_| ())?;
+    }
+    write_frame(serial, &buf[..len])
+}
+
+/// Parses a received SLIP frame as an IPv4 packet.
+pub fn parse_ipv4(frame: &[u8]) -> Result<Ipv4Packet<Ipv4Kind>, ParseError> {
+    Ipv4Packet::parse(frame)
+}
+
+/// Reassembles SLIP frames from received bytes into a caller-provided buffer.
+///
+/// Frames that don't fit into the buffer or contain invalid escape sequences are
+/// dropped.
+pub struct SlipDecoder<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+    escaped: bool,
+    dropping: bool,
+    frame_complete: bool,
+}
+
+impl<'b> SlipDecoder<'b> {
+    pub fn new(buf: &'b mut [u8]) -> Self {
+        SlipDecoder {
+            buf,
+            len: 0,
+            escaped: false,
+            dropping: false,
+            frame_complete: false,
+        }
+    }
+
+    /// Feeds a received byte to the decoder.
+    ///
+    /// Returns the length of a completed frame, which can be retrieved through
+    /// `frame` until the next byte is pushed. Empty frames are ignored.
+    pub fn push(&mut self, byte: u8) -> Option<usize> {
+        if self.frame_complete {
+            self.frame_complete = false;
+            self.len = 0;
+        }
+
+        if byte == END {
+            let complete = !self.dropping && !self.escaped && self.len > 0;
+            self.escaped = false;
+            self.dropping = false;
+            if complete {
+                self.frame_complete = true;
+                return Some(self.len);
+            }
+            self.len = 0;
+            return None;
+        }
+        if self.dropping {
+            return None;
+        }
+
+        let byte = if self.escaped {
+            self.escaped = false;
+            match byte {
+                ESC_END => END,
+                ESC_ESC => ESC,
+                _ => {
+                    self.dropping = true;
+                    return None;
+                }
+            }
+        } else if byte == ESC {
+            self.escaped = true;
+            return None;
+        } else {
+            byte
+        };
+
+        if self.len == self.buf.len() {
+            self.dropping = true;
+            return None;
+        }
+        self.buf[self.len] = byte;
+        self.len += 1;
+        None
+    }
+
+    /// The last completed frame, or an empty slice.
+    pub fn frame(&self) -> &[u8] {
+        if self.frame_complete {
+            &self.buf[..self.len]
+        } else {
+            &[]
+        }
+    }
+}
+
+#[test]
+fn round_trip() {
+    use alloc::vec::Vec;
+    use ipv4::Ipv4Address;
+    use udp::{UdpKind, UdpPacket};
+
+    struct Uart(Vec<u8>);
+
+    impl SerialWrite for Uart {
+        fn write_byte(&mut self, byte: u8) -> Result<(), ()> {
+            self.0.push(byte);
+            Ok(())
+        }
+    }
+
+    let mut line = Uart(Vec::new());
+    write_frame(&mut line, &[1, END, 2, ESC, 3]).unwrap();
+    assert_eq!(line.0, [END, 1, ESC, ESC_END, 2, ESC, ESC_ESC, 3, END]);
+
+    let udp = UdpPacket::new(1000, 7, &[END, ESC][..]);
+    let ip = Ipv4Packet::new_udp(Ipv4Address::new(10, 0, 0, 2), Ipv4Address::new(10, 0, 0, 1), udp);
+    let mut buf = [0; 64];
+    line.0.clear();
+    send_ipv4(&mut line, &ip, &mut buf).unwrap();
+
+    let mut rx_buf = [0; 64];
+    let mut decoder = SlipDecoder::new(&mut rx_buf);
+    let mut frames = 0;
+    for &byte in &line.0 {
+        if let Some(len) = decoder.push(byte) {
+            assert_eq!(len, 20 + 8 + 2);
+            match parse_ipv4(decoder.frame()).unwrap().payload {
+                Ipv4Kind::Udp(udp) => match udp.payload {
+                    UdpKind::Unknown(data) => assert_eq!(data, &[END, ESC]),
+                    _ => panic!(),
+                },
+                _ => panic!(),
+            }
+            frames += 1;
+        }
+    }
+    assert_eq!(frames, 1);
+}
@@ -0,0 +1,467 @@
+//! A minimal SNMPv2c agent: GET and GETNEXT requests over UDP, answered from a table
+//! of registered OIDs.
+//!
+//! Only the BER subset of SNMP messages is supported: definite lengths, integers,
+//! octet strings, OIDs, and the SNMPv2 exception values.
+
+use alloc::boxed::Box;
+use alloc::{BTreeMap, Vec};
+use parse::{Layer, Parse, ParseError};
+use stats::Stats;
+
+pub const SNMP_PORT: u16 = 161;
+
+/// The only supported version, SNMPv2c.
+const VERSION_2C: i32 = 1;
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_NULL: u8 = 0x05;
+const TAG_OID: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_COUNTER32: u8 = 0x41;
+const TAG_GAUGE32: u8 = 0x42;
+const TAG_TIMETICKS: u8 = 0x43;
+const TAG_NO_SUCH_OBJECT: u8 = 0x80;
+const TAG_END_OF_MIB_VIEW: u8 = 0x82;
+
+const PDU_GET: u8 = 0xa0;
+const PDU_GET_NEXT: u8 = 0xa1;
+const PDU_RESPONSE: u8 = 0xa2;
+
+/// An object identifier, e.g. `[1, 3, 6, 1, 2, 1, 1, 3, 0]`. The derived order is the
+/// lexicographic OID order that GETNEXT walks in.
+pub type Oid = Vec<u32>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PduType {
+    Get,
+    GetNext,
+}
+
+/// A decoded GET or GETNEXT request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnmpRequest<'a> {
+    pub community: &'a [u8],
+    pub pdu_type: PduType,
+    pub request_id: i32,
+    pub oids: Vec<Oid>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnmpValue {
+    Integer(i32),
+    OctetString(Vec<u8>),
+    Counter32(u32),
+    Gauge32(u32),
+    TimeTicks(u32),
+    /// The requested OID doesn't exist.
+    NoSuchObject,
+    /// There is no OID after the requested one.
+    EndOfMibView,
+    /// The placeholder value of requests.
+    Null,
+}
+
+/// A BER reader over the content of a constructed value.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    /// The offset of `data` in the message, for error offsets.
+    base: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader {
+            data,
+            pos: 0,
+            base: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    fn offset(&self) -> usize {
+        self.base + self.pos
+    }
+
+    /// Reads the next value, which must have the tag `tag`.
+    fn read(&mut self, tag: u8) -> Result<Reader<'a>, ParseError> {
+        let (found, content) = self.read_any()?;
+        if found != tag {
+            return Err(ParseError::malformed(Layer::Snmp,
+                                             content.base - 2,
+                                             "unexpected BER tag"));
+        }
+        Ok(content)
+    }
+
+    fn read_any(&mut self) -> Result<(u8, Reader<'a>), ParseError> {
+        let start = self.offset();
+        let data = &self.data[self.pos..];
+        if data.len() < 2 {
+            return Err(ParseError::truncated(Layer::Snmp, self.base + self.data.len()));
+        }
+        let (len, header_len) = match data[1] {
+            len @ 0...0x7f => (usize::from(len), 2),
+            0x81 if data.len() >= 3 => (usize::from(data[2]), 3),
+            0x82 if data.len() >= 4 => (usize::from(data[2]) << 8 | usize::from(data[3]), 4),
+            0x81 | 0x82 => {
+                return Err(ParseError::truncated(Layer::Snmp, self.base + self.data.len()))
+            }
+            _ => return Err(ParseError::unimplemented(Layer::Snmp, start + 1, "BER length form")),
+        };
+        if data.len() < header_len + len {
+            return Err(ParseError::truncated(Layer::Snmp, self.base + self.data.len()));
+        }
+        self.pos += header_len + len;
+        Ok((data[0],
+            Reader {
+                data: &data[header_len..header_len + len],
+                pos: 0,
+                base: start + header_len,
+            }))
+    }
+
+    fn read_integer(&mut self) -> Result<i32, ParseError> {
+        let content = self.read(TAG_INTEGER)?;
+        if content.data.is_empty() || content.data.len() > 4 {
+            return Err(ParseError::malformed(Layer::Snmp, content.base, "invalid integer"));
+        }
+        let sign = if content.data[0] & 0x80 != 0 { -1 } else { 0 };
+        Ok(content.data.iter().fold(sign, |value, &byte| value << 8 | i32::from(byte)))
+    }
+
+    fn read_oid(&mut self) -> Result<Oid, ParseError> {
+        let content = self.read(TAG_OID)?;
+        let mut oid = Vec::new();
+        let mut value: u32 = 0;
+        for (i, &byte) in content.data.iter().enumerate() {
+            if value > (u32::max_value() >> 7) {
+                return Err(ParseError::malformed(Layer::Snmp, content.base + i, "OID overflow"));
+            }
+            value = value << 7 | u32::from(byte & 0x7f);
+            if byte & 0x80 == 0 {
+                if oid.is_empty() {
+                    let first = u32::min(value / 40, 2);
+                    oid.push(first);
+                    oid.push(value - 40 * first);
+                } else {
+                    oid.push(value);
+                }
+                value = 0;
+            }
+        }
+        if oid.is_empty() || content.data.last().map_or(false, |&b| b & 0x80 != 0) {
+            return Err(ParseError::malformed(Layer::Snmp, content.base, "invalid OID"));
+        }
+        Ok(oid)
+    }
+}
+
+impl<'a> Parse<'a> for SnmpRequest<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
+        let mut message = Reader::new(data).read(TAG_SEQUENCE)?;
+        let version_offset = message.offset();
+        if message.read_integer()? != VERSION_2C {
+            return Err(ParseError::unimplemented(Layer::Snmp,
+                                                 version_offset,
+                                                 "only SNMPv2c is supported"));
+        }
+        let community = message.read(TAG_OCTET_STRING)?.data;
+
+        let pdu_offset = message.offset();
+        let (tag, mut pdu) = message.read_any()?;
+        let pdu_type = match tag {
+            PDU_GET => PduType::Get,
+            PDU_GET_NEXT => PduType::GetNext,
+            _ => return Err(ParseError::unimplemented(Layer::Snmp, pdu_offset, "SNMP PDU type")),
+        };
+        let request_id = pdu.read_integer()?;
+        pdu.read_integer()?; // error status
+        pdu.read_integer()?; // error index
+
+        let mut bindings = pdu.read(TAG_SEQUENCE)?;
+        let mut oids = Vec::new();
+        while !bindings.is_empty() {
+            let mut binding = bindings.read(TAG_SEQUENCE)?;
+            oids.push(binding.read_oid()?);
+            binding.read(TAG_NULL)?;
+        }
+
+        Ok(SnmpRequest {
+               community,
+               pdu_type,
+               request_id,
+               oids,
+           })
+    }
+}
+
+/// Appends a value with its tag and definite length.
+fn push_tlv(out: &mut Vec<u8>, tag: u8, content: &[u8]) {
+    out.push(tag);
+    match content.len() {
+        len @ 0...0x7f => out.push(len as u8),
+        len @ 0x80...0xff => out.extend_from_slice(&[0x81, len as u8]),
+        len => out.extend_from_slice(&[0x82, (len >> 8) as u8, len as u8]),
+    }
+    out.extend_from_slice(content);
+}
+
+fn push_integer(out: &mut Vec<u8>, tag: u8, value: i64) {
+    let bytes = [(value >> 32) as u8,
+                 (value >> 24) as u8,
+                 (value >> 16) as u8,
+                 (value >> 8) as u8,
+                 value as u8];
+    // skip the leading bytes that only repeat the sign
+    let mut start = 0;
+    while start < 4 &&
+          ((bytes[start] == 0 && bytes[start + 1] & 0x80 == 0) ||
+           (bytes[start] == 0xff && bytes[start + 1] & 0x80 != 0)) {
+        start += 1;
+    }
+    push_tlv(out, tag, &bytes[start..]);
+}
+
+fn push_oid(out: &mut Vec<u8>, oid: &[u32]) {
+    let mut content = Vec::new();
+    let first = oid.get(0).cloned().unwrap_or(0) * 40 + oid.get(1).cloned().unwrap_or(0);
+    for &value in Some(first).iter().chain(oid.iter().skip(2)) {
+        let mut shift = 28;
+        while shift > 0 && value >> shift == 0 {
+            shift -= 7;
+        }
+        while shift > 0 {
+            content.push(0x80 | (value >> shift) as u8 & 0x7f);
+            shift -= 7;
+        }
+        content.push(value as u8 & 0x7f);
+    }
+    push_tlv(out, TAG_OID, &content);
+}
+
+impl SnmpValue {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match *self {
+            SnmpValue::Integer(value) => push_integer(out, TAG_INTEGER, i64::from(value)),
+            SnmpValue::OctetString(ref value) => push_tlv(out, TAG_OCTET_STRING, value),
+            SnmpValue::Counter32(value) => push_integer(out, TAG_COUNTER32, i64::from(value)),
+            SnmpValue::Gauge32(value) => push_integer(out, TAG_GAUGE32, i64::from(value)),
+            SnmpValue::TimeTicks(value) => push_integer(out, TAG_TIMETICKS, i64::from(value)),
+            SnmpValue::NoSuchObject => push_tlv(out, TAG_NO_SUCH_OBJECT, &[]),
+            SnmpValue::EndOfMibView => push_tlv(out, TAG_END_OF_MIB_VIEW, &[]),
+            SnmpValue::Null => push_tlv(out, TAG_NULL, &[]),
+        }
+    }
+}
+
+/// Encodes a response message with the given variable bindings.
+pub fn encode_response(community: &[u8],
+                       request_id: i32,
+                       bindings: &[(Oid, SnmpValue)])
+                       -> Vec<u8> {
+    encode_message(PDU_RESPONSE, community, request_id, bindings)
+}
+
+fn encode_message(pdu_type: u8,
+                  community: &[u8],
+                  request_id: i32,
+                  bindings: &[(Oid, SnmpValue)])
+                  -> Vec<u8> {
+    let mut list = Vec::new();
+    for &(ref oid, ref value) in bindings {
+        let mut binding = Vec::new();
+        push_oid(&mut binding, oid);
+        value.encode(&mut binding);
+        push_tlv(&mut list, TAG_SEQUENCE, &binding);
+    }
+
+    let mut pdu = Vec::new();
+    push_integer(&mut pdu, TAG_INTEGER, i64::from(request_id));
+    push_integer(&mut pdu, TAG_INTEGER, 0); // error status
+    push_integer(&mut pdu, TAG_INTEGER, 0); // error index
+    push_tlv(&mut pdu, TAG_SEQUENCE, &list);
+
+    let mut message = Vec::new();
+    push_integer(&mut message, TAG_INTEGER, i64::from(VERSION_2C));
+    push_tlv(&mut message, TAG_OCTET_STRING, community);
+    push_tlv(&mut message, pdu_type, &pdu);
+
+    let mut out = Vec::new();
+    push_tlv(&mut out, TAG_SEQUENCE, &message);
+    out
+}
+
+pub type SnmpGetter = Box<Fn(&Stats) -> SnmpValue>;
+
+/// Answers GET and GETNEXT requests from a table of OIDs.
+///
+/// `new` registers the MIB-II counters of the interface, IP, ICMP, TCP, and UDP
+/// groups that the `Stats` counters map to. Further OIDs are added with `register`.
+pub struct SnmpAgent {
+    community: Vec<u8>,
+    table: BTreeMap<Oid, SnmpGetter>,
+}
+
+impl SnmpAgent {
+    pub fn new(community: &[u8]) -> SnmpAgent {
+        let mut agent = SnmpAgent {
+            community: community.to_vec(),
+            table: BTreeMap::new(),
+        };
+        agent.register_stats();
+        agent
+    }
+
+    /// Registers an OID whose value is computed on each request. Replaces a previous
+    /// registration of the same OID.
+    pub fn register<F>(&mut self, oid: &[u32], getter: F)
+        where F: Fn(&Stats) -> SnmpValue + 'static
+    {
+        self.table.insert(oid.to_vec(), Box::new(getter));
+    }
+
+    pub fn unregister(&mut self, oid: &[u32]) {
+        self.table.remove(oid);
+    }
+
+    fn register_stats(&mut self) {
+        const INTERFACE: [u32; 9] = [1, 3, 6, 1, 2, 1, 2, 2, 1]; // ifEntry
+        let counters: [(&[u32], fn(&Stats) -> u32); 15] =
+            [(&[10], |s| s.rx_bytes), // ifInOctets
+             (&[11], |s| s.rx_frames), // ifInUcastPkts
+             (&[13], |s| s.dropped_frames), // ifInDiscards
+             (&[14], |s| s.parse_errors), // ifInErrors
+             (&[16], |s| s.tx_bytes), // ifOutOctets
+             (&[17], |s| s.tx_frames), // ifOutUcastPkts
+             (&[0, 4, 3], |s| s.ipv4.rx), // ipInReceives
+             (&[0, 4, 10], |s| s.ipv4.tx), // ipOutRequests
+             (&[0, 5, 1], |s| s.icmp.rx), // icmpInMsgs
+             (&[0, 5, 14], |s| s.icmp.tx), // icmpOutMsgs
+             (&[0, 6, 10], |s| s.tcp.rx), // tcpInSegs
+             (&[0, 6, 11], |s| s.tcp.tx), // tcpOutSegs
+             (&[0, 6, 12], |s| s.tcp_retransmissions), // tcpRetransSegs
+             (&[0, 7, 1], |s| s.udp.rx), // udpInDatagrams
+             (&[0, 7, 4], |s| s.udp.tx)]; // udpOutDatagrams
+
+        for &(suffix, counter) in counters.iter() {
+            let oid = if suffix[0] == 0 {
+                // scalar of a MIB-II group: 1.3.6.1.2.1.<group>.<object>.0
+                let mut oid = INTERFACE[..6].to_vec();
+                oid.extend_from_slice(&suffix[1..]);
+                oid.push(0);
+                oid
+            } else {
+                // column of the first interface: ifEntry.<column>.1
+                let mut oid = INTERFACE.to_vec();
+                oid.push(suffix[0]);
+                oid.push(1);
+                oid
+            };
+            self.register(&oid, move |stats| SnmpValue::Counter32(counter(stats)));
+        }
+    }
+
+    /// Handles a request datagram and returns the response datagram.
+    ///
+    /// Requests with a wrong community or that can't be parsed are dropped, as
+    /// required for SNMP agents.
+    pub fn handle_request(&self, data: &[u8], stats: &Stats) -> Option<Vec<u8>> {
+        let request = match SnmpRequest::parse(data) {
+            Ok(request) => request,
+            Err(_) => return None,
+        };
+        if request.community != &self.community[..] {
+            return None;
+        }
+
+        let pdu_type = request.pdu_type;
+        let bindings: Vec<_> = request
+            .oids
+            .into_iter()
+            .map(|oid| match pdu_type {
+                     PduType::Get => {
+                         let value = self.table
+                             .get(&oid)
+                             .map_or(SnmpValue::NoSuchObject, |getter| getter(stats));
+                         (oid, value)
+                     }
+                     PduType::GetNext => {
+                         let next = self.table.range(oid.clone()..).find(|&(o, _)| *o > oid);
+                         match next {
+                             Some((next, getter)) => (next.clone(), getter(stats)),
+                             None => (oid, SnmpValue::EndOfMibView),
+                         }
+                     }
+                 })
+            .collect();
+        Some(encode_response(request.community, request.request_id, &bindings))
+    }
+}
+
+#[test]
+fn get_and_get_next() {
+    // snmpget -v2c -c public <host> 1.3.6.1.2.1.2.2.1 1.3.6.1.2
+    let get = hex!("30 33 02 01 01 04 06 70 75 62 6c 69 63 a0 26 02 04 12 34 56 78 02 01 00
+                    02 01 00 30 18 30 0c 06 08 2b 06 01 02 01 02 02 01 05 00 30 08 06 04 2b
+                    06 01 02 05 00");
+    let request = SnmpRequest::parse(&get).unwrap();
+    assert_eq!(request.community, b"public");
+    assert_eq!(request.pdu_type, PduType::Get);
+    assert_eq!(request.request_id, 0x12345678);
+    assert_eq!(request.oids, vec![vec![1, 3, 6, 1, 2, 1, 2, 2, 1], vec![1, 3, 6, 1, 2]]);
+
+    let mut agent = SnmpAgent::new(b"public");
+    agent.register(&[1, 3, 6, 1, 2, 1, 1, 5, 0],
+                   |_| SnmpValue::OctetString(b"sensor".to_vec())); // sysName
+    let mut stats = Stats::new();
+    stats.rx_bytes = 300;
+    stats.tcp_retransmissions = 0x80;
+
+    let get = encode_request(PDU_GET,
+                             b"public",
+                             -2,
+                             &[&[1, 3, 6, 1, 2, 1, 2, 2, 1, 10, 1], &[1, 3, 6, 1, 2, 1, 99, 0]]);
+    let response = agent.handle_request(&get, &stats).unwrap();
+    assert_eq!(response,
+               encode_response(b"public",
+                               -2,
+                               &[(vec![1, 3, 6, 1, 2, 1, 2, 2, 1, 10, 1],
+                                  SnmpValue::Counter32(300)),
+                                 (vec![1, 3, 6, 1, 2, 1, 99, 0], SnmpValue::NoSuchObject)]));
+    assert!(response.ends_with(&[0x80, 0x00]));
+
+    // walk from sysName into the interface table and from tcpRetransSegs
+    let get_next = encode_request(PDU_GET_NEXT,
+                                  b"public",
+                                  7,
+                                  &[&[1, 3, 6, 1, 2, 1, 1, 5, 0], &[1, 3, 6, 1, 2, 1, 6, 12]]);
+    let response = agent.handle_request(&get_next, &stats).unwrap();
+    assert_eq!(response,
+               encode_response(b"public",
+                               7,
+                               &[(vec![1, 3, 6, 1, 2, 1, 2, 2, 1, 10, 1],
+                                  SnmpValue::Counter32(300)),
+                                 (vec![1, 3, 6, 1, 2, 1, 6, 12, 0],
+                                  SnmpValue::Counter32(0x80))]));
+    assert!(response.ends_with(&[0x41, 0x02, 0x00, 0x80]));
+
+    let get_next = encode_request(PDU_GET_NEXT, b"public", 8, &[&[1, 3, 6, 1, 2, 1, 7, 4, 0]]);
+    let response = agent.handle_request(&get_next, &stats).unwrap();
+    assert!(response.ends_with(&[0x82, 0x00]));
+
+    assert!(agent.handle_request(&get, &stats).is_some());
+    let wrong_community = encode_request(PDU_GET, b"private", 1, &[&[1, 3, 6, 1, 2, 1, 1, 5, 0]]);
+    assert!(agent.handle_request(&wrong_community, &stats).is_none());
+}
+
+#[cfg(test)]
+fn encode_request(pdu_type: u8, community: &[u8], request_id: i32, oids: &[&[u32]]) -> Vec<u8> {
+    let bindings: Vec<_> = oids.iter().map(|oid| (oid.to_vec(), SnmpValue::Null)).collect();
+    encode_message(pdu_type, community, request_id, &bindings)
+}
@@ -0,0 +1,253 @@
+//! SNTP server mode according to [RFC 4330][rfc4330]: client requests are answered
+//! from a clock of the application, e.g. a GPS receiver or RTC of a gateway that
+//! serves time to an isolated network.
+//!
+//! [rfc4330]: https://tools.ietf.org/html/rfc4330
+
+use {TxPacket, WriteOut};
+use byteorder::{ByteOrder, NetworkEndian};
+
+pub const SNTP_PORT: u16 = 123;
+
+/// The length of an NTP message without extension fields and authenticator.
+pub const NTP_PACKET_LEN: usize = 48;
+
+/// The seconds from the NTP epoch (1900) to the Unix epoch (1970).
+pub const UNIX_EPOCH_OFFSET: u32 = 2_208_988_800;
+
+/// Seconds since 1900 and a binary fraction of a second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct NtpTimestamp {
+    pub seconds: u32,
+    pub fraction: u32,
+}
+
+impl NtpTimestamp {
+    pub fn from_unix(seconds: u32, nanos: u32) -> Self {
+        NtpTimestamp {
+            seconds: seconds.wrapping_add(UNIX_EPOCH_OFFSET),
+            fraction: ((u64::from(nanos) << 32) / 1_000_000_000) as u32,
+        }
+    }
+
+    fn read(data: &[u8]) -> Self {
+        NtpTimestamp {
+            seconds: NetworkEndian::read_u32(&data[0..4]),
+            fraction: NetworkEndian::read_u32(&data[4..8]),
+        }
+    }
+
+    fn write(&self, data: &mut [u8]) {
+        NetworkEndian::write_u32(&mut data[0..4], self.seconds);
+        NetworkEndian::write_u32(&mut data[4..8], self.fraction);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NtpMode {
+    Client,
+    Server,
+    Broadcast,
+    Other(u8),
+}
+
+impl NtpMode {
+    pub fn from_number(number: u8) -> NtpMode {
+        match number {
+            3 => NtpMode::Client,
+            4 => NtpMode::Server,
+            5 => NtpMode::Broadcast,
+            number => NtpMode::Other(number),
+        }
+    }
+
+    pub fn number(&self) -> u8 {
+        match *self {
+            NtpMode::Client => 3,
+            NtpMode::Server => 4,
+            NtpMode::Broadcast => 5,
+            NtpMode::Other(number) => number,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NtpPacket {
+    /// 3 means that the clock is not synchronized.
+    pub leap_indicator: u8,
+    pub version: u8,
+    pub mode: NtpMode,
+    pub stratum: u8,
+    /// The log2 of the poll interval in seconds.
+    pub poll: i8,
+    /// The log2 of the clock precision in seconds.
+    pub precision: i8,
+    /// In 16.16 fixed point seconds.
+    pub root_delay: u32,
+    pub root_dispersion: u32,
+    pub reference_id: [u8; 4],
+    pub reference_timestamp: NtpTimestamp,
+    pub originate_timestamp: NtpTimestamp,
+    pub receive_timestamp: NtpTimestamp,
+    pub transmit_timestamp: NtpTimestamp,
+}
+
+impl NtpPacket {
+    pub fn as_bytes(&self) -> [u8; NTP_PACKET_LEN] {
+        let mut bytes = [0; NTP_PACKET_LEN];
+        bytes[0] = self.leap_indicator << 6 | (self.version & 0x7) << 3 | self.mode.number();
+        bytes[1] = self.stratum;
+        bytes[2] = self.poll as u8;
+        bytes[3] = self.precision as u8;
+        NetworkEndian::write_u32(&mut bytes[4..8], self.root_delay);
+        NetworkEndian::write_u32(&mut bytes[8..12], self.root_dispersion);
+        bytes[12..16].copy_from_slice(&self.reference_id);
+        self.reference_timestamp.write(&mut bytes[16..24]);
+        self.originate_timestamp.write(&mut bytes[24..32]);
+        self.receive_timestamp.write(&mut bytes[32..40]);
+        self.transmit_timestamp.write(&mut bytes[40..48]);
+        bytes
+    }
+}
+
+impl WriteOut for NtpPacket {
+    fn len(&self) -> usize {
+        NTP_PACKET_LEN
+    }
+
+    fn write_out<P: TxPacket>(&self, packet: &mut P) -> Result<(), ()> {
+        packet.push_bytes(&self.as_bytes()).map(|_| ())
+    }
+}
+
+use parse::{Layer, Parse, ParseError};
+
+/// Extension fields and authenticators after the 48 byte header are ignored.
+impl<'a> Parse<'a> for NtpPacket {
+    fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
+        if data.len() < NTP_PACKET_LEN {
+            return Err(ParseError::truncated(Layer::Ntp, data.len()));
+        }
+        let mut reference_id = [0; 4];
+        reference_id.copy_from_slice(&data[12..16]);
+        Ok(NtpPacket {
+               leap_indicator: data[0] >> 6,
+               version: (data[0] >> 3) & 0x7,
+               mode: NtpMode::from_number(data[0] & 0x7),
+               stratum: data[1],
+               poll: data[2] as i8,
+               precision: data[3] as i8,
+               root_delay: NetworkEndian::read_u32(&data[4..8]),
+               root_dispersion: NetworkEndian::read_u32(&data[8..12]),
+               reference_id,
+               reference_timestamp: NtpTimestamp::read(&data[16..24]),
+               originate_timestamp: NtpTimestamp::read(&data[24..32]),
+               receive_timestamp: NtpTimestamp::read(&data[32..40]),
+               transmit_timestamp: NtpTimestamp::read(&data[40..48]),
+           })
+    }
+}
+
+/// Answers SNTP requests that arrive on a UDP socket bound to `SNTP_PORT`.
+pub struct SntpServer<F> {
+    clock: F,
+    stratum: u8,
+    precision: i8,
+    reference_id: [u8; 4],
+}
+
+impl<F> SntpServer<F>
+    where F: FnMut() -> Option<NtpTimestamp>
+{
+    /// `clock` returns the current time, or `None` while it isn't synchronized.
+    ///
+    /// The server announces itself as stratum 1 with an uncalibrated local clock
+    /// (`LOCL`) and a precision of about a millisecond.
+    pub fn new(clock: F) -> Self {
+        SntpServer {
+            clock,
+            stratum: 1,
+            precision: -10,
+            reference_id: *b"LOCL",
+        }
+    }
+
+    /// Sets the stratum and reference identifier, e.g. 1 and `GPS` for a GPS clock,
+    /// or a higher stratum and the IPv4 address of the upstream server.
+    pub fn set_reference(&mut self, stratum: u8, reference_id: [u8; 4]) {
+        self.stratum = stratum;
+        self.reference_id = reference_id;
+    }
+
+    pub fn set_precision(&mut self, precision: i8) {
+        self.precision = precision;
+    }
+
+    /// Returns the reply to a request datagram.
+    ///
+    /// Datagrams that aren't client requests are ignored, and so are all requests
+    /// while the clock isn't synchronized, so that clients use other servers.
+    pub fn handle_request(&mut self, data: &[u8]) -> Option<NtpPacket> {
+        let request = match NtpPacket::parse(data) {
+            Ok(request) => request,
+            Err(_) => return None,
+        };
+        if request.mode != NtpMode::Client || request.version < 1 || request.version > 4 {
+            return None;
+        }
+        let receive_timestamp = match (self.clock)() {
+            Some(now) => now,
+            None => return None,
+        };
+        let transmit_timestamp = (self.clock)().unwrap_or(receive_timestamp);
+        Some(NtpPacket {
+                 leap_indicator: 0,
+                 version: request.version,
+                 mode: NtpMode::Server,
+                 stratum: self.stratum,
+                 poll: request.poll,
+                 precision: self.precision,
+                 root_delay: 0,
+                 root_dispersion: 0,
+                 reference_id: self.reference_id,
+                 reference_timestamp: receive_timestamp,
+                 originate_timestamp: request.transmit_timestamp,
+                 receive_timestamp,
+                 transmit_timestamp,
+             })
+    }
+}
+
+#[test]
+fn answer_request() {
+    // ntpdate -q <host>
+    let request = hex!("e3 00 04 fa 00 01 00 00 00 01 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+                        00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 e9 1c 4f 2a 80 00 00 00");
+    let mut now = NtpTimestamp::from_unix(1_500_000_000, 500_000_000);
+    assert_eq!(now,
+               NtpTimestamp {
+                   seconds: 0xdd12_ad80,
+                   fraction: 0x8000_0000,
+               });
+
+    let mut server = SntpServer::new(|| {
+                                         now.fraction += 1;
+                                         Some(now)
+                                     });
+    let reply = server.handle_request(&request).unwrap();
+    assert_eq!(reply.mode, NtpMode::Server);
+    assert_eq!(reply.version, 4);
+    assert_eq!(reply.originate_timestamp,
+               NtpTimestamp {
+                   seconds: 0xe91c_4f2a,
+                   fraction: 0x8000_0000,
+               });
+    assert_eq!(reply.receive_timestamp.fraction, 0x8000_0001);
+    assert_eq!(reply.transmit_timestamp.fraction, 0x8000_0002);
+    assert_eq!(NtpPacket::parse(&reply.as_bytes()), Ok(reply));
+
+    // replies and requests to an unsynchronized clock aren't answered
+    assert_eq!(server.handle_request(&reply.as_bytes()), None);
+    let mut server = SntpServer::new(|| None);
+    assert_eq!(server.handle_request(&request), None);
+}
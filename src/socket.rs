@@ -0,0 +1,301 @@
+//! Non-blocking socket handles in the style of the `nb` crate.
+//!
+//! The handles don't own any state, they only name a socket of an `Interface`.
+//! Each operation returns `nb::Error::WouldBlock` until `Interface::receive` or
+//! `Interface::poll` made enough progress, so it can be retried from a superloop or
+//! wrapped in `block!` or a future by the executor.
+
+use core::cmp;
+use nb;
+use interface::{Interface, SendError};
+use ipv4::{Endpoint, Ipv4Packet};
+#[cfg(any(test, feature = "tcp"))]
+use tcp::{TcpConnection, TcpState};
+use udp::UdpPacket;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketError {
+    /// The connection doesn't exist (anymore) or can't transfer data in its state.
+    Closed,
+    /// There is no route to the destination.
+    NoRoute,
+    /// The datagram doesn't fit into the MTU of the interface.
+    TooLarge,
+}
+
+/// A bound UDP port of an `Interface`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdpSocket {
+    port: u16,
+}
+
+impl UdpSocket {
+    pub fn bind(interface: &mut Interface, port: u16) -> UdpSocket {
+        interface.bind_udp(port);
+        UdpSocket { port }
+    }
+
+    /// Binds an ephemeral port, see `Interface::allocate_port`. Returns `None` if
+    /// all ports are in use.
+    pub fn bind_ephemeral(interface: &mut Interface) -> Option<UdpSocket> {
+        interface.allocate_port().map(|port| UdpSocket::bind(interface, port))
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Copies the next datagram into `buf` and returns its length and sender. A
+    /// datagram longer than `buf` is truncated.
+    pub fn recv(&self,
+                interface: &mut Interface,
+                buf: &mut [u8])
+                -> nb::Result<(usize, Endpoint), SocketError> {
+        self.recv_timestamped(interface, buf).map(|(len, src, _)| (len, src))
+    }
+
+    /// Like `recv`, but also returns the receive timestamp of the datagram, see
+    /// `Interface::receive_timestamped`.
+    pub fn recv_timestamped(&self,
+                            interface: &mut Interface,
+                            buf: &mut [u8])
+                            -> nb::Result<(usize, Endpoint, Option<u64>), SocketError> {
+        match interface.receive_udp_timestamped(self.port) {
+            Some((src, timestamp, data)) => {
+                let len = cmp::min(buf.len(), data.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                Ok((len, src, timestamp))
+            }
+            None => Err(nb::Error::WouldBlock),
+        }
+    }
+
+    /// Queues a datagram for sending. Blocks while too many packets wait for ARP
+    /// resolution.
+    pub fn send(&self,
+                interface: &mut Interface,
+                remote: Endpoint,
+                data: &[u8])
+                -> nb::Result<(), SocketError> {
+        if !interface.has_route(remote.addr) {
+            return Err(nb::Error::Other(SocketError::NoRoute));
+        }
+        let udp = UdpPacket::new(self.port, remote.port, data);
+        let packet = Ipv4Packet::new_udp(interface.ip(), remote.addr, udp);
+        interface.send_ipv4(packet).map_err(|error| match error {
+            SendError::NoRoute => nb::Error::Other(SocketError::NoRoute),
+            SendError::ExceedsMtu { .. } => nb::Error::Other(SocketError::TooLarge),
+            _ => nb::Error::WouldBlock,
+        })
+    }
+}
+
+/// A connection accepted by a TCP listener of an `Interface`.
+///
+/// The listener needs enabled receive buffers, see
+/// `TcpListener::enable_receive_buffers`. Otherwise the received data goes to the
+/// handler of the listener instead.
+#[cfg(any(test, feature = "tcp"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpSocket {
+    local_port: u16,
+    remote: Endpoint,
+}
+
+#[cfg(any(test, feature = "tcp"))]
+impl TcpSocket {
+    pub fn new(local_port: u16, remote: Endpoint) -> TcpSocket {
+        TcpSocket { local_port, remote }
+    }
+
+    pub fn remote(&self) -> Endpoint {
+        self.remote
+    }
+
+    fn connection<'a>(&self,
+                      interface: &'a mut Interface)
+                      -> nb::Result<&'a mut TcpConnection, SocketError> {
+        interface
+            .tcp_listener(self.local_port)
+            .and_then(|listener| listener.connection(self.remote))
+            .ok_or(nb::Error::Other(SocketError::Closed))
+    }
+
+    /// Reads buffered data into `buf`. Returns `Ok(0)` once the peer closed the
+    /// connection and all data was read.
+    pub fn read(&self,
+                interface: &mut Interface,
+                buf: &mut [u8])
+                -> nb::Result<usize, SocketError> {
+        let connection = self.connection(interface)?;
+        match connection.read(buf) {
+            0 if buf.is_empty() => Ok(0),
+            0 => {
+                match connection.state() {
+                    TcpState::SynReceived | TcpState::Established | TcpState::FinWait1 |
+                    TcpState::FinWait2 => Err(nb::Error::WouldBlock),
+                    _ => Ok(0),
+                }
+            }
+            len => Ok(len),
+        }
+    }
+
+    /// The receive timestamp of the last segment from the peer, see
+    /// `Interface::receive_timestamped`.
+    pub fn rx_timestamp(&self, interface: &mut Interface) -> nb::Result<Option<u64>, SocketError> {
+        self.connection(interface).map(|connection| connection.rx_timestamp())
+    }
+
+    /// Queues data for sending and returns the number of bytes that fit into the send
+    /// buffer. The segments are sent by the next `Interface::poll`.
+    pub fn write(&self,
+                 interface: &mut Interface,
+                 data: &[u8])
+                 -> nb::Result<usize, SocketError> {
+        let connection = self.connection(interface)?;
+        match connection.state() {
+            TcpState::Established | TcpState::CloseWait => {}
+            TcpState::SynReceived => return Err(nb::Error::WouldBlock),
+            _ => return Err(nb::Error::Other(SocketError::Closed)),
+        }
+        match connection.send(data) {
+            0 if !data.is_empty() => Err(nb::Error::WouldBlock),
+            len => Ok(len),
+        }
+    }
+
+    /// Sends a FIN after the buffered data. After the peer closed its side, `read`
+    /// returns `Ok(0)` but `write` still works until this is called.
+    pub fn close(&self, interface: &mut Interface) -> Result<(), SocketError> {
+        match self.connection(interface) {
+            Ok(connection) => {
+                connection.close();
+                Ok(())
+            }
+            Err(_) => Err(SocketError::Closed),
+        }
+    }
+}
+
+#[test]
+fn udp_and_tcp_sockets() {
+    use alloc::boxed::Box;
+    use ethernet::{EthernetAddress, EthernetKind, EthernetPacket};
+    use ipv4::{Ipv4Address, Ipv4Kind};
+    use parse::parse;
+    use tcp::{TcpKind, TcpListener};
+    use testing::{frames, tcp_ack_for};
+    use udp::UdpKind;
+    use {HeapTxPacket, TxPacket, WriteOut};
+
+    let mac = EthernetAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    let ip = Ipv4Address::new(192, 168, 1, 1);
+    let client_mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let client = Ipv4Address::new(192, 168, 1, 100);
+    let mut interface = Interface::new(mac, ip);
+    interface.arp_cache_insert(client, client_mac);
+
+    let payload = |frame: Box<[u8]>| -> Vec<u8> {
+        match parse(&frame).unwrap().payload {
+            EthernetKind::Ipv4(ip) => {
+                match ip.payload {
+                    Ipv4Kind::Udp(UdpPacket { payload: UdpKind::Unknown(data), .. }) => {
+                        data.to_vec()
+                    }
+                    Ipv4Kind::Tcp(tcp) => {
+                        let TcpKind::Unknown(data) = tcp.payload;
+                        data.to_vec()
+                    }
+                    other => panic!("unexpected packet {:?}", other),
+                }
+            }
+            other => panic!("unexpected packet {:?}", other),
+        }
+    };
+
+    let udp = UdpSocket::bind(&mut interface, 7);
+    let mut buf = [0; 16];
+    assert_eq!(udp.recv(&mut interface, &mut buf), Err(nb::Error::WouldBlock));
+
+    let request = Ipv4Packet::new_udp(client, ip, UdpPacket::new(1000, 7, &b"ping"[..]));
+    let mut frame = HeapTxPacket::new(60);
+    EthernetPacket::new_ipv4(client_mac, mac, request).write_out(&mut frame).unwrap();
+    let padding = 60 - frame.len();
+    frame.push_bytes(&vec![0; padding]).unwrap();
+    interface.receive(&frame).unwrap();
+
+    let (len, src) = udp.recv(&mut interface, &mut buf).unwrap();
+    assert_eq!((&buf[..len], src), (&b"ping"[..], Endpoint::new(client, 1000)));
+    udp.send(&mut interface, src, b"pong").unwrap();
+    assert_eq!(payload(interface.poll(0).unwrap()), b"pong");
+
+    let mut listener = TcpListener::new(Endpoint::new(ip, 80), 4, 2);
+    listener.enable_receive_buffers(64);
+    interface.add_tcp_listener(listener, |_, _| None);
+    let tcp = TcpSocket::new(80, Endpoint::new(client, 0xc000));
+    assert_eq!(tcp.read(&mut interface, &mut buf),
+               Err(nb::Error::Other(SocketError::Closed)));
+
+    interface.receive(&hex!(frames::TCP_SYN)).unwrap();
+    assert_eq!(tcp.write(&mut interface, b"hi"), Err(nb::Error::WouldBlock));
+    let syn_ack = interface.poll(0).unwrap();
+    interface.receive(&tcp_ack_for(&syn_ack)).unwrap();
+    assert_eq!(tcp.read(&mut interface, &mut buf), Err(nb::Error::WouldBlock));
+    assert_eq!(tcp.write(&mut interface, b"hi"), Ok(2));
+    assert_eq!(payload(interface.poll(0).unwrap()), b"hi");
+}
+
+#[test]
+fn receive_timestamps() {
+    use ethernet::{EthernetAddress, EthernetPacket};
+    use ipv4::Ipv4Address;
+    use tcp::TcpListener;
+    use testing::{frames, tcp_ack_for};
+    use HeapTxPacket;
+
+    let mac = EthernetAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    let ip = Ipv4Address::new(192, 168, 1, 1);
+    let client_mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let client = Ipv4Address::new(192, 168, 1, 100);
+    let mut interface = Interface::new(mac, ip);
+    interface.arp_cache_insert(client, client_mac);
+
+    let udp = UdpSocket::bind(&mut interface, 7);
+    let mut buf = [0; 16];
+    let request = Ipv4Packet::new_udp(client, ip, UdpPacket::new(1000, 7, &b"ping"[..]));
+    let frame = EthernetPacket::new_ipv4(client_mac, mac, request);
+    let frame = HeapTxPacket::write_out(frame).unwrap();
+    interface.receive_timestamped(&frame, 1234).unwrap();
+    interface.receive(&frame).unwrap();
+    let (len, src, timestamp) = udp.recv_timestamped(&mut interface, &mut buf).unwrap();
+    assert_eq!((&buf[..len], src), (&b"ping"[..], Endpoint::new(client, 1000)));
+    assert_eq!(timestamp, Some(1234));
+    let (_, _, timestamp) = udp.recv_timestamped(&mut interface, &mut buf).unwrap();
+    assert_eq!(timestamp, None);
+
+    interface.add_tcp_listener(TcpListener::new(Endpoint::new(ip, 80), 4, 2), |_, _| None);
+    let tcp = TcpSocket::new(80, Endpoint::new(client, 0xc000));
+    interface.receive_timestamped(&hex!(frames::TCP_SYN), 5000).unwrap();
+    assert_eq!(tcp.rx_timestamp(&mut interface), Ok(Some(5000)));
+    let syn_ack = interface.poll(0).unwrap();
+    interface.receive_timestamped(&tcp_ack_for(&syn_ack), 5678).unwrap();
+    assert_eq!(tcp.rx_timestamp(&mut interface), Ok(Some(5678)));
+}
+
+#[test]
+fn bind_ephemeral() {
+    use ethernet::EthernetAddress;
+    use ipv4::Ipv4Address;
+
+    let mac = EthernetAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    let mut interface = Interface::new(mac, Ipv4Address::new(192, 168, 1, 1));
+    interface.port_allocator().set_range(50000, 50001).unwrap();
+
+    let first = UdpSocket::bind_ephemeral(&mut interface).unwrap();
+    let second = UdpSocket::bind_ephemeral(&mut interface).unwrap();
+    let mut ports = [first.port(), second.port()];
+    ports.sort();
+    assert_eq!(ports, [50000, 50001]);
+    assert_eq!(UdpSocket::bind_ephemeral(&mut interface), None);
+}
@@ -0,0 +1,148 @@
+//! Traffic counters, for exposing the health of a device.
+//!
+//! All counters wrap around on overflow, like the counters of SNMP.
+
+use byteorder::{ByteOrder, NetworkEndian};
+use ethernet::EtherType;
+use ipv4::IpProtocol;
+use parse::{Layer, ParseError};
+
+/// Frames received and sent with one protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ProtocolCounters {
+    pub rx: u32,
+    pub tx: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Stats {
+    pub rx_frames: u32,
+    pub rx_bytes: u32,
+    pub tx_frames: u32,
+    pub tx_bytes: u32,
+    /// Received frames that failed to parse, including checksum errors.
+    pub parse_errors: u32,
+    pub checksum_errors: u32,
+    /// Outgoing packets that needed an ARP request first.
+    pub arp_misses: u32,
+    /// Frames that were dropped, e.g. because a queue was full or an echo request
+    /// wasn't answered.
+    pub dropped_frames: u32,
+    /// Echo requests to us that weren't answered, because of the echo policy, the
+    /// payload limit, or the ICMP rate limit.
+    pub dropped_echo_requests: u32,
+    pub tcp_retransmissions: u32,
+    pub arp: ProtocolCounters,
+    pub ipv4: ProtocolCounters,
+    pub icmp: ProtocolCounters,
+    pub udp: ProtocolCounters,
+    pub tcp: ProtocolCounters,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats::default()
+    }
+
+    /// Counts a received frame and its protocol.
+    pub fn record_rx(&mut self, frame: &[u8]) {
+        self.rx_frames = self.rx_frames.wrapping_add(1);
+        self.rx_bytes = self.rx_bytes.wrapping_add(frame.len() as u32);
+        self.record_protocols(frame, |counters| counters.rx = counters.rx.wrapping_add(1));
+    }
+
+    /// Counts a sent frame and its protocol.
+    pub fn record_tx(&mut self, frame: &[u8]) {
+        self.tx_frames = self.tx_frames.wrapping_add(1);
+        self.tx_bytes = self.tx_bytes.wrapping_add(frame.len() as u32);
+        self.record_protocols(frame, |counters| counters.tx = counters.tx.wrapping_add(1));
+    }
+
+    pub fn record_parse_error(&mut self, error: &ParseError) {
+        self.parse_errors = self.parse_errors.wrapping_add(1);
+        if let ParseError::Checksum { .. } = *error {
+            self.checksum_errors = self.checksum_errors.wrapping_add(1);
+        }
+    }
+
+    pub fn record_arp_miss(&mut self) {
+        self.arp_misses = self.arp_misses.wrapping_add(1);
+    }
+
+    pub fn record_drop(&mut self) {
+        self.dropped_frames = self.dropped_frames.wrapping_add(1);
+    }
+
+    /// Also counts the request as a dropped frame, whichever check dropped it.
+    pub fn record_dropped_echo_request(&mut self) {
+        self.record_drop();
+        self.dropped_echo_requests = self.dropped_echo_requests.wrapping_add(1);
+    }
+
+    /// The counters of `layer`, if they are kept.
+    pub fn protocol(&self, layer: Layer) -> Option<&ProtocolCounters> {
+        match layer {
+            Layer::Arp => Some(&self.arp),
+            Layer::Ipv4 => Some(&self.ipv4),
+            Layer::Icmp => Some(&self.icmp),
+            Layer::Udp => Some(&self.udp),
+            Layer::Tcp => Some(&self.tcp),
+            _ => None,
+        }
+    }
+
+    fn protocol_mut(&mut self, layer: Layer) -> Option<&mut ProtocolCounters> {
+        match layer {
+            Layer::Arp => Some(&mut self.arp),
+            Layer::Ipv4 => Some(&mut self.ipv4),
+            Layer::Icmp => Some(&mut self.icmp),
+            Layer::Udp => Some(&mut self.udp),
+            Layer::Tcp => Some(&mut self.tcp),
+            _ => None,
+        }
+    }
+
+    /// Counts the network and transport protocol of a frame, based on the EtherType
+    /// and IPv4 protocol fields.
+    fn record_protocols<F>(&mut self, frame: &[u8], count: F)
+        where F: Fn(&mut ProtocolCounters)
+    {
+        if frame.len() < 14 {
+            return;
+        }
+        let ether_type = EtherType::from_number(NetworkEndian::read_u16(&frame[12..14]));
+        let network = Layer::from_ether_type(ether_type);
+        if let Some(counters) = network.and_then(|layer| self.protocol_mut(layer)) {
+            count(counters);
+        }
+        if network != Some(Layer::Ipv4) {
+            return;
+        }
+        let transport = frame.get(14 + 9)
+            .and_then(|&number| Layer::from_ip_protocol(IpProtocol::from_number(number)));
+        if let Some(counters) = transport.and_then(|layer| self.protocol_mut(layer)) {
+            count(counters);
+        }
+    }
+}
+
+#[test]
+fn protocol_counters() {
+    use testing::frames;
+
+    let mut stats = Stats::new();
+    stats.record_rx(&hex!(frames::TCP_SYN));
+    stats.record_rx(&hex!(frames::DNS_REPLY));
+    stats.record_tx(&hex!(frames::TCP_SYN_ACK));
+    assert_eq!(stats.rx_frames, 2);
+    assert_eq!(stats.ipv4, ProtocolCounters { rx: 2, tx: 1 });
+    assert_eq!(stats.tcp, ProtocolCounters { rx: 1, tx: 1 });
+    assert_eq!(stats.udp, ProtocolCounters { rx: 1, tx: 0 });
+    assert_eq!(stats.arp, ProtocolCounters::default());
+    assert_eq!(stats.protocol(Layer::Udp), Some(&stats.udp));
+    assert_eq!(stats.protocol(Layer::Dhcp), None);
+
+    stats.record_parse_error(&ParseError::checksum(::Layer::Udp, 6));
+    stats.record_parse_error(&ParseError::truncated(::Layer::Ipv4, 3));
+    assert_eq!((stats.parse_errors, stats.checksum_errors), (2, 1));
+}
@@ -0,0 +1,176 @@
+use core::fmt;
+use ethernet::{EthernetAddress, EthernetPacket};
+use ipv4::{Ipv4Address, Ipv4Packet};
+use udp::{self, UdpPacket};
+
+pub const SYSLOG_PORT: u16 = 514;
+
+/// Sends `message` to the syslog collector at `collector_ip`.
+pub fn new_syslog_packet<'a>(src_mac: EthernetAddress,
+                             dst_mac: EthernetAddress,
+                             src_ip: Ipv4Address,
+                             collector_ip: Ipv4Address,
+                             message: &'a [u8])
+                             -> EthernetPacket<Ipv4Packet<UdpPacket<&'a [u8]>>> {
+    udp::new_udp_packet(src_mac,
+                        dst_mac,
+                        src_ip,
+                        collector_ip,
+                        SYSLOG_PORT,
+                        SYSLOG_PORT,
+                        message)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Facility {
+    Kernel,
+    User,
+    Mail,
+    Daemon,
+    Auth,
+    Syslog,
+    /// `Local(0)` to `Local(7)`.
+    Local(u8),
+}
+
+impl Facility {
+    /// Fails for `Local` facilities above 7.
+    pub fn number(&self) -> Result<u8, ()> {
+        match *self {
+            Facility::Kernel => Ok(0),
+            Facility::User => Ok(1),
+            Facility::Mail => Ok(2),
+            Facility::Daemon => Ok(3),
+            Facility::Auth => Ok(4),
+            Facility::Syslog => Ok(5),
+            Facility::Local(n) if n < 8 => Ok(16 + n),
+            Facility::Local(_) => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Emergency,
+    Alert,
+    Critical,
+    Error,
+    Warning,
+    Notice,
+    Informational,
+    Debug,
+}
+
+impl Severity {
+    pub fn number(&self) -> u8 {
+        *self as u8
+    }
+}
+
+/// The header fields of an [RFC 5424][rfc5424] message.
+///
+/// The timestamp, process id, and message id are sent as nil values, so the collector
+/// stamps the message with its arrival time.
+///
+/// [rfc5424]: https://tools.ietf.org/html/rfc5424
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyslogHeader<'a> {
+    pub facility: Facility,
+    pub severity: Severity,
+    pub hostname: &'a str,
+    pub app_name: &'a str,
+}
+
+/// A syslog message that is formatted into a caller-provided buffer.
+///
+/// The header is written on creation, the message text is appended through
+/// `core::fmt::Write`. Text that doesn't fit is cut off.
+pub struct SyslogMessage<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+    truncated: bool,
+}
+
+impl<'b> SyslogMessage<'b> {
+    /// Fails if the header has an invalid facility.
+    pub fn new(buf: &'b mut [u8], header: &SyslogHeader) -> Result<Self, ()> {
+        use core::fmt::Write;
+
+        fn or_nil(value: &str) -> &str {
+            if value.is_empty() { "-" } else { value }
+        }
+
+        let priority = header.facility.number()? * 8 + header.severity.number();
+        let mut message = SyslogMessage {
+            buf,
+            len: 0,
+            truncated: false,
+        };
+        let _ = write!(message,
+                       "<{}>1 - {} {} - - - ",
+                       priority,
+                       or_nil(header.hostname),
+                       or_nil(header.app_name));
+        Ok(message)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    /// Returns true if some text didn't fit into the buffer.
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
+impl<'b> fmt::Write for SyslogMessage<'b> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let bytes = s.as_bytes();
+        let len = usize::min(bytes.len(), self.buf.len() - self.len);
+        self.buf[self.len..(self.len + len)].copy_from_slice(&bytes[..len]);
+        self.len += len;
+        if len < bytes.len() {
+            self.truncated = true;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn format_message() {
+    use core::fmt::Write;
+    use {HeapTxPacket, WriteOut};
+
+    let header = SyslogHeader {
+        facility: Facility::Local(3),
+        severity: Severity::Warning,
+        hostname: "sensor-7",
+        app_name: "",
+    };
+    let mut buf = [0; 64];
+    let mut message = SyslogMessage::new(&mut buf, &header).unwrap();
+    write!(message, "battery at {}%", 9).unwrap();
+    assert_eq!(message.as_bytes(),
+               &b"<156>1 - sensor-7 - - - - battery at 9%"[..]);
+    assert!(!message.is_truncated());
+
+    let mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let packet = new_syslog_packet(mac,
+                                   mac,
+                                   Ipv4Address::new(192, 168, 1, 2),
+                                   Ipv4Address::new(192, 168, 1, 1),
+                                   message.as_bytes());
+    let mut tx_packet = HeapTxPacket::new(packet.len());
+    packet.write_out(&mut tx_packet).unwrap();
+    assert_eq!(&tx_packet.as_slice()[36..38], &[0x02, 0x02]); // destination port 514
+
+    let mut small = [0; 30];
+    let mut message = SyslogMessage::new(&mut small, &header).unwrap();
+    write!(message, "battery at {}%", 9).unwrap();
+    assert!(message.is_truncated());
+    assert_eq!(message.as_bytes().len(), 30);
+
+    let header = SyslogHeader { facility: Facility::Local(8), ..header };
+    assert!(SyslogMessage::new(&mut small, &header).is_err());
+}
@@ -1,12 +1,19 @@
 use {TxPacket, WriteOut};
-use ip_checksum;
+use ip_checksum::ChecksummedTxPacket;
+use tlv::TlvFormat;
 use byteorder::{ByteOrder, NetworkEndian};
-use ipv4::Ipv4Address;
-use bit_field::BitField;
+#[cfg(any(test, feature = "alloc"))]
+use ipv4::{ConnectionId, Endpoint, Ipv4Address};
+use retry::{Backoff, Policy};
+#[cfg(any(test, feature = "alloc"))]
+use core::fmt;
 use core::num::Wrapping;
+#[cfg(any(test, feature = "alloc"))]
 use alloc::borrow::Cow;
+#[cfg(any(test, feature = "alloc"))]
 use alloc::boxed::Box;
-use alloc::{Vec, BTreeMap};
+#[cfg(any(test, feature = "alloc"))]
+use alloc::{Vec, VecDeque, BTreeMap, BTreeSet};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct TcpHeader {
@@ -14,66 +21,294 @@ pub struct TcpHeader {
     pub dst_port: u16,
     pub sequence_number: Wrapping<u32>,
     pub ack_number: Wrapping<u32>,
-    pub options: TcpOptions,
+    pub flags: TcpFlags,
     pub window_size: u16,
+    /// The offset of the first byte after the urgent data. Only valid if URG is set.
+    pub urgent_pointer: u16,
+    /// The sender accepts SACK options. Only sent on SYN segments.
+    pub sack_permitted: bool,
+    /// Out-of-order data held by the sender, according to [RFC 2018][rfc2018].
+    ///
+    /// [rfc2018]: https://tools.ietf.org/html/rfc2018
+    pub sack_blocks: [Option<SackBlock>; MAX_SACK_BLOCKS],
+    /// The timestamp option according to [RFC 7323][rfc7323].
+    ///
+    /// [rfc7323]: https://tools.ietf.org/html/rfc7323
+    pub timestamp: Option<TcpTimestamp>,
 }
 
+impl TcpHeader {
+    fn options_len(&self) -> usize {
+        let blocks = self.sack_blocks.iter().filter(|b| b.is_some()).count();
+        let mut len = 0;
+        // each option is aligned by two NOPs
+        if self.sack_permitted {
+            len += 2 + OPTIONS.option_len(0);
+        }
+        if self.timestamp.is_some() {
+            len += 2 + OPTIONS.option_len(8);
+        }
+        if blocks > 0 {
+            len += 2 + OPTIONS.option_len(8 * blocks);
+        }
+        len
+    }
+}
+
+/// Builds a `TcpHeader`. Unset fields are zero, or empty for the SACK options.
+///
+/// The header length is not part of the header, it is derived from the options
+/// when the packet is written.
+#[derive(Debug, Clone, Copy)]
+pub struct TcpHeaderBuilder {
+    header: TcpHeader,
+}
+
+impl TcpHeaderBuilder {
+    pub fn new(src_port: u16, dst_port: u16) -> Self {
+        TcpHeaderBuilder {
+            header: TcpHeader {
+                src_port,
+                dst_port,
+                sequence_number: Wrapping(0),
+                ack_number: Wrapping(0),
+                flags: TcpFlags::empty(),
+                window_size: 0,
+                urgent_pointer: 0,
+                sack_permitted: false,
+                sack_blocks: [None; MAX_SACK_BLOCKS],
+                timestamp: None,
+            },
+        }
+    }
+
+    pub fn sequence_number(mut self, sequence_number: u32) -> Self {
+        self.header.sequence_number = Wrapping(sequence_number);
+        self
+    }
+
+    pub fn ack_number(mut self, ack_number: u32) -> Self {
+        self.header.ack_number = Wrapping(ack_number);
+        self
+    }
+
+    /// Sets all flags at once, e.g. `TcpFlags::FIN | TcpFlags::ACK | TcpFlags::PSH`.
+    pub fn flags(mut self, flags: TcpFlags) -> Self {
+        self.header.flags = flags;
+        self
+    }
+
+    pub fn window_size(mut self, window_size: u16) -> Self {
+        self.header.window_size = window_size;
+        self
+    }
+
+    /// Sets the urgent pointer and the URG flag.
+    pub fn urgent_pointer(mut self, urgent_pointer: u16) -> Self {
+        self.header.urgent_pointer = urgent_pointer;
+        self.header.flags |= TcpFlags::URG;
+        self
+    }
+
+    pub fn sack_permitted(mut self, sack_permitted: bool) -> Self {
+        self.header.sack_permitted = sack_permitted;
+        self
+    }
+
+    pub fn sack_blocks(mut self, sack_blocks: [Option<SackBlock>; MAX_SACK_BLOCKS]) -> Self {
+        self.header.sack_blocks = sack_blocks;
+        self
+    }
+
+    pub fn timestamp(mut self, timestamp: TcpTimestamp) -> Self {
+        self.header.timestamp = Some(timestamp);
+        self
+    }
+
+    pub fn build(self) -> TcpHeader {
+        self.header
+    }
+}
+
+/// The number of SACK blocks that fit into the options next to a timestamp.
+pub const MAX_SACK_BLOCKS: usize = 3;
+
+/// A range of received sequence numbers. `right` is the first sequence number after
+/// the block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SackBlock {
+    pub left: Wrapping<u32>,
+    pub right: Wrapping<u32>,
+}
+
+impl SackBlock {
+    #[cfg(any(test, feature = "alloc"))]
+    fn contains(&self, start: Wrapping<u32>, end: Wrapping<u32>) -> bool {
+        let size = (self.right - self.left).0;
+        (start - self.left).0 <= size && (end - self.left).0 <= size
+    }
+}
+
+/// The clock of the sender in `value`, and the most recent `value` received from the
+/// peer in `echo_reply`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpTimestamp {
+    pub value: u32,
+    pub echo_reply: u32,
+}
+
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_SACK_PERMITTED: u8 = 4;
+const OPTION_SACK: u8 = 5;
+const OPTION_TIMESTAMP: u8 = 8;
+
+/// The option length includes the kind and length bytes.
+const OPTIONS: TlvFormat = TlvFormat {
+    pad: OPTION_NOP,
+    end: OPTION_END,
+    len_includes_header: true,
+};
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct TcpPacket<T> {
     pub header: TcpHeader,
     pub payload: T,
 }
 
-impl<'a, T: WriteOut> WriteOut for &'a TcpPacket<T> {
+/// The checksum only covers the TCP bytes. Wrap the packet in an `Ipv4Packet` to add
+/// the pseudo header.
+impl<T: WriteOut> WriteOut for TcpPacket<T> {
     fn len(&self) -> usize {
-        self.payload.len() + 6 * 2 + 2 * 4
+        self.payload.len() + 6 * 2 + 2 * 4 + self.header.options_len()
     }
 
     fn write_out<P: TxPacket>(&self, packet: &mut P) -> Result<(), ()> {
-        let start_index = packet.len();
+        let mut packet = ChecksummedTxPacket::new(packet);
 
         packet.push_u16(self.header.src_port)?;
         packet.push_u16(self.header.dst_port)?;
         packet.push_u32(self.header.sequence_number.0)?;
         packet.push_u32(self.header.ack_number.0)?;
-        packet.push_u16(self.header.options.bits())?;
+        let header_len = ((20 + self.header.options_len()) / 4) as u16;
+        packet.push_u16(self.header.flags.bits() | (header_len << 12))?;
         packet.push_u16(self.header.window_size)?;
         let checksum_idx = packet.push_u16(0)?; // checksum
-        packet.push_u16(0)?; // urgent pointer
+        packet.push_u16(self.header.urgent_pointer)?;
+
+        if self.header.sack_permitted {
+            packet.push_bytes(&[OPTION_NOP, OPTION_NOP])?;
+            OPTIONS.push(&mut packet, OPTION_SACK_PERMITTED, &[])?;
+        }
+        if let Some(timestamp) = self.header.timestamp {
+            packet.push_bytes(&[OPTION_NOP, OPTION_NOP])?;
+            let option = OPTIONS.start(&mut packet, OPTION_TIMESTAMP)?;
+            packet.push_u32(timestamp.value)?;
+            packet.push_u32(timestamp.echo_reply)?;
+            option.finish(&mut packet)?;
+        }
+        if self.header.sack_blocks.iter().any(|b| b.is_some()) {
+            packet.push_bytes(&[OPTION_NOP, OPTION_NOP])?;
+            let option = OPTIONS.start(&mut packet, OPTION_SACK)?;
+            for block in self.header.sack_blocks.iter().filter_map(|b| *b) {
+                packet.push_u32(block.left.0)?;
+                packet.push_u32(block.right.0)?;
+            }
+            option.finish(&mut packet)?;
+        }
 
-        self.payload.write_out(packet)?;
-        let end_index = packet.len();
+        let payload_start = packet.len();
+        self.payload.write_out(&mut packet)?;
+        let payload_end = packet.len();
+        packet.mark_payload(payload_start..payload_end);
 
-        // calculate tcp checksum (without pseudo header)
-        let checksum = !ip_checksum::data(&packet[start_index..end_index]);
+        // tcp checksum (without pseudo header)
+        let checksum = !packet.checksum();
         packet.set_u16(checksum_idx, checksum);
 
         Ok(())
     }
+
+    fn pseudo_header_checksum_offset(&self) -> Option<usize> {
+        Some(16)
+    }
 }
 
-use parse::{Parse, ParseError};
+use parse::{Layer, Parse, ParseError};
 
 impl<'a> Parse<'a> for TcpPacket<&'a [u8]> {
     fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
         use bit_field::BitField;
 
+        if data.len() < 20 {
+            return Err(ParseError::truncated(Layer::Tcp, data.len()));
+        }
         let header_len = data[12].get_bits(4..8);
         let header_len_bytes = usize::from(header_len) * 4;
+        if header_len_bytes < 20 {
+            return Err(ParseError::malformed(Layer::Tcp, 12, "header length too small"));
+        }
+        if data.len() < header_len_bytes {
+            return Err(ParseError::truncated(Layer::Tcp, data.len()));
+        }
+        let mut header = TcpHeader {
+            src_port: NetworkEndian::read_u16(&data[0..2]),
+            dst_port: NetworkEndian::read_u16(&data[2..4]),
+            sequence_number: Wrapping(NetworkEndian::read_u32(&data[4..8])),
+            ack_number: Wrapping(NetworkEndian::read_u32(&data[8..12])),
+            // the data offset in the upper bits is not a flag and dropped
+            flags: TcpFlags::from_bits_truncate(NetworkEndian::read_u16(&data[12..14])),
+            window_size: NetworkEndian::read_u16(&data[14..16]),
+            urgent_pointer: NetworkEndian::read_u16(&data[18..20]),
+            sack_permitted: false,
+            sack_blocks: [None; MAX_SACK_BLOCKS],
+            timestamp: None,
+        };
+        parse_options(&data[20..header_len_bytes], &mut header)?;
         Ok(TcpPacket {
-               header: TcpHeader {
-                   src_port: NetworkEndian::read_u16(&data[0..2]),
-                   dst_port: NetworkEndian::read_u16(&data[2..4]),
-                   sequence_number: Wrapping(NetworkEndian::read_u32(&data[4..8])),
-                   ack_number: Wrapping(NetworkEndian::read_u32(&data[8..12])),
-                   options: TcpOptions::from_bits(NetworkEndian::read_u16(&data[12..14])),
-                   window_size: NetworkEndian::read_u16(&data[14..16]),
-               },
+               header,
                payload: &data[header_len_bytes..],
            })
     }
 }
 
+/// Parses the SACK and timestamp options into `header`. Other options are skipped.
+fn parse_options(options: &[u8], header: &mut TcpHeader) -> Result<(), ParseError> {
+    for option in OPTIONS.options(options, Layer::Tcp, 20) {
+        let option = option?;
+        let value = option.value;
+        match option.code {
+            OPTION_SACK_PERMITTED => header.sack_permitted = true,
+            OPTION_SACK => {
+                if value.len() % 8 != 0 {
+                    return Err(ParseError::malformed(Layer::Tcp,
+                                                     option.offset + 1,
+                                                     "invalid SACK length"));
+                }
+                for (slot, block) in header.sack_blocks.iter_mut().zip(value.chunks(8)) {
+                    *slot = Some(SackBlock {
+                                     left: Wrapping(NetworkEndian::read_u32(&block[0..4])),
+                                     right: Wrapping(NetworkEndian::read_u32(&block[4..8])),
+                                 });
+                }
+            }
+            OPTION_TIMESTAMP => {
+                if value.len() != 8 {
+                    return Err(ParseError::malformed(Layer::Tcp,
+                                                     option.offset + 1,
+                                                     "invalid timestamp length"));
+                }
+                header.timestamp = Some(TcpTimestamp {
+                                            value: NetworkEndian::read_u32(&value[0..4]),
+                                            echo_reply: NetworkEndian::read_u32(&value[4..8]),
+                                        });
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
 #[derive(Debug)]
 pub enum TcpKind<'a> {
     Unknown(&'a [u8]),
@@ -90,185 +325,2515 @@ impl<'a> Parse<'a> for TcpPacket<TcpKind<'a>> {
     }
 }
 
-#[derive(Debug)]
+#[cfg(any(test, feature = "alloc"))]
 pub struct TcpConnection {
-    src_ip: Ipv4Address,
-    dst_ip: Ipv4Address,
-    src_port: u16,
-    dst_port: u16,
+    id: ConnectionId,
     state: TcpState,
     sequence_number: Wrapping<u32>,
     ack_number: Wrapping<u32>,
     window_size: u16,
     packet_queue: BTreeMap<Wrapping<u32>, TcpPacket<Box<[u8]>>>,
+    /// Keys of `packet_queue` entries that still need to be (re)transmitted.
+    unsent: VecDeque<Wrapping<u32>>,
+    time_wait_deadline: Option<u64>,
+    send_buffer: VecDeque<u8>,
+    send_unacknowledged: Wrapping<u32>,
+    peer_window: u16,
+    congestion: CongestionControl,
+    retransmission_timeout: u64,
+    retransmission_deadline: Option<u64>,
+    retry: Policy,
+    fin_pending: bool,
+    /// Both sides sent the SACK-permitted option.
+    sack_permitted: bool,
+    /// Keys of `packet_queue` entries that the peer reported in SACK blocks.
+    sacked: BTreeSet<Wrapping<u32>>,
+    /// Received data after a gap, by sequence number.
+    out_of_order: BTreeMap<Wrapping<u32>, (Box<[u8]>, DataMarks)>,
+    last_out_of_order: Option<Wrapping<u32>>,
+    data_marks: DataMarks,
+    /// Received in-order data that wasn't read yet, if enabled.
+    receive_buffer: Option<Vec<u8>>,
+    receive_buffer_capacity: usize,
+    /// Consecutive retransmission timeouts without progress.
+    retransmissions: u8,
+    retransmitted_segments: u32,
+    events: VecDeque<TcpEvent>,
+    /// Disables Nagle's algorithm.
+    nodelay: bool,
+    close_on_peer_fin: bool,
+    /// The time of the last `poll`, sent in timestamp options.
+    now: u64,
+    /// A RST that answers an unacceptable segment. It isn't retransmitted.
+    reset_segment: Option<TcpPacket<Box<[u8]>>>,
+    /// Both sides sent the timestamp option. In `SynSent`, our SYN offers it.
+    timestamps: bool,
+    /// The timestamp that is echoed to the peer.
+    recent_timestamp: u32,
+    rtt: RttEstimator,
+    /// The receive timestamp of the last segment, see `rx_timestamp`.
+    rx_timestamp: Option<u64>,
+    /// The time of the last `poll` before the last segment was received.
+    last_received: u64,
+}
+
+/// A one-line summary of the connection state, without the buffered data.
+#[cfg(any(test, feature = "alloc"))]
+impl fmt::Debug for TcpConnection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "TcpConnection({:?} -> {:?} {:?} snd={} una={} rcv={} wnd={} in_flight={} \
+                rtx={})",
+               self.id.local,
+               self.id.remote,
+               self.state,
+               self.sequence_number,
+               self.send_unacknowledged,
+               self.ack_number,
+               self.peer_window,
+               self.bytes_in_flight(),
+               self.retransmissions)
+    }
+}
+
+/// A state change that the application has to react to, see `poll_event`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpEvent {
+    /// The handshake completed.
+    Established,
+    /// The peer sent a FIN and won't send more data.
+    PeerClosed,
+    /// The peer aborted the connection with a RST.
+    Reset,
+    /// The peer stopped acknowledging data, so the connection was given up.
+    TimedOut,
+    /// The connection reached `Closed` after a regular close.
+    Closed,
+    /// The connection was given up locally by `abort`.
+    Aborted,
+    /// The connection was reset locally by `TcpListener::evict_idle`, or evicted for a
+    /// new connection with a full backlog.
+    Evicted,
+}
+
+/// How the sender marked the data that is passed to the `handle_packet` callback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DataMarks {
+    /// PSH was set, so the data ends a message of the sender and shouldn't wait for
+    /// more data.
+    pub push: bool,
+    /// The number of urgent bytes at the start of the data, if URG was set.
+    pub urgent_len: Option<usize>,
+}
+
+#[cfg(any(test, feature = "alloc"))]
+impl DataMarks {
+    fn new(header: &TcpHeader, len: usize) -> Self {
+        DataMarks {
+            push: header.flags.contains(TcpFlags::PSH),
+            urgent_len: if header.flags.contains(TcpFlags::URG) {
+                Some(usize::min(usize::from(header.urgent_pointer), len))
+            } else {
+                None
+            },
+        }
+    }
+
+    /// The marks of the data after skipping its first `offset` bytes.
+    fn skip(self, offset: usize) -> Self {
+        DataMarks {
+            push: self.push,
+            urgent_len: match self.urgent_len {
+                Some(len) if len > offset => Some(len - offset),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// The maximum segment lifetime in milliseconds. Connections stay in `TimeWait` for
+/// twice this duration.
+pub const MAX_SEGMENT_LIFETIME: u64 = 60_000;
+
+/// The maximum segment size used for sending, unless it is derived from the MTU.
+pub const DEFAULT_MSS: usize = 536;
+
+/// The number of bytes `send` buffers before it refuses more data.
+pub const SEND_BUFFER_SIZE: usize = 8 * DEFAULT_MSS;
+
+/// The number of retransmission timeouts in a row after which the connection is
+/// given up.
+pub const MAX_RETRANSMISSIONS: u8 = 8;
+
+/// Retransmissions start with the measured timeout, or the initial delay before the
+/// first measurement, and back off exponentially.
+pub const DEFAULT_RETRY_POLICY: Policy = Policy {
+    initial_delay: INITIAL_RETRANSMISSION_TIMEOUT,
+    backoff: Backoff::Exponential,
+    max_delay: MAX_RETRANSMISSION_TIMEOUT,
+    max_retries: MAX_RETRANSMISSIONS,
+    jitter_percent: 0,
+};
+
+/// The retransmission timeout in milliseconds before the first RTT measurement.
+pub const INITIAL_RETRANSMISSION_TIMEOUT: u64 = 1000;
+
+/// The lower bound of the measured retransmission timeout in milliseconds. It is
+/// below the one second of RFC 6298, so that losses on a LAN are recovered quickly.
+pub const MIN_RETRANSMISSION_TIMEOUT: u64 = 200;
+
+const MAX_RETRANSMISSION_TIMEOUT: u64 = 60_000;
+
+/// Derives the initial sequence number of a connection like [RFC 6528][rfc6528]: a
+/// hash of the endpoints and a secret `key` (FNV-1a), plus a clock that ticks every
+/// 4 µs. Connections to different peers don't reveal each other's numbers.
+///
+/// [rfc6528]: https://tools.ietf.org/html/rfc6528
+#[cfg(any(test, feature = "alloc"))]
+fn initial_sequence_number(id: ConnectionId, key: u32, now: u64) -> Wrapping<u32> {
+    let mut bytes = [0; 16];
+    NetworkEndian::write_u32(&mut bytes[0..4], key);
+    bytes[4..8].copy_from_slice(&id.local.addr.as_bytes());
+    NetworkEndian::write_u16(&mut bytes[8..10], id.local.port);
+    bytes[10..14].copy_from_slice(&id.remote.addr.as_bytes());
+    NetworkEndian::write_u16(&mut bytes[14..16], id.remote.port);
+    let hash = bytes.iter().fold(0x811c_9dc5, |hash: u32, &byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(16_777_619)
+    });
+    Wrapping(hash) + Wrapping(now.wrapping_mul(250) as u32)
+}
+
+#[cfg(any(test, feature = "alloc"))]
+/// Congestion control state according to [RFC 5681][rfc5681].
+///
+/// [rfc5681]: https://tools.ietf.org/html/rfc5681
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CongestionControl {
+    mss: usize,
+    cwnd: usize,
+    ssthresh: usize,
+    duplicate_acks: u8,
+    fast_recovery: bool,
+}
+
+#[cfg(any(test, feature = "alloc"))]
+impl CongestionControl {
+    fn new(mss: usize) -> Self {
+        CongestionControl {
+            mss,
+            cwnd: usize::min(4 * mss, usize::max(2 * mss, 4380)),
+            ssthresh: usize::max_value(),
+            duplicate_acks: 0,
+            fast_recovery: false,
+        }
+    }
+
+    /// The congestion window in bytes.
+    pub fn window(&self) -> usize {
+        self.cwnd
+    }
+
+    pub fn slow_start_threshold(&self) -> usize {
+        self.ssthresh
+    }
+
+    pub fn in_fast_recovery(&self) -> bool {
+        self.fast_recovery
+    }
+
+    /// Called when an ACK acknowledges `acked` bytes of new data.
+    fn on_ack(&mut self, acked: usize) {
+        self.duplicate_acks = 0;
+        if self.fast_recovery {
+            self.fast_recovery = false;
+            self.cwnd = self.ssthresh;
+        } else if self.cwnd < self.ssthresh {
+            // slow start
+            self.cwnd += usize::min(acked, self.mss);
+        } else {
+            // congestion avoidance
+            self.cwnd += usize::max(1, self.mss * self.mss / self.cwnd);
+        }
+    }
+
+    /// Returns true if the oldest unacknowledged segment should be retransmitted.
+    fn on_duplicate_ack(&mut self, in_flight: usize) -> bool {
+        self.duplicate_acks = self.duplicate_acks.saturating_add(1);
+        if self.duplicate_acks == 3 {
+            self.ssthresh = usize::max(in_flight / 2, 2 * self.mss);
+            self.cwnd = self.ssthresh + 3 * self.mss;
+            self.fast_recovery = true;
+            true
+        } else {
+            if self.fast_recovery {
+                self.cwnd += self.mss;
+            }
+            false
+        }
+    }
+
+    fn on_timeout(&mut self, in_flight: usize) {
+        self.ssthresh = usize::max(in_flight / 2, 2 * self.mss);
+        self.cwnd = self.mss;
+        self.duplicate_acks = 0;
+        self.fast_recovery = false;
+    }
+}
+
+#[cfg(any(test, feature = "alloc"))]
+/// Round-trip time estimation according to [RFC 6298][rfc6298], from the echoed
+/// timestamps of acknowledgements.
+///
+/// [rfc6298]: https://tools.ietf.org/html/rfc6298
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RttEstimator {
+    srtt: Option<u64>,
+    rttvar: u64,
+}
+
+#[cfg(any(test, feature = "alloc"))]
+impl RttEstimator {
+    fn new() -> Self {
+        RttEstimator {
+            srtt: None,
+            rttvar: 0,
+        }
+    }
+
+    /// The smoothed round-trip time in milliseconds, if it was measured yet.
+    pub fn smoothed_rtt(&self) -> Option<u64> {
+        self.srtt
+    }
+
+    /// The retransmission timeout in milliseconds before any backoff.
+    pub fn retransmission_timeout(&self) -> u64 {
+        match self.srtt {
+            Some(srtt) => {
+                let timeout = srtt + u64::max(1, 4 * self.rttvar);
+                u64::min(u64::max(timeout, MIN_RETRANSMISSION_TIMEOUT),
+                         MAX_RETRANSMISSION_TIMEOUT)
+            }
+            None => INITIAL_RETRANSMISSION_TIMEOUT,
+        }
+    }
+
+    fn on_sample(&mut self, rtt: u64) {
+        match self.srtt {
+            Some(srtt) => {
+                let error = if srtt > rtt { srtt - rtt } else { rtt - srtt };
+                self.rttvar = (3 * self.rttvar + error) / 4;
+                self.srtt = Some((7 * srtt + rtt) / 8);
+            }
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+        }
+    }
 }
 
+#[cfg(any(test, feature = "alloc"))]
 impl TcpConnection {
-    pub fn new(id: (Ipv4Address, Ipv4Address, u16, u16)) -> TcpConnection {
+    pub fn new(id: ConnectionId) -> TcpConnection {
+        let sequence_number = initial_sequence_number(id, 0, 0);
         TcpConnection {
-            src_ip: id.0,
-            dst_ip: id.1,
-            src_port: id.2,
-            dst_port: id.3,
+            id,
             state: TcpState::Listen,
-            sequence_number: Wrapping(0x12345), // TODO random
+            sequence_number,
             ack_number: Wrapping(0),
             window_size: 1000, // TODO
             packet_queue: BTreeMap::new(),
+            unsent: VecDeque::new(),
+            time_wait_deadline: None,
+            send_buffer: VecDeque::new(),
+            send_unacknowledged: sequence_number,
+            peer_window: 0,
+            congestion: CongestionControl::new(DEFAULT_MSS),
+            retransmission_timeout: INITIAL_RETRANSMISSION_TIMEOUT,
+            retransmission_deadline: None,
+            retry: DEFAULT_RETRY_POLICY,
+            fin_pending: false,
+            sack_permitted: false,
+            sacked: BTreeSet::new(),
+            out_of_order: BTreeMap::new(),
+            last_out_of_order: None,
+            data_marks: DataMarks::default(),
+            receive_buffer: None,
+            receive_buffer_capacity: 0,
+            retransmissions: 0,
+            retransmitted_segments: 0,
+            events: VecDeque::new(),
+            nodelay: false,
+            close_on_peer_fin: false,
+            now: 0,
+            reset_segment: None,
+            timestamps: false,
+            recent_timestamp: 0,
+            rtt: RttEstimator::new(),
+            rx_timestamp: None,
+            last_received: 0,
         }
     }
 
-    pub fn handle_packet<'a, F>(&mut self, packet: &'a TcpPacket<&[u8]>, mut f: F)
-        where for<'d> F: FnMut(&TcpConnection, &'d [u8]) -> Option<Cow<'d, [u8]>>
-    {
-        let empty = Vec::new().into_boxed_slice();
+    fn queue(&mut self, packet: TcpPacket<Box<[u8]>>) {
+        let sequence_number = packet.header.sequence_number;
+        self.packet_queue.insert(sequence_number, packet);
+        if !self.unsent.contains(&sequence_number) {
+            self.unsent.push_back(sequence_number);
+        }
+    }
 
-        let reply = match self.state {
-            TcpState::Closed => None,
-            TcpState::Listen | TcpState::SynReceived if packet.header.options.flags == TcpFlags::SYN => {
-                self.ack_number = packet.header.sequence_number + Wrapping(1);
-                let header = TcpHeader {
-                    src_port: self.dst_port,
-                    dst_port: self.src_port,
-                    sequence_number: self.sequence_number,
-                    ack_number: self.ack_number,
-                    window_size: self.window_size,
-                    options: TcpOptions::new(TcpFlags::SYN | TcpFlags::ACK),
-                };
-                self.state = TcpState::SynReceived;
-                Some(TcpPacket {
-                    payload: Box::from(empty),
-                    header: header,
-                })
+    fn queue_fin(&mut self) {
+        let header = self.header(TcpFlags::FIN | TcpFlags::ACK);
+        self.sequence_number += Wrapping(1);
+        self.state = match self.state {
+            TcpState::CloseWait => TcpState::LastAck,
+            _ => TcpState::FinWait1,
+        };
+        self.queue(TcpPacket {
+                       header,
+                       payload: Vec::new().into_boxed_slice(),
+                   });
+    }
+
+    fn header(&self, flags: TcpFlags) -> TcpHeader {
+        let mut header = TcpHeaderBuilder::new(self.id.local.port, self.id.remote.port)
+            .sequence_number(self.sequence_number.0)
+            .ack_number(self.ack_number.0)
+            .flags(flags)
+            .window_size(self.receive_window())
+            .sack_blocks(self.sack_blocks())
+            .build();
+        header.timestamp = self.timestamp();
+        header
+    }
+
+    fn timestamp(&self) -> Option<TcpTimestamp> {
+        if self.timestamps {
+            Some(TcpTimestamp {
+                     value: self.now as u32,
+                     echo_reply: self.recent_timestamp,
+                 })
+        } else {
+            None
+        }
+    }
+
+    /// Describes the out-of-order data, starting with the block that contains the most
+    /// recently received segment.
+    fn sack_blocks(&self) -> [Option<SackBlock>; MAX_SACK_BLOCKS] {
+        let mut blocks = [None; MAX_SACK_BLOCKS];
+        if !self.sack_permitted {
+            return blocks;
+        }
+
+        let mut ranges: Vec<SackBlock> = Vec::new();
+        for (&start, &(ref data, _)) in &self.out_of_order {
+            let end = start + Wrapping(data.len() as u32);
+            let merged = match ranges.last_mut() {
+                Some(last) if start <= last.right => {
+                    last.right = Wrapping::max(last.right, end);
+                    true
+                }
+                _ => false,
+            };
+            if !merged {
+                ranges.push(SackBlock {
+                                left: start,
+                                right: end,
+                            });
             }
-            TcpState::SynReceived if packet.header.options.flags == TcpFlags::ACK => {
-                self.sequence_number += Wrapping(1);
-                self.state = TcpState::Established;
-                None
+        }
+        if let Some(recent) = self.last_out_of_order {
+            if let Some(i) = ranges.iter().position(|b| b.contains(recent, recent)) {
+                let block = ranges.remove(i);
+                ranges.insert(0, block);
             }
-            TcpState::LastAck if packet.header.options.flags == TcpFlags::ACK => {
-                self.state = TcpState::Closed;
-                self.packet_queue.clear(); // TODO remaining packets?
-                None
+        }
+        for (slot, block) in blocks.iter_mut().zip(ranges) {
+            *slot = Some(block);
+        }
+        blocks
+    }
+
+    /// Holds a segment that arrived after a gap, as far as the receive window allows.
+    fn store_out_of_order(&mut self, packet: &TcpPacket<&[u8]>) {
+        let buffered: usize = self.out_of_order.values().map(|&(ref data, _)| data.len()).sum();
+        if packet.payload.is_empty() ||
+           buffered + packet.payload.len() > usize::from(self.receive_window()) {
+            return;
+        }
+        let sequence_number = packet.header.sequence_number;
+        let marks = DataMarks::new(&packet.header, packet.payload.len());
+        self.out_of_order.insert(sequence_number, (Box::from(packet.payload), marks));
+        self.last_out_of_order = Some(sequence_number);
+    }
+
+    /// Removes the out-of-order data that is contiguous with `ack_number` now and
+    /// advances `ack_number` past it.
+    fn take_reassembled(&mut self) -> Vec<(Box<[u8]>, DataMarks)> {
+        let mut reassembled = Vec::new();
+        while let Some(&start) = self.out_of_order.keys().next() {
+            let ahead = (start - self.ack_number).0;
+            if ahead != 0 && ahead < u32::from(self.receive_window()) {
+                break; // there is still a gap
             }
-            TcpState::Established => {
-                if packet.header.sequence_number == self.ack_number {
-                    self.ack_number += Wrapping(packet.payload.len() as u32);
-                } else if packet.header.sequence_number < self.ack_number {
-                    // old packet, do nothing
-                    return;
-                } else {
-                    panic!("TCP packet out of order. Expected seq no: {}, received: {}", self.ack_number, packet.header.sequence_number);
-                }
+            let (data, marks) = self.out_of_order.remove(&start).unwrap();
+            let offset = (self.ack_number - start).0 as usize;
+            if offset < data.len() {
+                self.ack_number += Wrapping((data.len() - offset) as u32);
+                reassembled.push((Box::from(&data[offset..]), marks.skip(offset)));
+            }
+        }
+        if self.out_of_order.is_empty() {
+            self.last_out_of_order = None;
+        }
+        reassembled
+    }
 
-                if packet.header.options.flags == TcpFlags::ACK {
-                    self.packet_queue = self.packet_queue.split_off(&packet.header.ack_number); // TODO: efficient?
-                }
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
 
-                if packet.header.options.flags.contains(TcpFlags::FIN) {
-                    let options = TcpOptions::new(TcpFlags::ACK | TcpFlags::FIN);
-                    self.ack_number += Wrapping(1);
-                    let header = TcpHeader {
-                        src_port: self.dst_port,
-                        dst_port: self.src_port,
-                        sequence_number: self.sequence_number,
-                        ack_number: self.ack_number,
-                        window_size: 1000, // TODO
-                        options,
-                    };
-                    self.state = TcpState::LastAck;
-                    self.sequence_number += Wrapping(1);
-                    Some(TcpPacket {
-                        payload: empty,
-                        header: header,
-                    })
-                } else if packet.payload.len() == 0 {
-                    None
-                } else {
-                    let header = TcpHeader {
-                        src_port: self.dst_port,
-                        dst_port: self.src_port,
-                        sequence_number: self.sequence_number,
-                        ack_number: self.ack_number,
-                        window_size: self.window_size,
-                        options: TcpOptions::new(TcpFlags::ACK),
-                    };
-
-                    let reply = f(self, packet.payload).map(|payload| TcpPacket {
-                            header, payload: payload.into_owned().into_boxed_slice(),
-                        });
-                    if let Some(ref r) = reply {
-                        self.sequence_number += Wrapping(r.payload.len() as u32);
-                    }
-                    Some(reply.unwrap_or(TcpPacket { header, payload: empty }))
-                }
-            },
-            _ => None, // TODO
+    pub fn state(&self) -> TcpState {
+        self.state
+    }
+
+    /// Collects received data in a buffer of `capacity` bytes instead of passing it
+    /// to the `handle_packet` callback. Fetch it with `read`.
+    ///
+    /// The advertised window is the free space of the buffer, so a slow reader
+    /// throttles the peer.
+    pub fn enable_receive_buffer(&mut self, capacity: usize) {
+        if self.receive_buffer.is_none() {
+            self.receive_buffer = Some(Vec::with_capacity(capacity));
+        }
+        self.receive_buffer_capacity = capacity;
+    }
+
+    /// The buffered data, without consuming it.
+    pub fn peek(&self) -> &[u8] {
+        self.receive_buffer.as_ref().map(|buffer| &buffer[..]).unwrap_or(&[])
+    }
+
+    /// Moves buffered data into `buf` and returns the number of bytes.
+    ///
+    /// A window update is queued if the window was closed before.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let window_was_closed = self.receive_window() == 0;
+        let len = match self.receive_buffer {
+            Some(ref mut buffer) => {
+                let len = usize::min(buf.len(), buffer.len());
+                buf[..len].copy_from_slice(&buffer[..len]);
+                buffer.drain(..len);
+                len
+            }
+            None => 0,
         };
+        let receiving = match self.state {
+            TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2 => true,
+            _ => false,
+        };
+        if window_was_closed && len > 0 && receiving {
+            let header = self.header(TcpFlags::ACK);
+            self.queue(TcpPacket {
+                           header,
+                           payload: Vec::new().into_boxed_slice(),
+                       });
+        }
+        len
+    }
 
-        if let Some(reply) = reply {
-            self.packet_queue.insert(reply.header.sequence_number, reply);
+    fn receive_window(&self) -> u16 {
+        match self.receive_buffer {
+            Some(ref buffer) => {
+                let free = self.receive_buffer_capacity.saturating_sub(buffer.len());
+                usize::min(free, usize::from(u16::max_value())) as u16
+            }
+            None => self.window_size,
         }
     }
 
-    pub fn packets<'a>(&'a mut self) -> impl Iterator<Item = &'a TcpPacket<Box<[u8]>>> {
-        self.packet_queue.values()
+    /// The PSH and URG marks of the data that is currently passed to the
+    /// `handle_packet` callback.
+    pub fn data_marks(&self) -> DataMarks {
+        self.data_marks
     }
-}
 
-/// The state of a TCP socket, according to [RFC 793][rfc793].
-/// [rfc793]: https://tools.ietf.org/html/rfc793
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
-pub enum TcpState {
-    Closed,
-    Listen,
-    SynSent,
-    SynReceived,
-    Established,
-    FinWait1,
-    FinWait2,
-    CloseWait,
-    Closing,
-    LastAck,
-    TimeWait
-}
+    pub fn congestion(&self) -> &CongestionControl {
+        &self.congestion
+    }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct TcpOptions {
-    header_len: u16,
-    flags: TcpFlags,
-}
+    /// The maximum segment size used for sending.
+    pub fn mss(&self) -> usize {
+        self.congestion.mss
+    }
+
+    /// Replaces `DEFAULT_MSS`, e.g. with a size derived from the MTU of the link.
+    pub fn set_mss(&mut self, mss: usize) {
+        self.congestion.mss = mss;
+    }
 
-impl TcpOptions {
-    pub fn new(flags: TcpFlags) -> Self {
-        TcpOptions {
-            header_len: 5,
-            flags: flags,
+    /// Derives the initial sequence number from a secret `key` and the time of the
+    /// last `poll`, so that off-path attackers can't guess it. The key should be
+    /// random. Has no effect after the handshake started.
+    pub fn set_isn_key(&mut self, key: u32) {
+        if self.state == TcpState::Listen {
+            self.sequence_number = initial_sequence_number(self.id, key, self.now);
+            self.send_unacknowledged = self.sequence_number;
         }
     }
 
-    pub fn from_bits(bits: u16) -> Self {
-        TcpOptions {
-            header_len: bits.get_bits(12..16), // TODO
-            flags: TcpFlags::from_bits_truncate(bits),
+    /// The round-trip time measurement, which needs the timestamp option.
+    pub fn rtt(&self) -> &RttEstimator {
+        &self.rtt
+    }
+
+    /// The receive timestamp of the last segment from the peer, if the driver
+    /// provided one.
+    pub fn rx_timestamp(&self) -> Option<u64> {
+        self.rx_timestamp
+    }
+
+    /// When the last segment from the peer was received, measured with the time of
+    /// the last `poll`.
+    pub fn last_received(&self) -> u64 {
+        self.last_received
+    }
+
+    /// The number of sent bytes that are not acknowledged yet.
+    pub fn bytes_in_flight(&self) -> usize {
+        (self.sequence_number - self.send_unacknowledged).0 as usize
+    }
+
+    /// The sequence number of the next new byte that is sent (SND.NXT).
+    pub fn sequence_number(&self) -> u32 {
+        self.sequence_number.0
+    }
+
+    /// The oldest unacknowledged sequence number (SND.UNA).
+    pub fn send_unacknowledged(&self) -> u32 {
+        self.send_unacknowledged.0
+    }
+
+    /// The next sequence number expected from the peer (RCV.NXT).
+    pub fn ack_number(&self) -> u32 {
+        self.ack_number.0
+    }
+
+    /// The receive window last advertised by the peer.
+    pub fn peer_window(&self) -> u16 {
+        self.peer_window
+    }
+
+    /// The number of consecutive retransmission timeouts without progress. The
+    /// connection times out after the `max_retries` of the retry policy.
+    pub fn retransmissions(&self) -> u8 {
+        self.retransmissions
+    }
+
+    /// Replaces `DEFAULT_RETRY_POLICY`. Its initial delay is only used until the
+    /// round-trip time was measured, and the jitter isn't applied.
+    pub fn set_retry_policy(&mut self, policy: Policy) {
+        self.retry = policy;
+        if self.rtt.smoothed_rtt().is_none() && self.retransmissions == 0 {
+            self.retransmission_timeout = policy.initial_delay;
         }
     }
 
-    pub fn bits(&self) -> u16 {
-        self.flags.bits() | (self.header_len << 12) // TODO
+    /// The retransmission timeout before any backoff.
+    fn initial_retransmission_timeout(&self) -> u64 {
+        match self.rtt.smoothed_rtt() {
+            Some(_) => self.rtt.retransmission_timeout(),
+            None => self.retry.initial_delay,
+        }
     }
-}
 
-bitflags! {
-    pub flags TcpFlags: u16 {
-        const NS = 1 << 8,
-        const CWR = 1 << 7,
-        const ECE = 1 << 6,
-        const URG = 1 << 5,
-        const ACK = 1 << 4,
-        const PSH = 1 << 3,
-        const RST = 1 << 2,
-        const SYN = 1 << 1,
-        const FIN = 1 << 0,
+    /// Buffers `data` for sending and returns the number of accepted bytes.
+    ///
+    /// The data is released in segments as far as the peer window and the congestion
+    /// window allow; fetch them with `next_segment`.
+    pub fn send(&mut self, data: &[u8]) -> usize {
+        if (self.state != TcpState::Established && self.state != TcpState::CloseWait) ||
+           self.fin_pending {
+            return 0;
+        }
+        let len = usize::min(data.len(), SEND_BUFFER_SIZE - self.send_buffer.len());
+        self.send_buffer.extend(&data[..len]);
+        self.segment_send_buffer();
+        len
+    }
+
+    /// Disables Nagle's algorithm if `nodelay` is true, so that every `send` is
+    /// transmitted immediately, e.g. for interactive protocols. By default, data
+    /// smaller than a full segment waits until all sent data was acknowledged, so
+    /// that small writes are coalesced.
+    pub fn set_nodelay(&mut self, nodelay: bool) {
+        self.nodelay = nodelay;
+        self.segment_send_buffer();
+    }
+
+    /// Whether small segments are sent immediately, see `set_nodelay`.
+    pub fn nodelay(&self) -> bool {
+        self.nodelay
+    }
+
+    /// Closes the connection as soon as the peer sent its FIN, e.g. for servers that
+    /// answer every request in the `handle_packet` callback. By default, the
+    /// connection stays in `CloseWait` until `close`.
+    pub fn set_close_on_peer_fin(&mut self, close: bool) {
+        self.close_on_peer_fin = close;
+    }
+
+    /// Returns the next segment that has to be put on the wire, either for the first
+    /// time or as a retransmission.
+    pub fn next_segment(&mut self) -> Option<TcpPacket<Box<[u8]>>> {
+        if let Some(rst) = self.reset_segment.take() {
+            return Some(rst);
+        }
+        while let Some(sequence_number) = self.unsent.pop_front() {
+            if self.sacked.contains(&sequence_number) {
+                continue; // the peer has it already
+            }
+            if let Some(packet) = self.packet_queue.get(&sequence_number) {
+                let mut packet = packet.clone();
+                if packet.header.flags.contains(TcpFlags::ACK) {
+                    packet.header.ack_number = self.ack_number;
+                    packet.header.sack_blocks = self.sack_blocks();
+                }
+                // retransmissions carry the current time, so their ACKs measure the
+                // time since the retransmission
+                packet.header.timestamp = self.timestamp();
+                // pure ACKs aren't retransmitted, so they don't start the timer
+                let occupies_sequence = !packet.payload.is_empty() ||
+                                        packet.header.flags.intersects(TcpFlags::SYN |
+                                                                       TcpFlags::FIN);
+                if occupies_sequence && self.retransmission_deadline.is_none() {
+                    self.retransmission_deadline = Some(self.now + self.retransmission_timeout);
+                }
+                return Some(packet);
+            }
+        }
+        None
+    }
+
+    fn segment_send_buffer(&mut self) {
+        let window = usize::min(usize::from(self.peer_window), self.congestion.window());
+        while !self.send_buffer.is_empty() && self.bytes_in_flight() < window {
+            let len = usize::min(usize::min(self.send_buffer.len(), self.congestion.mss),
+                                 window - self.bytes_in_flight());
+            let nagle = !self.nodelay && !self.fin_pending && self.bytes_in_flight() > 0;
+            if nagle && len < self.congestion.mss && len == self.send_buffer.len() {
+                break; // wait for the ACK to coalesce more data
+            }
+            let payload: Vec<u8> = self.send_buffer.drain(..len).collect();
+            let header = self.header(TcpFlags::ACK | TcpFlags::PSH);
+            self.sequence_number += Wrapping(len as u32);
+            self.queue(TcpPacket {
+                           header,
+                           payload: payload.into_boxed_slice(),
+                       });
+        }
+        if self.fin_pending && self.send_buffer.is_empty() {
+            self.fin_pending = false;
+            self.queue_fin();
+        }
+    }
+
+    /// Queues the oldest segment that the peer didn't report in a SACK block.
+    fn retransmit_oldest(&mut self) {
+        let oldest = {
+            let sacked = &self.sacked;
+            self.packet_queue.keys().find(|seq| !sacked.contains(seq)).cloned()
+        };
+        if let Some(sequence_number) = oldest {
+            if !self.unsent.contains(&sequence_number) {
+                self.unsent.push_front(sequence_number);
+                self.retransmitted_segments = self.retransmitted_segments.wrapping_add(1);
+            }
+        }
+    }
+
+    /// The number of retransmissions by timeout or fast retransmit.
+    pub fn retransmitted_segments(&self) -> u32 {
+        self.retransmitted_segments
+    }
+
+    /// Processes the acknowledgement number and window of an incoming segment.
+    fn handle_ack(&mut self, packet: &TcpPacket<&[u8]>) {
+        let header = &packet.header;
+        if !header.flags.contains(TcpFlags::ACK) {
+            return;
+        }
+
+        if self.sack_permitted {
+            self.mark_sacked(&header.sack_blocks);
+        }
+
+        let in_flight = self.bytes_in_flight();
+        let acked = (header.ack_number - self.send_unacknowledged).0 as usize;
+        if acked > 0 && acked <= in_flight {
+            self.send_unacknowledged = header.ack_number;
+            self.packet_queue = self.packet_queue.split_off(&header.ack_number); // TODO: efficient?
+            self.sacked = self.sacked.split_off(&header.ack_number);
+            self.congestion.on_ack(acked);
+            self.measure_rtt(header);
+            self.retransmission_timeout = self.initial_retransmission_timeout();
+            // restart the timer for the rest of the segments in flight
+            self.retransmission_deadline = match self.bytes_in_flight() {
+                0 => None,
+                _ => Some(self.now + self.retransmission_timeout),
+            };
+            self.retransmissions = 0;
+        } else if acked == 0 && in_flight > 0 && packet.payload.is_empty() &&
+                  header.flags == TcpFlags::ACK &&
+                  header.window_size == self.peer_window {
+            if self.congestion.on_duplicate_ack(in_flight) {
+                // fast retransmit
+                self.retransmit_oldest();
+            }
+        }
+        self.peer_window = header.window_size;
+    }
+
+    /// Feeds the RTT estimator with the echoed timestamp of an acknowledgement.
+    fn measure_rtt(&mut self, header: &TcpHeader) {
+        if let (true, Some(timestamp)) = (self.timestamps, header.timestamp) {
+            let rtt = u64::from((self.now as u32).wrapping_sub(timestamp.echo_reply));
+            if rtt <= MAX_RETRANSMISSION_TIMEOUT {
+                self.rtt.on_sample(rtt);
+            }
+        }
+    }
+
+    /// Remembers the timestamp of a segment for echoing, if it isn't older than the
+    /// recent one and doesn't start after the acknowledged data.
+    fn update_recent_timestamp(&mut self, header: &TcpHeader) {
+        if let (true, Some(timestamp)) = (self.timestamps, header.timestamp) {
+            let after_ack = (header.sequence_number - self.ack_number).0 as i32 > 0;
+            if !after_ack && timestamp.value.wrapping_sub(self.recent_timestamp) as i32 >= 0 {
+                self.recent_timestamp = timestamp.value;
+            }
+        }
+    }
+
+    fn mark_sacked(&mut self, blocks: &[Option<SackBlock>]) {
+        for block in blocks.iter().filter_map(|b| *b) {
+            for (&sequence_number, packet) in &self.packet_queue {
+                let end = sequence_number + Wrapping(packet.payload.len() as u32);
+                if !packet.payload.is_empty() && block.contains(sequence_number, end) {
+                    self.sacked.insert(sequence_number);
+                }
+            }
+        }
+    }
+
+    /// Returns the next state change since the last call.
+    pub fn poll_event(&mut self) -> Option<TcpEvent> {
+        self.events.pop_front()
+    }
+
+    /// Moves to `Closed` and drops everything that is still queued for sending.
+    fn set_closed(&mut self, event: TcpEvent) {
+        self.state = TcpState::Closed;
+        self.packet_queue.clear();
+        self.unsent.clear();
+        self.send_buffer.clear();
+        self.time_wait_deadline = None;
+        self.retransmission_deadline = None;
+        self.events.push_back(event);
+    }
+
+    /// Returns true when the connection is fully closed and can be dropped.
+    pub fn is_closed(&self) -> bool {
+        self.state == TcpState::Closed
+    }
+
+    /// Closes the connection immediately, without sending anything to the peer, e.g.
+    /// because the link went down.
+    pub fn abort(&mut self) {
+        if self.state != TcpState::Closed {
+            self.set_closed(TcpEvent::Aborted);
+        }
+    }
+
+    /// Closes the connection immediately and returns the RST that tells the peer, if
+    /// the connection is synchronized and not closing already (RFC 793, ABORT call).
+    fn reset(&mut self, event: TcpEvent) -> Option<TcpPacket<Box<[u8]>>> {
+        let rst = match self.state {
+            TcpState::SynReceived | TcpState::Established | TcpState::FinWait1 |
+            TcpState::FinWait2 | TcpState::CloseWait => {
+                let header = TcpHeaderBuilder::new(self.id.local.port, self.id.remote.port)
+                    .sequence_number(self.sequence_number.0)
+                    .flags(TcpFlags::RST)
+                    .build();
+                Some(TcpPacket {
+                         header,
+                         payload: Vec::new().into_boxed_slice(),
+                     })
+            }
+            _ => None,
+        };
+        if self.state != TcpState::Closed {
+            self.set_closed(event);
+        }
+        rst
+    }
+
+    /// Retransmits the oldest unacknowledged segment right away and restarts the
+    /// retransmission backoff, e.g. after the link came back up.
+    pub fn probe(&mut self) {
+        if self.bytes_in_flight() > 0 {
+            self.retransmit_oldest();
+            self.retransmission_timeout = self.initial_retransmission_timeout();
+            self.retransmission_deadline = None;
+            self.retransmissions = 0;
+        }
+    }
+
+    /// Opens the connection actively by queueing a SYN. Does nothing unless the
+    /// connection is new.
+    ///
+    /// If the peer opens the connection at the same time, the SYNs cross and both
+    /// sides continue in `SynReceived` (simultaneous open).
+    ///
+    /// The SYN offers the timestamp option, which is used if the peer's SYN carries
+    /// it as well.
+    pub fn connect(&mut self) {
+        if self.state != TcpState::Listen {
+            return;
+        }
+        self.timestamps = true;
+        let mut header = self.header(TcpFlags::SYN);
+        header.sack_permitted = true;
+        self.state = TcpState::SynSent;
+        self.queue(TcpPacket {
+                       header,
+                       payload: Vec::new().into_boxed_slice(),
+                   });
+    }
+
+    /// Initiates the close handshake by queueing a FIN. The FIN is sent after all
+    /// buffered data.
+    ///
+    /// After the peer closed its side (`TcpEvent::PeerClosed`), the connection can
+    /// still send until it is closed with this.
+    pub fn close(&mut self) {
+        match self.state {
+            TcpState::Listen | TcpState::SynSent => self.set_closed(TcpEvent::Closed),
+            TcpState::SynReceived | TcpState::Established | TcpState::CloseWait => {
+                if self.send_buffer.is_empty() {
+                    self.queue_fin();
+                } else {
+                    self.fin_pending = true;
+                }
+            }
+            _ => {} // already closing
+        }
+    }
+
+    /// Drives the time-based parts of the state machine. `now` is a monotonic
+    /// timestamp in milliseconds.
+    ///
+    /// The `TimeWait` timer starts on entering `TimeWait`, the retransmission timer
+    /// when a segment is sent, both at the time of the last call. Timestamp options
+    /// carry the time of the last call as well.
+    pub fn poll(&mut self, now: u64) {
+        self.now = now;
+        if self.state == TcpState::TimeWait {
+            self.poll_time_wait(now);
+        } else {
+            self.poll_retransmission(now);
+        }
+    }
+
+    fn poll_retransmission(&mut self, now: u64) {
+        let in_flight = self.bytes_in_flight();
+        if in_flight == 0 {
+            self.retransmission_deadline = None;
+            return;
+        }
+        match self.retransmission_deadline {
+            // nothing was sent yet
+            None => {}
+            Some(deadline) if now >= deadline && self.retry.exhausted(self.retransmissions) => {
+                self.set_closed(TcpEvent::TimedOut);
+            }
+            Some(deadline) if now >= deadline => {
+                self.retransmissions += 1;
+                self.congestion.on_timeout(in_flight);
+                // the peer may have discarded out-of-order data, so forget its SACKs
+                self.sacked.clear();
+                self.retransmit_oldest();
+                self.retransmission_timeout = self.retry.next_delay(self.retransmission_timeout);
+                self.retransmission_deadline = Some(now + self.retransmission_timeout);
+            }
+            Some(_) => {}
+        }
+    }
+
+    fn poll_time_wait(&mut self, now: u64) {
+        if self.time_wait_deadline.map_or(false, |deadline| now >= deadline) {
+            self.set_closed(TcpEvent::Closed);
+        }
+    }
+
+    /// Handles a segment after our FIN was queued.
+    ///
+    /// In `FinWait1` and `FinWait2`, the peer can still send data, which is passed to
+    /// the receive buffer or the callback. Replies of the callback are dropped, since
+    /// nothing can be sent after the FIN.
+    fn handle_close_packet<F>(&mut self,
+                              packet: &TcpPacket<&[u8]>,
+                              f: &mut F)
+                              -> Option<TcpPacket<Box<[u8]>>>
+        where for<'d> F: FnMut(&TcpConnection, &'d [u8]) -> Option<Cow<'d, [u8]>>
+    {
+        let flags = packet.header.flags;
+
+        self.handle_ack(packet);
+
+        let receiving = self.state == TcpState::FinWait1 || self.state == TcpState::FinWait2;
+        let in_order = packet.header.sequence_number == self.ack_number;
+        let mut accepted = 0;
+        if receiving && in_order && !packet.payload.is_empty() {
+            accepted = match self.receive_buffer {
+                Some(_) => usize::min(packet.payload.len(), usize::from(self.receive_window())),
+                None => packet.payload.len(),
+            };
+            let data = &packet.payload[..accepted];
+            self.ack_number += Wrapping(accepted as u32);
+            match self.receive_buffer {
+                Some(ref mut buffer) => buffer.extend_from_slice(data),
+                None => {
+                    self.data_marks = DataMarks::new(&packet.header, packet.payload.len());
+                    let _ = f(self, data);
+                }
+            }
+        }
+
+        let fin_acked = flags.contains(TcpFlags::ACK) &&
+                        packet.header.ack_number == self.sequence_number;
+        // a FIN after data that wasn't accepted is retransmitted with the data
+        let fin_received = flags.contains(TcpFlags::FIN) &&
+                           (!receiving || (in_order && accepted == packet.payload.len()));
+
+        let next_state = match self.state {
+            TcpState::FinWait1 if fin_received && fin_acked => TcpState::TimeWait,
+            TcpState::FinWait1 if fin_received => TcpState::Closing,
+            TcpState::FinWait1 if fin_acked => TcpState::FinWait2,
+            TcpState::FinWait2 if fin_received => TcpState::TimeWait,
+            TcpState::Closing if fin_acked => TcpState::TimeWait,
+            TcpState::TimeWait if fin_received => {
+                // retransmitted FIN: our ACK was lost, so ack again and restart the timer
+                self.time_wait_deadline = Some(self.now + 2 * MAX_SEGMENT_LIFETIME);
+                return Some(TcpPacket {
+                    header: self.header(TcpFlags::ACK),
+                    payload: Vec::new().into_boxed_slice(),
+                });
+            }
+            state => state,
+        };
+
+        let reply = if fin_received && self.state == TcpState::Closing {
+            // retransmitted FIN: our ACK was lost
+            Some(TcpPacket {
+                     header: self.header(TcpFlags::ACK),
+                     payload: Vec::new().into_boxed_slice(),
+                 })
+        } else if fin_received {
+            self.events.push_back(TcpEvent::PeerClosed);
+            self.ack_number = packet.header.sequence_number +
+                              Wrapping(packet.payload.len() as u32) + Wrapping(1);
+            Some(TcpPacket {
+                header: self.header(TcpFlags::ACK),
+                payload: Vec::new().into_boxed_slice(),
+            })
+        } else if receiving && !packet.payload.is_empty() {
+            // acknowledge the data, or the expected sequence number after a gap
+            Some(TcpPacket {
+                     header: self.header(TcpFlags::ACK),
+                     payload: Vec::new().into_boxed_slice(),
+                 })
+        } else {
+            None
+        };
+        if next_state == TcpState::TimeWait && self.state != TcpState::TimeWait {
+            self.time_wait_deadline = Some(self.now + 2 * MAX_SEGMENT_LIFETIME);
+        }
+        self.state = next_state;
+        reply
+    }
+
+    /// Enters `CloseWait` after the FIN of the peer was accepted. The connection stays
+    /// open for sending until `close`, unless `set_close_on_peer_fin` was enabled.
+    ///
+    /// `reply` is queued before our FIN. Returns a separate ACK if no other segment
+    /// acknowledges the FIN of the peer.
+    fn handle_peer_fin(&mut self,
+                       reply: Option<TcpPacket<Box<[u8]>>>)
+                       -> Option<TcpPacket<Box<[u8]>>> {
+        self.ack_number += Wrapping(1);
+        self.state = TcpState::CloseWait;
+        self.events.push_back(TcpEvent::PeerClosed);
+        let replied = reply.is_some();
+        if let Some(reply) = reply {
+            self.queue(reply);
+        }
+        if self.close_on_peer_fin {
+            self.close();
+        }
+        if replied || self.state == TcpState::LastAck {
+            None
+        } else {
+            Some(TcpPacket {
+                     header: self.header(TcpFlags::ACK),
+                     payload: Vec::new().into_boxed_slice(),
+                 })
+        }
+    }
+
+    pub fn handle_packet<'a, F>(&mut self, packet: &'a TcpPacket<&[u8]>, f: F)
+        where for<'d> F: FnMut(&TcpConnection, &'d [u8]) -> Option<Cow<'d, [u8]>>
+    {
+        self.handle_timestamped_packet(None, packet, f)
+    }
+
+    /// Like `handle_packet`, with the time the segment was received, which the
+    /// callback can query with `rx_timestamp`.
+    pub fn handle_timestamped_packet<'a, F>(&mut self,
+                                            timestamp: Option<u64>,
+                                            packet: &'a TcpPacket<&[u8]>,
+                                            mut f: F)
+        where for<'d> F: FnMut(&TcpConnection, &'d [u8]) -> Option<Cow<'d, [u8]>>
+    {
+        self.rx_timestamp = timestamp;
+        self.last_received = self.now;
+        let empty = Vec::new().into_boxed_slice();
+
+        if packet.header.flags.contains(TcpFlags::RST) {
+            self.handle_reset(packet);
+            return;
+        }
+        self.update_recent_timestamp(&packet.header);
+
+        let flags = packet.header.flags;
+        if self.state == TcpState::SynReceived && flags.contains(TcpFlags::ACK) {
+            if packet.header.ack_number != self.sequence_number + Wrapping(1) {
+                // not an ACK of our SYN, e.g. from an old connection: reset the sender
+                // and stay half-open (RFC 793, SYN-RECEIVED)
+                let header = TcpHeaderBuilder::new(self.id.local.port, self.id.remote.port)
+                    .sequence_number(packet.header.ack_number.0)
+                    .flags(TcpFlags::RST)
+                    .build();
+                self.reset_segment = Some(TcpPacket {
+                                              header,
+                                              payload: empty,
+                                          });
+                return;
+            }
+            self.establish(&packet.header);
+            // data and a FIN are handled like in `Established`, the SYN of a
+            // simultaneous open needs no answer
+            if flags.contains(TcpFlags::SYN) ||
+               (packet.payload.is_empty() && !flags.contains(TcpFlags::FIN)) {
+                self.segment_send_buffer();
+                return;
+            }
+        }
+
+        let reply = match self.state {
+            TcpState::Closed => None,
+            TcpState::Listen | TcpState::SynSent | TcpState::SynReceived
+                if flags == TcpFlags::SYN => {
+                self.ack_number = packet.header.sequence_number + Wrapping(1);
+                self.peer_window = packet.header.window_size;
+                self.sack_permitted = packet.header.sack_permitted;
+                // in a simultaneous open, only if our SYN offered them as well
+                let offered = self.state == TcpState::Listen || self.timestamps;
+                self.timestamps = offered && packet.header.timestamp.is_some();
+                if let (true, Some(timestamp)) = (self.timestamps, packet.header.timestamp) {
+                    self.recent_timestamp = timestamp.value;
+                }
+                let mut header = self.header(TcpFlags::SYN | TcpFlags::ACK);
+                header.sack_permitted = self.sack_permitted;
+                self.state = TcpState::SynReceived;
+                Some(TcpPacket {
+                    payload: Box::from(empty),
+                    header: header,
+                })
+            }
+            TcpState::SynSent if flags.contains(TcpFlags::SYN | TcpFlags::ACK) &&
+                                 packet.header.ack_number ==
+                                 self.sequence_number + Wrapping(1) => {
+                self.ack_number = packet.header.sequence_number + Wrapping(1);
+                self.sack_permitted = packet.header.sack_permitted;
+                self.timestamps = packet.header.timestamp.is_some();
+                if let Some(timestamp) = packet.header.timestamp {
+                    self.recent_timestamp = timestamp.value;
+                }
+                self.establish(&packet.header);
+                Some(TcpPacket {
+                         header: self.header(TcpFlags::ACK),
+                         payload: empty,
+                     })
+            }
+            TcpState::Listen | TcpState::SynSent | TcpState::SynReceived => None,
+            TcpState::Established => {
+                let ahead = (packet.header.sequence_number - self.ack_number).0;
+                if ahead != 0 && ahead < u32::from(self.receive_window()) {
+                    // data after a gap: hold it and send a duplicate ACK
+                    self.handle_ack(packet);
+                    self.store_out_of_order(packet);
+                    self.queue(TcpPacket {
+                                   header: self.header(TcpFlags::ACK),
+                                   payload: empty,
+                               });
+                    self.segment_send_buffer();
+                    return;
+                } else if ahead != 0 {
+                    // old packet, do nothing
+                    return;
+                }
+                let payload = match self.receive_buffer {
+                    // only accept what fits, the peer retransmits the rest
+                    Some(_) => {
+                        let len = usize::min(packet.payload.len(),
+                                             usize::from(self.receive_window()));
+                        &packet.payload[..len]
+                    }
+                    None => packet.payload,
+                };
+                // a FIN after data that didn't fit is retransmitted with the data
+                let fin = packet.header.flags.contains(TcpFlags::FIN) &&
+                          payload.len() == packet.payload.len();
+                self.ack_number += Wrapping(payload.len() as u32);
+                let reassembled = self.take_reassembled();
+
+                self.handle_ack(packet);
+
+                let reply = if payload.is_empty() && reassembled.is_empty() {
+                    None
+                } else if let Some(ref mut buffer) = self.receive_buffer {
+                    buffer.extend_from_slice(payload);
+                    for &(ref data, _) in &reassembled {
+                        buffer.extend_from_slice(data);
+                    }
+                    None
+                } else {
+                    let header = self.header(TcpFlags::ACK);
+
+                    let mut reply_data = Vec::new();
+                    if !packet.payload.is_empty() {
+                        self.data_marks = DataMarks::new(&packet.header, packet.payload.len());
+                        if let Some(reply) = f(self, packet.payload) {
+                            reply_data.extend_from_slice(&reply);
+                        }
+                    }
+                    for &(ref data, marks) in &reassembled {
+                        self.data_marks = marks;
+                        if let Some(reply) = f(self, data) {
+                            reply_data.extend_from_slice(&reply);
+                        }
+                    }
+                    self.sequence_number += Wrapping(reply_data.len() as u32);
+                    Some(TcpPacket {
+                             header,
+                             payload: reply_data.into_boxed_slice(),
+                         })
+                };
+                if fin {
+                    self.handle_peer_fin(reply)
+                } else {
+                    reply
+                }
+            },
+            TcpState::CloseWait | TcpState::LastAck => {
+                self.handle_ack(packet);
+                if self.state == TcpState::LastAck && self.bytes_in_flight() == 0 {
+                    self.set_closed(TcpEvent::Closed);
+                    None
+                } else if packet.header.flags.contains(TcpFlags::FIN) {
+                    // retransmitted FIN: our ACK was lost
+                    Some(TcpPacket {
+                             header: self.header(TcpFlags::ACK),
+                             payload: empty,
+                         })
+                } else {
+                    None
+                }
+            }
+            TcpState::FinWait1 | TcpState::FinWait2 | TcpState::Closing | TcpState::TimeWait => {
+                self.handle_close_packet(packet, &mut f)
+            }
+        };
+
+        if let Some(reply) = reply {
+            self.queue(reply);
+        } else if self.receive_buffer.is_some() && self.state == TcpState::Established &&
+                  !packet.payload.is_empty() {
+            // acknowledge buffered data, with the reduced window
+            let header = self.header(TcpFlags::ACK);
+            self.queue(TcpPacket {
+                           header,
+                           payload: Vec::new().into_boxed_slice(),
+                       });
+        }
+        self.segment_send_buffer();
+    }
+
+    /// Completes the handshake when our SYN was acknowledged.
+    fn establish(&mut self, header: &TcpHeader) {
+        self.sequence_number += Wrapping(1);
+        self.send_unacknowledged = self.sequence_number;
+        self.packet_queue = self.packet_queue.split_off(&self.sequence_number);
+        self.peer_window = header.window_size;
+        self.measure_rtt(header);
+        self.retransmission_timeout = self.initial_retransmission_timeout();
+        self.retransmission_deadline = None;
+        self.state = TcpState::Established;
+        self.events.push_back(TcpEvent::Established);
+    }
+
+    /// Aborts the connection if the RST is in the receive window.
+    fn handle_reset(&mut self, packet: &TcpPacket<&[u8]>) {
+        let in_window = (packet.header.sequence_number - self.ack_number).0 <
+                        u32::max(u32::from(self.receive_window()), 1);
+        match self.state {
+            TcpState::Closed | TcpState::Listen => {}
+            TcpState::TimeWait => {
+                if in_window {
+                    self.set_closed(TcpEvent::Closed);
+                }
+            }
+            _ => {
+                if in_window {
+                    self.set_closed(TcpEvent::Reset);
+                }
+            }
+        }
+    }
+
+    pub fn packets<'a>(&'a mut self) -> impl Iterator<Item = &'a TcpPacket<Box<[u8]>>> {
+        self.packet_queue.values()
+    }
+}
+
+/// The connection table of a `TcpListener`, with the connections in the order they
+/// were accepted.
+///
+/// It is a `Vec` by default. With the `heapless` feature, a `heapless::Vec` bounds
+/// the table statically, see `TcpListener::with_table`.
+#[cfg(any(test, feature = "alloc"))]
+pub trait ConnectionTable {
+    fn entries(&self) -> &[(Endpoint, TcpConnection)];
+
+    fn entries_mut(&mut self) -> &mut [(Endpoint, TcpConnection)];
+
+    /// Returns the entry back if the table is full.
+    fn push(&mut self,
+            entry: (Endpoint, TcpConnection))
+            -> Result<(), (Endpoint, TcpConnection)>;
+
+    fn pop(&mut self) -> Option<(Endpoint, TcpConnection)>;
+
+    /// The maximum number of connections, or `None` if the table grows as needed.
+    fn capacity(&self) -> Option<usize>;
+}
+
+#[cfg(any(test, feature = "alloc"))]
+impl ConnectionTable for Vec<(Endpoint, TcpConnection)> {
+    fn entries(&self) -> &[(Endpoint, TcpConnection)] {
+        self
+    }
+
+    fn entries_mut(&mut self) -> &mut [(Endpoint, TcpConnection)] {
+        self
+    }
+
+    fn push(&mut self,
+            entry: (Endpoint, TcpConnection))
+            -> Result<(), (Endpoint, TcpConnection)> {
+        Vec::push(self, entry);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<(Endpoint, TcpConnection)> {
+        Vec::pop(self)
+    }
+
+    fn capacity(&self) -> Option<usize> {
+        None
+    }
+}
+
+#[cfg(all(feature = "alloc", feature = "heapless"))]
+impl<N> ConnectionTable for ::heapless::Vec<(Endpoint, TcpConnection), N>
+    where N: ::heapless::ArrayLength<(Endpoint, TcpConnection)>
+{
+    fn entries(&self) -> &[(Endpoint, TcpConnection)] {
+        self
+    }
+
+    fn entries_mut(&mut self) -> &mut [(Endpoint, TcpConnection)] {
+        self
+    }
+
+    fn push(&mut self,
+            entry: (Endpoint, TcpConnection))
+            -> Result<(), (Endpoint, TcpConnection)> {
+        ::heapless::Vec::push(self, entry)
+    }
+
+    fn pop(&mut self) -> Option<(Endpoint, TcpConnection)> {
+        ::heapless::Vec::pop(self)
+    }
+
+    fn capacity(&self) -> Option<usize> {
+        Some(::heapless::Vec::capacity(self))
+    }
+}
+
+#[cfg(any(test, feature = "alloc"))]
+/// Accepts connections on a local port and keeps a bounded table of them.
+///
+/// Half-open connections (SYN received, handshake not completed) are limited to
+/// `max_half_open`; when a new SYN arrives with a full backlog, the oldest half-open
+/// connection is evicted. This keeps a burst of spoofed SYNs from exhausting the
+/// connection table.
+#[derive(Debug)]
+pub struct TcpListener<T = Vec<(Endpoint, TcpConnection)>> {
+    local: Endpoint,
+    max_connections: usize,
+    max_half_open: usize,
+    connections: T,
+    /// Events of all connections, collected before closed connections are dropped.
+    events: VecDeque<(Endpoint, TcpEvent)>,
+    /// RSTs of evicted half-open connections, with the address of their peer.
+    resets: VecDeque<(Ipv4Address, TcpPacket<Box<[u8]>>)>,
+    /// The receive buffer size of new connections, if enabled.
+    receive_buffer_capacity: Option<usize>,
+    retry: Policy,
+    mss: usize,
+    isn_key: u32,
+    close_on_peer_fin: bool,
+    /// Retransmissions of the connections that were already dropped.
+    closed_retransmissions: u32,
+    /// The time of the last `poll`.
+    now: u64,
+}
+
+#[cfg(any(test, feature = "alloc"))]
+impl TcpListener {
+    pub fn new(local: Endpoint, max_connections: usize, max_half_open: usize) -> TcpListener {
+        TcpListener::with_table(local, Vec::new(), max_connections, max_half_open)
+    }
+}
+
+#[cfg(any(test, feature = "alloc"))]
+impl<T: ConnectionTable> TcpListener<T> {
+    /// Creates a listener that keeps its connections in `connections`, e.g. an empty
+    /// `heapless::Vec<_, U8>`. `max_connections` is limited to the capacity of the
+    /// table.
+    pub fn with_table(local: Endpoint,
+                      connections: T,
+                      max_connections: usize,
+                      max_half_open: usize)
+                      -> TcpListener<T> {
+        let max_connections = match connections.capacity() {
+            Some(capacity) => usize::min(max_connections, capacity),
+            None => max_connections,
+        };
+        TcpListener {
+            local,
+            max_connections,
+            max_half_open: usize::min(max_half_open, max_connections),
+            connections,
+            events: VecDeque::new(),
+            resets: VecDeque::new(),
+            receive_buffer_capacity: None,
+            retry: DEFAULT_RETRY_POLICY,
+            mss: DEFAULT_MSS,
+            isn_key: 0,
+            close_on_peer_fin: false,
+            closed_retransmissions: 0,
+            now: 0,
+        }
+    }
+
+    /// Enables the receive buffer of all connections accepted from now on, see
+    /// `TcpConnection::enable_receive_buffer`.
+    pub fn enable_receive_buffers(&mut self, capacity: usize) {
+        self.receive_buffer_capacity = Some(capacity);
+    }
+
+    /// Sets the retry policy of the current and future connections, see
+    /// `TcpConnection::set_retry_policy`.
+    pub fn set_retry_policy(&mut self, policy: Policy) {
+        self.retry = policy;
+        for connection in self.connections() {
+            connection.set_retry_policy(policy);
+        }
+    }
+
+    pub fn mss(&self) -> usize {
+        self.mss
+    }
+
+    /// Sets the maximum segment size of the current and future connections, see
+    /// `TcpConnection::set_mss`.
+    pub fn set_mss(&mut self, mss: usize) {
+        self.mss = mss;
+        for connection in self.connections() {
+            connection.set_mss(mss);
+        }
+    }
+
+    /// Sets whether the current and future connections close when the peer sent its
+    /// FIN, see `TcpConnection::set_close_on_peer_fin`.
+    pub fn set_close_on_peer_fin(&mut self, close: bool) {
+        self.close_on_peer_fin = close;
+        for connection in self.connections() {
+            connection.set_close_on_peer_fin(close);
+        }
+    }
+
+    /// Sets the key of the initial sequence numbers of future connections, see
+    /// `TcpConnection::set_isn_key`.
+    pub fn set_isn_key(&mut self, key: u32) {
+        self.isn_key = key;
+    }
+
+    pub fn local(&self) -> Endpoint {
+        self.local
+    }
+
+    pub fn handle_packet<F>(&mut self, src_ip: Ipv4Address, packet: &TcpPacket<&[u8]>, f: F)
+        where for<'d> F: FnMut(&TcpConnection, &'d [u8]) -> Option<Cow<'d, [u8]>>
+    {
+        self.handle_timestamped_packet(src_ip, None, packet, f)
+    }
+
+    /// Like `handle_packet`, with the time the segment was received, see
+    /// `TcpConnection::rx_timestamp`.
+    pub fn handle_timestamped_packet<F>(&mut self,
+                                        src_ip: Ipv4Address,
+                                        timestamp: Option<u64>,
+                                        packet: &TcpPacket<&[u8]>,
+                                        f: F)
+        where for<'d> F: FnMut(&TcpConnection, &'d [u8]) -> Option<Cow<'d, [u8]>>
+    {
+        if packet.header.dst_port != self.local.port {
+            return;
+        }
+
+        let remote = Endpoint::new(src_ip, packet.header.src_port);
+        if self.position(remote).is_none() {
+            if packet.header.flags != TcpFlags::SYN || !self.make_room() {
+                return;
+            }
+            let mut connection = TcpConnection::new(ConnectionId::new(self.local, remote));
+            if let Some(capacity) = self.receive_buffer_capacity {
+                connection.enable_receive_buffer(capacity);
+            }
+            connection.set_retry_policy(self.retry);
+            connection.set_mss(self.mss);
+            connection.set_close_on_peer_fin(self.close_on_peer_fin);
+            connection.now = self.now;
+            connection.set_isn_key(self.isn_key);
+            if self.connections.push((remote, connection)).is_err() {
+                return;
+            }
+        }
+
+        if let Some(connection) = self.connection(remote) {
+            connection.handle_timestamped_packet(timestamp, packet, f);
+        }
+        self.prune();
+    }
+
+    fn position(&self, remote: Endpoint) -> Option<usize> {
+        self.connections.entries().iter().position(|e| e.0 == remote)
+    }
+
+    /// Removes the connection at `index`, keeping the order of the others.
+    fn remove(&mut self, index: usize) -> Option<(Endpoint, TcpConnection)> {
+        self.connections.entries_mut()[index..].rotate_left(1);
+        self.connections.pop()
+    }
+
+    /// Evicts the oldest half-open connection if necessary, like `evict_idle`, and
+    /// queues its RST for `next_reset`. Returns false if there is no room for another
+    /// connection.
+    fn make_room(&mut self) -> bool {
+        if self.half_open_count() >= self.max_half_open ||
+           self.connections.entries().len() >= self.max_connections {
+            let oldest = self.connections
+                .entries()
+                .iter()
+                .position(|e| e.1.state() == TcpState::SynReceived);
+            match oldest {
+                Some(index) => {
+                    let entry = &mut self.connections.entries_mut()[index];
+                    if let Some(rst) = entry.1.reset(TcpEvent::Evicted) {
+                        self.resets.push_back((entry.0.addr, rst));
+                    }
+                    self.prune();
+                }
+                None => return false,
+            }
+        }
+        self.connections.entries().len() < self.max_connections
+    }
+
+    /// Returns the next event of any connection, with the remote endpoint of the
+    /// connection.
+    pub fn poll_event(&mut self) -> Option<(Endpoint, TcpEvent)> {
+        self.events.pop_front()
+    }
+
+    /// Returns the next RST of a half-open connection that was evicted for a new one,
+    /// with the address of its peer.
+    pub fn next_reset(&mut self) -> Option<(Ipv4Address, TcpPacket<Box<[u8]>>)> {
+        self.resets.pop_front()
+    }
+
+    fn prune(&mut self) {
+        for &mut (remote, ref mut connection) in self.connections.entries_mut() {
+            while let Some(event) = connection.poll_event() {
+                self.events.push_back((remote, event));
+            }
+        }
+
+        let mut i = 0;
+        while i < self.connections.entries().len() {
+            if !self.connections.entries()[i].1.is_closed() {
+                i += 1;
+                continue;
+            }
+            if let Some((_, connection)) = self.remove(i) {
+                self.closed_retransmissions = self.closed_retransmissions
+                    .wrapping_add(connection.retransmitted_segments());
+            }
+        }
+    }
+
+    pub fn poll(&mut self, now: u64) {
+        self.now = now;
+        for connection in self.connections() {
+            connection.poll(now);
+        }
+        self.prune();
+    }
+
+    /// Resets the connections that received nothing for at least `older_than`
+    /// milliseconds before the last `poll`, e.g. to make room in a full table. They
+    /// report `TcpEvent::Evicted`.
+    ///
+    /// Returns the RSTs to send, with the address of their peer.
+    pub fn evict_idle(&mut self, older_than: u64) -> Vec<(Ipv4Address, TcpPacket<Box<[u8]>>)> {
+        let now = self.now;
+        let mut resets = Vec::new();
+        for &mut (remote, ref mut connection) in self.connections.entries_mut() {
+            if now.saturating_sub(connection.last_received()) < older_than {
+                continue;
+            }
+            if let Some(rst) = connection.reset(TcpEvent::Evicted) {
+                resets.push((remote.addr, rst));
+            }
+        }
+        self.prune();
+        resets
+    }
+
+    pub fn connection(&mut self, remote: Endpoint) -> Option<&mut TcpConnection> {
+        match self.position(remote) {
+            Some(index) => Some(&mut self.connections.entries_mut()[index].1),
+            None => None,
+        }
+    }
+
+    pub fn connections<'a>(&'a mut self) -> impl Iterator<Item = &'a mut TcpConnection> {
+        self.connections.entries_mut().iter_mut().map(|e| &mut e.1)
+    }
+
+    pub fn half_open_count(&self) -> usize {
+        self.connections
+            .entries()
+            .iter()
+            .filter(|e| e.1.state() == TcpState::SynReceived)
+            .count()
+    }
+
+    /// The number of retransmitted segments of all connections, including closed ones.
+    pub fn retransmitted_segments(&self) -> u32 {
+        self.connections
+            .entries()
+            .iter()
+            .fold(self.closed_retransmissions,
+                  |sum, e| sum.wrapping_add(e.1.retransmitted_segments()))
+    }
+}
+
+/// The state of a TCP socket, according to [RFC 793][rfc793].
+/// [rfc793]: https://tools.ietf.org/html/rfc793
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TcpState {
+    Closed,
+    Listen,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait
+}
+
+bitflags! {
+    /// The control bits of a TCP segment.
+    pub flags TcpFlags: u16 {
+        const NS = 1 << 8,
+        const CWR = 1 << 7,
+        const ECE = 1 << 6,
+        const URG = 1 << 5,
+        const ACK = 1 << 4,
+        const PSH = 1 << 3,
+        const RST = 1 << 2,
+        const SYN = 1 << 1,
+        const FIN = 1 << 0,
+    }
+}
+
+#[cfg(test)]
+fn segment(sequence_number: u32, ack_number: u32, flags: TcpFlags) -> TcpPacket<&'static [u8]> {
+    TcpPacket {
+        header: TcpHeaderBuilder::new(50000, 80)
+            .sequence_number(sequence_number)
+            .ack_number(ack_number)
+            .flags(flags)
+            .window_size(1000)
+            .build(),
+        payload: &[],
+    }
+}
+
+#[cfg(test)]
+fn test_connection_id() -> ConnectionId {
+    ConnectionId::new(Endpoint::new(Ipv4Address::new(192, 168, 1, 2), 80),
+                      Endpoint::new(Ipv4Address::new(192, 168, 1, 1), 50000))
+}
+
+#[cfg(test)]
+fn no_data<'d>(_: &TcpConnection, _: &'d [u8]) -> Option<Cow<'d, [u8]>> {
+    None
+}
+
+#[test]
+fn active_close() {
+    let mut connection = TcpConnection::new(test_connection_id());
+
+    connection.handle_packet(&segment(100, 0, TcpFlags::SYN), no_data);
+    let our_seq = connection.packets().next().unwrap().header.sequence_number.0;
+    connection.handle_packet(&segment(101, our_seq + 1, TcpFlags::ACK), no_data);
+    assert_eq!(connection.state(), TcpState::Established);
+
+    connection.close();
+    assert_eq!(connection.state(), TcpState::FinWait1);
+    {
+        let fin = connection.packets().last().unwrap();
+        assert_eq!(fin.header.flags, TcpFlags::FIN | TcpFlags::ACK);
+        assert_eq!(fin.header.sequence_number.0, our_seq + 1);
+    }
+
+    connection.handle_packet(&segment(101, our_seq + 2, TcpFlags::ACK), no_data);
+    assert_eq!(connection.state(), TcpState::FinWait2);
+
+    connection.handle_packet(&segment(101, our_seq + 2, TcpFlags::FIN | TcpFlags::ACK),
+                             no_data);
+    assert_eq!(connection.state(), TcpState::TimeWait);
+    {
+        let ack = connection.packets().last().unwrap();
+        assert_eq!(ack.header.flags, TcpFlags::ACK);
+        assert_eq!(ack.header.ack_number.0, 102);
+    }
+
+    // the timer started on entering TimeWait, not at the first poll
+    connection.poll(1000);
+    assert_eq!(connection.state(), TcpState::TimeWait);
+    connection.poll(2 * MAX_SEGMENT_LIFETIME);
+    assert!(connection.is_closed());
+}
+
+#[test]
+fn simultaneous_close() {
+    let mut connection = TcpConnection::new(test_connection_id());
+    connection.handle_packet(&segment(100, 0, TcpFlags::SYN), no_data);
+    let our_seq = connection.packets().next().unwrap().header.sequence_number.0;
+    connection.handle_packet(&segment(101, our_seq + 1, TcpFlags::ACK), no_data);
+    connection.close();
+    while connection.next_segment().is_some() {}
+
+    // the FIN of the peer crosses ours
+    connection.handle_packet(&segment(101, our_seq + 1, TcpFlags::FIN | TcpFlags::ACK),
+                             no_data);
+    assert_eq!(connection.state(), TcpState::Closing);
+    let ack = connection.next_segment().unwrap();
+    assert_eq!((ack.header.flags, ack.header.ack_number.0), (TcpFlags::ACK, 102));
+
+    // the ACK was lost, so the peer retransmits its FIN
+    connection.handle_packet(&segment(101, our_seq + 1, TcpFlags::FIN | TcpFlags::ACK),
+                             no_data);
+    assert_eq!(connection.state(), TcpState::Closing);
+    let ack = connection.next_segment().unwrap();
+    assert_eq!((ack.header.flags, ack.header.ack_number.0), (TcpFlags::ACK, 102));
+
+    connection.handle_packet(&segment(102, our_seq + 2, TcpFlags::ACK), no_data);
+    assert_eq!(connection.state(), TcpState::TimeWait);
+}
+
+#[test]
+fn observers() {
+    let mut connection = TcpConnection::new(test_connection_id());
+    let isn = connection.sequence_number();
+    connection.handle_packet(&segment(100, 0, TcpFlags::SYN), no_data);
+    connection.handle_packet(&segment(101, isn.wrapping_add(1), TcpFlags::ACK), no_data);
+    connection.send(b"hello");
+    connection.next_segment().unwrap();
+    assert_eq!((connection.sequence_number(), connection.send_unacknowledged()),
+               (isn.wrapping_add(6), isn.wrapping_add(1)));
+    assert_eq!((connection.ack_number(), connection.peer_window()), (101, 1000));
+
+    connection.poll(0);
+    connection.poll(INITIAL_RETRANSMISSION_TIMEOUT);
+    assert_eq!(connection.retransmissions(), 1);
+    assert_eq!(format!("{:?}", connection),
+               format!("TcpConnection(192.168.1.2:80 -> 192.168.1.1:50000 Established snd={} \
+                        una={} rcv=101 wnd=1000 in_flight=5 rtx=1)",
+                       isn.wrapping_add(6),
+                       isn.wrapping_add(1)));
+}
+
+#[test]
+fn syn_received_unacceptable_ack() {
+    let mut connection = TcpConnection::new(test_connection_id());
+    let isn = connection.sequence_number();
+    connection.handle_packet(&segment(100, 0, TcpFlags::SYN), no_data);
+    while connection.next_segment().is_some() {}
+
+    // an ACK of something we never sent is reset, the handshake goes on
+    connection.handle_packet(&segment(101, isn.wrapping_add(1000), TcpFlags::ACK), no_data);
+    assert_eq!(connection.state(), TcpState::SynReceived);
+    let rst = connection.next_segment().unwrap();
+    assert_eq!((rst.header.flags, rst.header.sequence_number.0),
+               (TcpFlags::RST, isn.wrapping_add(1000)));
+    assert!(connection.next_segment().is_none());
+
+    connection.handle_packet(&segment(101, isn.wrapping_add(1), TcpFlags::ACK), no_data);
+    assert_eq!(connection.poll_event(), Some(TcpEvent::Established));
+
+    // the initial sequence number depends on the key
+    let mut keyed = TcpConnection::new(test_connection_id());
+    keyed.set_isn_key(0x5eed);
+    assert!(keyed.sequence_number() != isn);
+}
+
+#[test]
+fn listener_half_open_backlog() {
+    let ip = Ipv4Address::new(192, 168, 1, 2);
+    let peer = Ipv4Address::new(192, 168, 1, 1);
+    let mut listener = TcpListener::new(Endpoint::new(ip, 80), 4, 2);
+
+    let syn = |src_port| {
+        let mut packet = segment(100, 0, TcpFlags::SYN);
+        packet.header.src_port = src_port;
+        packet
+    };
+
+    listener.handle_packet(peer, &syn(1000), no_data);
+    listener.handle_packet(peer, &syn(1001), no_data);
+    listener.handle_packet(peer, &syn(1002), no_data);
+    assert_eq!(listener.half_open_count(), 2);
+    assert!(listener.connection(Endpoint::new(peer, 1000)).is_none());
+
+    // the evicted connection is reset like an idle one
+    assert_eq!(listener.poll_event(), Some((Endpoint::new(peer, 1000), TcpEvent::Evicted)));
+    let (addr, rst) = listener.next_reset().unwrap();
+    assert_eq!((addr, rst.header.dst_port, rst.header.flags), (peer, 1000, TcpFlags::RST));
+    assert!(listener.next_reset().is_none());
+
+    let remote = Endpoint::new(peer, 1001);
+    let our_seq = listener.connection(remote).unwrap().packets().next().unwrap()
+        .header.sequence_number.0;
+    let mut ack = segment(101, our_seq + 1, TcpFlags::ACK);
+    ack.header.src_port = 1001;
+    listener.handle_packet(peer, &ack, no_data);
+    assert_eq!(listener.half_open_count(), 1);
+    assert_eq!(listener.connection(remote).unwrap().state(), TcpState::Established);
+
+    // unknown non-SYN segments don't create connections
+    let mut stray = segment(500, 0, TcpFlags::ACK);
+    stray.header.src_port = 2000;
+    listener.handle_packet(peer, &stray, no_data);
+    assert!(listener.connection(Endpoint::new(peer, 2000)).is_none());
+}
+
+#[cfg(feature = "heapless")]
+#[test]
+fn listener_static_table() {
+    use heapless::consts::U2;
+
+    let ip = Ipv4Address::new(192, 168, 1, 2);
+    let peer = Ipv4Address::new(192, 168, 1, 1);
+    let table = ::heapless::Vec::<_, U2>::new();
+    let mut listener = TcpListener::with_table(Endpoint::new(ip, 80), table, 4, 4);
+    let syn = |src_port| {
+        let mut packet = segment(100, 0, TcpFlags::SYN);
+        packet.header.src_port = src_port;
+        packet
+    };
+
+    // the table holds two connections, so the third SYN evicts the oldest one
+    listener.handle_packet(peer, &syn(1000), no_data);
+    listener.handle_packet(peer, &syn(1001), no_data);
+    listener.handle_packet(peer, &syn(1002), no_data);
+    assert_eq!(listener.half_open_count(), 2);
+    assert!(listener.connection(Endpoint::new(peer, 1000)).is_none());
+    assert!(listener.connection(Endpoint::new(peer, 1002)).is_some());
+}
+
+#[test]
+fn listener_evict_idle() {
+    let ip = Ipv4Address::new(192, 168, 1, 2);
+    let peer = Ipv4Address::new(192, 168, 1, 1);
+    let mut listener = TcpListener::new(Endpoint::new(ip, 80), 4, 2);
+    let from = |src_port, mut packet: TcpPacket<&'static [u8]>| {
+        packet.header.src_port = src_port;
+        packet
+    };
+
+    listener.poll(1000);
+    listener.handle_packet(peer, &from(1001, segment(100, 0, TcpFlags::SYN)), no_data);
+    let idle = Endpoint::new(peer, 1001);
+    let our_seq = listener.connection(idle).unwrap().next_segment().unwrap()
+        .header.sequence_number.0 + 1;
+    listener.handle_packet(peer, &from(1001, segment(101, our_seq, TcpFlags::ACK)), no_data);
+    listener.poll(5000);
+    listener.handle_packet(peer, &from(1002, segment(100, 0, TcpFlags::SYN)), no_data);
+
+    let resets = listener.evict_idle(2000);
+    assert_eq!(resets.len(), 1);
+    let (addr, ref rst) = resets[0];
+    assert_eq!((addr, rst.header.dst_port), (peer, 1001));
+    assert_eq!((rst.header.flags, rst.header.sequence_number.0), (TcpFlags::RST, our_seq));
+    assert!(listener.connection(idle).is_none());
+    assert!(listener.connection(Endpoint::new(peer, 1002)).is_some());
+    assert_eq!(listener.poll_event(), Some((idle, TcpEvent::Established)));
+    assert_eq!(listener.poll_event(), Some((idle, TcpEvent::Evicted)));
+}
+
+#[test]
+fn congestion_control() {
+    let mut connection = TcpConnection::new(test_connection_id());
+    let ack = |ack_number| {
+        let mut packet = segment(101, ack_number, TcpFlags::ACK);
+        packet.header.window_size = 65535;
+        packet
+    };
+
+    connection.handle_packet(&segment(100, 0, TcpFlags::SYN), no_data);
+    let our_seq = connection.next_segment().unwrap().header.sequence_number.0 + 1;
+    connection.handle_packet(&ack(our_seq), no_data);
+    assert!(connection.next_segment().is_none());
+
+    // slow start: the initial window allows four segments
+    assert_eq!(connection.send(&[0; 10000]), SEND_BUFFER_SIZE);
+    let mut segments = Vec::new();
+    while let Some(segment) = connection.next_segment() {
+        segments.push(segment);
+    }
+    assert_eq!(segments.len(), 4);
+    assert_eq!(connection.bytes_in_flight(), 4 * DEFAULT_MSS);
+
+    // each ACK grows the window by one segment and releases more data
+    let mss = DEFAULT_MSS as u32;
+    connection.handle_packet(&ack(our_seq + mss), no_data);
+    connection.handle_packet(&ack(our_seq + 2 * mss), no_data);
+    assert_eq!(connection.congestion().window(), 6 * DEFAULT_MSS);
+    let mut count = 0;
+    while let Some(segment) = connection.next_segment() {
+        assert_eq!(segment.header.ack_number.0, 101);
+        count += 1;
+    }
+    assert_eq!(count, 4);
+    assert_eq!(connection.bytes_in_flight(), 6 * DEFAULT_MSS);
+
+    // three duplicate ACKs trigger a fast retransmit
+    for _ in 0..3 {
+        connection.handle_packet(&ack(our_seq + 2 * mss), no_data);
+    }
+    assert!(connection.congestion().in_fast_recovery());
+    assert_eq!(connection.congestion().slow_start_threshold(), 3 * DEFAULT_MSS);
+    let retransmitted = connection.next_segment().unwrap();
+    assert_eq!(retransmitted.header.sequence_number.0, our_seq + 2 * mss);
+    assert!(connection.next_segment().is_none());
+
+    // a timeout collapses the window to one segment
+    connection.handle_packet(&ack(our_seq + 3 * mss), no_data);
+    connection.poll(0);
+    connection.poll(INITIAL_RETRANSMISSION_TIMEOUT);
+    assert_eq!(connection.congestion().window(), DEFAULT_MSS);
+    assert_eq!(connection.next_segment().unwrap().header.sequence_number.0,
+               our_seq + 3 * mss);
+}
+
+#[test]
+fn selective_acknowledgment() {
+    use HeapTxPacket;
+
+    let mut connection = TcpConnection::new(test_connection_id());
+    let mut syn = segment(100, 0, TcpFlags::SYN);
+    syn.header.sack_permitted = true;
+    connection.handle_packet(&syn, no_data);
+    let syn_ack = connection.next_segment().unwrap();
+    assert!(syn_ack.header.sack_permitted);
+    let our_seq = syn_ack.header.sequence_number.0 + 1;
+    connection.handle_packet(&segment(101, our_seq, TcpFlags::ACK), no_data);
+
+    // data after a gap is held and reported in a SACK block
+    let data = [0x42; 20];
+    let mut late = segment(111, our_seq, TcpFlags::ACK);
+    late.payload = &data[10..];
+    connection.handle_packet(&late, no_data);
+    let duplicate_ack = connection.next_segment().unwrap();
+    assert_eq!(duplicate_ack.header.ack_number.0, 101);
+    assert_eq!(duplicate_ack.header.sack_blocks[0],
+               Some(SackBlock {
+                        left: Wrapping(111),
+                        right: Wrapping(121),
+                    }));
+
+    // the SACK option survives serialization
+    let mut tx_packet = HeapTxPacket::new((&duplicate_ack).len());
+    (&duplicate_ack).write_out(&mut tx_packet).unwrap();
+    let parsed = TcpPacket::<&[u8]>::parse(tx_packet.as_slice()).unwrap();
+    assert_eq!(parsed.header.sack_blocks, duplicate_ack.header.sack_blocks);
+    assert!(parsed.payload.is_empty());
+
+    // filling the gap delivers the held data too
+    let mut received = 0;
+    let mut missing = segment(101, our_seq, TcpFlags::ACK);
+    missing.payload = &data[..10];
+    connection.handle_packet(&missing, |_, data| {
+        received += data.len();
+        None
+    });
+    assert_eq!(received, 20);
+    let ack = connection.next_segment().unwrap();
+    assert_eq!(ack.header.ack_number.0, 121);
+    assert_eq!(ack.header.sack_blocks, [None; MAX_SACK_BLOCKS]);
+
+    // only the hole that the peer reports is retransmitted
+    let mut ack = segment(121, our_seq, TcpFlags::ACK);
+    ack.header.window_size = 65535;
+    connection.handle_packet(&ack, no_data);
+    connection.send(&[0; 4 * DEFAULT_MSS]);
+    while connection.next_segment().is_some() {}
+    let mss = DEFAULT_MSS as u32;
+    ack.header.sack_blocks[0] = Some(SackBlock {
+                                         left: Wrapping(our_seq + mss),
+                                         right: Wrapping(our_seq + 4 * mss),
+                                     });
+    for _ in 0..3 {
+        connection.handle_packet(&ack, no_data);
+    }
+    assert_eq!(connection.next_segment().unwrap().header.sequence_number.0, our_seq);
+    assert!(connection.next_segment().is_none());
+}
+
+#[test]
+fn push_and_urgent() {
+    use HeapTxPacket;
+
+    let mut connection = TcpConnection::new(test_connection_id());
+    connection.handle_packet(&segment(100, 0, TcpFlags::SYN), no_data);
+    let our_seq = connection.next_segment().unwrap().header.sequence_number.0 + 1;
+    connection.handle_packet(&segment(101, our_seq, TcpFlags::ACK), no_data);
+
+    let mut urgent = segment(101, our_seq, TcpFlags::ACK | TcpFlags::PSH | TcpFlags::URG);
+    urgent.header.urgent_pointer = 2;
+    urgent.payload = &[0xff, 0xf4, b'a', b'b', b'c'];
+
+    let mut tx_packet = HeapTxPacket::new((&urgent).len());
+    (&urgent).write_out(&mut tx_packet).unwrap();
+    let parsed = TcpPacket::<&[u8]>::parse(tx_packet.as_slice()).unwrap();
+    assert_eq!(parsed.header.urgent_pointer, 2);
+
+    let mut marks = None;
+    connection.handle_packet(&parsed, |connection, _| {
+        marks = Some(connection.data_marks());
+        None
+    });
+    assert_eq!(marks,
+               Some(DataMarks {
+                        push: true,
+                        urgent_len: Some(2),
+                    }));
+}
+
+#[test]
+fn receive_buffer() {
+    let mut connection = TcpConnection::new(test_connection_id());
+    connection.enable_receive_buffer(8);
+    connection.handle_packet(&segment(100, 0, TcpFlags::SYN), no_data);
+    let syn_ack = connection.next_segment().unwrap();
+    assert_eq!(syn_ack.header.window_size, 8);
+    let our_seq = syn_ack.header.sequence_number.0 + 1;
+    connection.handle_packet(&segment(101, our_seq, TcpFlags::ACK), no_data);
+
+    let mut data = segment(101, our_seq, TcpFlags::ACK | TcpFlags::PSH);
+    data.payload = b"\x00\x06hel";
+    connection.handle_packet(&data, |_, _| panic!("data is buffered"));
+    data.header.sequence_number = Wrapping(106);
+    data.payload = b"lo world";
+    connection.handle_packet(&data, no_data);
+
+    // only the free buffer space is accepted
+    while let Some(ack) = connection.next_segment() {
+        assert_eq!(ack.header.ack_number.0, 109);
+        assert_eq!(ack.header.window_size, 0);
+    }
+    assert_eq!(connection.peek(), b"\x00\x06hello ");
+
+    let mut buf = [0; 2];
+    assert_eq!(connection.read(&mut buf), 2);
+    assert_eq!(buf, [0x00, 0x06]);
+    let window_update = connection.next_segment().unwrap();
+    assert_eq!(window_update.header.window_size, 2);
+    assert_eq!(connection.peek(), b"hello ");
+}
+
+#[test]
+fn connection_events() {
+    let ip = Ipv4Address::new(192, 168, 1, 2);
+    let peer = Ipv4Address::new(192, 168, 1, 1);
+    let remote = Endpoint::new(peer, 50000);
+    let mut listener = TcpListener::new(Endpoint::new(ip, 80), 4, 2);
+
+    listener.handle_packet(peer, &segment(100, 0, TcpFlags::SYN), no_data);
+    let our_seq = listener.connection(remote).unwrap().next_segment().unwrap()
+        .header.sequence_number.0 + 1;
+    listener.handle_packet(peer, &segment(101, our_seq, TcpFlags::ACK), no_data);
+    assert_eq!(listener.poll_event(), Some((remote, TcpEvent::Established)));
+
+    listener.handle_packet(peer, &segment(101, our_seq, TcpFlags::FIN | TcpFlags::ACK), no_data);
+    assert_eq!(listener.poll_event(), Some((remote, TcpEvent::PeerClosed)));
+    listener.connection(remote).unwrap().close();
+    listener.handle_packet(peer, &segment(102, our_seq + 1, TcpFlags::ACK), no_data);
+    assert_eq!(listener.poll_event(), Some((remote, TcpEvent::Closed)));
+    assert!(listener.connection(remote).is_none());
+
+    // a reset aborts the connection
+    let mut connection = TcpConnection::new(test_connection_id());
+    connection.handle_packet(&segment(100, 0, TcpFlags::SYN), no_data);
+    connection.handle_packet(&segment(101, our_seq, TcpFlags::ACK), no_data);
+    assert_eq!(connection.poll_event(), Some(TcpEvent::Established));
+    connection.handle_packet(&segment(5000, 0, TcpFlags::RST), no_data);
+    assert_eq!(connection.state(), TcpState::Established); // outside of the window
+    connection.handle_packet(&segment(101, 0, TcpFlags::RST), no_data);
+    assert_eq!(connection.poll_event(), Some(TcpEvent::Reset));
+    assert!(connection.is_closed());
+
+    // a peer that stops acknowledging times out
+    let mut connection = TcpConnection::new(test_connection_id());
+    connection.handle_packet(&segment(100, 0, TcpFlags::SYN), no_data);
+    let mut ack = segment(101, our_seq, TcpFlags::ACK);
+    ack.header.window_size = 65535;
+    connection.handle_packet(&ack, no_data);
+    connection.send(b"hello");
+    let mut now = 0;
+    let mut last_retransmission = 0;
+    while !connection.is_closed() {
+        let retransmissions = connection.retransmissions;
+        connection.poll(now);
+        while connection.next_segment().is_some() {}
+        if connection.retransmissions != retransmissions {
+            last_retransmission = now;
+        }
+        now += 1000;
+    }
+    // the last retransmission gets its full timeout
+    assert!(now - 1000 >= last_retransmission + connection.retransmission_timeout);
+    assert_eq!(connection.poll_event(), Some(TcpEvent::Established));
+    assert_eq!(connection.poll_event(), Some(TcpEvent::TimedOut));
+}
+
+#[test]
+fn header_builder() {
+    use HeapTxPacket;
+
+    let header = TcpHeaderBuilder::new(80, 50000)
+        .sequence_number(1000)
+        .ack_number(2000)
+        .flags(TcpFlags::FIN | TcpFlags::ACK | TcpFlags::PSH)
+        .window_size(512)
+        .sack_permitted(true)
+        .build();
+    let packet = TcpPacket { header, payload: &b"bye"[..] };
+    let mut tx_packet = HeapTxPacket::new((&packet).len());
+    (&packet).write_out(&mut tx_packet).unwrap();
+
+    // the data offset covers the SACK-permitted option, but isn't a flag
+    assert_eq!(tx_packet.as_slice()[12] >> 4, 6);
+    let parsed = TcpPacket::<&[u8]>::parse(tx_packet.as_slice()).unwrap();
+    assert_eq!(parsed, packet);
+    assert_eq!(parsed.header.flags.bits(), 0x19);
+}
+
+#[test]
+fn nagle() {
+    let mut connection = TcpConnection::new(test_connection_id());
+    let ack = |ack_number| {
+        let mut packet = segment(101, ack_number, TcpFlags::ACK);
+        packet.header.window_size = 65535;
+        packet
+    };
+    connection.handle_packet(&segment(100, 0, TcpFlags::SYN), no_data);
+    let our_seq = connection.next_segment().unwrap().header.sequence_number.0 + 1;
+    connection.handle_packet(&ack(our_seq), no_data);
+
+    // small writes are coalesced while data is unacknowledged
+    connection.send(b"a");
+    connection.send(b"b");
+    connection.send(b"c");
+    assert_eq!(&connection.next_segment().unwrap().payload[..], b"a");
+    assert!(connection.next_segment().is_none());
+    connection.handle_packet(&ack(our_seq + 1), no_data);
+    assert_eq!(&connection.next_segment().unwrap().payload[..], b"bc");
+
+    connection.set_nodelay(true);
+    connection.send(b"d");
+    assert_eq!(&connection.next_segment().unwrap().payload[..], b"d");
+}
+
+#[test]
+fn timestamps() {
+    use HeapTxPacket;
+
+    let mut connection = TcpConnection::new(test_connection_id());
+    let segment = |sequence_number, ack_number, flags, echo_reply| {
+        let mut packet = segment(sequence_number, ack_number, flags);
+        packet.header.window_size = 65535;
+        packet.header.timestamp = Some(TcpTimestamp {
+                                           value: 7000,
+                                           echo_reply,
+                                       });
+        packet
+    };
+    connection.poll(1000);
+    connection.handle_packet(&segment(100, 0, TcpFlags::SYN, 0), no_data);
+    let syn_ack = connection.next_segment().unwrap();
+    assert_eq!(syn_ack.header.timestamp,
+               Some(TcpTimestamp {
+                        value: 1000,
+                        echo_reply: 7000,
+                    }));
+    let our_seq = syn_ack.header.sequence_number.0 + 1;
+
+    // the option survives serialization
+    let mut tx_packet = HeapTxPacket::new((&syn_ack).len());
+    (&syn_ack).write_out(&mut tx_packet).unwrap();
+    let parsed = TcpPacket::<&[u8]>::parse(tx_packet.as_slice()).unwrap();
+    assert_eq!(parsed.header, syn_ack.header);
+
+    connection.poll(1040);
+    connection.handle_packet(&segment(101, our_seq, TcpFlags::ACK, 1000), no_data);
+    assert_eq!(connection.rtt().smoothed_rtt(), Some(40));
+    assert_eq!(connection.rtt().retransmission_timeout(), MIN_RETRANSMISSION_TIMEOUT);
+
+    // a lost segment is retransmitted after the measured timeout, counted from sending
+    connection.send(b"hello");
+    assert_eq!(connection.next_segment().unwrap().header.timestamp.unwrap().value, 1040);
+    connection.poll(1040 + MIN_RETRANSMISSION_TIMEOUT - 1);
+    assert!(connection.next_segment().is_none());
+    connection.poll(1040 + MIN_RETRANSMISSION_TIMEOUT);
+    let retransmitted = connection.next_segment().unwrap();
+    assert_eq!(retransmitted.header.timestamp.unwrap().value, 1240);
+
+    connection.poll(1340);
+    connection.handle_packet(&segment(101, our_seq + 5, TcpFlags::ACK, 1240), no_data);
+    assert_eq!(connection.rtt().smoothed_rtt(), Some((7 * 40 + 100) / 8));
+}
+
+#[test]
+fn active_open_timestamps() {
+    let with_timestamp = |mut packet: TcpPacket<&'static [u8]>, echo_reply| {
+        packet.header.timestamp = Some(TcpTimestamp {
+                                           value: 9000,
+                                           echo_reply,
+                                       });
+        packet
+    };
+
+    // the SYN offers timestamps and the SYN-ACK accepts them
+    let mut connection = TcpConnection::new(test_connection_id());
+    connection.poll(500);
+    connection.connect();
+    let syn = connection.next_segment().unwrap();
+    assert_eq!(syn.header.timestamp,
+               Some(TcpTimestamp {
+                        value: 500,
+                        echo_reply: 0,
+                    }));
+    let our_seq = syn.header.sequence_number.0 + 1;
+    connection.poll(530);
+    let syn_ack = with_timestamp(segment(100, our_seq, TcpFlags::SYN | TcpFlags::ACK), 500);
+    connection.handle_packet(&syn_ack, no_data);
+    assert_eq!(connection.state(), TcpState::Established);
+    assert_eq!(connection.rtt().smoothed_rtt(), Some(30));
+    assert_eq!(connection.next_segment().unwrap().header.timestamp.unwrap().echo_reply, 9000);
+
+    // a peer without timestamps
+    let mut connection = TcpConnection::new(test_connection_id());
+    connection.connect();
+    let our_seq = connection.next_segment().unwrap().header.sequence_number.0 + 1;
+    connection.handle_packet(&segment(100, our_seq, TcpFlags::SYN | TcpFlags::ACK), no_data);
+    assert_eq!(connection.state(), TcpState::Established);
+    assert_eq!(connection.next_segment().unwrap().header.timestamp, None);
+    assert_eq!(connection.retransmission_deadline, None); // the ACK isn't retransmitted
+
+    // simultaneous open: only if both SYNs carry them
+    let mut connection = TcpConnection::new(test_connection_id());
+    connection.connect();
+    connection.next_segment().unwrap();
+    connection.handle_packet(&segment(100, 0, TcpFlags::SYN), no_data);
+    assert_eq!(connection.state(), TcpState::SynReceived);
+    assert_eq!(connection.next_segment().unwrap().header.timestamp, None);
+
+    let mut connection = TcpConnection::new(test_connection_id());
+    connection.connect();
+    connection.next_segment().unwrap();
+    connection.handle_packet(&with_timestamp(segment(100, 0, TcpFlags::SYN), 0), no_data);
+    assert_eq!(connection.next_segment().unwrap().header.timestamp.unwrap().echo_reply, 9000);
+}
+
+#[test]
+fn half_close() {
+    let mut connection = TcpConnection::new(test_connection_id());
+    connection.enable_receive_buffer(64);
+    let isn = connection.sequence_number();
+    connection.handle_packet(&segment(100, 0, TcpFlags::SYN), no_data);
+    connection.handle_packet(&segment(101, isn.wrapping_add(1), TcpFlags::ACK), no_data);
+    while connection.next_segment().is_some() {}
+
+    // the request ends with a FIN
+    let flags = TcpFlags::FIN | TcpFlags::ACK | TcpFlags::PSH;
+    let mut request = segment(101, isn.wrapping_add(1), flags);
+    request.payload = b"GET /";
+    connection.handle_packet(&request, no_data);
+    assert_eq!(connection.state(), TcpState::CloseWait);
+    assert_eq!(connection.poll_event(), Some(TcpEvent::Established));
+    assert_eq!(connection.poll_event(), Some(TcpEvent::PeerClosed));
+    let ack = connection.next_segment().unwrap();
+    assert_eq!((ack.header.flags, ack.header.ack_number.0), (TcpFlags::ACK, 107));
+    assert_eq!(connection.peek(), b"GET /");
+
+    // the response is sent before our FIN
+    assert_eq!(connection.send(b"200"), 3);
+    connection.close();
+    assert_eq!(connection.state(), TcpState::LastAck);
+    assert_eq!(connection.next_segment().unwrap().payload, Box::from(&b"200"[..]));
+    let fin = connection.next_segment().unwrap();
+    assert_eq!(fin.header.flags, TcpFlags::FIN | TcpFlags::ACK);
+    assert_eq!(fin.header.sequence_number.0, isn.wrapping_add(4));
+
+    connection.handle_packet(&segment(107, isn.wrapping_add(4), TcpFlags::ACK), no_data);
+    assert_eq!(connection.state(), TcpState::LastAck);
+    connection.handle_packet(&segment(107, isn.wrapping_add(5), TcpFlags::ACK), no_data);
+    assert_eq!(connection.poll_event(), Some(TcpEvent::Closed));
+}
+
+#[test]
+fn close_on_peer_fin() {
+    fn echo<'d>(_: &TcpConnection, data: &'d [u8]) -> Option<Cow<'d, [u8]>> {
+        Some(Cow::Borrowed(data))
+    }
+
+    for &close in &[false, true] {
+        let mut connection = TcpConnection::new(test_connection_id());
+        connection.set_close_on_peer_fin(close);
+        let isn = connection.sequence_number();
+        connection.handle_packet(&segment(100, 0, TcpFlags::SYN), echo);
+        connection.handle_packet(&segment(101, isn.wrapping_add(1), TcpFlags::ACK), echo);
+        while connection.next_segment().is_some() {}
+
+        // the callback answers a request that ends with a FIN
+        let flags = TcpFlags::FIN | TcpFlags::ACK | TcpFlags::PSH;
+        let mut request = segment(101, isn.wrapping_add(1), flags);
+        request.payload = b"ping";
+        connection.handle_packet(&request, echo);
+        let reply = connection.next_segment().unwrap();
+        assert_eq!((&reply.payload[..], reply.header.ack_number.0), (&b"ping"[..], 106));
+
+        if close {
+            assert_eq!(connection.state(), TcpState::LastAck);
+            let fin = connection.next_segment().unwrap();
+            assert_eq!(fin.header.flags, TcpFlags::FIN | TcpFlags::ACK);
+        } else {
+            // the application can still send until it closes the connection
+            assert_eq!(connection.state(), TcpState::CloseWait);
+            assert!(connection.next_segment().is_none());
+            connection.handle_packet(&segment(106, isn.wrapping_add(5), TcpFlags::ACK), echo);
+            assert_eq!(connection.send(b"pong"), 4);
+            connection.close();
+            assert_eq!(connection.state(), TcpState::LastAck);
+        }
+    }
+}
+
+#[test]
+fn random_segment_sequences() {
+    use alloc::VecDeque;
+
+    struct Side {
+        connection: TcpConnection,
+        sent: Vec<u8>,
+        received: Vec<u8>,
+        closed: bool,
+    }
+
+    fn is_open(side: &Side) -> bool {
+        match side.connection.state() {
+            TcpState::Established | TcpState::CloseWait => true,
+            _ => false,
+        }
+    }
+
+    fn transmit(from: &mut Side, wire: &mut VecDeque<TcpPacket<Box<[u8]>>>) {
+        while let Some(segment) = from.connection.next_segment() {
+            wire.push_back(segment);
+        }
+    }
+
+    fn deliver(wire: &mut VecDeque<TcpPacket<Box<[u8]>>>, to: &mut Side) {
+        if let Some(segment) = wire.pop_front() {
+            let segment = TcpPacket {
+                header: segment.header,
+                payload: &segment.payload[..],
+            };
+            to.connection.handle_packet(&segment, no_data);
+        }
+        let mut buf = [0; 64];
+        loop {
+            let len = to.connection.read(&mut buf);
+            if len == 0 {
+                break;
+            }
+            to.received.extend_from_slice(&buf[..len]);
+        }
+    }
+
+    let mut random: u32 = 0x2545_f491;
+    let mut next = move |n: u32| {
+        random ^= random << 13;
+        random ^= random >> 17;
+        random ^= random << 5;
+        random % n
+    };
+
+    for _ in 0..200 {
+        let server_id = test_connection_id();
+        let client_id = ConnectionId::new(server_id.remote, server_id.local);
+        let mut sides = [Side {
+                             connection: TcpConnection::new(client_id),
+                             sent: Vec::new(),
+                             received: Vec::new(),
+                             closed: false,
+                         },
+                         Side {
+                             connection: TcpConnection::new(server_id),
+                             sent: Vec::new(),
+                             received: Vec::new(),
+                             closed: false,
+                         }];
+        let mut wires = [VecDeque::new(), VecDeque::new()];
+        for side in &mut sides {
+            side.connection.enable_receive_buffer(64 + next(512) as usize);
+        }
+        sides[0].connection.connect();
+        if next(2) == 0 {
+            sides[1].connection.connect(); // simultaneous open
+        }
+
+        let mut now = 0;
+        for _ in 0..next(200) {
+            let i = next(2) as usize;
+            let (this, other) = if i == 0 {
+                let (a, b) = sides.split_at_mut(1);
+                (&mut a[0], &mut b[0])
+            } else {
+                let (a, b) = sides.split_at_mut(1);
+                (&mut b[0], &mut a[0])
+            };
+            match next(6) {
+                0 => {
+                    let data: Vec<u8> = (0..next(300)).map(|_| next(256) as u8).collect();
+                    let len = this.connection.send(&data);
+                    this.sent.extend_from_slice(&data[..len]);
+                }
+                1 if next(8) == 0 && !this.closed && is_open(this) => {
+                    this.closed = true;
+                    this.connection.close();
+                }
+                2 => {
+                    now += u64::from(next(50));
+                    this.connection.poll(now);
+                }
+                _ => {
+                    transmit(this, &mut wires[i]);
+                    deliver(&mut wires[i], other);
+                }
+            }
+        }
+
+        // close both sides after the handshake and exchange everything that is left
+        for _ in 0..1000 {
+            for i in 0..2 {
+                let (a, b) = sides.split_at_mut(1);
+                let (this, other) = if i == 0 {
+                    (&mut a[0], &mut b[0])
+                } else {
+                    (&mut b[0], &mut a[0])
+                };
+                if is_open(this) {
+                    this.connection.close();
+                }
+                transmit(this, &mut wires[i]);
+                while !wires[i].is_empty() {
+                    deliver(&mut wires[i], other);
+                }
+            }
+            if sides.iter().all(|side| side.connection.is_closed()) {
+                break;
+            }
+            now += 500;
+            for side in &mut sides {
+                side.connection.poll(now);
+            }
+        }
+
+        for side in &mut sides {
+            assert!(side.connection.is_closed(), "stuck in {:?}", side.connection.state());
+            let mut events = Vec::new();
+            while let Some(event) = side.connection.poll_event() {
+                events.push(event);
+            }
+            assert_eq!(events.first(), Some(&TcpEvent::Established));
+            assert_eq!(events.last(), Some(&TcpEvent::Closed));
+        }
+        assert!(sides[0].sent == sides[1].received);
+        assert!(sides[1].sent == sides[0].received);
     }
 }
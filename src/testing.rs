@@ -0,0 +1,331 @@
+//! Helpers for byte-exact packet tests and soak tests with corrupted frames, for
+//! this crate and downstream crates.
+//!
+//! Requires the `testing` feature.
+
+use alloc::Vec;
+use byteorder::{ByteOrder, NetworkEndian};
+use core::fmt;
+use interface::Interface;
+use ip_checksum::Checksum;
+use {HeapTxPacket, WriteOut};
+
+/// Parses a hex string like `"45 00 00 1c"` into a `Vec<u8>`, see `parse_hex`.
+#[macro_export]
+macro_rules! hex {
+    ($s:expr) => ($crate::testing::parse_hex($s))
+}
+
+/// Parses pairs of hex digits into bytes. Whitespace is ignored.
+///
+/// Panics on invalid input, since it's meant for literals in tests.
+pub fn parse_hex(s: &str) -> Vec<u8> {
+    let digits: Vec<u8> = s.bytes()
+        .filter(|&b| !(b as char).is_whitespace())
+        .map(|b| match b {
+                 b'0'...b'9' => b - b'0',
+                 b'a'...b'f' => b - b'a' + 10,
+                 b'A'...b'F' => b - b'A' + 10,
+                 _ => panic!("invalid hex digit {:?}", b as char),
+             })
+        .collect();
+    assert!(digits.len() % 2 == 0, "odd number of hex digits");
+    digits.chunks(2).map(|pair| pair[0] << 4 | pair[1]).collect()
+}
+
+/// Serializes `packet` and panics with a hex dump of both versions if the result
+/// differs from `expected`.
+pub fn assert_serializes_to<T: WriteOut>(packet: &T, expected: &[u8]) {
+    let mut tx_packet = HeapTxPacket::new(packet.len());
+    packet.write_out(&mut tx_packet).expect("serialization failed");
+    let data = tx_packet.as_slice();
+    assert_eq!(data.len(), packet.len(), "`len` doesn't match the written bytes");
+
+    if data != expected {
+        let offset = data.iter()
+            .zip(expected)
+            .position(|(a, b)| a != b)
+            .unwrap_or(usize::min(data.len(), expected.len()));
+        panic!("packets differ at offset {:#x}\nwritten: {:?}\nexpected: {:?}",
+               offset,
+               HexDumpPrint(data),
+               HexDumpPrint(expected));
+    }
+}
+
+pub struct HexDumpPrint<'a>(pub &'a [u8]);
+
+impl<'a> fmt::Debug for HexDumpPrint<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        for (i, byte) in self.0.iter().enumerate() {
+            if i % 16 == 0 {
+                write!(fmt, "\n{:04x}  ", i)?;
+            }
+            write!(fmt, " {:02x}", byte)?;
+        }
+        writeln!(fmt, "")
+    }
+}
+
+/// A lossy link for soak tests, which flips bits in, truncates, duplicates, and
+/// drops the frames that pass through it.
+///
+/// Each fault is applied independently with its probability in percent, decided by
+/// a pseudo-random generator, so a run is reproducible from its seed.
+#[derive(Debug, Clone)]
+pub struct Corrupt {
+    random: u32,
+    pub bit_flip_percent: u8,
+    pub truncate_percent: u8,
+    pub duplicate_percent: u8,
+    pub drop_percent: u8,
+    pub stats: CorruptStats,
+}
+
+/// The number of frames that were affected by each fault.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CorruptStats {
+    pub frames: u64,
+    pub bit_flips: u64,
+    pub truncations: u64,
+    pub duplicates: u64,
+    pub drops: u64,
+}
+
+impl Corrupt {
+    /// Creates a link that corrupts nothing until the probabilities are set.
+    pub fn new(seed: u32) -> Corrupt {
+        Corrupt {
+            random: seed | 1,
+            bit_flip_percent: 0,
+            truncate_percent: 0,
+            duplicate_percent: 0,
+            drop_percent: 0,
+            stats: CorruptStats::default(),
+        }
+    }
+
+    /// Applies every fault to the given percentage of the frames.
+    pub fn with_faults(self, percent: u8) -> Corrupt {
+        Corrupt {
+            bit_flip_percent: percent,
+            truncate_percent: percent,
+            duplicate_percent: percent,
+            drop_percent: percent,
+            ..self
+        }
+    }
+
+    fn random(&mut self) -> u32 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 17;
+        self.random ^= self.random << 5;
+        self.random
+    }
+
+    fn chance(&mut self, percent: u8) -> bool {
+        self.random() % 100 < u32::from(percent)
+    }
+
+    /// Passes a frame through the link and returns the frames that arrive, none if
+    /// it was dropped and two if it was duplicated.
+    pub fn transmit(&mut self, frame: &[u8]) -> Vec<Vec<u8>> {
+        self.stats.frames += 1;
+        if self.chance(self.drop_percent) {
+            self.stats.drops += 1;
+            return Vec::new();
+        }
+        let mut frame = frame.to_vec();
+        if !frame.is_empty() && self.chance(self.bit_flip_percent) {
+            self.stats.bit_flips += 1;
+            let bit = self.random() as usize % (frame.len() * 8);
+            frame[bit / 8] ^= 1 << (bit % 8);
+        }
+        if !frame.is_empty() && self.chance(self.truncate_percent) {
+            self.stats.truncations += 1;
+            let len = self.random() as usize % frame.len();
+            frame.truncate(len);
+        }
+        let mut frames = Vec::new();
+        if self.chance(self.duplicate_percent) {
+            self.stats.duplicates += 1;
+            frames.push(frame.clone());
+        }
+        frames.push(frame);
+        frames
+    }
+
+    /// Polls `from` and passes the frames it sends through the link to `to`. Parse
+    /// errors of `to` are ignored. Returns the number of frames that `from` sent.
+    pub fn forward(&mut self, from: &mut Interface, to: &mut Interface, now: u64) -> usize {
+        let mut sent = 0;
+        while let Some(frame) = from.poll(now) {
+            sent += 1;
+            for frame in self.transmit(&frame) {
+                let _ = to.receive(&frame);
+            }
+        }
+        sent
+    }
+}
+
+/// Captured frames between a client 00:08:dc:ab:cd:ef (192.168.1.100) and a router
+/// 00:11:22:33:44:55 (192.168.1.1), as hex strings for `hex!`.
+pub mod frames {
+    /// A DHCP offer of 192.168.1.100 from the server at 192.168.1.1.
+    pub const DHCP_OFFER: &str = "\
+        00 08 dc ab cd ef 00 11 22 33 44 55 08 00 45 00\
+        01 2e 1c 46 40 00 40 11 99 c3 c0 a8 01 01 c0 a8\
+        01 64 00 43 00 44 01 1a 05 4d 02 01 06 00 39 03\
+        f3 26 00 00 00 00 00 00 00 00 c0 a8 01 64 c0 a8\
+        01 01 00 00 00 00 00 08 dc ab cd ef 00 00 00 00\
+        00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\
+        00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\
+        00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\
+        00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\
+        00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\
+        00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\
+        00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\
+        00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\
+        00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\
+        00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\
+        00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\
+        00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\
+        00 00 00 00 00 00 63 82 53 63 35 01 02 36 04 c0\
+        a8 01 01 33 04 00 00 0e 10 01 04 ff ff ff 00 03\
+        04 c0 a8 01 01 06 04 c0 a8 01 01 ff";
+
+    /// A DNS reply from 192.168.1.1 that resolves `example.com` to 93.184.216.34.
+    pub const DNS_REPLY: &str = "\
+        00 08 dc ab cd ef 00 11 22 33 44 55 08 00 45 00\
+        00 49 2e 01 40 00 40 11 88 ed c0 a8 01 01 c0 a8\
+        01 64 00 35 c0 00 00 35 53 7a 1a 2b 81 80 00 01\
+        00 01 00 00 00 00 07 65 78 61 6d 70 6c 65 03 63\
+        6f 6d 00 00 01 00 01 c0 0c 00 01 00 01 00 00 0e\
+        10 00 04 5d b8 d8 22";
+
+    /// The SYN from 192.168.1.100:49152 to 192.168.1.1:80, with MSS, SACK-permitted,
+    /// and window scale options.
+    pub const TCP_SYN: &str = "\
+        00 11 22 33 44 55 00 08 dc ab cd ef 08 00 45 00\
+        00 34 00 01 40 00 40 06 b7 0d c0 a8 01 64 c0 a8\
+        01 01 c0 00 00 50 01 00 00 00 00 00 00 00 80 02\
+        fa f0 2f 1b 00 00 02 04 05 b4 04 02 01 03 03 07\
+        01 00";
+
+    /// The SYN-ACK answering `TCP_SYN`.
+    pub const TCP_SYN_ACK: &str = "\
+        00 08 dc ab cd ef 00 11 22 33 44 55 08 00 45 00\
+        00 30 00 00 40 00 40 06 b7 12 c0 a8 01 01 c0 a8\
+        01 64 00 50 c0 00 00 00 20 00 01 00 00 01 70 12\
+        72 10 ab f7 00 00 02 04 05 b4 01 01 04 02";
+
+    /// The ACK completing the handshake, with Ethernet padding.
+    pub const TCP_ACK: &str = "\
+        00 11 22 33 44 55 00 08 dc ab cd ef 08 00 45 00\
+        00 28 00 02 40 00 40 06 b7 18 c0 a8 01 64 c0 a8\
+        01 01 c0 00 00 50 01 00 00 01 00 00 20 01 50 10\
+        fa f0 4f db 00 00 00 00 00 00 00 00";
+}
+
+/// Returns `frames::TCP_ACK`, but acknowledging the SYN of `syn_ack` instead of the
+/// one of `frames::TCP_SYN_ACK`, since the initial sequence numbers of the stack
+/// aren't fixed.
+pub fn tcp_ack_for(syn_ack: &[u8]) -> Vec<u8> {
+    let mut frame = hex!(frames::TCP_ACK);
+    let mut ack_number = [0; 4];
+    NetworkEndian::write_u32(&mut ack_number,
+                             NetworkEndian::read_u32(&syn_ack[38..42]).wrapping_add(1));
+    let mut checksum = Checksum::new();
+    checksum.add_sum(!NetworkEndian::read_u16(&frame[50..52]), 20);
+    checksum.replace_bytes(8, &frame[42..46], &ack_number);
+    frame[42..46].copy_from_slice(&ack_number);
+    NetworkEndian::write_u16(&mut frame[50..52], !checksum.sum());
+    frame
+}
+
+#[test]
+fn canned_frames() {
+    use ethernet::{EthernetAddress, EthernetKind};
+    use ipv4::Ipv4Kind;
+    use parse::parse;
+    use tcp::TcpFlags;
+    use udp::UdpKind;
+
+    let offer = hex!(frames::DHCP_OFFER);
+    match parse(&offer).unwrap().payload {
+        EthernetKind::Ipv4(ip) => {
+            match ip.payload {
+                Ipv4Kind::Udp(udp) => {
+                    match udp.payload {
+                        UdpKind::Dhcp(dhcp) => {
+                            let mac = [0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef];
+                            assert_eq!(dhcp.mac(), Some(EthernetAddress::new(mac)))
+                        }
+                        _ => panic!(),
+                    }
+                }
+                _ => panic!(),
+            }
+        }
+        _ => panic!(),
+    }
+    assert!(parse(&hex!(frames::DNS_REPLY)).is_ok());
+
+    for &(frame, flags) in &[(frames::TCP_SYN, TcpFlags::SYN),
+                             (frames::TCP_SYN_ACK, TcpFlags::SYN | TcpFlags::ACK),
+                             (frames::TCP_ACK, TcpFlags::ACK)] {
+        let frame = hex!(frame);
+        match parse(&frame).unwrap().payload {
+            EthernetKind::Ipv4(ip) => {
+                match ip.payload {
+                    Ipv4Kind::Tcp(tcp) => assert_eq!(tcp.header.flags, flags),
+                    _ => panic!(),
+                }
+            }
+            _ => panic!(),
+        }
+    }
+
+    assert_eq!(hex!("0a FF\n 10"), [0x0a, 0xff, 0x10]);
+    assert_serializes_to(&&b"abc"[..], &hex!("61 62 63"));
+}
+
+#[test]
+fn corrupt_soak() {
+    use alloc::borrow::Cow;
+    use ethernet::EthernetAddress;
+    use ipv4::{Endpoint, Ipv4Address};
+    use parse::parse;
+    use tcp::TcpListener;
+
+    let client_mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let router_ip = Ipv4Address::new(192, 168, 1, 1);
+    let mut client = Interface::new(client_mac, Ipv4Address::new(192, 168, 1, 100));
+    let mut router =
+        Interface::new(EthernetAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]), router_ip);
+    let listener = TcpListener::new(Endpoint::new(router_ip, 80), 4, 2);
+    router.add_tcp_listener(listener, |_, data| Some(Cow::Borrowed(data)));
+
+    let frames: Vec<Vec<u8>> = [frames::DHCP_OFFER,
+                                frames::DNS_REPLY,
+                                frames::TCP_SYN,
+                                frames::TCP_ACK]
+            .iter()
+            .map(|&frame| hex!(frame))
+            .collect();
+    let mut link = Corrupt::new(42).with_faults(20);
+    for now in 0..2000 {
+        let frame = &frames[now as usize % frames.len()];
+        for frame in link.transmit(frame) {
+            let _ = parse(&frame);
+            let _ = client.receive(&frame);
+            let _ = router.receive(&frame);
+        }
+        link.forward(&mut router, &mut client, now * 10);
+        link.forward(&mut client, &mut router, now * 10);
+    }
+    let stats = link.stats;
+    assert!(stats.bit_flips > 0 && stats.truncations > 0 && stats.duplicates > 0);
+    assert!(stats.drops > 0);
+}
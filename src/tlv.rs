@@ -0,0 +1,190 @@
+//! Options in the type-length-value format with one-byte codes and lengths, as used
+//! by DHCP and TCP.
+//!
+//! The formats differ in whether the length counts the code and length bytes, and
+//! in their single-byte pad and end codes, which is described by a `TlvFormat`.
+
+use TxPacket;
+use parse::{Layer, ParseError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TlvFormat {
+    /// The code of single-byte padding options.
+    pub pad: u8,
+    /// The code of the single-byte option that ends the list.
+    pub end: u8,
+    /// The length byte counts the code and the length byte itself.
+    pub len_includes_header: bool,
+}
+
+impl TlvFormat {
+    /// The number of bytes of an option with a `value_len` bytes value.
+    pub fn option_len(&self, value_len: usize) -> usize {
+        2 + value_len
+    }
+
+    fn len_byte(&self, value_len: usize) -> Result<u8, ()> {
+        let len = if self.len_includes_header { value_len + 2 } else { value_len };
+        if len > usize::from(u8::max_value()) {
+            return Err(());
+        }
+        Ok(len as u8)
+    }
+
+    /// Writes an option. Fails if the value is too long for the length byte.
+    pub fn push<T: TxPacket>(&self, packet: &mut T, code: u8, value: &[u8]) -> Result<(), ()> {
+        let len = self.len_byte(value.len())?;
+        packet.push_bytes(&[code, len])?;
+        packet.push_bytes(value)?;
+        Ok(())
+    }
+
+    /// Writes the code and a placeholder length of an option whose value is written
+    /// next, e.g. as sub-options. `TlvStart::finish` sets the length.
+    pub fn start<T: TxPacket>(&self, packet: &mut T, code: u8) -> Result<TlvStart, ()> {
+        let index = packet.push_bytes(&[code, 0])?;
+        Ok(TlvStart {
+               format: *self,
+               index,
+           })
+    }
+
+    /// Iterates over the options in `data`, which start at `offset` in the data of
+    /// `layer`.
+    pub fn options<'a>(&self, data: &'a [u8], layer: Layer, offset: usize) -> TlvOptions<'a> {
+        TlvOptions {
+            format: *self,
+            data,
+            layer,
+            offset,
+            index: 0,
+            ended: false,
+        }
+    }
+}
+
+/// An option whose length isn't written yet, see `TlvFormat::start`.
+#[derive(Debug)]
+#[must_use]
+pub struct TlvStart {
+    format: TlvFormat,
+    index: usize,
+}
+
+impl TlvStart {
+    /// Sets the length to the bytes written since `start`.
+    pub fn finish<T: TxPacket>(self, packet: &mut T) -> Result<(), ()> {
+        let len = self.format.len_byte(packet.len() - self.index - 2)?;
+        packet.set_bytes(self.index + 1, &[len]);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TlvOption<'a> {
+    pub code: u8,
+    /// The offset of the code in the data of the layer.
+    pub offset: usize,
+    pub value: &'a [u8],
+}
+
+impl<'a> TlvOption<'a> {
+    /// The offset of the value in the data of the layer.
+    pub fn value_offset(&self) -> usize {
+        self.offset + 2
+    }
+}
+
+/// Yields the options up to the end option or the end of the data, skipping padding.
+/// An option that exceeds the data is an error, after which the iteration stops.
+#[derive(Debug, Clone)]
+pub struct TlvOptions<'a> {
+    format: TlvFormat,
+    data: &'a [u8],
+    layer: Layer,
+    offset: usize,
+    index: usize,
+    ended: bool,
+}
+
+impl<'a> TlvOptions<'a> {
+    /// The end option was found.
+    pub fn ended(&self) -> bool {
+        self.ended
+    }
+}
+
+impl<'a> Iterator for TlvOptions<'a> {
+    type Item = Result<TlvOption<'a>, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let data = self.data;
+        while self.index < data.len() && data[self.index] == self.format.pad {
+            self.index += 1;
+        }
+        if self.index >= data.len() || self.ended {
+            return None;
+        }
+        let index = self.index;
+        if data[index] == self.format.end {
+            self.ended = true;
+            return None;
+        }
+
+        self.index = data.len(); // stop after errors
+        let len = match data.get(index + 1) {
+            Some(&len) => usize::from(len),
+            None => return Some(Err(ParseError::truncated(self.layer, self.offset + data.len()))),
+        };
+        let end = if self.format.len_includes_header {
+            if len < 2 {
+                return Some(Err(ParseError::malformed(self.layer,
+                                                      self.offset + index + 1,
+                                                      "invalid option length")));
+            }
+            index + len
+        } else {
+            index + 2 + len
+        };
+        if end > data.len() {
+            return Some(Err(ParseError::truncated(self.layer, self.offset + data.len())));
+        }
+        self.index = end;
+        Some(Ok(TlvOption {
+                    code: data[index],
+                    offset: self.offset + index,
+                    value: &data[(index + 2)..end],
+                }))
+    }
+}
+
+#[test]
+fn sub_options() {
+    use HeapTxPacket;
+
+    let format = TlvFormat {
+        pad: 0,
+        end: 255,
+        len_includes_header: false,
+    };
+    let mut packet = HeapTxPacket::new(16);
+    let option = format.start(&mut packet, 82).unwrap();
+    format.push(&mut packet, 1, b"eth0").unwrap();
+    option.finish(&mut packet).unwrap();
+    packet.push_bytes(&[0, 255]).unwrap();
+    let data = packet.as_slice();
+    assert_eq!(data, &[82, 6, 1, 4, b'e', b't', b'h', b'0', 0, 255]);
+
+    let mut options = format.options(data, Layer::Dhcp, 240);
+    let option = options.next().unwrap().unwrap();
+    assert_eq!((option.code, option.offset, option.value), (82, 240, &data[2..8]));
+    let sub_options: Result<Vec<_>, _> =
+        format.options(option.value, Layer::Dhcp, option.value_offset()).collect();
+    assert_eq!(sub_options.unwrap()[0].value, b"eth0");
+    assert!(options.next().is_none());
+    assert!(options.ended());
+
+    // the length exceeds the data
+    assert_eq!(format.options(&data[..5], Layer::Dhcp, 240).next(),
+               Some(Err(ParseError::truncated(Layer::Dhcp, 245))));
+}
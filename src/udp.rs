@@ -1,11 +1,12 @@
 use core::convert::TryInto;
 
 use {TxPacket, WriteOut};
-use ip_checksum;
+use ip_checksum::{self, ChecksummedTxPacket};
+#[cfg(any(test, feature = "dhcp"))]
 use dhcp::DhcpPacket;
 use byteorder::{ByteOrder, NetworkEndian};
 use ethernet::{EthernetPacket, EthernetAddress};
-use ipv4::{Ipv4Packet, Ipv4Address};
+use ipv4::{IpProtocol, Ipv4Address, Ipv4Header, Ipv4Packet};
 
 pub fn new_udp_packet<T>(src_mac: EthernetAddress,
                          dst_mac: EthernetAddress,
@@ -26,6 +27,9 @@ pub fn new_udp_packet<T>(src_mac: EthernetAddress,
 pub struct UdpHeader {
     pub src_port: u16,
     pub dst_port: u16,
+    /// Whether the packet carries a checksum. A zero checksum field means that the
+    /// sender didn't compute one, which saves a pass over the payload.
+    pub checksum: bool,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -37,7 +41,11 @@ pub struct UdpPacket<T> {
 impl<T> UdpPacket<T> {
     pub fn new(src_port: u16, dst_port: u16, payload: T) -> Self {
         UdpPacket {
-            header: UdpHeader { src_port, dst_port },
+            header: UdpHeader {
+                src_port,
+                dst_port,
+                checksum: true,
+            },
             payload,
         }
     }
@@ -49,41 +57,141 @@ impl<T: WriteOut> WriteOut for UdpPacket<T> {
     }
 
     fn write_out<P: TxPacket>(&self, packet: &mut P) -> Result<(), ()> {
-        let start_index = packet.len();
+        let mut packet = ChecksummedTxPacket::new(packet);
 
         packet.push_u16(self.header.src_port)?;
         packet.push_u16(self.header.dst_port)?;
         packet.push_u16(self.len().try_into().unwrap())?; // len
         let checksum_idx = packet.push_u16(0)?; // checksum
 
-        self.payload.write_out(packet)?;
-        let end_index = packet.len();
+        let payload_start = packet.len();
+        self.payload.write_out(&mut packet)?;
+        let payload_end = packet.len();
+        packet.mark_payload(payload_start..payload_end);
 
-        // calculate udp checksum (without pseudo header)
-        let checksum = !ip_checksum::data(&packet[start_index..end_index]);
-        packet.set_u16(checksum_idx, checksum);
+        if self.header.checksum {
+            // udp checksum (without pseudo header)
+            let checksum = !packet.checksum();
+            packet.set_u16(checksum_idx, checksum);
+        }
 
         Ok(())
     }
+
+    fn pseudo_header_checksum_offset(&self) -> Option<usize> {
+        if self.header.checksum { Some(6) } else { None }
+    }
 }
 
-use parse::{Parse, ParseError};
+use parse::{Layer, Parse, ParseError};
 
 impl<'a> Parse<'a> for UdpPacket<&'a [u8]> {
     fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
+        if data.len() < 8 {
+            return Err(ParseError::truncated(Layer::Udp, data.len()));
+        }
+        let len = usize::from(NetworkEndian::read_u16(&data[4..6]));
+        if len < 8 {
+            return Err(ParseError::malformed(Layer::Udp, 4, "UDP length smaller than header"));
+        }
+        if data.len() < len {
+            return Err(ParseError::truncated(Layer::Udp, data.len()));
+        }
+
         Ok(UdpPacket {
                header: UdpHeader {
                    src_port: NetworkEndian::read_u16(&data[0..2]),
                    dst_port: NetworkEndian::read_u16(&data[2..4]),
+                   checksum: NetworkEndian::read_u16(&data[6..8]) != 0,
                },
-               payload: &data[8..],
+               payload: &data[8..len],
            })
     }
 }
 
+/// Verifies the checksum of the UDP packet in `data` against the pseudo header of
+/// `ip`. Packets without a checksum are accepted.
+pub fn verify_checksum(ip: &Ipv4Header, data: &[u8]) -> Result<(), ParseError> {
+    let udp = UdpPacket::<&[u8]>::parse(data)?;
+    if !udp.header.checksum {
+        return Ok(());
+    }
+    let len = 8 + udp.payload.len();
+    let checksums = [ip_checksum::data(&data[..len]),
+                     ip_checksum::pseudo_header(&ip.src_addr, &ip.dst_addr, IpProtocol::Udp, len)];
+    if ip_checksum::combine(&checksums) == 0xffff {
+        Ok(())
+    } else {
+        Err(ParseError::checksum(Layer::Udp, 6))
+    }
+}
+
+/// A parser for the payload of a UDP protocol, chosen by port, see `UdpKind` and
+/// `PacketVisitor::udp_decoders`.
+#[derive(Clone, Copy)]
+pub struct UdpDecoder {
+    pub port: u16,
+    pub layer: Layer,
+    /// Validates the payload. Error offsets are relative to its start.
+    pub parse: fn(&[u8]) -> Result<(), ParseError>,
+}
+
+/// The decoders of the enabled protocols. DHCP between ports 67 and 68 is parsed
+/// into `UdpKind::Dhcp` instead.
+pub const DECODERS: &[UdpDecoder] = &[
+    #[cfg(any(test, feature = "dhcp"))]
+    UdpDecoder { port: 67, layer: Layer::Dhcp, parse: parse_dhcp },
+    #[cfg(any(test, feature = "dhcp"))]
+    UdpDecoder { port: 68, layer: Layer::Dhcp, parse: parse_dhcp },
+    #[cfg(any(test, feature = "dns"))]
+    UdpDecoder { port: ::dns::DNS_PORT, layer: Layer::Dns, parse: ::dns::check_message },
+    #[cfg(any(test, feature = "mdns"))]
+    UdpDecoder { port: ::mdns::MDNS_PORT, layer: Layer::Dns, parse: ::dns::check_message },
+    #[cfg(any(test, feature = "sntp"))]
+    UdpDecoder { port: ::sntp::SNTP_PORT, layer: Layer::Ntp, parse: parse_ntp },
+    #[cfg(any(test, feature = "coap"))]
+    UdpDecoder { port: ::coap::COAP_PORT, layer: Layer::Coap, parse: parse_coap },
+    UdpDecoder { port: ::ptp::EVENT_PORT, layer: Layer::Ptp, parse: parse_ptp },
+    UdpDecoder { port: ::ptp::GENERAL_PORT, layer: Layer::Ptp, parse: parse_ptp },
+];
+
+/// Finds the decoder for the destination port of a datagram, or else for its
+/// source port, e.g. for a response from a server.
+pub fn find_decoder<'d>(decoders: &'d [UdpDecoder], udp: &UdpHeader) -> Option<&'d UdpDecoder> {
+    decoders
+        .iter()
+        .find(|d| d.port == udp.dst_port)
+        .or_else(|| decoders.iter().find(|d| d.port == udp.src_port))
+}
+
+#[cfg(any(test, feature = "dhcp"))]
+fn parse_dhcp(data: &[u8]) -> Result<(), ParseError> {
+    DhcpPacket::parse(data).map(|_| ())
+}
+
+#[cfg(any(test, feature = "sntp"))]
+fn parse_ntp(data: &[u8]) -> Result<(), ParseError> {
+    ::sntp::NtpPacket::parse(data).map(|_| ())
+}
+
+#[cfg(any(test, feature = "coap"))]
+fn parse_coap(data: &[u8]) -> Result<(), ParseError> {
+    ::coap::CoapMessage::parse(data).map(|_| ())
+}
+
+fn parse_ptp(data: &[u8]) -> Result<(), ParseError> {
+    ::ptp::PtpMessage::parse(data).map(|_| ())
+}
+
 #[derive(Debug)]
 pub enum UdpKind<'a> {
-    Dhcp(DhcpPacket),
+    #[cfg(any(test, feature = "dhcp"))]
+    Dhcp(DhcpPacket<'a>),
+    /// A payload that the decoder of its port in `DECODERS` accepted, e.g. a DNS
+    /// message, with the layer of the decoder.
+    Decoded(Layer, &'a [u8]),
+    /// Also DHCP messages if the `dhcp` feature is disabled, and payloads that the
+    /// decoder of their port rejected.
     Unknown(&'a [u8]),
 }
 
@@ -91,19 +199,27 @@ impl<'a> Parse<'a> for UdpPacket<UdpKind<'a>> {
     fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
         let udp = UdpPacket::parse(data)?;
 
-        let src_dst = (udp.header.src_port, udp.header.dst_port);
-        if src_dst == (67, 68) || src_dst == (68, 67) {
-            let dhcp = DhcpPacket::parse(udp.payload)?;
-            Ok(UdpPacket {
-                   header: udp.header,
-                   payload: UdpKind::Dhcp(dhcp),
-               })
-        } else {
-            Ok(UdpPacket {
-                   header: udp.header,
-                   payload: UdpKind::Unknown(udp.payload),
-               })
+        #[cfg(any(test, feature = "dhcp"))]
+        {
+            let src_dst = (udp.header.src_port, udp.header.dst_port);
+            if src_dst == (67, 68) || src_dst == (68, 67) {
+                let dhcp = DhcpPacket::parse(udp.payload).map_err(|e| e.at(8))?;
+                return Ok(UdpPacket {
+                              header: udp.header,
+                              payload: UdpKind::Dhcp(dhcp),
+                          });
+            }
         }
+        let payload = match find_decoder(DECODERS, &udp.header) {
+            Some(decoder) if (decoder.parse)(udp.payload).is_ok() => {
+                UdpKind::Decoded(decoder.layer, udp.payload)
+            }
+            _ => UdpKind::Unknown(udp.payload),
+        };
+        Ok(UdpPacket {
+               header: udp.header,
+               payload,
+           })
     }
 }
 
@@ -117,6 +233,7 @@ fn checksum() {
         header: UdpHeader {
             src_port: 53,
             dst_port: 57529,
+            checksum: true,
         },
         payload: Empty,
     };
@@ -138,3 +255,102 @@ fn checksum() {
                HexDumpPrint(data),
                HexDumpPrint(reference_data));
 }
+
+#[test]
+fn parse_ignores_ethernet_padding() {
+    use ethernet::{EthernetAddress, EthernetKind};
+    use ipv4::Ipv4Kind;
+    use parse::parse;
+    use {HeapTxPacket, TxPacket};
+
+    let mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let ip = Ipv4Address::new(192, 168, 1, 2);
+    let payload = [0xde, 0xad, 0xbe, 0xef];
+    let frame = new_udp_packet(mac, mac, ip, ip, 1000, 69, &payload[..]);
+
+    let mut packet = HeapTxPacket::new(60);
+    frame.write_out(&mut packet).unwrap();
+    packet.push_bytes(&[0; 60][frame.len()..]).unwrap();
+
+    match parse(&packet).unwrap().payload {
+        EthernetKind::Ipv4(Ipv4Packet { payload: Ipv4Kind::Udp(udp), .. }) => {
+            match udp.payload {
+                UdpKind::Unknown(data) => assert_eq!(data, &payload),
+                other => panic!("unexpected udp payload {:?}", other),
+            }
+        }
+        other => panic!("unexpected packet {:?}", other),
+    }
+
+    // UDP length larger than the IP payload
+    packet.set_u16(14 + 20 + 4, 100);
+    assert_eq!(parse(&packet).unwrap_err(),
+               ParseError::truncated(Layer::Udp, 14 + 20 + 12));
+}
+
+#[test]
+fn zero_checksum() {
+    use ethernet::EthernetAddress;
+    use HeapTxPacket;
+
+    let mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let ip = Ipv4Address::new(192, 168, 1, 2);
+    let serialize = |payload: [u8; 2], checksum| {
+        let mut frame = new_udp_packet(mac, mac, ip, ip, 1000, 7, &payload[..]);
+        frame.payload.payload.header.checksum = checksum;
+        let mut packet = HeapTxPacket::new(frame.len());
+        frame.write_out(&mut packet).unwrap();
+        packet.to_vec()
+    };
+
+    let packet = serialize([0, 0], false);
+    assert_eq!(&packet[40..42], &[0, 0]);
+    let ip_header = Ipv4Packet::<&[u8]>::parse(&packet[14..]).unwrap().header;
+    assert!(!UdpPacket::<&[u8]>::parse(&packet[34..]).unwrap().header.checksum);
+    assert_eq!(verify_checksum(&ip_header, &packet[34..]), Ok(()));
+
+    // a payload equal to the checksum of a zero payload makes the computed checksum 0,
+    // which is sent as 0xffff
+    let packet = serialize([0, 0], true);
+    let payload = [packet[40], packet[41]];
+    let packet = serialize(payload, true);
+    assert_eq!(&packet[40..42], &[0xff, 0xff]);
+    assert_eq!(verify_checksum(&ip_header, &packet[34..]), Ok(()));
+
+    let mut data = packet[34..].to_vec();
+    data[8] ^= 0x01;
+    assert_eq!(verify_checksum(&ip_header, &data),
+               Err(ParseError::checksum(Layer::Udp, 6)));
+}
+
+#[test]
+fn decoded_payloads() {
+    use ethernet::{EthernetAddress, EthernetKind};
+    use ipv4::Ipv4Kind;
+    use parse::parse;
+    use testing::frames;
+    use HeapTxPacket;
+
+    let udp_payload = |frame: &[u8]| -> Option<(Option<Layer>, usize)> {
+        match parse(frame).unwrap().payload {
+            EthernetKind::Ipv4(Ipv4Packet { payload: Ipv4Kind::Udp(udp), .. }) => {
+                match udp.payload {
+                    UdpKind::Decoded(layer, data) => Some((Some(layer), data.len())),
+                    UdpKind::Unknown(data) => Some((None, data.len())),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    };
+
+    let dns_reply = hex!(frames::DNS_REPLY);
+    assert_eq!(udp_payload(&dns_reply), Some((Some(Layer::Dns), dns_reply.len() - 42)));
+
+    // a payload that its decoder rejects isn't an error
+    let mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let ip = Ipv4Address::new(192, 168, 1, 2);
+    let frame = new_udp_packet(mac, mac, ip, ip, 123, 1000, &b"junk"[..]);
+    let frame = HeapTxPacket::write_out(frame).unwrap().to_vec();
+    assert_eq!(udp_payload(&frame), Some((None, 4)));
+}
@@ -0,0 +1,198 @@
+//! UDP-Lite according to [RFC 3828][rfc3828].
+//!
+//! Unlike UDP, the checksum may cover only the start of a datagram, so that
+//! payloads with bit errors in the rest are still delivered. The checksum itself is
+//! mandatory.
+//!
+//! [rfc3828]: https://tools.ietf.org/html/rfc3828
+
+use {TxPacket, WriteOut};
+use byteorder::{ByteOrder, NetworkEndian};
+use ip_checksum::{self, ChecksummedTxPacket};
+use ipv4::{IpProtocol, Ipv4Header};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdpLiteHeader {
+    pub src_port: u16,
+    pub dst_port: u16,
+    /// The number of bytes covered by the checksum, including the 8 byte header. 0
+    /// covers the whole datagram.
+    pub checksum_coverage: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdpLitePacket<T> {
+    pub header: UdpLiteHeader,
+    pub payload: T,
+}
+
+impl<T> UdpLitePacket<T> {
+    /// Creates a datagram whose checksum covers the header and the first
+    /// `covered_payload` bytes of the payload.
+    pub fn new(src_port: u16, dst_port: u16, covered_payload: u16, payload: T) -> Self {
+        UdpLitePacket {
+            header: UdpLiteHeader {
+                src_port,
+                dst_port,
+                checksum_coverage: 8 + covered_payload,
+            },
+            payload,
+        }
+    }
+}
+
+impl<T: WriteOut> UdpLitePacket<T> {
+    /// The number of bytes covered by the checksum, or `None` if the coverage is
+    /// invalid for this datagram.
+    fn covered_len(&self) -> Option<usize> {
+        match usize::from(self.header.checksum_coverage) {
+            0 => Some(self.len()),
+            coverage if coverage < 8 || coverage > self.len() => None,
+            coverage => Some(coverage),
+        }
+    }
+}
+
+impl<T: WriteOut> WriteOut for UdpLitePacket<T> {
+    fn len(&self) -> usize {
+        self.payload.len() + 4 * 2
+    }
+
+    fn write_out<P: TxPacket>(&self, packet: &mut P) -> Result<(), ()> {
+        let covered_len = match self.covered_len() {
+            Some(len) => len,
+            None => return Err(()),
+        };
+        let mut packet = ChecksummedTxPacket::with_limit(packet, covered_len);
+
+        packet.push_u16(self.header.src_port)?;
+        packet.push_u16(self.header.dst_port)?;
+        packet.push_u16(self.header.checksum_coverage)?;
+        let checksum_idx = packet.push_u16(0)?; // checksum
+
+        let payload_start = packet.len();
+        self.payload.write_out(&mut packet)?;
+        let payload_end = packet.len();
+        packet.mark_payload(payload_start..payload_end);
+
+        // checksum without pseudo header
+        let checksum = packet.checksum();
+        packet.set_u16(checksum_idx, !checksum);
+
+        Ok(())
+    }
+
+    /// The checksum is mandatory, so it is never zero.
+    fn pseudo_header_checksum_offset(&self) -> Option<usize> {
+        Some(6)
+    }
+}
+
+use parse::{Layer, Parse, ParseError};
+
+impl<'a> Parse<'a> for UdpLitePacket<&'a [u8]> {
+    fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
+        if data.len() < 8 {
+            return Err(ParseError::truncated(Layer::UdpLite, data.len()));
+        }
+        let checksum_coverage = NetworkEndian::read_u16(&data[4..6]);
+        match usize::from(checksum_coverage) {
+            0 => {}
+            1...7 => {
+                return Err(ParseError::malformed(Layer::UdpLite,
+                                                 4,
+                                                 "UDP-Lite coverage smaller than header"));
+            }
+            coverage if coverage > data.len() => {
+                return Err(ParseError::malformed(Layer::UdpLite,
+                                                 4,
+                                                 "UDP-Lite coverage exceeds datagram"));
+            }
+            _ => {}
+        }
+
+        Ok(UdpLitePacket {
+               header: UdpLiteHeader {
+                   src_port: NetworkEndian::read_u16(&data[0..2]),
+                   dst_port: NetworkEndian::read_u16(&data[2..4]),
+                   checksum_coverage,
+               },
+               payload: &data[8..],
+           })
+    }
+}
+
+/// Verifies the checksum of the UDP-Lite datagram in `data`, which is the complete
+/// IP payload, against the pseudo header of `ip`. Bytes outside of the checksum
+/// coverage are not checked.
+pub fn verify_checksum(ip: &Ipv4Header, data: &[u8]) -> Result<(), ParseError> {
+    let udp = UdpLitePacket::<&[u8]>::parse(data)?;
+    if NetworkEndian::read_u16(&data[6..8]) == 0 {
+        return Err(ParseError::malformed(Layer::UdpLite, 6, "missing UDP-Lite checksum"));
+    }
+    let covered_len = match udp.header.checksum_coverage {
+        0 => data.len(),
+        coverage => usize::from(coverage),
+    };
+    let checksums = [ip_checksum::data(&data[..covered_len]),
+                     ip_checksum::pseudo_header(&ip.src_addr,
+                                                &ip.dst_addr,
+                                                IpProtocol::UdpLite,
+                                                data.len())];
+    if ip_checksum::combine(&checksums) == 0xffff {
+        Ok(())
+    } else {
+        Err(ParseError::checksum(Layer::UdpLite, 6))
+    }
+}
+
+#[test]
+fn partial_coverage() {
+    use ethernet::{EthernetAddress, EthernetKind, EthernetPacket};
+    use ipv4::{Ipv4Address, Ipv4Kind, Ipv4Packet};
+    use packet_memory::MemoryTxPacket;
+    use HeapTxPacket;
+
+    let mac = EthernetAddress::new([0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef]);
+    let src = Ipv4Address::new(192, 168, 1, 100);
+    let dst = Ipv4Address::new(192, 168, 1, 1);
+    let data = [0x10, 0x20, 0x30, 0x40, 0x50, 0x60];
+    let udp = UdpLitePacket::new(5004, 5004, 2, &data[..]);
+    let ip = Ipv4Packet::new_udp_lite(src, dst, udp);
+    let packet = HeapTxPacket::write_out(EthernetPacket::new_ipv4(mac, mac, ip)).unwrap();
+    let mut frame = packet.to_vec();
+    frame.resize(60, 0); // padding
+
+    let parse_payload = |frame: &[u8]| -> Result<Vec<u8>, ParseError> {
+        match EthernetPacket::<EthernetKind>::parse(frame)?.payload {
+            EthernetKind::Ipv4(Ipv4Packet { payload: Ipv4Kind::UdpLite(udp), .. }) => {
+                Ok(udp.payload.to_vec())
+            }
+            other => panic!("unexpected payload {:?}", other),
+        }
+    };
+    assert_eq!(parse_payload(&frame).unwrap(), &data[..]);
+
+    // damage outside of the coverage is accepted
+    frame[14 + 20 + 8 + 5] ^= 0xff;
+    assert_eq!(parse_payload(&frame).unwrap()[5], 0x60 ^ 0xff);
+    frame[14 + 20 + 8 + 1] ^= 0xff;
+    match parse_payload(&frame).unwrap_err() {
+        ParseError::Checksum { layer: Layer::UdpLite, .. } => {}
+        other => panic!("unexpected error {:?}", other),
+    }
+
+    // a coverage that exceeds a read of packet memory
+    let long = [0x5a; 100];
+    let udp = UdpLitePacket::new(5004, 5004, 80, &long[..]);
+    let mut memory = [0; 108];
+    udp.write_out(&mut MemoryTxPacket::new(&mut memory[..], 0, 108)).unwrap();
+    let mut reference = HeapTxPacket::new(udp.len());
+    udp.write_out(&mut reference).unwrap();
+    assert_eq!(&memory[..], reference.as_slice());
+    assert_eq!(ip_checksum::data(&memory[..88]), 0xffff);
+
+    // the coverage exceeds the datagram
+    let invalid = UdpLitePacket::new(5004, 5004, 7, &data[..]);
+    assert!(invalid.write_out(&mut HeapTxPacket::new(invalid.len())).is_err());
+}
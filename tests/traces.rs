@@ -0,0 +1,237 @@
+//! Replays the pcap files in `tests/traces` through the parser and state machines.
+//!
+//! `ping_linux.pcap` and `tcp_session_curl.pcap` are captures of a Linux host (kernel
+//! 6.x) talking to the device of the test over a TAP interface: echo requests with
+//! the payload of iputils `ping`, and `curl --http0.9` fetching from the echo server.
+//! The server frames of `dhcp_handshake.golden.pcap` are still synthetic, it should
+//! be replaced by a capture of a real server, e.g. dnsmasq.
+//!
+//! The frames of the peer are passed to a device, and the frames that the device sends
+//! in response must match the frames it sent in the trace, byte for byte and in the
+//! same order. After an intended change of the output, run the tests with
+//! `NET_RECORD_TRACES=1` to regenerate the device frames, and review the new files in
+//! Wireshark. The frames of the peer are never rewritten, so changes that the peer
+//! would have answered differently, e.g. of the TCP sequence numbers, need a new
+//! capture.
+
+extern crate net;
+
+use net::dhcp::{self, DhcpType};
+use net::ethernet::{EthernetAddress, EthernetKind};
+use net::icmp::IcmpKind;
+use net::interface::Interface;
+use net::ipv4::{Endpoint, Ipv4Address, Ipv4Kind};
+use net::tcp::TcpListener;
+use net::udp::UdpKind;
+use net::{parse, HeapTxPacket};
+use std::borrow::Cow;
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const LINKTYPE_ETHERNET: u32 = 1;
+
+/// A frame and its capture time in microseconds.
+type Record = (u64, Vec<u8>);
+
+fn read_u32(data: &[u8]) -> u32 {
+    u32::from(data[0]) | u32::from(data[1]) << 8 | u32::from(data[2]) << 16 |
+    u32::from(data[3]) << 24
+}
+
+/// Reads a little-endian pcap file with microsecond timestamps.
+fn read_pcap(name: &str) -> Vec<Record> {
+    let mut data = Vec::new();
+    File::open(trace_path(name)).and_then(|mut file| file.read_to_end(&mut data)).unwrap();
+    assert_eq!(read_u32(&data[0..4]), PCAP_MAGIC, "{}: not a little-endian pcap file", name);
+    assert_eq!(read_u32(&data[20..24]), LINKTYPE_ETHERNET, "{}: not an Ethernet trace", name);
+
+    let mut records = Vec::new();
+    let mut offset = 24;
+    while offset < data.len() {
+        let header = &data[offset..offset + 16];
+        let time = u64::from(read_u32(&header[0..4])) * 1_000_000 +
+                   u64::from(read_u32(&header[4..8]));
+        let len = read_u32(&header[8..12]) as usize;
+        assert_eq!(len, read_u32(&header[12..16]) as usize, "{}: truncated frame", name);
+        records.push((time, data[offset + 16..offset + 16 + len].to_vec()));
+        offset += 16 + len;
+    }
+    records
+}
+
+fn write_pcap(name: &str, records: &[Record]) {
+    let mut data = Vec::new();
+    for &field in &[PCAP_MAGIC, 0x0004_0002, 0, 0, 65535, LINKTYPE_ETHERNET] {
+        data.extend_from_slice(&field.to_le_bytes());
+    }
+    for &(time, ref frame) in records {
+        for &field in &[(time / 1_000_000) as u32,
+                        (time % 1_000_000) as u32,
+                        frame.len() as u32,
+                        frame.len() as u32] {
+            data.extend_from_slice(&field.to_le_bytes());
+        }
+        data.extend_from_slice(frame);
+    }
+    File::create(trace_path(name)).and_then(|mut file| file.write_all(&data)).unwrap();
+}
+
+fn trace_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/traces").join(name)
+}
+
+/// The side of a trace whose frames are generated by this crate.
+trait Device {
+    fn mac(&self) -> EthernetAddress;
+
+    /// The frames sent before anything was received.
+    fn start(&mut self) -> Vec<Vec<u8>> {
+        Vec::new()
+    }
+
+    /// Handles a frame of the peer, `now` is in milliseconds since the first frame.
+    fn receive(&mut self, frame: &[u8], now: u64) -> Vec<Vec<u8>>;
+}
+
+fn replay<D: Device>(name: &str, mut device: D) {
+    let trace = read_pcap(name);
+    let mac = device.mac().as_bytes();
+    let start = trace.first().map_or(0, |record| record.0);
+
+    let mut replayed: Vec<Record> =
+        device.start().into_iter().map(|frame| (start, frame)).collect();
+    for &(time, ref frame) in trace.iter().filter(|record| record.1[6..12] != mac) {
+        replayed.push((time, frame.clone()));
+        for response in device.receive(frame, (time - start) / 1000) {
+            replayed.push((time, response));
+        }
+    }
+
+    if env::var_os("NET_RECORD_TRACES").is_some() {
+        write_pcap(name, &replayed);
+        return;
+    }
+    for (i, (replayed, recorded)) in replayed.iter().zip(&trace).enumerate() {
+        assert_eq!(replayed.1, recorded.1, "{}: frame {} differs", name, i + 1);
+    }
+    assert_eq!(replayed.len(), trace.len(), "{}: different number of frames", name);
+}
+
+const CLIENT_MAC: [u8; 6] = [0x00, 0x08, 0xdc, 0xab, 0xcd, 0xef];
+const ROUTER_MAC: [u8; 6] = [0x00, 0x11, 0x22, 0x33, 0x44, 0x55];
+
+/// A DHCP client that requests the first offer.
+struct DhcpClient;
+
+impl Device for DhcpClient {
+    fn mac(&self) -> EthernetAddress {
+        EthernetAddress::new(CLIENT_MAC)
+    }
+
+    fn start(&mut self) -> Vec<Vec<u8>> {
+        let discover = dhcp::new_discover_msg(self.mac());
+        vec![HeapTxPacket::write_out(discover).unwrap().to_vec()]
+    }
+
+    fn receive(&mut self, frame: &[u8], _now: u64) -> Vec<Vec<u8>> {
+        let dhcp = match parse(frame).unwrap().payload {
+            EthernetKind::Ipv4(ip) => {
+                match ip.payload {
+                    Ipv4Kind::Udp(udp) => {
+                        match udp.payload {
+                            UdpKind::Dhcp(dhcp) => dhcp,
+                            _ => return Vec::new(),
+                        }
+                    }
+                    _ => return Vec::new(),
+                }
+            }
+            _ => return Vec::new(),
+        };
+        match dhcp.operation {
+            DhcpType::Offer { ip, dhcp_server_ip } => {
+                let request = dhcp::new_request_msg(self.mac(), ip, dhcp_server_ip);
+                vec![HeapTxPacket::write_out(request).unwrap().to_vec()]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// An application that answers echo requests with `IcmpPacket::echo_reply_packet`.
+struct PingResponder;
+
+impl Device for PingResponder {
+    fn mac(&self) -> EthernetAddress {
+        EthernetAddress::new(ROUTER_MAC)
+    }
+
+    fn receive(&mut self, frame: &[u8], _now: u64) -> Vec<Vec<u8>> {
+        let packet = parse(frame).unwrap();
+        if let EthernetKind::Ipv4(ip) = packet.payload {
+            if let Ipv4Kind::Icmp(icmp @ IcmpKind::EchoRequest { .. }) = ip.payload {
+                let request = icmp.packet().unwrap();
+                let reply = request.echo_reply_packet(self.mac(),
+                                                      packet.header.src_addr,
+                                                      ip.header.dst_addr,
+                                                      ip.header.src_addr);
+                return vec![HeapTxPacket::write_out(reply).unwrap().to_vec()];
+            }
+        }
+        Vec::new()
+    }
+}
+
+/// An `Interface` with an HTTP-like server that echoes requests and closes the
+/// connection after the request.
+struct EchoServer(Interface);
+
+impl EchoServer {
+    fn new() -> EchoServer {
+        let ip = Ipv4Address::new(192, 168, 1, 1);
+        let mut interface = Interface::new(EthernetAddress::new(ROUTER_MAC), ip);
+        interface.arp_cache_insert(Ipv4Address::new(192, 168, 1, 100),
+                                   EthernetAddress::new(CLIENT_MAC));
+        let mut listener = TcpListener::new(Endpoint::new(ip, 80), 4, 2);
+        listener.set_close_on_peer_fin(true);
+        interface.add_tcp_listener(listener, |_, data| Some(Cow::Borrowed(data)));
+        EchoServer(interface)
+    }
+}
+
+impl Device for EchoServer {
+    fn mac(&self) -> EthernetAddress {
+        self.0.mac()
+    }
+
+    fn receive(&mut self, frame: &[u8], now: u64) -> Vec<Vec<u8>> {
+        // advance the timers first, like a device that polls until the frame arrives
+        let mut frames = Vec::new();
+        while let Some(frame) = self.0.poll(now) {
+            frames.push(frame.to_vec());
+        }
+        self.0.receive(frame).unwrap();
+        while let Some(frame) = self.0.poll(now) {
+            frames.push(frame.to_vec());
+        }
+        frames
+    }
+}
+
+#[test]
+fn dhcp_handshake() {
+    replay("dhcp_handshake.golden.pcap", DhcpClient);
+}
+
+#[test]
+fn ping() {
+    replay("ping_linux.pcap", PingResponder);
+}
+
+#[test]
+fn tcp_session() {
+    replay("tcp_session_curl.pcap", EchoServer::new());
+}